@@ -2087,6 +2087,7 @@ rec {
         src = if ((lib.versionOlder builtins.nixVersion "2.4pre20211007") || (lib.versionOlder "2.5" builtins.nixVersion ))
           then lib.cleanSourceWith { filter = sourceFilter;  src = ./.; }
           else ./.;
+        libName = "nix_du";
         authors = [
           "Guillaume Girol <symphorien+git@xlumurb.eu>"
         ];
@@ -2100,6 +2101,10 @@ rec {
             packageId = "clap";
             features = [ "derive" "suggestions" "color" "wrap_help" ];
           }
+          {
+            name = "console";
+            packageId = "console";
+          }
           {
             name = "dashmap";
             packageId = "dashmap";
@@ -2120,10 +2125,19 @@ rec {
             name = "lazy_static";
             packageId = "lazy_static";
           }
+          {
+            name = "libc";
+            packageId = "libc";
+          }
           {
             name = "petgraph";
             packageId = "petgraph";
           }
+          {
+            name = "rand";
+            packageId = "rand";
+            optional = true;
+          }
           {
             name = "rayon";
             packageId = "rayon";
@@ -2173,7 +2187,10 @@ rec {
             packageId = "which";
           }
         ];
-
+        features = {
+          "selftest" = [ "dep:rand" ];
+          "store-selftest" = [ "selftest" ];
+        };
       };
       "nom" = rec {
         crateName = "nom";
@@ -53,7 +53,10 @@ fn main() {
         // bindings for.
         .header("wrapper.hpp")
         .allowlist_function("populateGraph")
+        .allowlist_function("addTextPath")
+        .allowlist_function("addRoot")
         .allowlist_type("path_t")
+        .allowlist_type("store_error_t")
         .opaque_type("std::.*")
         .clang_arg(format!("-DNIXVER={}", version))
         .clang_arg(standard)
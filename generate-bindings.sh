@@ -12,6 +12,8 @@ nixver="$(pkg-config --modversion nix-main | cut -d. -f 1,2 | tr . 0)"
 bindgen \
     --impl-debug \
     --whitelist-function populateGraph \
+    --whitelist-function addTextPath \
+    --whitelist-function addRoot \
     --whitelist-type path_t \
     --opaque-type 'std::.*' \
     wrapper.hpp \
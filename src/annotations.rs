@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead};
+use std::path::Path;
+
+/// Human descriptions of gc-roots, given by the user as a list of globs.
+///
+/// The file format is one `GLOB = DESCRIPTION` per line, for example
+/// `/home/ci/.cache/** = CI runner cache`. Empty lines and lines starting with `#` are
+/// ignored. In globs, `*` and `?` do not match `/` but `**` does, and a leading `~`
+/// stands for `$HOME`. When several globs match, the first one wins.
+///
+/// Descriptions of exact paths, like the nixpkgs attribute a store path was built from, can
+/// also be added; they take precedence over globs.
+#[derive(Debug, Clone, Default)]
+pub struct Annotations {
+    globs: Vec<(regex::bytes::Regex, String)>,
+    exact: BTreeMap<Vec<u8>, String>,
+}
+
+/// translates a glob to an anchored regex
+fn glob_to_regex(glob: &str) -> String {
+    let mut res = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                res.push_str(".*");
+            }
+            '*' => res.push_str("[^/]*"),
+            '?' => res.push_str("[^/]"),
+            c => res.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    res.push('$');
+    res
+}
+
+/// Compiles a glob following the rules described for `Annotations` to a regex matching whole paths.
+pub fn compile_glob(glob: &str) -> Result<regex::bytes::Regex, regex::Error> {
+    let glob = match (glob.strip_prefix('~'), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}{}", home, rest),
+        _ => glob.to_owned(),
+    };
+    regex::bytes::Regex::new(&glob_to_regex(&glob))
+}
+
+impl Annotations {
+    /// Parses annotations in the format described above.
+    pub fn parse<R: BufRead>(r: R) -> io::Result<Self> {
+        let mut res = Vec::new();
+        for (i, line) in r.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |msg: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", i + 1, msg),
+                )
+            };
+            let (glob, description) = line
+                .split_once(" = ")
+                .ok_or_else(|| invalid("expected `GLOB = DESCRIPTION`".into()))?;
+            let regex = compile_glob(glob.trim_end()).map_err(|e| invalid(e.to_string()))?;
+            res.push((regex, description.trim_start().to_owned()));
+        }
+        Ok(Annotations {
+            globs: res,
+            exact: BTreeMap::new(),
+        })
+    }
+
+    /// Reads annotations from a file.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    /// Describes the paths of `descriptions` exactly
+    pub fn extend_exact<I: IntoIterator<Item = (Vec<u8>, String)>>(&mut self, descriptions: I) {
+        self.exact.extend(descriptions);
+    }
+
+    /// Returns the description of `path`, or else of the first glob matching it
+    pub fn lookup(&self, path: &[u8]) -> Option<&str> {
+        if let Some(description) = self.exact.get(path) {
+            return Some(description);
+        }
+        self.globs
+            .iter()
+            .find(|(regex, _)| regex.is_match(path))
+            .map(|(_, description)| description.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::annotations::*;
+
+    #[test]
+    fn check_annotations() {
+        let text = "# comment\n\n/home/*/result = project\n/home/ci/** = CI cache\n";
+        let a = Annotations::parse(text.as_bytes()).unwrap();
+        assert_eq!(a.lookup(b"/home/alice/result"), Some("project"));
+        assert_eq!(a.lookup(b"/home/ci/result"), Some("project"));
+        assert_eq!(a.lookup(b"/home/ci/a/b/result"), Some("CI cache"));
+        assert_eq!(a.lookup(b"/home/alice/a/result"), None);
+        assert!(Annotations::parse("no separator".as_bytes()).is_err());
+        let mut a = a;
+        a.extend_exact(vec![(b"/home/ci/result".to_vec(), "exact".to_owned())]);
+        assert_eq!(a.lookup(b"/home/ci/result"), Some("exact"));
+    }
+}
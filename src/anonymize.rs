@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Replacing what identifies a machine or its users in a graph, so that it can be shared.
+//!
+//! Store hashes, user names, host names and the components of the paths of gc-roots are
+//! replaced by pseudonyms. Pseudonyms are stable: the same input is always replaced by the
+//! same pseudonym, so the structure of the graph is kept. Names of packages and sizes are
+//! kept as they are what makes the graph useful.
+
+use crate::depgraph::*;
+use crate::stableid::{fnv, FNV_OFFSET};
+use lazy_static::lazy_static;
+
+/// the digits of nix base32
+const BASE32: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Returns a pseudonym of `len` base32 chars for `value`. It is a hash of `value` with
+/// FNV-1a, the same with every version of rust.
+fn pseudonym(value: &[u8], len: usize) -> Vec<u8> {
+    let mut res = Vec::with_capacity(len);
+    let mut round = 0u64;
+    while res.len() < len {
+        let mut h = fnv(fnv(FNV_OFFSET, &round.to_le_bytes()), value);
+        for _ in 0..12 {
+            if res.len() == len {
+                break;
+            }
+            res.push(BASE32[(h & 31) as usize]);
+            h >>= 5;
+        }
+        round += 1;
+    }
+    res
+}
+
+/// Replaces the host name contained in the name of a store path, if any.
+fn anonymize_name(name: &[u8]) -> Vec<u8> {
+    lazy_static! {
+        static ref NIXOS_SYSTEM: regex::bytes::Regex =
+            regex::bytes::Regex::new(r"^(nixos-system-)([^-]+)(-[0-9].*)$")
+                .expect("regex compilation failed");
+    }
+    match NIXOS_SYSTEM.captures(name) {
+        Some(c) => {
+            let mut res = c[1].to_vec();
+            res.extend_from_slice(b"host-");
+            res.extend(pseudonym(&c[2], 6));
+            res.extend_from_slice(&c[3]);
+            res
+        }
+        None => name.to_vec(),
+    }
+}
+
+/// Anonymizes one component of a path. `after` is the previous component.
+fn anonymize_component(component: &[u8], after: &[u8]) -> Vec<u8> {
+    lazy_static! {
+        static ref STORE_NAME: regex::bytes::Regex =
+            regex::bytes::Regex::new(r"^[0-9abcdfghijklmnpqrsvwxyz]{32}-(.*)$")
+                .expect("regex compilation failed");
+        /// components which say nothing about the machine
+        static ref GENERIC: regex::bytes::Regex = regex::bytes::Regex::new(
+            r"^(|nix|store|var|gcroots|auto|profiles|per-user|home|Users|run|proc|root|tmp|\.nix-profile|\.local|state|\.cache|lorri|gc_roots|gc_root|shell_gc_root|nix_file|result(-[a-z]+)?|current-system|booted-system|sw|profile|channels|home-manager|[0-9]+|[a-z]+(-[0-9]+)?-link|\{[a-z]+(:[0-9]+)?\}|maps|environ|cmdline|exe|fd)$"
+        )
+        .expect("regex compilation failed");
+    }
+    if matches!(after, b"home" | b"Users" | b"per-user") && !component.is_empty() {
+        let mut res = b"user-".to_vec();
+        res.extend(pseudonym(component, 6));
+        return res;
+    }
+    if let Some(c) = STORE_NAME.captures(component) {
+        let mut res = pseudonym(&component[..32], 32);
+        res.push(b'-');
+        res.extend(anonymize_name(&c[1]));
+        return res;
+    }
+    if GENERIC.is_match(component) {
+        return component.to_vec();
+    }
+    pseudonym(component, 8)
+}
+
+/// Anonymizes all the components of a path
+pub fn anonymize_path(path: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(path.len());
+    let mut previous: &[u8] = b"";
+    for (i, component) in path.split(|&c| c == b'/').enumerate() {
+        if i > 0 {
+            res.push(b'/');
+        }
+        res.extend(anonymize_component(component, previous));
+        previous = component;
+    }
+    res
+}
+
+/// Anonymizes the descriptions of all nodes of `di`.
+pub fn anonymize(di: &mut DepInfos) {
+    use self::NodeDescription::*;
+    for node in di.graph.node_weights_mut() {
+        node.description = match &node.description {
+            Path(path) => Path(anonymize_path(path)),
+            Link(path) => Link(anonymize_path(path)),
+            Memory(path) => Memory(anonymize_path(path)),
+            Temporary(path) => Temporary(anonymize_path(path)),
+            File(path) => File(anonymize_path(path)),
+            Derivation(path) => Derivation(anonymize_path(path)),
+            Shared(name) => Shared(anonymize_name(name)),
+            Group(prefix) => Group(anonymize_path(prefix)),
+            Generations(profile, count) => Generations(anonymize_path(profile), *count),
+            Other(label) => Other(anonymize_path(label)),
+            Package(name, count) => Package(anonymize_name(name), *count),
+            // messages name the paths concerned
+            Error(_) => Error(Vec::new()),
+            d @ (Dummy | FilteredOut | Transient | Process(_) | System(_) | Common) => d.clone(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::anonymize::*;
+
+    #[test]
+    fn check_anonymize_path() {
+        let path = b"/nix/store/0123456789abcdfghijklmnpqrsvwxyz-nixos-system-myhost-23.11pre";
+        let anonymized = anonymize_path(path);
+        assert_eq!(anonymized, anonymize_path(path));
+        let anonymized = String::from_utf8(anonymized).unwrap();
+        assert!(anonymized.starts_with("/nix/store/"));
+        assert!(!anonymized.contains("0123456789abcdfghijklmnpqrsvwxyz"));
+        assert!(!anonymized.contains("myhost"));
+        assert!(anonymized.contains("-nixos-system-host-"));
+        assert!(anonymized.ends_with("-23.11pre"));
+        assert_eq!(
+            anonymized.len(),
+            path.len() + "host-".len() + 6 - "myhost".len()
+        );
+
+        let link = String::from_utf8(anonymize_path(b"/home/alice/src/secret/result")).unwrap();
+        assert!(link.starts_with("/home/user-"));
+        assert!(link.ends_with("/result"));
+        assert!(!link.contains("alice") && !link.contains("secret"));
+
+        let profile = b"/nix/var/nix/profiles/per-user/alice/profile-12-link";
+        let anonymized = String::from_utf8(anonymize_path(profile)).unwrap();
+        assert!(anonymized.starts_with("/nix/var/nix/profiles/per-user/user-"));
+        assert!(anonymized.ends_with("/profile-12-link"));
+        // the same user has the same pseudonym everywhere
+        assert_eq!(link[6..17], anonymized[31..42]);
+        // pseudonyms do not depend on the version of rust
+        assert_eq!(pseudonym(b"alice", 6), b"7i6rl3".to_vec());
+    }
+}
@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Copying the closure of a gc-root to another store, like a binary cache on a backup disk,
+//! before removing the gc-root to free space.
+
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// How many symlinks to follow at most, like the kernel
+const MAX_LINKS: usize = 40;
+
+/// Returns the directory of the store
+pub fn store_dir() -> PathBuf {
+    std::env::var_os("NIX_STORE_DIR")
+        .unwrap_or_else(|| "/nix/store".into())
+        .into()
+}
+
+/// Follows the symlinks from `root` until a path of `store` is reached, and returns the store
+/// path it belongs to. The store path itself need not exist.
+pub fn store_path_of(root: &Path, store: &Path) -> io::Result<PathBuf> {
+    let mut current = root.to_path_buf();
+    for _ in 0..MAX_LINKS {
+        if let Ok(rest) = current.strip_prefix(store) {
+            return match rest.components().next() {
+                Some(Component::Normal(name)) => Ok(store.join(name)),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is the store itself", root.display()),
+                )),
+            };
+        }
+        let target = std::fs::read_link(&current).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "{} does not lead to {}: {}",
+                    root.display(),
+                    store.display(),
+                    e
+                ),
+            )
+        })?;
+        current = match current.parent() {
+            Some(parent) => parent.join(target),
+            None => target,
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("too many levels of symbolic links from {}", root.display()),
+    ))
+}
+
+/// Copies the closure of the store path retained by `root` to the store `to`, with
+/// `nix copy`, and then removes `root` if `remove` is set. Reports progress to `w`.
+pub fn run<W: Write>(root: &Path, to: &str, remove: bool, w: &mut W) -> io::Result<()> {
+    let path = store_path_of(root, &store_dir())?;
+    if remove && !root.symlink_metadata()?.file_type().is_symlink() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is not a symlink, only symlinks can be removed",
+                root.display()
+            ),
+        ));
+    }
+    writeln!(w, "Copying the closure of {} to {}...", path.display(), to)?;
+    let status = crate::preview::nix()
+        .args(["copy", "--to", to])
+        .arg(&path)
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "nix copy failed with {}, {} was kept",
+            status,
+            root.display()
+        )));
+    }
+    if remove {
+        std::fs::remove_file(root)?;
+        writeln!(
+            w,
+            "Removed {}. Run nix-collect-garbage to free the space.",
+            root.display()
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::*;
+
+    #[test]
+    fn check_store_path_of() {
+        let dir = std::env::temp_dir().join(format!("nix-du-archive-{}", std::process::id()));
+        let store = dir.join("store");
+        std::fs::create_dir_all(&store).unwrap();
+        // like a profile: a relative link to a generation, which links into the store
+        std::os::unix::fs::symlink(store.join("aaaa-foo/bin/foo"), dir.join("profile-1-link"))
+            .unwrap();
+        std::os::unix::fs::symlink("profile-1-link", dir.join("profile")).unwrap();
+        assert_eq!(
+            store_path_of(&dir.join("profile"), &store).unwrap(),
+            store.join("aaaa-foo")
+        );
+        assert!(store_path_of(&dir, &store).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Mapping store paths back to the nixpkgs attributes they are built from, like
+//! `haskellPackages.pandoc`, which tells users what to remove from their configuration.
+//!
+//! Evaluating the output path of every package of nixpkgs is slow, so `nix eval` only
+//! evaluates that of the packages whose name is the name of a store path of the graph.
+
+use crate::depgraph::*;
+use crate::json::{self, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+/// The package sets of nixpkgs searched in addition to the top-level attributes
+pub const PACKAGE_SETS: &[&str] = &[
+    "haskellPackages",
+    "python3Packages",
+    "perlPackages",
+    "nodePackages",
+    "ocamlPackages",
+];
+
+/// Returns the Nix expression listing, as `{ attr, path }`, the attributes of the nixpkgs
+/// `nixpkgs` and of its `sets` whose derivation has a name listed in the JSON file named
+/// by the environment variable `NIX_DU_NAMES`.
+fn expression(nixpkgs: &str, sets: &[&str]) -> String {
+    let sets: Vec<String> = sets.iter().map(|s| format!("\"{}\"", s)).collect();
+    format!(
+        r#"let
+  pkgs = import ({}) {{ }};
+  wanted = builtins.listToAttrs (map (name: {{ inherit name; value = true; }})
+    (builtins.fromJSON (builtins.readFile (builtins.getEnv "NIX_DU_NAMES"))));
+  found = prefix: set: builtins.concatMap (attr:
+    let
+      r = builtins.tryEval (let
+        v = set.${{attr}};
+        res = if builtins.isAttrs v && (v.type or "") == "derivation" && wanted ? ${{v.name}}
+          then [ {{ attr = prefix + attr; path = v.outPath; }} ] else [ ];
+      in builtins.deepSeq res res);
+    in if r.success then r.value else [ ]) (builtins.attrNames set);
+in found "" pkgs ++ builtins.concatMap
+  (s: let r = builtins.tryEval (pkgs.${{s}} or null); in
+    if r.success && builtins.isAttrs r.value then found (s + ".") r.value else [ ])
+  [ {} ]"#,
+        nixpkgs,
+        sets.join(" ")
+    )
+}
+
+/// Parses the output of `nix eval --json` of `expression` into the attribute of each
+/// path. When several attributes, like aliases, evaluate to the same path, the shortest
+/// is kept.
+pub fn parse(data: &[u8]) -> io::Result<BTreeMap<Path, String>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "unexpected output of nix eval");
+    let value = json::parse(data)?;
+    let mut res: BTreeMap<Path, String> = BTreeMap::new();
+    for item in value.as_array().ok_or_else(invalid)? {
+        let attr = item
+            .get("attr")
+            .and_then(Value::as_str)
+            .ok_or_else(invalid)?;
+        let path = item
+            .get("path")
+            .and_then(Value::as_str)
+            .ok_or_else(invalid)?;
+        let entry = res
+            .entry(path.as_bytes().to_vec())
+            .or_insert_with(|| attr.to_owned());
+        if (attr.len(), attr) < (entry.len(), entry.as_str()) {
+            *entry = attr.to_owned();
+        }
+    }
+    Ok(res)
+}
+
+/// Returns the attribute of `nixpkgs`, a Nix expression like `<nixpkgs>` or a path, each
+/// store path of `di` is built from, for the store paths it knows.
+pub fn attr_names(di: &DepInfos, nixpkgs: &str) -> io::Result<BTreeMap<Path, String>> {
+    let names: BTreeSet<std::borrow::Cow<[u8]>> = di
+        .graph
+        .node_weights()
+        .filter(|node| node.kind() == NodeKind::Path)
+        .map(DepNode::name)
+        .filter(|name| !name.ends_with(b".drv"))
+        .collect();
+    let file = std::env::temp_dir().join(format!("nix-du-names-{}.json", std::process::id()));
+    let res = (|| {
+        let mut f = io::BufWriter::new(std::fs::File::create(&file)?);
+        f.write_all(b"[")?;
+        for (i, name) in names.iter().enumerate() {
+            if i > 0 {
+                f.write_all(b",")?;
+            }
+            json::write_string(&mut f, name)?;
+        }
+        f.write_all(b"]")?;
+        f.flush()?;
+        drop(f);
+        let mut cmd = crate::preview::nix();
+        cmd.args(["eval", "--json", "--impure", "--expr"])
+            .arg(expression(nixpkgs, PACKAGE_SETS))
+            .env("NIX_DU_NAMES", &file);
+        parse(&crate::preview::output_of(&mut cmd)?)
+    })();
+    let _ = std::fs::remove_file(&file);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attrs::*;
+
+    #[test]
+    fn check_parse() {
+        let out = br#"[{"attr":"pandoc","path":"/nix/store/a-pandoc-3.1"},
+            {"attr":"haskellPackages.pandoc-cli","path":"/nix/store/a-pandoc-3.1"},
+            {"attr":"hello","path":"/nix/store/b-hello-2.12"}]"#;
+        let attrs = parse(out).unwrap();
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs[&b"/nix/store/a-pandoc-3.1".to_vec()], "pandoc");
+        assert!(parse(b"{}").is_err());
+        assert!(expression("<nixpkgs>", &["haskellPackages"]).contains("import (<nixpkgs>)"));
+    }
+}
@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Writing bug reports against nix-du: a tar archive of text files and of the anonymized
+//! graph, which can be attached to an issue.
+
+use std::io::{self, Write};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+/// Writes the header of a file of the ustar format
+fn write_header<W: Write>(w: &mut W, name: &str, size: usize, mtime: u64) -> io::Result<()> {
+    let mut header = [0u8; 512];
+    let name = name.as_bytes();
+    if name.len() > 100 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "file name too long for tar",
+        ));
+    }
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name);
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, format!("{:011o}\0", mtime).as_bytes());
+    // the checksum is computed with its own field filled with spaces
+    field(148, b"        ");
+    field(156, b"0");
+    field(257, b"ustar\0");
+    field(263, b"00");
+    let checksum: u32 = header.iter().map(|&c| c as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    w.write_all(&header)
+}
+
+/// Writes a tar archive containing `files`, as pairs of names and contents.
+pub fn write_tar<W: Write>(w: &mut W, files: &[(&str, Vec<u8>)]) -> io::Result<()> {
+    let mtime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    for (name, content) in files {
+        write_header(w, name, content.len(), mtime)?;
+        w.write_all(content)?;
+        let padding = (512 - content.len() % 512) % 512;
+        w.write_all(&vec![0; padding])?;
+    }
+    // end of archive
+    w.write_all(&[0; 1024])
+}
+
+/// Returns the first line printed by `cmd --version`, or the error.
+fn version_of(cmd: &str) -> String {
+    match Command::new(cmd).arg("--version").output() {
+        Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .next()
+            .unwrap_or("")
+            .to_owned(),
+        Ok(out) => format!("failed with {}", out.status),
+        Err(e) => format!("could not run: {}", e),
+    }
+}
+
+/// Describes the version of nix-du, of nix and the platform
+pub fn versions() -> String {
+    format!(
+        "nix-du {}\nplatform: {}-{}\nnix-store: {}\nnix-daemon: {}\nNIX_REMOTE: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        version_of("nix-store"),
+        version_of("nix-daemon"),
+        std::env::var("NIX_REMOTE").unwrap_or_default(),
+    )
+}
+
+/// Records how long the steps of a run took
+#[derive(Debug, Default)]
+pub struct Timings(Vec<(&'static str, Duration)>);
+
+impl Timings {
+    /// Runs `f` and records its duration as `step`
+    pub fn time<T, F: FnOnce() -> T>(&mut self, step: &'static str, f: F) -> T {
+        let start = std::time::Instant::now();
+        let res = f();
+        self.0.push((step, start.elapsed()));
+        res
+    }
+
+    pub fn render(&self) -> String {
+        self.0
+            .iter()
+            .map(|(step, duration)| format!("{}: {:.3}s\n", step, duration.as_secs_f64()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bugreport::*;
+
+    #[test]
+    fn check_write_tar() {
+        let mut tar = Vec::new();
+        write_tar(
+            &mut tar,
+            &[("a.txt", b"hello".to_vec()), ("b", vec![1; 512])],
+        )
+        .unwrap();
+        assert_eq!(tar.len(), 512 + 512 + 512 + 512 + 1024);
+        assert_eq!(&tar[..5], b"a.txt");
+        assert_eq!(&tar[512..517], b"hello");
+        assert_eq!(&tar[124..136], b"00000000005\0");
+        let checksum: u32 = tar[..512]
+            .iter()
+            .enumerate()
+            .map(|(i, &c)| if (148..156).contains(&i) { b' ' } else { c } as u32)
+            .sum();
+        let written = std::str::from_utf8(&tar[148..154]).unwrap();
+        assert_eq!(u32::from_str_radix(written, 8).unwrap(), checksum);
+        assert!(tar[tar.len() - 1024..].iter().all(|&c| c == 0));
+    }
+}
@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! How long store paths took to build, to tell what would be expensive to build again once
+//! deleted, when no substituter has it.
+//!
+//! Durations come from the build logs of the local store, created when a build starts and
+//! last written when it ends, and from the build metadata of Hydra, as returned by its
+//! `/build/ID` API as JSON, for paths built by a Hydra instance.
+
+use crate::depgraph::*;
+use crate::json::{self, Value};
+use crate::logs::Log;
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Duration;
+
+/// Store paths which took longer than this to build are marked as expensive to rebuild
+pub const EXPENSIVE: Duration = Duration::from_secs(3600);
+
+/// Returns how long the build writing the log `file` took, if the filesystem records the
+/// creation time of files
+pub fn log_duration(file: &std::path::Path) -> Option<Duration> {
+    let metadata = std::fs::metadata(file).ok()?;
+    metadata
+        .modified()
+        .ok()?
+        .duration_since(metadata.created().ok()?)
+        .ok()
+}
+
+/// Returns the build time of the outputs of the derivations of `logs`, as given by
+/// `duration` for their log file. `read_drv` returns the contents of a `.drv` file, if it can
+/// be read.
+pub fn local_build_times<F, G>(logs: &[Log], read_drv: F, duration: G) -> BTreeMap<Path, Duration>
+where
+    F: Fn(&[u8]) -> Option<Vec<u8>>,
+    G: Fn(&std::path::Path) -> Option<Duration>,
+{
+    let mut res = BTreeMap::new();
+    for log in logs {
+        let outputs = match read_drv(&log.drv).and_then(|drv| crate::logs::parse_outputs(&drv)) {
+            Some(outputs) => outputs,
+            None => continue,
+        };
+        if let Some(duration) = duration(&log.file) {
+            for output in outputs.into_iter().flatten() {
+                res.insert(output, duration);
+            }
+        }
+    }
+    res
+}
+
+/// Parses builds in the JSON format of the Hydra API, one object or a list of them, into the
+/// build time of their outputs.
+pub fn parse_hydra(data: &[u8]) -> io::Result<BTreeMap<Path, Duration>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a Hydra build");
+    let value = json::parse(data)?;
+    let builds = match &value {
+        Value::Array(builds) => builds.as_slice(),
+        build => std::slice::from_ref(build),
+    };
+    let mut res = BTreeMap::new();
+    for build in builds {
+        let time = |field| build.get(field).and_then(Value::as_u64).ok_or_else(invalid);
+        let duration = Duration::from_secs(time("stoptime")?.saturating_sub(time("starttime")?));
+        let outputs = match build.get("buildoutputs") {
+            Some(Value::Object(outputs)) => outputs,
+            _ => return Err(invalid()),
+        };
+        for (_, output) in outputs {
+            let path = output
+                .get("path")
+                .and_then(Value::as_str)
+                .ok_or_else(invalid)?;
+            res.insert(path.as_bytes().to_vec(), duration);
+        }
+    }
+    Ok(res)
+}
+
+/// Returns, for each node of `di`, in seconds, how long building again the store paths it
+/// stands for takes, as far as `times` knows.
+pub fn rebuild_times(di: &DepInfos, times: &BTreeMap<Path, Duration>) -> Vec<u64> {
+    di.graph
+        .node_weights()
+        .map(|node| {
+            node.store_paths()
+                .iter()
+                .filter_map(|&(path, _)| times.get(path))
+                .map(Duration::as_secs)
+                .sum()
+        })
+        .collect()
+}
+
+/// Returns the `EXPENSIVE` store paths of `times` with a description marking them as such,
+/// to be shown as annotations
+pub fn markers(times: &BTreeMap<Path, Duration>) -> Vec<(Path, String)> {
+    times
+        .iter()
+        .filter(|&(_, &duration)| duration >= EXPENSIVE)
+        .map(|(path, &duration)| {
+            (
+                path.clone(),
+                format!(
+                    "expensive to rebuild ({})",
+                    crate::procinfo::human_duration(duration)
+                ),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::buildtime::*;
+
+    #[test]
+    fn check_hydra() {
+        let build = br#"{"id":1,"starttime":1000,"stoptime":8200,"buildoutputs":{
+            "out":{"path":"/nix/store/aaaa-chromium"},"sandbox":{"path":"/nix/store/bbbb-chromium-sandbox"}}}"#;
+        let times = parse_hydra(build).unwrap();
+        assert_eq!(times.len(), 2);
+        assert_eq!(
+            times[&b"/nix/store/aaaa-chromium".to_vec()],
+            Duration::from_secs(7200)
+        );
+        assert_eq!(
+            parse_hydra(&[b"[", &build[..], b"]"].concat()).unwrap(),
+            times
+        );
+        assert!(parse_hydra(b"{}").is_err());
+        assert_eq!(
+            markers(&times)[0],
+            (
+                b"/nix/store/aaaa-chromium".to_vec(),
+                "expensive to rebuild (2h)".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn check_local_build_times() {
+        let logs = vec![Log {
+            file: "/nix/var/log/nix/drvs/cc/cc-hello.drv.bz2".into(),
+            drv: b"/nix/store/cccc-hello.drv".to_vec(),
+            size: 10,
+        }];
+        let drv =
+            br#"Derive([("out","/nix/store/dddd-hello","","")],[],[],"x86_64-linux","","",[])"#;
+        let times = local_build_times(
+            &logs,
+            |_| Some(drv.to_vec()),
+            |_| Some(Duration::from_secs(60)),
+        );
+        assert_eq!(times[&b"/nix/store/dddd-hello".to_vec()].as_secs(), 60);
+        assert!(markers(&times).is_empty());
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"path":"/nix/store/dddd-hello","size":10},
+            {"id":3,"path":"/nix/store/eeee-glibc","size":100}
+        ],"edges":[[1,2],[2,3]]}"#;
+        let di = crate::import::graph(text);
+        let di = crate::reduction::condense(di);
+        assert_eq!(rebuild_times(&di, &times).iter().sum::<u64>(), 60);
+    }
+}
@@ -0,0 +1,283 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::*;
+use petgraph::prelude::NodeIndex;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+/// Returns the directory where nix-du keeps data between runs, creating it if needed.
+///
+/// This is `$XDG_CACHE_HOME/nix-du`, or `~/.cache/nix-du` as a fallback.
+pub fn cache_dir() -> io::Result<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".cache"),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "neither XDG_CACHE_HOME nor HOME is set",
+                ))
+            }
+        },
+    };
+    let dir = base.join("nix-du");
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Returns the file of the subdirectory `kind` of the cache directory where data about the
+/// current store is saved
+fn store_file(kind: &str) -> io::Result<PathBuf> {
+    let store = std::env::var_os("NIX_STORE_DIR").unwrap_or_else(|| "/nix/store".into());
+    let name: Vec<u8> = store
+        .as_bytes()
+        .iter()
+        .map(|&c| if c == b'/' { b'%' } else { c })
+        .collect();
+    let dir = cache_dir()?.join(kind);
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir.join(OsStr::from_bytes(&name)))
+}
+
+/// Returns the file where the flags of the last run on the current store are saved
+fn flags_file() -> io::Result<PathBuf> {
+    store_file("flags")
+}
+
+/// Remembers `flags` as the flags of the last run on the current store.
+pub fn save_flags(flags: &[OsString]) -> io::Result<()> {
+    let mut content = Vec::new();
+    for flag in flags {
+        content.extend_from_slice(flag.as_bytes());
+        content.push(0);
+    }
+    std::fs::write(flags_file()?, content)
+}
+
+/// Returns the flags saved by `save_flags` for the current store, if any.
+pub fn load_flags() -> io::Result<Option<Vec<OsString>>> {
+    let content = match std::fs::read(flags_file()?) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let content = match content.strip_suffix(&[0]) {
+        Some(content) => content,
+        None => return Ok(Some(Vec::new())),
+    };
+    let flags = content
+        .split(|&c| c == 0)
+        .map(|flag| OsStr::from_bytes(flag).to_owned())
+        .collect();
+    Ok(Some(flags))
+}
+
+/// Forgets the flags saved by `save_flags` for the current store.
+pub fn forget_flags() -> io::Result<()> {
+    match std::fs::remove_file(flags_file()?) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the file where `--cache` saves the graph of the current store by default
+pub fn default_graph_file() -> io::Result<PathBuf> {
+    store_file("graphs")
+}
+
+/// Returns the file where `--incremental` saves the classes of the last run on the current
+/// store
+pub fn classes_file() -> io::Result<PathBuf> {
+    store_file("classes")
+}
+
+/// Returns what a graph read from the store depends on besides its gc-roots: the schema
+/// version of the database of the store, when the store directory was last modified, which
+/// garbage collections and new store paths do, and `options`, those of the reading.
+pub fn graph_key(options: &str) -> io::Result<String> {
+    let state_dir = std::env::var_os("NIX_STATE_DIR").unwrap_or_else(|| "/nix/var/nix".into());
+    let schema = std::fs::read_to_string(PathBuf::from(state_dir).join("db/schema"))?;
+    let store = crate::archive::store_dir();
+    let modified = std::fs::metadata(&store)?
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(io::Error::other)?;
+    Ok(format!(
+        "schema {}\nstore {} modified {}.{:09}\n{}\n",
+        schema.trim(),
+        store.display(),
+        modified.as_secs(),
+        modified.subsec_nanos(),
+        options
+    ))
+}
+
+/// the file next to the graph saved in `file` holding its key
+fn key_file(file: &std::path::Path) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(".key");
+    PathBuf::from(name)
+}
+
+/// Saves `di` to `file`, as valid as long as `graph_key` returns `key`.
+pub fn save_graph(di: &DepInfos, file: &std::path::Path, key: &str) -> io::Result<()> {
+    // the key is written last, so that a graph partly written is never loaded
+    match std::fs::remove_file(key_file(file)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    crate::snapshot::save(di, file)?;
+    std::fs::write(key_file(file), key)
+}
+
+/// Loads the graph saved by `save_graph` in `file`, or returns `None` if there is none, or
+/// if it was saved with another key than `key`.
+pub fn load_graph(file: &std::path::Path, key: &str) -> io::Result<Option<DepInfos>> {
+    match std::fs::read_to_string(key_file(file)) {
+        Ok(saved) if saved == key => crate::snapshot::load(file).map(Some),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Updates the gc-roots of `di`, a graph loaded from the cache, to `links`, the links to store
+/// paths found now as `find_link_roots` returns them: the links which are gone or point
+/// elsewhere are no longer roots, and new links become roots. Transient roots are kept only
+/// if `running` tells that their process is still running.
+///
+/// Returns `None` if a link points to a store path which is not in the graph: the store
+/// changed in a way the key of the cache misses, and must be read again.
+pub fn restate_roots<F: Fn(u32) -> bool>(
+    mut di: DepInfos,
+    links: &[(Path, PathBuf)],
+    running: F,
+) -> Option<DepInfos> {
+    if di.graph[di.root].kind() != NodeKind::Dummy {
+        return None;
+    }
+    let paths: HashMap<&[u8], NodeIndex> = di
+        .graph
+        .node_indices()
+        .filter(|&idx| di.graph[idx].kind().is_store_path())
+        .filter_map(|idx| Some((di.graph[idx].description.path()?.as_slice(), idx)))
+        .collect();
+    let mut targets: HashMap<&[u8], NodeIndex> = HashMap::new();
+    for (link, target) in links {
+        targets.insert(link, *paths.get(target.as_os_str().as_bytes())?);
+    }
+
+    let roots: Vec<NodeIndex> = di.roots().collect();
+    for root in roots {
+        let keep = match &di.graph[root].description {
+            NodeDescription::Link(link) => match targets.remove(link.as_slice()) {
+                Some(target) => {
+                    if !di.graph.neighbors(root).eq(std::iter::once(target)) {
+                        while let Some(edx) = di.graph.first_edge(root, petgraph::Outgoing) {
+                            let old = di.graph.edge_endpoints(edx).unwrap().1;
+                            di.graph[old].referrers = di.graph[old].referrers.saturating_sub(1);
+                            di.graph.remove_edge(edx);
+                        }
+                        di.graph.add_edge(root, target, Edge::Reference);
+                        di.graph[root].references = 1;
+                        di.graph[target].referrers += 1;
+                    }
+                    true
+                }
+                None => false,
+            },
+            description if description.kind().is_transient() => {
+                match crate::procinfo::pid_of(description) {
+                    Some(pid) => running(pid),
+                    None => true,
+                }
+            }
+            _ => true,
+        };
+        if !keep {
+            let edx = di.graph.find_edge(di.root, root).unwrap();
+            di.graph.remove_edge(edx);
+        }
+    }
+    let mut added: Vec<(&[u8], NodeIndex)> = targets.into_iter().collect();
+    added.sort();
+    for (link, target) in added {
+        let idx = di.graph.add_node(DepNode {
+            description: NodeDescription::Link(link.to_vec()),
+            references: 1,
+            ..DepNode::dummy()
+        });
+        di.graph.add_edge(di.root, idx, Edge::Root);
+        di.graph.add_edge(idx, target, Edge::Reference);
+        di.graph[target].referrers += 1;
+    }
+    di.record_ages();
+
+    // what the gc-roots retain changed, but not the nodes
+    for sizes in di.metadata.size.values_mut() {
+        sizes[Reachability::Connected] = None;
+    }
+    if di.metadata.reachable == Reachability::Connected {
+        di = crate::reduction::keep_reachable(di);
+    }
+    di.record_metadata();
+    Some(di)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::*;
+
+    #[test]
+    fn check_restate_roots() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/home/alice/result","root":true},
+            {"id":2,"kind":"Link","path":"/home/alice/old","root":true},
+            {"id":3,"kind":"Link","path":"/home/alice/moved","root":true},
+            {"id":4,"kind":"Temporary","path":"{temp:10}","root":true},
+            {"id":5,"kind":"Temporary","path":"{temp:11}","root":true},
+            {"id":6,"path":"/nix/store/a-hello","size":1},
+            {"id":7,"path":"/nix/store/b-old","size":10},
+            {"id":8,"path":"/nix/store/c-firefox","size":100},
+            {"id":9,"path":"/nix/store/d-build","size":1000}
+        ],"edges":[[1,6],[2,7],[3,7],[4,9],[5,9]]}"#;
+        let di = crate::import::graph(text);
+        let links = |links: &[(&str, &str)]| -> Vec<(Path, PathBuf)> {
+            links
+                .iter()
+                .map(|&(link, target)| (link.into(), target.into()))
+                .collect()
+        };
+        let now = links(&[
+            ("/home/alice/result", "/nix/store/a-hello"),
+            ("/home/alice/moved", "/nix/store/c-firefox"),
+            ("/home/alice/new", "/nix/store/a-hello"),
+        ]);
+        let restated = restate_roots(di.clone(), &now, |pid| pid == 10).unwrap();
+        restated.check_metadata();
+        let mut roots: Vec<String> = restated
+            .roots()
+            .map(|idx| format!("{:?}", restated.graph[idx].description))
+            .collect();
+        roots.sort();
+        assert_eq!(
+            roots,
+            vec![
+                "Link(/home/alice/moved)",
+                "Link(/home/alice/new)",
+                "Link(/home/alice/result)",
+                "Temporary({temp:10})"
+            ]
+        );
+        assert_eq!(restated.reachable_size(), 1101);
+        let stale = links(&[("/home/alice/result", "/nix/store/e-unknown")]);
+        assert!(restate_roots(di, &stale, |_| true).is_none());
+    }
+}
@@ -0,0 +1,249 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Assertions about the store, like `live-size < 50GB`, checked by `--format junit` so that
+//! store hygiene can be enforced by CI pipelines, each assertion being a test case.
+//!
+//! An assertion is `SUBJECT < LIMIT` or `SUBJECT <= LIMIT`, where `SUBJECT` is one of:
+//!  * `live-size`: the size of live store paths
+//!  * `roots`: the number of gc-roots
+//!  * `root-closure`: the size of the closure of each gc-root
+//!  * `node-size`: the size of each node of the condensed graph
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use std::io::{self, Write};
+
+/// What an assertion is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subject {
+    LiveSize,
+    Roots,
+    RootClosure,
+    NodeSize,
+}
+
+/// An assertion that a subject is below a limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assertion {
+    /// the text the assertion was parsed from, naming the test case
+    pub text: String,
+    pub subject: Subject,
+    /// the limit, in bytes or for `Roots` a number
+    pub limit: u64,
+    /// whether the limit itself is allowed
+    pub inclusive: bool,
+}
+
+impl Assertion {
+    /// Parses `SUBJECT < LIMIT` or `SUBJECT <= LIMIT`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (subject, inclusive, limit) = match text.split_once("<=") {
+            Some((subject, limit)) => (subject, true, limit),
+            None => match text.split_once('<') {
+                Some((subject, limit)) => (subject, false, limit),
+                None => return Err("expected SUBJECT < LIMIT".to_owned()),
+            },
+        };
+        let subject = match subject.trim() {
+            "live-size" => Subject::LiveSize,
+            "roots" => Subject::Roots,
+            "root-closure" => Subject::RootClosure,
+            "node-size" => Subject::NodeSize,
+            other => {
+                return Err(format!(
+                    "unknown subject {}, expected live-size, roots, root-closure or node-size",
+                    other
+                ))
+            }
+        };
+        let limit = limit.trim();
+        let limit = match subject {
+            Subject::Roots => limit.parse().map_err(|e| format!("{}: {}", limit, e))?,
+            _ => limit
+                .parse::<ByteSize>()
+                .map_err(|e| format!("{}: {}", limit, e))?
+                .as_u64(),
+        };
+        Ok(Assertion {
+            text: text.trim().to_owned(),
+            subject,
+            limit,
+            inclusive,
+        })
+    }
+
+    /// Returns whether `value` satisfies the assertion
+    fn holds(&self, value: u64) -> bool {
+        value < self.limit || (self.inclusive && value == self.limit)
+    }
+
+    /// Formats `value` like the limit
+    fn format(&self, value: u64) -> String {
+        match self.subject {
+            Subject::Roots => value.to_string(),
+            _ => ByteSize::b(value).to_string(),
+        }
+    }
+}
+
+/// The result of checking an assertion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// the name of the test case
+    pub name: String,
+    /// `None` if the assertion holds, else why it does not
+    pub failure: Option<String>,
+}
+
+/// Returns the size of the closure of `idx`
+fn closure_size(di: &DepInfos, idx: petgraph::prelude::NodeIndex) -> u64 {
+    let mut dfs = petgraph::visit::Dfs::new(&di.graph, idx);
+    let mut size = 0;
+    while let Some(idx) = dfs.next(&di.graph) {
+        size += di.graph[idx].size;
+    }
+    size
+}
+
+/// Checks `assertion` against `di`, a graph returned by `reduction::condense`
+pub fn check(di: &DepInfos, assertion: &Assertion) -> Outcome {
+    let single = |value: u64, what: &str| {
+        if assertion.holds(value) {
+            None
+        } else {
+            Some(format!("{} is {}", what, assertion.format(value)))
+        }
+    };
+    let each = |values: Vec<(std::borrow::Cow<[u8]>, u64)>, what: &str| {
+        let mut offenders: Vec<_> = values
+            .into_iter()
+            .filter(|&(_, value)| !assertion.holds(value))
+            .collect();
+        if offenders.is_empty() {
+            return None;
+        }
+        offenders.sort_by_key(|&(_, value)| std::cmp::Reverse(value));
+        let lines: Vec<String> = offenders
+            .iter()
+            .map(|(label, value)| {
+                format!(
+                    "{} of {}: {}",
+                    what,
+                    String::from_utf8_lossy(label),
+                    assertion.format(*value)
+                )
+            })
+            .collect();
+        Some(lines.join("\n"))
+    };
+    let failure = match assertion.subject {
+        Subject::LiveSize => single(di.reachable_size(), "the live size"),
+        Subject::Roots => single(di.roots().count() as u64, "the number of gc-roots"),
+        Subject::RootClosure => each(
+            di.roots()
+                .map(|idx| (di.label(idx), closure_size(di, idx)))
+                .collect(),
+            "closure",
+        ),
+        Subject::NodeSize => each(
+            di.graph
+                .node_indices()
+                .filter(|&idx| idx != di.root)
+                .map(|idx| (di.label(idx), di.graph[idx].size))
+                .collect(),
+            "size",
+        ),
+    };
+    Outcome {
+        name: assertion.text.clone(),
+        failure,
+    }
+}
+
+/// Writes `s` escaped for XML
+fn write_escaped<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    crate::render::write_xml_escaped(w, s.as_bytes())
+}
+
+/// Checks `assertions` against `di` and writes the outcomes as a JUnit XML report. Returns
+/// the number of failed assertions.
+pub fn render_junit<W: Write>(
+    di: &DepInfos,
+    assertions: &[Assertion],
+    w: &mut W,
+) -> io::Result<usize> {
+    let outcomes: Vec<Outcome> = assertions.iter().map(|a| check(di, a)).collect();
+    let failures = outcomes.iter().filter(|o| o.failure.is_some()).count();
+    writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        w,
+        r#"<testsuites><testsuite name="nix-du" tests="{}" failures="{}">"#,
+        outcomes.len(),
+        failures
+    )?;
+    for outcome in &outcomes {
+        w.write_all(br#"<testcase classname="nix-du" name=""#)?;
+        write_escaped(w, &outcome.name)?;
+        match &outcome.failure {
+            None => writeln!(w, r#""/>"#)?,
+            Some(failure) => {
+                w.write_all(br#""><failure message=""#)?;
+                write_escaped(w, failure.lines().next().unwrap_or(""))?;
+                w.write_all(br#"">"#)?;
+                write_escaped(w, failure)?;
+                writeln!(w, "</failure></testcase>")?;
+            }
+        }
+    }
+    writeln!(w, "</testsuite></testsuites>")?;
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::check::*;
+
+    #[test]
+    fn check_parse() {
+        let a = Assertion::parse("live-size < 50GB").unwrap();
+        assert_eq!(a.subject, Subject::LiveSize);
+        assert_eq!(a.limit, 50_000_000_000);
+        assert!(!a.inclusive);
+        let a = Assertion::parse("roots<=3").unwrap();
+        assert_eq!((a.subject, a.limit, a.inclusive), (Subject::Roots, 3, true));
+        assert!(Assertion::parse("size > 5GB").is_err());
+        assert!(Assertion::parse("foo < 5GB").is_err());
+        assert!(Assertion::parse("roots < 5GB").is_err());
+    }
+
+    #[test]
+    fn check_render_junit() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/a","size":10},
+            {"id":4,"path":"/nix/store/b","size":100}
+        ],"edges":[[1,3],[2,3],[2,4]]}"#;
+        let di = crate::import::graph(text);
+        let di = crate::reduction::condense(di);
+        let assertions: Vec<Assertion> = ["live-size < 1KB", "roots < 2", "root-closure < 50B"]
+            .iter()
+            .map(|text| Assertion::parse(text).unwrap())
+            .collect();
+        let outcomes: Vec<Outcome> = assertions.iter().map(|a| check(&di, a)).collect();
+        assert_eq!(outcomes[0].failure, None);
+        assert_eq!(
+            outcomes[1].failure.as_deref(),
+            Some("the number of gc-roots is 2")
+        );
+        assert_eq!(
+            outcomes[2].failure.as_deref(),
+            Some("closure of /r2: 110 B")
+        );
+        let mut out = Vec::new();
+        assert_eq!(render_junit(&di, &assertions, &mut out).unwrap(), 2);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(r#"tests="3" failures="2""#));
+        assert!(out.contains(r#"name="live-size &lt; 1KB"/>"#));
+    }
+}
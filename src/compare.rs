@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use fixedbitset::FixedBitSet;
+use petgraph::prelude::NodeIndex;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// returns the home directory of `user` according to /etc/passwd
+fn home_of(user: &str) -> Option<PathBuf> {
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() > 5 && fields[0] == user {
+            Some(PathBuf::from(fields[5]))
+        } else {
+            None
+        }
+    })
+}
+
+/// Finds the default profile of `user`, or interprets `user` as the path of a profile
+/// if it contains a `/`.
+pub fn find_profile(user: &str) -> io::Result<PathBuf> {
+    if user.contains('/') {
+        return Ok(PathBuf::from(user));
+    }
+    let state_dir = std::env::var_os("NIX_STATE_DIR").unwrap_or_else(|| "/nix/var/nix".into());
+    let mut candidates = vec![PathBuf::from(state_dir)
+        .join("profiles/per-user")
+        .join(user)
+        .join("profile")];
+    if let Some(home) = home_of(user) {
+        candidates.push(home.join(".local/state/nix/profiles/profile"));
+        candidates.push(home.join(".nix-profile"));
+    }
+    candidates
+        .into_iter()
+        .find(|path| path.exists())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("could not find the profile of user {}", user),
+            )
+        })
+}
+
+/// returns the set of nodes reachable from the store path `profile` points to
+fn closure(di: &DepInfos, profile: &Path) -> io::Result<FixedBitSet> {
+    let target = profile.canonicalize()?;
+    let target = target.as_os_str().as_bytes();
+    let start = di
+        .graph
+        .node_indices()
+        .find(|&idx| match &di.graph[idx].description {
+            NodeDescription::Path(path) => path.as_slice() == target,
+            _ => false,
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a valid store path", profile.display()),
+            )
+        })?;
+    let mut dfs = petgraph::visit::Dfs::new(&di.graph, start);
+    while dfs.next(&di.graph).is_some() {}
+    Ok(dfs.discovered)
+}
+
+/// Compares the closures of the profiles of two users: which size is shared,
+/// and which size is only needed by one of them.
+pub fn render<W: Write>(di: &DepInfos, users: [&str; 2], w: &mut W) -> io::Result<()> {
+    let mut closures = Vec::with_capacity(2);
+    for user in &users {
+        let profile = find_profile(user)?;
+        closures.push(closure(di, &profile)?);
+        writeln!(w, "Profile of {}: {}", user, profile.display())?;
+    }
+    let size_of = |pred: &dyn Fn(NodeIndex) -> bool| -> u64 {
+        di.graph
+            .node_indices()
+            .filter(|&idx| pred(idx))
+            .map(|idx| di.graph[idx].size)
+            .sum()
+    };
+    let in_first = |idx: NodeIndex| closures[0].contains(idx.index());
+    let in_second = |idx: NodeIndex| closures[1].contains(idx.index());
+    let shared = size_of(&|idx| in_first(idx) && in_second(idx));
+    writeln!(w, "Shared by both closures: {}", ByteSize::b(shared))?;
+    writeln!(
+        w,
+        "Only in the closure of {}: {}",
+        users[0],
+        ByteSize::b(size_of(&|idx| in_first(idx) && !in_second(idx)))
+    )?;
+    writeln!(
+        w,
+        "Only in the closure of {}: {}",
+        users[1],
+        ByteSize::b(size_of(&|idx| !in_first(idx) && in_second(idx)))
+    )?;
+    Ok(())
+}
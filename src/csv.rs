@@ -0,0 +1,46 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph;
+use petgraph::visit::IntoNodeReferences;
+use std::io::{self, Write};
+
+/// Writes a CSV field, quoted if needed. Invalid UTF-8 is replaced by U+FFFD.
+fn write_field<W: Write>(w: &mut W, s: &[u8]) -> io::Result<()> {
+    let s = String::from_utf8_lossy(s);
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        write!(w, "\"{}\"", s.replace('"', "\"\""))
+    } else {
+        w.write_all(s.as_bytes())
+    }
+}
+
+/// Writes one line per node, with a header line. Edges are not part of the output,
+/// but the number of referrers and references of each node is.
+pub fn render<W: Write>(dependencies: &depgraph::DepInfos, w: &mut W) -> io::Result<()> {
+    let roots: fixedbitset::FixedBitSet = dependencies.roots().map(|idx| idx.index()).collect();
+    let ids = crate::stableid::stable_ids(dependencies);
+    writeln!(
+        w,
+        "id,kind,label,path,size,referrers,references,self_reference,root,stable_id"
+    )?;
+    for (idx, node) in dependencies.graph.node_references() {
+        if idx == dependencies.root {
+            continue;
+        }
+        write!(w, "{},{:?},", idx.index(), node.kind())?;
+        write_field(w, &dependencies.label(idx))?;
+        w.write_all(b",")?;
+        write_field(w, node.description.path().map_or(&[][..], |p| p.as_slice()))?;
+        writeln!(
+            w,
+            ",{},{},{},{},{},{}",
+            node.size,
+            node.referrers,
+            node.references,
+            node.self_reference,
+            roots.contains(idx.index()),
+            ids[idx.index()]
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,586 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! A long-lived process keeping the condensed graph of the store up to date, and answering
+//! queries about it on a unix socket, so that interactive use does not need to read the
+//! whole store each time.
+//!
+//! The protocol is line based: the client sends one request line, like `size` or
+//! `locate HASHPREFIX`, and the daemon answers in plain text and closes the connection.
+//!
+//! A request line starting with `{` is a JSON-RPC 2.0 request instead, answered by one line
+//! of JSON, for other programs. The methods, of version `API_VERSION` of the API, are:
+//!  * `version`: `{"api": 1, "nix-du": VERSION}`
+//!  * `size`: `{"live": BYTES, "updated": SECONDS_SINCE_EPOCH}`
+//!  * `roots`, with optional param `n`: the `n` biggest gc-roots, as a list of
+//!    `{"root": LABEL, "path": PATH, "size": BYTES}`, where `size` is what removing the root
+//!    frees
+//!  * `growers`, with optional param `n`: the `n` gc-roots which grew the most when the store
+//!    last changed, with their `growth` in bytes in addition
+//!
+//! New methods and fields may be added without changing the version of the API.
+
+use crate::depgraph::*;
+use crate::digest::Summary;
+use crate::json::{self, Value};
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::io::{self, BufRead, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often to look for changes of the store, without inotify
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the store must stay unchanged before it is read again
+const SETTLE_DELAY: Duration = Duration::from_secs(2);
+/// How long to wait at most for the store to stop changing
+const MAX_SETTLE_DELAY: Duration = Duration::from_secs(60);
+/// How long a client may take to send its request or read the answer
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns the default path of the socket: `$XDG_RUNTIME_DIR/nix-du.sock`, or a file of the
+/// cache directory.
+pub fn default_socket() -> io::Result<PathBuf> {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir).join("nix-du.sock")),
+        _ => Ok(crate::cache::cache_dir()?.join("daemon.sock")),
+    }
+}
+
+/// The version of the JSON-RPC API, incremented on incompatible changes
+pub const API_VERSION: u64 = 1;
+
+/// What the daemon knows of the store
+pub struct State {
+    /// the graph, as returned by `reduction::condense`
+    pub graph: DepInfos,
+    /// when the graph was read
+    pub updated: SystemTime,
+    /// the summary of the graph before the last change, if any
+    pub previous: Option<Summary>,
+}
+
+impl State {
+    /// Returns the roots sorted by decreasing size, at most `n`
+    fn biggest_roots(&self, n: usize) -> Vec<NodeIndex> {
+        let di = &self.graph;
+        let mut roots: Vec<_> = di.roots().collect();
+        roots.sort_by_key(|&idx| std::cmp::Reverse(di.graph[idx].size));
+        roots.truncate(n);
+        roots
+    }
+
+    /// Returns the roots which grew when the store last changed, with their growth, biggest
+    /// first, at most `n`
+    fn growers(&self, n: usize) -> Vec<(NodeIndex, u64)> {
+        let di = &self.graph;
+        let previous = match &self.previous {
+            Some(previous) => previous,
+            None => return Vec::new(),
+        };
+        let mut res: Vec<(NodeIndex, u64)> = di
+            .roots()
+            .filter_map(|idx| {
+                let node = &di.graph[idx];
+                let old = previous
+                    .roots
+                    .get(node.description.path()?)
+                    .copied()
+                    .unwrap_or(0);
+                node.size
+                    .checked_sub(old)
+                    .filter(|&growth| growth > 0)
+                    .map(|g| (idx, g))
+            })
+            .collect();
+        res.sort_by_key(|&(_, growth)| std::cmp::Reverse(growth));
+        res.truncate(n);
+        res
+    }
+}
+
+/// Answers one request about `state`.
+pub fn answer<W: Write>(state: &State, request: &str, w: &mut W) -> io::Result<()> {
+    let di = &state.graph;
+    let mut words = request.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("size"), None) => writeln!(w, "{}", ByteSize::b(di.reachable_size())),
+        (Some("roots"), n) => {
+            let n = match n.map(str::parse) {
+                Some(Ok(n)) => n,
+                Some(Err(_)) => return writeln!(w, "error: invalid number of roots"),
+                None => usize::MAX,
+            };
+            for idx in state.biggest_roots(n) {
+                write!(w, "{}\t", ByteSize::b(di.graph[idx].size))?;
+                w.write_all(&di.label(idx))?;
+                writeln!(w)?;
+            }
+            Ok(())
+        }
+        (Some("growers"), n) => {
+            let n = match n.map(str::parse) {
+                Some(Ok(n)) => n,
+                Some(Err(_)) => return writeln!(w, "error: invalid number of roots"),
+                None => 5,
+            };
+            for (idx, growth) in state.growers(n) {
+                write!(w, "+{}\t", ByteSize::b(growth))?;
+                w.write_all(&di.label(idx))?;
+                writeln!(w)?;
+            }
+            Ok(())
+        }
+        (Some("locate"), Some(prefix)) => match crate::locate::render(di, prefix, w) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => writeln!(w, "error: {}", e),
+            res => res,
+        },
+        (Some("unlock"), Some(path)) => match crate::unlock::find_node(di, path) {
+            Ok(target) => crate::unlock::render(di, target, false, w),
+            Err(e) => writeln!(w, "error: {}", e),
+        },
+        (Some("updated"), None) => writeln!(
+            w,
+            "{} seconds ago",
+            state.updated.elapsed().unwrap_or_default().as_secs()
+        ),
+        _ => writeln!(
+            w,
+            "error: unknown request «{}». Requests are: size, roots [N], growers [N], \
+             locate HASHPREFIX, unlock PATH, updated",
+            request
+        ),
+    }
+}
+
+/// Writes the JSON-RPC description of the roots `roots`, with their growth if any
+fn write_roots<W: Write>(
+    di: &DepInfos,
+    roots: impl Iterator<Item = (NodeIndex, Option<u64>)>,
+    w: &mut W,
+) -> io::Result<()> {
+    w.write_all(b"[")?;
+    for (i, (idx, growth)) in roots.enumerate() {
+        if i > 0 {
+            w.write_all(b",")?;
+        }
+        let node = &di.graph[idx];
+        w.write_all(b"{\"root\":")?;
+        json::write_string(w, &di.label(idx))?;
+        w.write_all(b",\"path\":")?;
+        match node.description.path() {
+            Some(path) => json::write_string(w, path)?,
+            None => w.write_all(b"null")?,
+        }
+        write!(w, ",\"size\":{}", node.size)?;
+        if let Some(growth) = growth {
+            write!(w, ",\"growth\":{}", growth)?;
+        }
+        w.write_all(b"}")?;
+    }
+    w.write_all(b"]")
+}
+
+/// Answers one JSON-RPC 2.0 request about `state`, on one line.
+pub fn answer_rpc<W: Write>(state: &State, request: &str, w: &mut W) -> io::Result<()> {
+    let error = |w: &mut W, id: &Value, code: i32, message: &str| -> io::Result<()> {
+        w.write_all(b"{\"jsonrpc\":\"2.0\",\"id\":")?;
+        json::write_value(w, id)?;
+        write!(w, ",\"error\":{{\"code\":{},\"message\":", code)?;
+        json::write_string(w, message.as_bytes())?;
+        w.write_all(b"}}\n")
+    };
+    let request = match json::parse(request.as_bytes()) {
+        Ok(request) => request,
+        Err(e) => return error(w, &Value::Null, -32700, &e.to_string()),
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => return error(w, &id, -32600, "no method"),
+    };
+    let n = match request.get("params").and_then(|p| p.get("n")) {
+        None => None,
+        Some(n) => match n.as_u64() {
+            Some(n) => Some(n as usize),
+            None => return error(w, &id, -32602, "n must be a non-negative integer"),
+        },
+    };
+    let di = &state.graph;
+    let mut result = Vec::new();
+    match method {
+        "version" => write!(
+            result,
+            "{{\"api\":{},\"nix-du\":\"{}\"}}",
+            API_VERSION,
+            env!("CARGO_PKG_VERSION")
+        )?,
+        "size" => write!(
+            result,
+            "{{\"live\":{},\"updated\":{}}}",
+            di.reachable_size(),
+            state
+                .updated
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        )?,
+        "roots" => write_roots(
+            di,
+            state
+                .biggest_roots(n.unwrap_or(usize::MAX))
+                .into_iter()
+                .map(|idx| (idx, None)),
+            &mut result,
+        )?,
+        "growers" => write_roots(
+            di,
+            state
+                .growers(n.unwrap_or(5))
+                .into_iter()
+                .map(|(idx, growth)| (idx, Some(growth))),
+            &mut result,
+        )?,
+        _ => return error(w, &id, -32601, &format!("unknown method {}", method)),
+    }
+    w.write_all(b"{\"jsonrpc\":\"2.0\",\"id\":")?;
+    json::write_value(w, &id)?;
+    w.write_all(b",\"result\":")?;
+    w.write_all(&result)?;
+    w.write_all(b"}\n")
+}
+
+/// Answers the request sent on `stream` with the current `state`.
+fn serve_client(stream: UnixStream, state: &RwLock<State>) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut request = String::new();
+    io::BufReader::new(&stream).read_line(&mut request)?;
+    let mut w = io::BufWriter::new(&stream);
+    let state = state.read().expect("poisoned lock");
+    let request = request.trim();
+    if request.starts_with('{') {
+        answer_rpc(&state, request, &mut w)?;
+    } else {
+        answer(&state, request, &mut w)?;
+    }
+    w.flush()
+}
+
+/// Answers the requests sent on `listener` with the current `state`, forever. Each client
+/// is served by its own thread, so that a slow one does not delay the others.
+fn serve(listener: UnixListener, state: Arc<RwLock<State>>) {
+    for stream in listener.incoming() {
+        let state = state.clone();
+        let res = stream.and_then(|stream| {
+            std::thread::Builder::new()
+                .name("nix-du client".to_owned())
+                .spawn(move || {
+                    if let Err(e) = serve_client(stream, &state) {
+                        eprintln!("Could not answer a request: {}", e);
+                    }
+                })
+        });
+        if let Err(e) = res {
+            eprintln!("Could not answer a request: {}", e);
+        }
+    }
+}
+
+/// Returns the directories whose entries change when gc-roots or store paths are added or
+/// removed: the nix database, and the directories of gc-roots and profiles.
+fn watched_dirs() -> Vec<PathBuf> {
+    let state_dir = std::env::var_os("NIX_STATE_DIR").unwrap_or_else(|| "/nix/var/nix".into());
+    let state_dir = Path::new(&state_dir);
+    let mut res = vec![state_dir.join("db")];
+    let mut tops = vec![state_dir.join("gcroots"), state_dir.join("profiles")];
+    if let Some(home) = std::env::var_os("HOME") {
+        tops.push(Path::new(&home).join(".local/state/nix/profiles"));
+    }
+    for top in tops {
+        // gcroots/per-user/USER and profiles/per-user/USER are two levels deep
+        for entry in walkdir::WalkDir::new(top)
+            .max_depth(2)
+            .into_iter()
+            .flatten()
+        {
+            if entry.file_type().is_dir() {
+                res.push(entry.into_path());
+            }
+        }
+    }
+    res
+}
+
+/// Returns the modification times of the watched directories, and of the database.
+fn stamp() -> Vec<Option<SystemTime>> {
+    let mut paths = watched_dirs();
+    paths.push(paths[0].join("db.sqlite"));
+    paths
+        .iter()
+        .map(|path| path.symlink_metadata().and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Waits for changes of the store, with inotify where available, or else by polling the
+/// modification times of the watched directories.
+struct Watcher {
+    #[cfg(target_os = "linux")]
+    inotify: Option<crate::inotify::Inotify>,
+    stamp: Vec<Option<SystemTime>>,
+}
+
+impl Watcher {
+    fn new() -> Self {
+        let mut res = Watcher {
+            #[cfg(target_os = "linux")]
+            inotify: crate::inotify::Inotify::new()
+                .map_err(|e| eprintln!("Could not use inotify, polling instead: {}", e))
+                .ok(),
+            stamp: Vec::new(),
+        };
+        res.rewatch();
+        res
+    }
+
+    /// Watches the directories which appeared since the last call
+    fn rewatch(&mut self) {
+        self.stamp = stamp();
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(inotify) = &self.inotify {
+                for dir in watched_dirs() {
+                    if let Err(e) = inotify.watch(&dir) {
+                        eprintln!("Could not watch {}: {}", dir.display(), e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits at most `timeout`, and returns whether the store changed. Returns only once
+    /// changes have stopped for a while, so that a build or a switch is seen as one change.
+    fn wait(&mut self, timeout: Duration) -> bool {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(inotify) = &self.inotify {
+                let settled = || -> io::Result<()> {
+                    let start = Instant::now();
+                    while inotify.wait(SETTLE_DELAY)? && start.elapsed() < MAX_SETTLE_DELAY {}
+                    Ok(())
+                };
+                match inotify.wait(timeout).and_then(|changed| {
+                    if changed {
+                        settled()?;
+                    }
+                    Ok(changed)
+                }) {
+                    Ok(changed) => return changed,
+                    Err(e) => {
+                        eprintln!("Could not use inotify, polling instead: {}", e);
+                        self.inotify = None;
+                    }
+                }
+            }
+        }
+        std::thread::sleep(timeout.min(POLL_INTERVAL));
+        stamp() != self.stamp
+    }
+}
+
+/// Binds the socket at `path`, removing it first if it is left over by a previous daemon.
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("a daemon is already listening on {}", path.display()),
+                ));
+            }
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        res => res,
+    }
+}
+
+/// Runs `cmd` with `sh`, passing it the live size of the store before and after a change.
+fn notify(cmd: &str, previous: u64, current: u64) {
+    let res = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("NIX_DU_PREVIOUS_SIZE", previous.to_string())
+        .env("NIX_DU_LIVE_SIZE", current.to_string())
+        .status();
+    match res {
+        Ok(status) if status.success() => (),
+        Ok(status) => eprintln!("The --on-change command failed with {}", status),
+        Err(e) => eprintln!("Could not run the --on-change command: {}", e),
+    }
+}
+
+/// Runs the daemon on the socket at `path`. `read` returns the condensed graph of the store;
+/// it is called again when the store changes, and at least every `refresh`. After each
+/// change, `on_change` is run, if any, as with `notify`.
+pub fn run<F: FnMut() -> DepInfos>(
+    path: &Path,
+    refresh: Duration,
+    on_change: Option<&str>,
+    mut read: F,
+) -> io::Result<()> {
+    let mut watcher = Watcher::new();
+    let state = Arc::new(RwLock::new(State {
+        graph: read(),
+        updated: SystemTime::now(),
+        previous: None,
+    }));
+    let listener = bind(path)?;
+    eprintln!("Listening on {}", path.display());
+    {
+        let state = state.clone();
+        std::thread::spawn(move || serve(listener, state));
+    }
+    let mut last_read = Instant::now();
+    loop {
+        let timeout = refresh.saturating_sub(last_read.elapsed());
+        if !watcher.wait(timeout) && last_read.elapsed() < refresh {
+            continue;
+        }
+        last_read = Instant::now();
+        let graph = read();
+        watcher.rewatch();
+        let size = graph.reachable_size();
+        let mut state = state.write().expect("poisoned lock");
+        let previous = state.graph.reachable_size();
+        let summary = Summary::new(&state.graph);
+        // keep the growth of the last change when nothing changed
+        if Summary::new(&graph) != summary {
+            state.previous = Some(summary);
+        }
+        state.graph = graph;
+        state.updated = SystemTime::now();
+        drop(state);
+        if let Some(cmd) = on_change {
+            if previous != size {
+                notify(cmd, previous, size);
+            }
+        }
+    }
+}
+
+/// Sends `request` to the daemon listening at `path` and copies its answer to `w`.
+pub fn query<W: Write>(path: &Path, request: &str, w: &mut W) -> io::Result<()> {
+    let mut stream = UnixStream::connect(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "could not connect to {}: {}. Is nix-du daemon running?",
+                path.display(),
+                e
+            ),
+        )
+    })?;
+    writeln!(stream, "{}", request)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    io::copy(&mut stream, w)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::daemon::*;
+
+    #[test]
+    fn check_answer() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/0aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a","size":10},
+            {"id":4,"path":"/nix/store/1bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b","size":20}
+        ],"edges":[[1,3],[2,4]]}"#;
+        let di = crate::import::graph(text);
+        let graph = crate::reduction::condense(di);
+        let mut previous = Summary::new(&graph);
+        previous.roots.insert(b"/r1".to_vec(), 4);
+        let state = State {
+            graph,
+            updated: SystemTime::now(),
+            previous: Some(previous),
+        };
+        let ask = |request: &str| {
+            let mut out = Vec::new();
+            answer(&state, request, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(ask("size"), format!("{}\n", ByteSize::b(30)));
+        let roots = ask("roots 1");
+        assert_eq!(roots.lines().count(), 1);
+        assert!(roots.contains("r2"));
+        assert!(ask("locate 0aaa").contains("/nix/store/0aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a"));
+        assert!(ask("locate 2").starts_with("error: "));
+        assert!(ask("frobnicate").starts_with("error: "));
+        let growers = ask("growers");
+        assert_eq!(growers.lines().count(), 1);
+        assert!(growers.starts_with(&format!("+{}\t", ByteSize::b(6))));
+
+        let rpc = |request: &str| {
+            let mut out = Vec::new();
+            answer_rpc(&state, request, &mut out).unwrap();
+            assert!(out.ends_with(b"\n"));
+            json::parse(&out).unwrap()
+        };
+        let size = rpc(r#"{"jsonrpc":"2.0","id":1,"method":"size"}"#);
+        assert_eq!(size.get("id").and_then(Value::as_u64), Some(1));
+        let result = size.get("result").unwrap();
+        assert_eq!(result.get("live").and_then(Value::as_u64), Some(30));
+        let growers = rpc(r#"{"jsonrpc":"2.0","id":"g","method":"growers","params":{"n":5}}"#);
+        assert_eq!(growers.get("id").and_then(Value::as_str), Some("g"));
+        let growers = growers.get("result").and_then(Value::as_array).unwrap();
+        assert_eq!(growers.len(), 1);
+        assert_eq!(growers[0].get("path").and_then(Value::as_str), Some("/r1"));
+        assert_eq!(growers[0].get("growth").and_then(Value::as_u64), Some(6));
+        let version = rpc(r#"{"jsonrpc":"2.0","id":2,"method":"version"}"#);
+        let api = version.get("result").and_then(|r| r.get("api"));
+        assert_eq!(api.and_then(Value::as_u64), Some(API_VERSION));
+        let error = |request: &str| {
+            let answer = rpc(request);
+            let error = answer.get("error").expect("no error");
+            error.get("code").cloned()
+        };
+        let code = |c: &str| Some(Value::Number(c.to_owned()));
+        assert_eq!(error("{"), code("-32700"));
+        assert_eq!(error(r#"{"id":3,"method":"frobnicate"}"#), code("-32601"));
+        assert_eq!(
+            error(r#"{"id":3,"method":"roots","params":{"n":"x"}}"#),
+            code("-32602")
+        );
+    }
+
+    #[test]
+    fn check_serve_concurrently() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"path":"/nix/store/a","size":10}
+        ],"edges":[[1,2]]}"#;
+        let di = crate::import::graph(text);
+        let state = Arc::new(RwLock::new(State {
+            graph: crate::reduction::condense(di),
+            updated: SystemTime::now(),
+            previous: None,
+        }));
+        let path = std::env::temp_dir().join(format!("nix-du-daemon-{}.sock", std::process::id()));
+        let listener = bind(&path).unwrap();
+        std::thread::spawn(move || serve(listener, state));
+        // a client which sends nothing does not delay the next one
+        let _silent = UnixStream::connect(&path).unwrap();
+        let mut out = Vec::new();
+        query(&path, "size", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("{}\n", ByteSize::b(10))
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,10 +1,10 @@
 // SPDX-License-Identifier: LGPL-3.0
 
+use crate::annotations::Annotations;
 use crate::bindings;
 use enum_map::{enum_map, Enum};
 use std;
 use std::borrow::Cow;
-#[cfg(test)]
 use std::collections;
 use std::ffi::{CStr, OsStr, OsString};
 use std::fmt::{self, Display};
@@ -12,6 +12,7 @@ use std::os::raw::c_void;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, SystemTime};
 use std::vec::Vec;
 
@@ -33,14 +34,23 @@ pub enum NodeKind {
     Temporary,
     Transient,
     Shared,
+    Group,
+    Other,
+    System,
+    Common,
+    Error,
+    File,
+    Derivation,
+    Package,
 }
 
 impl NodeKind {
     pub fn is_gc_root(self) -> bool {
         use self::NodeKind::*;
         match self {
-            Transient | Link | Memory | Temporary => true,
-            FilteredOut | Path | Shared | Dummy => false,
+            Transient | Link | Memory | Temporary | Group => true,
+            FilteredOut | Path | Shared | Dummy | Other | System | Common | Error | File
+            | Derivation | Package => false,
         }
     }
 
@@ -48,9 +58,15 @@ impl NodeKind {
         use self::NodeKind::*;
         match self {
             Memory | Temporary => true,
-            Transient | Link | FilteredOut | Path | Shared | Dummy => false,
+            Transient | Link | FilteredOut | Path | Shared | Dummy | Group | Other | System
+            | Common | Error | File | Derivation | Package => false,
         }
     }
+
+    /// Whether nodes of this kind are a single store path
+    pub fn is_store_path(self) -> bool {
+        matches!(self, NodeKind::Path | NodeKind::Derivation)
+    }
 }
 
 pub type Path = Vec<u8>;
@@ -67,18 +83,66 @@ pub enum NodeDescription {
     FilteredOut,
     /// A node gathering all Memory and Temporary roots
     Transient,
+    /// A node gathering the Memory and Temporary roots of a running process, described like
+    /// `nixos-rebuild (pid 1234)`
+    Process(Path),
     /// An in-memory root
     Memory(Path),
     /// A temporary root
     Temporary(Path),
     /// Symbolises a set of inodes de-duplicated by store optimisation
     Shared(Path),
+    /// A node gathering all roots whose path starts with this prefix
+    Group(Path),
+    /// A node gathering this many generations of the profile with this path
+    Generations(Path, u32),
+    /// A node gathering the nodes not shown under the root with this name
+    Other(Path),
+    /// A node gathering the store paths built for this platform, or of unknown platform if empty
+    System(Path),
+    /// A node gathering the store paths reachable from several gc-roots
+    Common,
+    /// Why the store path referring to this node could not be read
+    Error(Vec<u8>),
+    /// A file or directory outside the store, like a project directory owning result links
+    /// in an imported graph
+    File(Path),
+    /// A store derivation, a `.drv` file, as read with `--build-deps`
+    Derivation(Path),
+    /// A node gathering this many nodes of store paths of this package, as named without
+    /// version nor output
+    Package(Path, u32),
 }
 
 const SHARED_PREFIX: &[u8] = b"shared:";
+const GROUP_PREFIX: &[u8] = b"{roots in ";
+const PROFILES_DIR: &[u8] = b"/nix/var/nix/profiles/";
+const OTHER_PREFIX: &[u8] = b"{other under ";
+const SYSTEM_PREFIX: &[u8] = b"{system ";
+const ERROR_PREFIX: &[u8] = b"{error: ";
+const PROCESS_PREFIX: &[u8] = b"held by: ";
+
+/// Whether the links of gc-roots are on this machine, which is not the case of the gc-roots
+/// of a remote store or of an image
+static LOCAL_ROOTS: AtomicBool = AtomicBool::new(true);
+
+pub fn local_roots() -> bool {
+    LOCAL_ROOTS.load(Ordering::Relaxed)
+}
+
+/// Tells whether the links of gc-roots can be looked at on this machine, to show their age
+pub fn set_local_roots(x: bool) {
+    LOCAL_ROOTS.store(x, Ordering::Relaxed);
+}
 
 /// Converts `/home/symphorien/.cache/lorri/gc_roots/02ebed43adca1d7ca863ce9b0a537205/gc_root/shell_gc_root/` into `/home/symphorien/src/lorri/tests/integration/bug23_gopath/shell.nix`
 fn resolve_lorri_root(path: &[u8]) -> std::io::Result<PathBuf> {
+    if !local_roots() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "the gc-roots are not on this machine",
+        ));
+    }
     let path = std::path::Path::new(std::ffi::OsStr::from_bytes(path));
     let mut path = match path.parent() {
         Some(p) => p.to_owned(),
@@ -93,10 +157,21 @@ fn resolve_lorri_root(path: &[u8]) -> std::io::Result<PathBuf> {
     std::fs::read_link(path)
 }
 
+/// Returns when the link `path` of a gc-root was created, if the gc-roots are on this machine
+pub fn link_time(path: &[u8]) -> Option<SystemTime> {
+    if !local_roots() {
+        return None;
+    }
+    std::path::Path::new(OsStr::from_bytes(path))
+        .symlink_metadata()
+        .and_then(|m| m.modified())
+        .ok()
+}
+
 /// A struct for human readable age of a link
 ///
 /// displays as `, 3d ago` where units are d=day, m=month and y=year.
-struct LinkAge(Option<SystemTime>);
+pub struct LinkAge(pub Option<SystemTime>);
 impl Display for LinkAge {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         const DAY: Duration = Duration::from_secs(3600 * 24);
@@ -120,6 +195,45 @@ impl Display for LinkAge {
 }
 
 impl NodeDescription {
+    /// Tells what a path registered by libnixstore is, `is_root` if it was reported as a
+    /// gc-root. Non-root paths outside `store_dir`, and names libnixstore does not use yet,
+    /// are other files.
+    pub fn classify(path: Path, is_root: bool, store_dir: &[u8]) -> Self {
+        use self::NodeDescription::*;
+        if path.first() == Some(&b'/') {
+            if path.starts_with(b"/proc/") {
+                Memory(path)
+            } else if is_root {
+                Link(path)
+            } else if path.starts_with(store_dir) && path.get(store_dir.len()) == Some(&b'/') {
+                Path(path)
+            } else {
+                File(path)
+            }
+        } else if path.starts_with(b"{memory:") || path == b"{lsof}" || path == b"{censored}" {
+            // {memory} is nix < 2.2 and was replaced by paths in /proc for linux and {lsof} for darwin in nix 2.3.
+            // See https://github.com/NixOS/nix/commit/a3f37d87eabcfb5dc581abcfa46e5e7d387dfa8c
+            // {censored} was introduced in nix 2.3:
+            // https://github.com/NixOS/nix/commit/53522cb6ac19bd1da35a657988231cce9387be9c
+            Memory(path)
+        } else if path.starts_with(b"{temp:") {
+            Temporary(path)
+        } else {
+            File(path)
+        }
+    }
+
+    /// Tells a store derivation, a store path whose name ends with `.drv`, from other store
+    /// paths.
+    pub fn with_derivation(self) -> Self {
+        match self {
+            NodeDescription::Path(path) if path.ends_with(b".drv") => {
+                NodeDescription::Derivation(path)
+            }
+            other => other,
+        }
+    }
+
     /// a short but human readable description of the node
     /// for a store path, only shows the name
     /// for a gc root, tells if it's a per-user profile, a NixOS generation, or a lorri gc
@@ -142,38 +256,32 @@ impl NodeDescription {
             .expect("regex compilation failed");
         };
         match self {
-            Path(path) => match STORE_PATH.captures(&path) {
+            File(path) => Cow::Borrowed(path),
+            Path(path) | Derivation(path) => match STORE_PATH.captures(path) {
                 Some(c) => {
                     let name = c.get(1).unwrap().as_bytes();
                     Cow::Borrowed(name)
                 }
-                None => Cow::Borrowed(&path),
+                None => Cow::Borrowed(path),
             },
             Link(path) => match std::str::from_utf8(path) {
                 Ok(path_str) => {
-                    let link_age = match std::path::Path::new(std::ffi::OsStr::from_bytes(path))
-                        .symlink_metadata()
-                        .map(|m| m.modified())
-                    {
-                        Ok(Ok(time)) => LinkAge(Some(time)),
-                        _ => LinkAge(None),
-                    };
                     let fancy_desc = match PER_USER_PROFILE.captures(&path_str) {
                         Some(c) => {
                             let user = c.get(1).unwrap().as_str();
                             let profile = c.get(2).unwrap().as_str();
                             let gen = c.get(3).unwrap().as_str();
                             let desc = if profile == "profile" {
-                                format!("generation {gen} of {user}'s profile{link_age}")
+                                format!("generation {gen} of {user}'s profile")
                             } else {
-                                format!("generation {gen} of {user}'s profile {profile}{link_age}")
+                                format!("generation {gen} of {user}'s profile {profile}")
                             };
                             Some(desc)
                         }
                         None => match SYSTEM_PROFILE.captures(&path_str) {
                             Some(c) => {
                                 let gen = c.get(1).unwrap().as_str();
-                                let desc = format!("NixOS generation {gen}{link_age}");
+                                let desc = format!("NixOS generation {gen}");
                                 Some(desc)
                             }
                             None => match LORRI.captures(&path_str) {
@@ -182,7 +290,7 @@ impl NodeDescription {
                                     match resolve_lorri_root(path) {
                                         Ok(nix_file) => {
                                             let desc = format!(
-                                                "{user}'s lorri cache for {}{link_age}",
+                                                "{user}'s lorri cache for {}",
                                                 nix_file.display()
                                             );
                                             Some(desc)
@@ -194,20 +302,66 @@ impl NodeDescription {
                             },
                         },
                     };
-                    match (&link_age, fancy_desc) {
-                        (_, Some(f)) => Cow::Owned(f.into_bytes()),
-                        (LinkAge(Some(_)), None) => {
-                            Cow::Owned(format!("{path_str}{link_age}").into_bytes())
-                        }
-                        _ => Cow::Borrowed(path),
+                    match fancy_desc {
+                        Some(f) => Cow::Owned(f.into_bytes()),
+                        None => Cow::Borrowed(path),
                     }
                 }
                 Err(_) => Cow::Borrowed(path),
             },
-            Memory(path) | Temporary(path) => Cow::Borrowed(&path),
+            Memory(path) | Temporary(path) => Cow::Borrowed(path),
             Dummy => Cow::Borrowed(b"{dummy}"),
             FilteredOut => Cow::Borrowed(b"{filtered out}"),
             Transient => Cow::Borrowed(b"{transient}"),
+            Process(process) => {
+                let mut res = PROCESS_PREFIX.to_vec();
+                res.extend(process);
+                Cow::Owned(res)
+            }
+            Common => Cow::Borrowed(b"{shared by several gc-roots}"),
+            Group(prefix) => {
+                let mut res = Vec::with_capacity(GROUP_PREFIX.len() + prefix.len() + 1);
+                res.extend(GROUP_PREFIX);
+                res.extend(prefix);
+                res.push(b'}');
+                Cow::Owned(res)
+            }
+            Generations(profile, count) => {
+                let mut res = profile
+                    .strip_prefix(PROFILES_DIR)
+                    .unwrap_or(profile)
+                    .to_vec();
+                res.extend(format!(" ({count} generations)").into_bytes());
+                Cow::Owned(res)
+            }
+            Package(name, count) => {
+                let mut res = name.clone();
+                res.extend(format!(" ({count} variants)").into_bytes());
+                Cow::Owned(res)
+            }
+            Other(root) => {
+                let mut res = Vec::with_capacity(OTHER_PREFIX.len() + root.len() + 1);
+                res.extend(OTHER_PREFIX);
+                res.extend(root);
+                res.push(b'}');
+                Cow::Owned(res)
+            }
+            System(system) if system.is_empty() => Cow::Borrowed(b"{unknown system}"),
+            System(system) => {
+                let mut res = Vec::with_capacity(SYSTEM_PREFIX.len() + system.len() + 1);
+                res.extend(SYSTEM_PREFIX);
+                res.extend(system);
+                res.push(b'}');
+                Cow::Owned(res)
+            }
+            Error(message) if message.is_empty() => Cow::Borrowed(b"{error}"),
+            Error(message) => {
+                let mut res = Vec::with_capacity(ERROR_PREFIX.len() + message.len() + 1);
+                res.extend(ERROR_PREFIX);
+                res.extend(message);
+                res.push(b'}');
+                Cow::Owned(res)
+            }
             Shared(name) => {
                 let mut res = Vec::with_capacity(SHARED_PREFIX.len() + name.len());
                 res.extend(SHARED_PREFIX);
@@ -221,7 +375,9 @@ impl NodeDescription {
     pub fn path_as_os_str(&self) -> Option<&OsStr> {
         use self::NodeDescription::*;
         match self {
-            Link(path) | Path(path) => Some(OsStr::from_bytes(path)),
+            Link(path) | Path(path) | File(path) | Derivation(path) => {
+                Some(OsStr::from_bytes(path))
+            }
             _ => None,
         }
     }
@@ -229,9 +385,17 @@ impl NodeDescription {
     pub fn path(&self) -> Option<&Path> {
         use self::NodeDescription::*;
         match self {
-            Link(path) | Path(path) | Memory(path) | Temporary(path) => Some(&path),
-            Shared(name) => Some(&name),
-            Transient | Dummy | FilteredOut => None,
+            Link(path) | Path(path) | Memory(path) | Temporary(path) | File(path)
+            | Derivation(path) => Some(path),
+            Shared(name)
+            | Group(name)
+            | Generations(name, _)
+            | Package(name, _)
+            | Other(name)
+            | System(name)
+            | Process(name)
+            | Error(name) => Some(name),
+            Transient | Dummy | FilteredOut | Common => None,
         }
     }
 
@@ -245,7 +409,15 @@ impl NodeDescription {
             Shared(_) => NodeKind::Shared,
             Dummy => NodeKind::Dummy,
             FilteredOut => NodeKind::FilteredOut,
-            Transient => NodeKind::Transient,
+            Transient | Process(_) => NodeKind::Transient,
+            Group(_) | Generations(..) => NodeKind::Group,
+            Other(_) => NodeKind::Other,
+            System(_) => NodeKind::System,
+            Common => NodeKind::Common,
+            Error(_) => NodeKind::Error,
+            File(_) => NodeKind::File,
+            Derivation(_) => NodeKind::Derivation,
+            Package(..) => NodeKind::Package,
         }
     }
 }
@@ -266,6 +438,30 @@ pub struct DepNode {
     pub description: NodeDescription,
     /// size in bytes
     pub size: u64,
+    /// number of references to the store paths this node stands for, from store paths it
+    /// does not stand for, in the graph as read from the store
+    pub referrers: u32,
+    /// number of references from the store paths this node stands for to store paths it
+    /// does not stand for, in the graph as read from the store
+    pub references: u32,
+    /// whether one of the store paths this node stands for refers to itself. Such
+    /// references are not part of the graph.
+    pub self_reference: bool,
+    /// the platform of the derivation which built this store path, like `x86_64-linux`,
+    /// if it was looked up and is known
+    pub system: Option<Path>,
+    /// the paths and sizes of the other store paths merged into this node by
+    /// `reduction::condense`, in the order they were reached
+    pub members: Vec<(Path, u64)>,
+    /// for a gc-root which is a link, when it was created, and for a group of gc-roots, when
+    /// the newest of them was, if known
+    pub age: Option<SystemTime>,
+    /// for a gc-root, the user it belongs to, once tagged by `owners::tag_users`
+    pub user: Option<String>,
+    /// for a memory or temporary root of the running system, the command of the process
+    /// holding it and for how long it was running, once looked up by
+    /// `procinfo::record_processes`
+    pub process: Option<String>,
 }
 
 impl DepNode {
@@ -273,35 +469,19 @@ impl DepNode {
     /// # Safety
     /// `p` must be a valid pointer and contain no null pointer members.
     /// Its `path` field must contain a valid C string.
-    unsafe fn new(p: &bindings::path_t) -> Self {
+    unsafe fn new(p: &bindings::path_t, store_dir: &[u8]) -> Self {
         let path: Vec<u8> = CStr::from_ptr(p.path).to_bytes().to_vec();
-        use self::NodeDescription::*;
-        let description;
-        if path[0] == b'/' {
-            if path.starts_with(b"/proc/") {
-                description = Memory(path);
-            } else if p.is_root != 0 {
-                description = Link(path);
-            } else {
-                description = Path(path);
-            }
-        } else if path.starts_with(b"{memory:") || path == b"{lsof}" || path == b"{censored}" {
-            // {memory} is nix < 2.2 and was replaced by paths in /proc for linux and {lsof} for darwin in nix 2.3.
-            // See https://github.com/NixOS/nix/commit/a3f37d87eabcfb5dc581abcfa46e5e7d387dfa8c
-            // {censored} was introduced in nix 2.3:
-            // https://github.com/NixOS/nix/commit/53522cb6ac19bd1da35a657988231cce9387be9c
-            description = Memory(path);
-        } else if path.starts_with(b"{temp:") {
-            description = Temporary(path);
+        let description = NodeDescription::classify(path, p.is_root != 0, store_dir);
+        let system = if p.system.is_null() {
+            None
         } else {
-            panic!(
-                "Unknown store path type: {}",
-                String::from_utf8_lossy(&path)
-            );
-        }
+            Some(CStr::from_ptr(p.system).to_bytes().to_vec())
+        };
         Self {
             description,
             size: p.size,
+            system,
+            ..Self::dummy()
         }
     }
 
@@ -309,6 +489,14 @@ impl DepNode {
         DepNode {
             description: NodeDescription::Dummy,
             size: 0,
+            referrers: 0,
+            references: 0,
+            self_reference: false,
+            system: None,
+            members: Vec::new(),
+            age: None,
+            user: None,
+            process: None,
         }
     }
 
@@ -316,8 +504,38 @@ impl DepNode {
         self.description.kind()
     }
 
-    pub fn name(&self) -> Cow<[u8]> {
-        self.description.name()
+    /// The name of its description, followed by the process holding it or its age if known,
+    /// like `result, 3d ago`
+    pub fn name(&self) -> Cow<'_, [u8]> {
+        let name = self.description.name();
+        if self.age.is_none() && self.process.is_none() {
+            return name;
+        }
+        let mut res = name.into_owned();
+        if let Some(process) = &self.process {
+            res.extend(b" of ");
+            res.extend(process.as_bytes());
+        }
+        if self.age.is_some() {
+            res.extend(LinkAge(self.age).to_string().into_bytes());
+        }
+        Cow::Owned(res)
+    }
+
+    /// Returns the store paths this node stands for, with their size: its members, and its
+    /// own path if any, with the size not accounted for by members.
+    pub fn store_paths(&self) -> Vec<(&[u8], u64)> {
+        let mut res: Vec<(&[u8], u64)> = self
+            .members
+            .iter()
+            .map(|(path, size)| (path.as_slice(), *size))
+            .collect();
+        if let NodeDescription::Path(path) | NodeDescription::Derivation(path) = &self.description {
+            // its own size, and what filters merged into it
+            let members: u64 = res.iter().map(|(_, size)| size).sum();
+            res.push((path, self.size.saturating_sub(members)));
+        }
+        res
     }
 }
 
@@ -348,7 +566,45 @@ pub struct SizeMetadata {
     pub size: EnumMap<DedupAwareness, EnumMap<Reachability, Option<u64>>>,
 }
 
-pub type Edge = ();
+/// Where an edge of the graph comes from. Only references are facts about the store, the
+/// other edges are added to give the graph a single root or to bypass merged nodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Edge {
+    /// a store path refers to another, or a gc-root to its target
+    Reference,
+    /// the dummy root depends on a gc-root
+    Root,
+    /// added when merging nodes, like transient roots, roots grouped by prefix, or the
+    /// nodes removed by filters
+    Synthetic,
+}
+
+impl Edge {
+    /// Whether the edge is not a reference
+    pub fn is_synthetic(self) -> bool {
+        self != Edge::Reference
+    }
+
+    /// Returns the name of the provenance, as written in exports
+    pub fn name(self) -> &'static str {
+        match self {
+            Edge::Reference => "reference",
+            Edge::Root => "root",
+            Edge::Synthetic => "synthetic",
+        }
+    }
+}
+
+/// Adds an edge from `a` to `b`, or if there already is one, makes it a reference if either
+/// is, as merged nodes refer to each other if any of their members do.
+pub fn merge_edge(graph: &mut DepGraph, a: NodeIndex, b: NodeIndex, edge: Edge) {
+    match graph.find_edge(a, b) {
+        Some(e) => graph[e] = graph[e].min(edge),
+        None => {
+            graph.add_edge(a, b, edge);
+        }
+    }
+}
 
 pub type DepGraph = petgraph::graph::Graph<DepNode, Edge, petgraph::Directed>;
 
@@ -357,73 +613,332 @@ pub struct DepInfos {
     pub graph: DepGraph,
     pub root: NodeIndex,
     pub metadata: SizeMetadata,
+    /// descriptions given by the user to some paths
+    pub annotations: Annotations,
+}
+
+/// Why the store could not be read, as reported by libnixstore
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreError {
+    /// the return code of the adapter
+    pub code: i32,
+    /// the errno of the failed system call, if the error was a system error
+    pub errno: Option<i32>,
+    /// the message of the nix exception, without colors
+    pub message: String,
+}
+
+impl StoreError {
+    /// Builds the error from what the adapter reported, with the message stripped from
+    /// color escape sequences and its `error: ` prefix.
+    fn new(code: i32, errno: i32, message: &[u8]) -> Self {
+        StoreError {
+            code,
+            errno: if errno == 0 { None } else { Some(errno) },
+            message: clean_message(message),
+        }
+    }
+
+    /// Builds the error from the return code `code` of a function of the adapter and the
+    /// `error` it filled, whose message is freed.
+    pub(crate) fn from_adapter(code: i32, error: bindings::store_error_t) -> Self {
+        let message = if error.message.is_null() {
+            Vec::new()
+        } else {
+            unsafe {
+                let message = CStr::from_ptr(error.message).to_bytes().to_vec();
+                libc::free(error.message as *mut c_void);
+                message
+            }
+        };
+        StoreError::new(code, error.err_no, &message)
+    }
+}
+
+/// Strips the message of a nix exception from color escape sequences and its `error: `
+/// prefix.
+fn clean_message(message: &[u8]) -> String {
+    lazy_static! {
+        static ref ANSI_ESCAPE: regex::bytes::Regex =
+            regex::bytes::Regex::new("\\x1b\\[[0-9;]*[a-zA-Z]").unwrap();
+    }
+    let message = ANSI_ESCAPE.replace_all(message, &b""[..]);
+    let message = String::from_utf8_lossy(&message);
+    let message = message.trim();
+    message
+        .strip_prefix("error:")
+        .unwrap_or(message)
+        .trim()
+        .to_owned()
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "unknown error (code {})", self.code)
+        } else {
+            f.write_str(&self.message)
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// What `read_from_store` has read so far
+pub struct Reading {
+    graph: DepGraph,
+    /// the store paths which could not be read, with why. Their nodes are added once
+    /// libnixstore is done, as it numbers nodes in the order it registers them.
+    errors: Vec<(NodeIndex, Vec<u8>)>,
+    /// the store directory, to tell store paths from other files
+    store_dir: Path,
+    /// the nodes of the paths whose closure is read, if any, in order
+    roots: Vec<NodeIndex>,
+    /// whether store derivations are told from other store paths
+    build_deps: bool,
+    /// counts the store paths read
+    progress: indicatif::ProgressBar,
+}
+
+/// The functions libnix_adapter calls to fill a `Reading`. They are exported whether public
+/// or not, as they are `no_mangle`.
+pub(crate) mod adapter {
+    use super::*;
+
+    // symbol exported to libnix_adapter
+    /// # Safety
+    /// `g` must have been obtained by rust code, and not modified by C code.
+    /// `p` must be a valid pointer and contain no null pointer members, except `system` and
+    /// `error`. Its `path` field, and `error` if not null, must contain a valid C string.
+    #[no_mangle]
+    pub unsafe extern "C" fn register_node(g: *mut Reading, p: *const bindings::path_t) {
+        let p: &bindings::path_t = p.as_ref().unwrap();
+        let g: &mut Reading = g.as_mut().unwrap();
+        let mut drv = DepNode::new(p, &g.store_dir);
+        if g.build_deps {
+            drv.description = drv.description.with_derivation();
+        }
+        let idx = g.graph.add_node(drv);
+        g.progress.inc(1);
+        if !p.error.is_null() {
+            let message = CStr::from_ptr(p.error).to_bytes().to_vec();
+            g.errors.push((idx, message));
+        }
+    }
+
+    // symbol exported to libnix_adapter
+    /// # Safety
+    /// `g` must have been obtained by rust code, and not modified by C code.
+    #[no_mangle]
+    pub unsafe extern "C" fn register_root(g: *mut Reading, index: u32) {
+        g.as_mut().unwrap().roots.push(NodeIndex::from(index));
+    }
+
+    // symbol exported to libnix_adapter
+    /// # Safety
+    /// `g` must have been obtained by rust code, and not modified by C code.
+    #[no_mangle]
+    pub unsafe extern "C" fn register_edge(g: *mut Reading, from: u32, to: u32) {
+        let g: &mut DepGraph = &mut g.as_mut().unwrap().graph;
+        if from == to {
+            g[NodeIndex::from(from)].self_reference = true;
+            return;
+        }
+        g.add_edge(NodeIndex::from(from), NodeIndex::from(to), Edge::Reference);
+    }
+}
+
+/// sets the `referrers` and `references` fields of all nodes of `g` to their degree
+pub fn record_degrees(g: &mut DepGraph) {
+    for node in g.node_weights_mut() {
+        node.referrers = 0;
+        node.references = 0;
+    }
+    let edges: Vec<_> = g
+        .raw_edges()
+        .iter()
+        .map(|e| (e.source(), e.target()))
+        .collect();
+    for (from, to) in edges {
+        g[from].references += 1;
+        g[to].referrers += 1;
+    }
 }
 
-// symbol exported to libnix_adapter
-/// # Safety
-/// `g` must have been obtained by rust code, and not modified by C code.
-/// `p` must be a valid pointer and contain no null pointer members.
-/// Its `path` field must contain a valid C string.
-#[no_mangle]
-pub unsafe extern "C" fn register_node(g: *mut DepGraph, p: *const bindings::path_t) {
-    let p: &bindings::path_t = p.as_ref().unwrap();
-    let g: &mut DepGraph = g.as_mut().unwrap();
-    let drv = DepNode::new(p);
-    g.add_node(drv);
+/// Returns the gc-roots in `dirs` as libnixstore finds them, with the store path each
+/// retains: the symlinks to a store path, and the symlinks which those symlinks point to,
+/// like the `result` links registered in `gcroots/auto`. Directories are searched
+/// recursively.
+pub fn find_link_roots(dirs: &[PathBuf], store_dir: &std::path::Path) -> Vec<(Path, PathBuf)> {
+    let mut res = Vec::new();
+    let mut stack = dirs.to_vec();
+    while let Some(path) = stack.pop() {
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            if let Ok(entries) = std::fs::read_dir(&path) {
+                stack.extend(entries.filter_map(|e| e.ok()).map(|e| e.path()));
+            }
+            continue;
+        }
+        let target = match std::fs::read_link(&path) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        let target = match path.parent() {
+            Some(parent) => parent.join(target),
+            None => target,
+        };
+        let link = if target.starts_with(store_dir) {
+            path
+        } else if matches!(target.symlink_metadata(), Ok(m) if m.file_type().is_symlink()) {
+            target
+        } else {
+            continue;
+        };
+        if let Ok(store_path) = crate::archive::store_path_of(&link, store_dir) {
+            res.push((link.into_os_string().into_vec(), store_path));
+        }
+    }
+    res
 }
 
-// symbol exported to libnix_adapter
-/// # Safety
-/// `g` must have been obtained by rust code, and not modified by C code.
-#[no_mangle]
-pub unsafe extern "C" fn register_edge(g: *mut DepGraph, from: u32, to: u32) {
-    if from == to {
-        return;
+/// Returns whether nix keeps the derivations of live store paths and the outputs of live
+/// derivations, the `keep-derivations` and `keep-outputs` settings of `nix.conf`.
+fn keep_settings() -> (bool, bool) {
+    let dir = std::env::var_os("NIX_CONF_DIR").unwrap_or_else(|| "/etc/nix".into());
+    let mut res = (true, false);
+    if let Ok(text) = std::fs::read_to_string(PathBuf::from(dir).join("nix.conf")) {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            if let Some((key, value)) = line.split_once('=') {
+                let value = value.trim() == "true";
+                match key.trim() {
+                    "keep-derivations" | "gc-keep-derivations" => res.0 = value,
+                    "keep-outputs" | "gc-keep-outputs" => res.1 = value,
+                    _ => (),
+                }
+            }
+        }
     }
-    let g: &mut DepGraph = g.as_mut().unwrap();
-    g.add_edge(NodeIndex::from(from), NodeIndex::from(to), ());
+    res
 }
 
 impl DepInfos {
-    /// returns the dependency graph of the nix-store
+    /// returns the dependency graph of the nix-store, or of the union of the closures of
+    /// `roots` if not empty
     /// actual connection specifics are left to libnixstore
     /// (reading ourselves, connecting to a daemon...)
-    pub fn read_from_store(root: Option<OsString>) -> Result<Self, i32> {
-        let mut g = DepGraph::new();
-        let gptr = &mut g as *mut _ as *mut c_void;
-        let root_data = root.map(|path| {
-            let mut bytes = path.into_vec();
-            bytes.push(0);
-            bytes
-        });
-        let rootptr: *const u8 = match root_data.as_ref() {
-            None => std::ptr::null(),
-            Some(path) => path.as_ptr(),
+    /// The `system` of nodes is only looked up if `with_systems` is true, as it needs reading
+    /// all derivations.
+    /// Store paths which cannot be read are kept without their references, and refer to an
+    /// `Error` node carrying why.
+    /// Up to `jobs` queries are sent at once to a remote store, a wave of paths at a time;
+    /// local stores are read one path at a time, as concurrent queries only contend there.
+    /// The store is opened at the URI `store`, like `ssh-ng://host`, or is the default one.
+    /// With `build_deps`, store derivations are `Derivation` nodes, and refer to all their
+    /// outputs if the store keeps them, so that what `keep-outputs` retains is accounted for.
+    pub fn read_from_store(
+        roots: Vec<OsString>,
+        store: Option<&OsStr>,
+        with_systems: bool,
+        build_deps: bool,
+        jobs: usize,
+    ) -> Result<Self, StoreError> {
+        let mut reading = Reading {
+            graph: DepGraph::new(),
+            errors: Vec::new(),
+            store_dir: crate::archive::store_dir().into_os_string().into_vec(),
+            roots: Vec::new(),
+            build_deps,
+            progress: crate::msg::progress(None, "store paths read"),
+        };
+        let gptr = &mut reading as *mut _ as *mut c_void;
+        let root_data: Vec<Vec<u8>> = roots
+            .into_iter()
+            .map(|path| {
+                let mut bytes = path.into_vec();
+                bytes.push(0);
+                bytes
+            })
+            .collect();
+        let rootptrs: Vec<*const std::os::raw::c_char> = root_data
+            .iter()
+            .map(|path| path.as_ptr() as *const std::os::raw::c_char)
+            .collect();
+        let store = match store {
+            Some(uri) => Some(
+                std::ffi::CString::new(uri.as_bytes())
+                    .map_err(|_| StoreError::new(1, 0, b"the store URI contains a null byte"))?,
+            ),
+            None => None,
+        };
+        let mut error = bindings::store_error_t {
+            err_no: 0,
+            message: std::ptr::null_mut(),
+        };
+        let res = unsafe {
+            bindings::populateGraph(
+                gptr,
+                store.as_ref().map_or(std::ptr::null(), |uri| uri.as_ptr()),
+                rootptrs.as_ptr() as *mut *const std::os::raw::c_char,
+                rootptrs.len() as std::os::raw::c_uint,
+                with_systems as std::os::raw::c_int,
+                build_deps as std::os::raw::c_int,
+                jobs as std::os::raw::c_uint,
+                &mut error,
+            )
         };
-        let res = unsafe { bindings::populateGraph(gptr, rootptr as *const std::os::raw::c_char) };
 
+        reading.progress.finish_and_clear();
         if res != 0 {
-            return Err(res);
+            return Err(StoreError::from_adapter(res, error));
         }
-        let root_idx = match &root_data {
-            None => g.add_node(DepNode::dummy()),
-            Some(_) => NodeIndex::from(0),
+        let Reading {
+            graph: mut g,
+            errors,
+            mut roots,
+            ..
+        } = reading;
+        roots.sort();
+        roots.dedup();
+        // a single path is the root itself, several are the references of a dummy root
+        let root_idx = match roots.as_slice() {
+            &[root] => root,
+            _ => g.add_node(DepNode::dummy()),
         };
-        let reachable = match &root_data {
-            None => Reachability::Disconnected,
-            Some(_) => Reachability::Connected,
+        let reachable = if root_data.is_empty() {
+            Reachability::Disconnected
+        } else {
+            Reachability::Connected
         };
         let metadata = SizeMetadata {
             reachable,
             dedup: DedupAwareness::Unaware,
             size: enum_map! { _ => enum_map!{ _ => None }},
         };
+        record_degrees(&mut g);
+        for (idx, message) in errors {
+            let error = g.add_node(DepNode {
+                description: NodeDescription::Error(clean_message(&message).into_bytes()),
+                ..DepNode::dummy()
+            });
+            g.add_edge(idx, error, Edge::Synthetic);
+        }
         let mut di = DepInfos {
             root: root_idx,
             graph: g,
             metadata,
+            annotations: Annotations::default(),
         };
-        if root_data.is_none() {
+        if roots.len() > 1 {
+            for &root in &roots {
+                di.graph.add_edge(di.root, root, Edge::Root);
+            }
+        }
+        if root_data.is_empty() {
             let gc_roots: Vec<_> = di
                 .graph
                 .node_references()
@@ -436,9 +951,128 @@ impl DepInfos {
                 })
                 .collect();
             for root in gc_roots {
-                di.graph.add_edge(di.root, root, ());
+                di.graph.add_edge(di.root, root, Edge::Root);
+            }
+        }
+        di.record_ages();
+        di.record_metadata();
+        Ok(di)
+    }
+
+    /// Same as `read_from_store`, but reads the database of the local store, `db/db.sqlite`
+    /// in `NIX_STATE_DIR`, without libnixstore.
+    /// Only the gc-roots which are links, in `gcroots` and `profiles`, are found: the store
+    /// paths used by running processes are garbage.
+    pub fn read_from_db(roots: Vec<OsString>) -> std::io::Result<Self> {
+        let state_dir = PathBuf::from(
+            std::env::var_os("NIX_STATE_DIR").unwrap_or_else(|| "/nix/var/nix".into()),
+        );
+        let store_dir = crate::archive::store_dir();
+        let db = crate::sqlite::Database::open(&state_dir.join("db/db.sqlite"))?;
+        let invalid = |table: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("malformed row in {}", table),
+            )
+        };
+
+        let mut builder = DepInfosBuilder::new();
+        let mut by_id = collections::HashMap::new();
+        let mut by_path = collections::HashMap::new();
+        let mut derivers = Vec::new();
+        let valid_paths = db.table("ValidPaths")?;
+        let (id, path, deriver, nar_size) = (
+            valid_paths.column("id")?,
+            valid_paths.column("path")?,
+            valid_paths.column("deriver")?,
+            valid_paths.column("narSize")?,
+        );
+        db.rows(&valid_paths, |row| {
+            let p = row[path].as_bytes().ok_or_else(|| invalid("ValidPaths"))?;
+            let size = row[nar_size].as_integer().unwrap_or(0) as u64;
+            let idx = builder.add_path(p, size);
+            by_id.insert(
+                row[id].as_integer().ok_or_else(|| invalid("ValidPaths"))?,
+                idx,
+            );
+            by_path.insert(p.to_vec(), idx);
+            if let Some(d) = row[deriver].as_bytes() {
+                derivers.push((idx, d.to_vec()));
+            }
+            Ok(())
+        })?;
+        let refs = db.table("Refs")?;
+        let (referrer, reference) = (refs.column("referrer")?, refs.column("reference")?);
+        db.rows(&refs, |row| {
+            let node = |i: usize| {
+                row[i]
+                    .as_integer()
+                    .and_then(|id| by_id.get(&id).copied())
+                    .ok_or_else(|| invalid("Refs"))
+            };
+            builder.add_reference(node(referrer)?, node(reference)?);
+            Ok(())
+        })?;
+        let (keep_derivations, keep_outputs) = keep_settings();
+        for (idx, deriver) in derivers {
+            if let Some(&drv) = by_path.get(&deriver) {
+                if keep_derivations {
+                    builder.add_reference(idx, drv);
+                }
+                if keep_outputs {
+                    builder.add_reference(drv, idx);
+                }
+            }
+        }
+
+        if roots.is_empty() {
+            let dirs = [state_dir.join("gcroots"), state_dir.join("profiles")];
+            for (link, target) in find_link_roots(&dirs, &store_dir) {
+                if let Some(&idx) = by_path.get(target.as_os_str().as_bytes()) {
+                    let root = builder.add_root(link, NodeKind::Link);
+                    builder.add_reference(root, idx);
+                }
+            }
+            let mut di = builder.build();
+            di.record_ages();
+            return Ok(di);
+        }
+
+        let mut targets = Vec::new();
+        for root in roots {
+            let path = crate::archive::store_path_of(std::path::Path::new(&root), &store_dir)?;
+            match by_path.get(path.as_os_str().as_bytes()) {
+                Some(&idx) => targets.push(idx),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("'{}' is not a valid path", path.display()),
+                    ))
+                }
+            }
+        }
+        targets.sort();
+        targets.dedup();
+        let mut di = builder.build();
+        // a single path is the root itself, several are the references of the dummy root
+        match targets.as_slice() {
+            &[target] => di.root = target,
+            _ => {
+                for &target in &targets {
+                    di.graph.add_edge(di.root, target, Edge::Root);
+                }
             }
         }
+        let mut di = crate::reduction::keep_reachable(di);
+        record_degrees(&mut di.graph);
+        if di.graph[di.root].kind() == NodeKind::Dummy {
+            // edges from the dummy root are not references
+            di.graph[di.root].references = 0;
+            for target in di.roots().collect::<Vec<_>>() {
+                di.graph[target].referrers -= 1;
+            }
+        }
+        di.record_ages();
         di.record_metadata();
         Ok(di)
     }
@@ -453,11 +1087,41 @@ impl DepInfos {
         sum
     }
 
+    /// returns the number of nodes referring to themselves
+    pub fn self_references(&self) -> usize {
+        self.graph
+            .raw_nodes()
+            .iter()
+            .filter(|n| n.weight.self_reference)
+            .count()
+    }
+
+    /// returns why the store paths which could not be read could not, from their `Error`
+    /// nodes
+    pub fn read_errors(&self) -> Vec<&[u8]> {
+        self.graph
+            .node_weights()
+            .filter_map(|n| match &n.description {
+                NodeDescription::Error(message) => Some(message.as_slice()),
+                _ => None,
+            })
+            .collect()
+    }
+
     /// returns the sum of the size of all the derivations
     pub fn size(&self) -> u64 {
         self.graph.raw_nodes().iter().map(|n| n.weight.size).sum()
     }
 
+    /// records the age of the gc-roots which are links, when they are on this machine.
+    pub fn record_ages(&mut self) {
+        for node in self.graph.node_weights_mut() {
+            if let NodeDescription::Link(path) = &node.description {
+                node.age = link_time(path);
+            }
+        }
+    }
+
     /// records the current size of the graph in its metadata field.
     pub fn record_metadata(&mut self) {
         let dedup = self.metadata.dedup;
@@ -476,19 +1140,38 @@ impl DepInfos {
         }
     }
 
+    /// the label of a node to show to the user: its name, prefixed by
+    /// its annotation if any
+    pub fn label(&self, idx: NodeIndex) -> Cow<'_, [u8]> {
+        let node = &self.graph[idx];
+        let annotation = match &node.description {
+            NodeDescription::Link(path)
+            | NodeDescription::Path(path)
+            | NodeDescription::Derivation(path) => self.annotations.lookup(path),
+            _ => None,
+        };
+        match annotation {
+            None => node.name(),
+            Some(annotation) => {
+                let mut res = format!("{}: ", annotation).into_bytes();
+                res.extend_from_slice(&node.name());
+                Cow::Owned(res)
+            }
+        }
+    }
+
     /// returns a Dfs suitable to visit all reachable nodes.
     pub fn dfs(&self) -> Dfs<NodeIndex, fixedbitset::FixedBitSet> {
         petgraph::visit::Dfs::new(&self.graph, self.root)
     }
 
     /// Returns the iterator of roots
-    pub fn roots(&self) -> petgraph::graph::Neighbors<(), u32> {
+    pub fn roots(&self) -> petgraph::graph::Neighbors<'_, Edge, u32> {
         self.graph.neighbors(self.root)
     }
 
     /// returns the set of paths of the roots
     /// intended for testing mainly
-    #[cfg(test)]
     pub fn roots_name(&self) -> collections::BTreeSet<String> {
         self.roots()
             .map(|idx| {
@@ -499,7 +1182,6 @@ impl DepInfos {
     }
 
     /// checks metadata is consistent
-    #[cfg(test)]
     pub fn check_metadata(&self) {
         use self::Reachability::*;
         if self.metadata.reachable == Connected {
@@ -520,3 +1202,175 @@ impl DepInfos {
         }
     }
 }
+
+/// Builds a `DepInfos` node by node, as `read_from_store` does from libnixstore, for tools
+/// and tests which do not read a real store.
+#[derive(Debug, Default)]
+pub struct DepInfosBuilder {
+    graph: DepGraph,
+    /// the node of each path added
+    by_path: collections::HashMap<NodeDescription, NodeIndex>,
+    roots: Vec<NodeIndex>,
+}
+
+impl DepInfosBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a node, or returns the existing one with the same description
+    fn add(&mut self, description: NodeDescription, size: u64) -> NodeIndex {
+        let graph = &mut self.graph;
+        *self
+            .by_path
+            .entry(description)
+            .or_insert_with_key(|description| {
+                graph.add_node(DepNode {
+                    description: description.clone(),
+                    size,
+                    ..DepNode::dummy()
+                })
+            })
+    }
+
+    /// Adds the store path `path` of `size` bytes. Adding a path again returns the node
+    /// of the first one.
+    pub fn add_path<P: Into<Path>>(&mut self, path: P, size: u64) -> NodeIndex {
+        self.add(NodeDescription::Path(path.into()), size)
+    }
+
+    /// Records that `from` refers to `to`, like a store path to another or a gc-root to
+    /// its target.
+    pub fn add_reference(&mut self, from: NodeIndex, to: NodeIndex) {
+        if from == to {
+            self.graph[from].self_reference = true;
+        } else {
+            merge_edge(&mut self.graph, from, to, Edge::Reference);
+        }
+    }
+
+    /// Adds the gc-root `path`, whose kind must be `Link`, `Memory` or `Temporary`, like
+    /// `/nix/var/nix/gcroots/auto/foo`, `/proc/42/maps` or `{temp:42}`. Its target is then
+    /// given with `add_reference`.
+    ///
+    /// # Panics
+    /// if `kind` is not one of these
+    pub fn add_root<P: Into<Path>>(&mut self, path: P, kind: NodeKind) -> NodeIndex {
+        let path = path.into();
+        let description = match kind {
+            NodeKind::Link => NodeDescription::Link(path),
+            NodeKind::Memory => NodeDescription::Memory(path),
+            NodeKind::Temporary => NodeDescription::Temporary(path),
+            _ => panic!("{:?} is not a kind of gc-root", kind),
+        };
+        let idx = self.add(description, 0);
+        if !self.roots.contains(&idx) {
+            self.roots.push(idx);
+        }
+        idx
+    }
+
+    /// Returns the graph built, the dummy root of which refers to all the gc-roots added.
+    pub fn build(self) -> DepInfos {
+        let mut graph = self.graph;
+        record_degrees(&mut graph);
+        let root = graph.add_node(DepNode::dummy());
+        for idx in self.roots {
+            graph.add_edge(root, idx, Edge::Root);
+        }
+        let mut di = DepInfos {
+            graph,
+            root,
+            metadata: SizeMetadata {
+                reachable: Reachability::Disconnected,
+                dedup: DedupAwareness::Unaware,
+                size: enum_map! { _ => enum_map!{ _ => None }},
+            },
+            annotations: Annotations::default(),
+        };
+        di.record_metadata();
+        di
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+
+    #[test]
+    fn check_builder() {
+        let mut builder = DepInfosBuilder::new();
+        let hello = builder.add_path(&b"/nix/store/aaaa-hello"[..], 100);
+        let glibc = builder.add_path(&b"/nix/store/bbbb-glibc"[..], 1000);
+        let unused = builder.add_path(&b"/nix/store/cccc-unused"[..], 10);
+        assert_eq!(builder.add_path(&b"/nix/store/aaaa-hello"[..], 100), hello);
+        builder.add_reference(hello, glibc);
+        builder.add_reference(glibc, glibc);
+        let root = builder.add_root(&b"/nix/var/nix/gcroots/auto/hello"[..], NodeKind::Link);
+        builder.add_reference(root, hello);
+        let di = builder.build();
+        di.check_metadata();
+        assert_eq!(di.roots().collect::<Vec<_>>(), vec![root]);
+        assert_eq!(di.reachable_size(), 1100);
+        assert_eq!(di.size(), 1110);
+        assert!(di.graph[glibc].self_reference);
+        assert_eq!(di.graph[glibc].referrers, 1);
+        assert_eq!(di.graph[unused].referrers, 0);
+    }
+
+    #[test]
+    fn check_classify() {
+        use NodeDescription::*;
+        let classify = |path: &[u8], is_root| {
+            NodeDescription::classify(path.to_vec(), is_root, b"/nix/store").kind()
+        };
+        assert_eq!(classify(b"/nix/store/aaaa-hello", false), NodeKind::Path);
+        assert_eq!(classify(b"/home/alice/result", true), NodeKind::Link);
+        assert_eq!(classify(b"/proc/42/maps", true), NodeKind::Memory);
+        assert_eq!(classify(b"{temp:42}", true), NodeKind::Temporary);
+        assert_eq!(classify(b"/nix/storefoo", false), NodeKind::File);
+        assert_eq!(classify(b"{unknown}", true), NodeKind::File);
+        let project = File(b"/home/alice/src/project".to_vec());
+        assert_eq!(project.name().as_ref(), b"/home/alice/src/project");
+        assert!(!project.kind().is_gc_root());
+        let drv = Path(b"/nix/store/aaaa-hello.drv".to_vec()).with_derivation();
+        assert_eq!(drv.kind(), NodeKind::Derivation);
+        assert_eq!(drv.name().as_ref(), b"hello.drv");
+        assert_eq!(
+            Path(b"/nix/store/aaaa-hello".to_vec())
+                .with_derivation()
+                .kind(),
+            NodeKind::Path
+        );
+    }
+
+    #[test]
+    fn check_find_link_roots() {
+        use std::os::unix::fs::symlink;
+        let dir = std::env::temp_dir().join(format!("nix-du-roots-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = dir.join("store");
+        for d in ["store/aaaa-hello/bin", "gcroots/auto", "home", "profiles"].iter() {
+            std::fs::create_dir_all(dir.join(d)).unwrap();
+        }
+        symlink(store.join("aaaa-hello/bin"), dir.join("home/result")).unwrap();
+        symlink(dir.join("home/result"), dir.join("gcroots/auto/xyz")).unwrap();
+        // a stale link registered by a deleted result
+        symlink(dir.join("home/gone"), dir.join("gcroots/auto/zyx")).unwrap();
+        symlink(store.join("bbbb-env"), dir.join("profiles/profile-1-link")).unwrap();
+        symlink("profile-1-link", dir.join("profiles/profile")).unwrap();
+        let mut roots = find_link_roots(&[dir.join("gcroots"), dir.join("profiles")], &store);
+        roots.sort();
+        roots.dedup();
+        let bytes = |p: PathBuf| p.into_os_string().into_vec();
+        let expected = vec![
+            (bytes(dir.join("home/result")), store.join("aaaa-hello")),
+            (
+                bytes(dir.join("profiles/profile-1-link")),
+                store.join("bbbb-env"),
+            ),
+        ];
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(roots, expected);
+    }
+}
@@ -0,0 +1,566 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Comparing the live store paths of two graphs, typically a snapshot saved with
+//! `--save-graph` and the current store.
+//!
+//! Store paths which were removed and added with the same package name but a different
+//! version or hash are reported as upgrades of one another, rather than as unrelated paths.
+//! The growth of each gc-root is blamed on the added and upgraded store paths it keeps alive
+//! on its own.
+
+use crate::depgraph::*;
+use crate::digest::delta;
+use crate::tree::DominatorTree;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+/// How many store paths to blame for the growth of each gc-root
+const CULPRITS: usize = 5;
+
+/// A live store path
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Entry {
+    /// the name of the store path, without hash
+    pub name: Vec<u8>,
+    pub path: Path,
+    pub size: u64,
+}
+
+/// The differences between the live store paths of two graphs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    pub old_size: u64,
+    pub new_size: u64,
+    /// pairs of a removed and an added store path of the same package
+    pub upgrades: Vec<(Entry, Entry)>,
+    pub added: Vec<Entry>,
+    pub removed: Vec<Entry>,
+}
+
+/// Splits the name of a store path into package name and version, like `nix` does: the
+/// version starts at the first dash followed by something else than a letter.
+pub fn split_name(name: &[u8]) -> (&[u8], &[u8]) {
+    match name
+        .windows(2)
+        .position(|w| w[0] == b'-' && !w[1].is_ascii_alphabetic())
+    {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, b""),
+    }
+}
+
+/// Returns the next component of the version `v`, a number or a word, after `.` and `-`
+/// separators, and the rest of `v`.
+fn next_component(v: &[u8]) -> (&[u8], &[u8]) {
+    let is_separator = |c: &u8| *c == b'.' || *c == b'-';
+    let start = v.iter().position(|c| !is_separator(c)).unwrap_or(v.len());
+    let v = &v[start..];
+    let end = match v.first() {
+        Some(c) if c.is_ascii_digit() => v.iter().position(|c| !c.is_ascii_digit()),
+        _ => v.iter().position(|c| c.is_ascii_digit() || is_separator(c)),
+    }
+    .unwrap_or(v.len());
+    v.split_at(end)
+}
+
+/// Compares two components of versions like `nix` does: numbers numerically, the missing
+/// component before numbers, `pre` before anything else, words before numbers, so that
+/// `2.3a` < `2.3.1`, and words lexicographically.
+fn compare_components(c1: &[u8], c2: &[u8]) -> Ordering {
+    let is_number = |c: &[u8]| !c.is_empty() && c.iter().all(u8::is_ascii_digit);
+    match (is_number(c1), is_number(c2)) {
+        (true, true) => {
+            let trim = |c: &[u8]| c.len() - c.iter().take_while(|&&d| d == b'0').count();
+            let (t1, t2) = (trim(c1), trim(c2));
+            t1.cmp(&t2)
+                .then_with(|| c1[c1.len() - t1..].cmp(&c2[c2.len() - t2..]))
+        }
+        _ if c1 == c2 => Ordering::Equal,
+        (false, true) if c1.is_empty() => Ordering::Less,
+        (true, false) if c2.is_empty() => Ordering::Greater,
+        _ if c1 == b"pre" => Ordering::Less,
+        _ if c2 == b"pre" => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (true, false) => Ordering::Greater,
+        (false, false) => c1.cmp(c2),
+    }
+}
+
+/// Compares two versions like `builtins.compareVersions`: `1.9` < `1.10` < `1.10.1`, and
+/// `1.0pre1` < `1.0`.
+pub fn compare_versions(v1: &[u8], v2: &[u8]) -> Ordering {
+    let (mut v1, mut v2) = (v1, v2);
+    while !v1.is_empty() || !v2.is_empty() {
+        let (c1, rest1) = next_component(v1);
+        let (c2, rest2) = next_component(v2);
+        match compare_components(c1, c2) {
+            Ordering::Equal => (),
+            other => return other,
+        }
+        v1 = rest1;
+        v2 = rest2;
+    }
+    Ordering::Equal
+}
+
+/// Returns the store paths reachable from the root of `di`, by path.
+fn live_paths(di: &DepInfos) -> BTreeMap<Path, Entry> {
+    let mut res = BTreeMap::new();
+    let mut dfs = di.dfs();
+    while let Some(idx) = dfs.next(&di.graph) {
+        let node = &di.graph[idx];
+        if let NodeDescription::Path(path) = &node.description {
+            let entry = Entry {
+                name: node.name().into_owned(),
+                path: path.clone(),
+                size: node.size,
+            };
+            res.insert(path.clone(), entry);
+        }
+    }
+    res
+}
+
+/// Compares the live store paths of `old` and `new`.
+pub fn diff(old: &DepInfos, new: &DepInfos) -> Diff {
+    let old_paths = live_paths(old);
+    let new_paths = live_paths(new);
+    // removed and added paths, by package name
+    let only_in = |paths: &BTreeMap<Path, Entry>, other: &BTreeMap<Path, Entry>| {
+        let mut by_pname: BTreeMap<Vec<u8>, BTreeSet<Entry>> = BTreeMap::new();
+        for (path, entry) in paths {
+            if !other.contains_key(path) {
+                let pname = split_name(&entry.name).0.to_vec();
+                by_pname.entry(pname).or_default().insert(entry.clone());
+            }
+        }
+        by_pname
+    };
+    let removed = only_in(&old_paths, &new_paths);
+    let mut added = only_in(&new_paths, &old_paths);
+    let mut res = Diff {
+        old_size: old_paths.values().map(|e| e.size).sum(),
+        new_size: new_paths.values().map(|e| e.size).sum(),
+        ..Diff::default()
+    };
+    for (pname, old_entries) in removed {
+        let new_entries = added.remove(&pname).unwrap_or_default();
+        // paths without version, like `source`, are only paired when there is no ambiguity
+        let versioned = old_entries
+            .iter()
+            .chain(new_entries.iter())
+            .all(|e| !split_name(&e.name).1.is_empty());
+        let unambiguous = old_entries.len() == 1 && new_entries.len() == 1;
+        let by_version = |entries: BTreeSet<Entry>| {
+            let mut entries: Vec<Entry> = entries.into_iter().collect();
+            entries.sort_by(|e1, e2| {
+                compare_versions(split_name(&e1.name).1, split_name(&e2.name).1)
+                    .then_with(|| e1.cmp(e2))
+            });
+            entries
+        };
+        let mut old_entries = by_version(old_entries);
+        let mut new_entries = by_version(new_entries);
+        if !pname.is_empty() && (versioned || unambiguous) {
+            // pair them in the order of versions
+            let paired = old_entries.len().min(new_entries.len());
+            res.upgrades
+                .extend(old_entries.drain(..paired).zip(new_entries.drain(..paired)));
+        }
+        res.removed.extend(old_entries);
+        res.added.extend(new_entries);
+    }
+    res.added.extend(added.into_values().flatten());
+    res.upgrades
+        .sort_by_key(|(o, n)| std::cmp::Reverse((n.size as i128 - o.size as i128).abs()));
+    res.added.sort_by_key(|e| std::cmp::Reverse(e.size));
+    res.removed.sort_by_key(|e| std::cmp::Reverse(e.size));
+    res
+}
+
+/// The growth, or shrinking, of a gc-root between two graphs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blame {
+    pub label: Vec<u8>,
+    /// the size freed by removing only this root, before and after
+    pub old: u64,
+    pub new: u64,
+    /// the biggest added or upgraded store paths which only this root keeps alive, if it grew
+    pub culprits: Vec<Entry>,
+}
+
+/// Returns, for each gc-root of `di`, the size removing only this root would free.
+fn retained_by_roots(di: &DepInfos, tree: &DominatorTree) -> BTreeMap<Path, (NodeIndex, u64)> {
+    di.roots()
+        .filter_map(|idx| {
+            let path = di.graph[idx].description.path()?;
+            Some((path.clone(), (idx, tree.retained[idx.index()])))
+        })
+        .collect()
+}
+
+/// Blames the growth of the gc-roots present in both `old` and `new` on the store paths
+/// `diff` reports as added or upgraded. Roots which grew the most come first, and those
+/// which shrank the most last.
+pub fn blame(old: &DepInfos, new: &DepInfos, diff: &Diff) -> Vec<Blame> {
+    let old_retained = retained_by_roots(old, &DominatorTree::new(old));
+    let tree = DominatorTree::new(new);
+    let new_retained = retained_by_roots(new, &tree);
+    let changed: BTreeSet<&Path> = diff
+        .added
+        .iter()
+        .chain(diff.upgrades.iter().map(|(_, n)| n))
+        .map(|e| &e.path)
+        .collect();
+    let mut res = Vec::new();
+    for (path, &(idx, retained)) in &new_retained {
+        let before = match old_retained.get(path) {
+            Some(&(_, before)) if before != retained => before,
+            _ => continue,
+        };
+        let mut culprits = Vec::new();
+        let mut stack = if before < retained {
+            vec![idx]
+        } else {
+            Vec::new()
+        };
+        while let Some(idx) = stack.pop() {
+            stack.extend(&tree.children[idx.index()]);
+            let node = &new.graph[idx];
+            if let NodeDescription::Path(path) = &node.description {
+                if changed.contains(path) {
+                    culprits.push(Entry {
+                        name: node.name().into_owned(),
+                        path: path.clone(),
+                        size: node.size,
+                    });
+                }
+            }
+        }
+        culprits.sort_by_key(|e| std::cmp::Reverse(e.size));
+        culprits.truncate(CULPRITS);
+        res.push(Blame {
+            label: new.label(idx).into_owned(),
+            old: before,
+            new: retained,
+            culprits,
+        });
+    }
+    res.sort_by_key(|b| std::cmp::Reverse(b.new as i128 - b.old as i128));
+    res
+}
+
+/// Returns the graph of the live store paths of `a` which are not live in `b`, with the
+/// references between them. Its gc-roots are the paths which no other such path refers to.
+pub fn subtract(a: &DepInfos, b: &DepInfos) -> DepInfos {
+    let other = live_paths(b);
+    let mut graph = DepGraph::new();
+    let root = graph.add_node(DepNode::dummy());
+    let mut new_ids = BTreeMap::new();
+    let mut dfs = a.dfs();
+    while let Some(idx) = dfs.next(&a.graph) {
+        let node = &a.graph[idx];
+        if let NodeDescription::Path(path) = &node.description {
+            if !other.contains_key(path) {
+                new_ids.insert(idx, graph.add_node(node.clone()));
+            }
+        }
+    }
+    for (&old, &new) in &new_ids {
+        for child in a.graph.neighbors(old) {
+            if let Some(&child) = new_ids.get(&child) {
+                graph.update_edge(new, child, Edge::Reference);
+            }
+        }
+    }
+    for &idx in new_ids.values() {
+        if graph
+            .neighbors_directed(idx, petgraph::Direction::Incoming)
+            .next()
+            .is_none()
+        {
+            graph.add_edge(root, idx, Edge::Root);
+        }
+    }
+    // a cycle which no other path refers to is rooted at one of its paths
+    let mut dfs = petgraph::visit::Dfs::new(&graph, root);
+    while dfs.next(&graph).is_some() {}
+    for &idx in new_ids.values() {
+        if !dfs.discovered.contains(idx.index()) {
+            graph.add_edge(root, idx, Edge::Root);
+            dfs.move_to(idx);
+            while dfs.next(&graph).is_some() {}
+        }
+    }
+    record_degrees(&mut graph);
+    let mut res = DepInfos {
+        graph,
+        root,
+        metadata: SizeMetadata {
+            reachable: Reachability::Connected,
+            dedup: a.metadata.dedup,
+            size: enum_map::enum_map! { _ => enum_map::enum_map!{ _ => None }},
+        },
+        annotations: a.annotations.clone(),
+    };
+    res.record_metadata();
+    res
+}
+
+/// Prints `diff`, and the growth and shrinking of gc-roots in `blame`
+pub fn render<W: Write>(diff: &Diff, blame: &[Blame], w: &mut W) -> io::Result<()> {
+    let total = |entries: &[Entry]| ByteSize::b(entries.iter().map(|e| e.size).sum());
+    writeln!(
+        w,
+        "Live store size: {} -> {} ({})",
+        ByteSize::b(diff.old_size),
+        ByteSize::b(diff.new_size),
+        delta(diff.new_size, diff.old_size)
+    )?;
+    writeln!(w, "Upgraded: {}", diff.upgrades.len())?;
+    for (old, new) in &diff.upgrades {
+        let (pname, old_version) = split_name(&old.name);
+        let new_version = split_name(&new.name).1;
+        write!(w, "\t{}\t", delta(new.size, old.size))?;
+        w.write_all(pname)?;
+        w.write_all(b" ")?;
+        if old_version == new_version {
+            w.write_all(old_version)?;
+            w.write_all(b" (rebuilt)")?;
+        } else {
+            w.write_all(old_version)?;
+            w.write_all(b" -> ")?;
+            w.write_all(new_version)?;
+        }
+        writeln!(w)?;
+    }
+    writeln!(w, "Added: {}, {}", diff.added.len(), total(&diff.added))?;
+    for entry in &diff.added {
+        write!(w, "\t+{}\t", ByteSize::b(entry.size))?;
+        w.write_all(&entry.name)?;
+        writeln!(w)?;
+    }
+    writeln!(
+        w,
+        "Removed: {}, {}",
+        diff.removed.len(),
+        total(&diff.removed)
+    )?;
+    for entry in &diff.removed {
+        write!(w, "\t-{}\t", ByteSize::b(entry.size))?;
+        w.write_all(&entry.name)?;
+        writeln!(w)?;
+    }
+    let (grown, shrunk): (Vec<&Blame>, Vec<&Blame>) = blame.iter().partition(|b| b.new > b.old);
+    writeln!(w, "Grown gc-roots: {}", grown.len())?;
+    for root in grown {
+        write!(w, "\t{}\t", delta(root.new, root.old))?;
+        w.write_all(&root.label)?;
+        writeln!(w)?;
+        for entry in &root.culprits {
+            write!(w, "\t\t{}\t", ByteSize::b(entry.size))?;
+            w.write_all(&entry.name)?;
+            writeln!(w)?;
+        }
+    }
+    writeln!(w, "Shrunk gc-roots: {}", shrunk.len())?;
+    for root in shrunk.into_iter().rev() {
+        write!(w, "\t{}\t", delta(root.new, root.old))?;
+        w.write_all(&root.label)?;
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::*;
+
+    #[test]
+    fn check_split_name() {
+        assert_eq!(
+            split_name(b"firefox-120.0"),
+            (&b"firefox"[..], &b"120.0"[..])
+        );
+        assert_eq!(
+            split_name(b"python3.11-numpy-1.26.2"),
+            (&b"python3.11-numpy"[..], &b"1.26.2"[..])
+        );
+        assert_eq!(split_name(b"source"), (&b"source"[..], &b""[..]));
+    }
+
+    #[test]
+    fn check_compare_versions() {
+        use std::cmp::Ordering::*;
+        assert_eq!(compare_versions(b"1.9", b"1.10"), Less);
+        assert_eq!(compare_versions(b"1.10", b"1.10.1"), Less);
+        assert_eq!(compare_versions(b"1.0pre1", b"1.0"), Less);
+        assert_eq!(compare_versions(b"2.3a", b"2.3.1"), Less);
+        assert_eq!(compare_versions(b"2.3a", b"2.3"), Greater);
+        assert_eq!(compare_versions(b"1.010", b"1.10"), Equal);
+        assert_eq!(compare_versions(b"120.0", b"119.0"), Greater);
+        assert_eq!(compare_versions(b"", b""), Equal);
+    }
+
+    #[test]
+    fn check_diff_pairs_by_version() {
+        let graph = |versions: &[&str]| {
+            let nodes: Vec<String> = versions
+                .iter()
+                .enumerate()
+                .map(|(i, v)| {
+                    format!(
+                        r#"{{"id":{},"path":"/nix/store/{:032}-foo-{}","size":1}}"#,
+                        i + 1,
+                        i,
+                        v
+                    )
+                })
+                .collect();
+            let edges: Vec<String> = (1..=versions.len()).map(|i| format!("[0,{}]", i)).collect();
+            let text = format!(
+                r#"{{"nodes":[{{"id":0,"kind":"Link","path":"/r","root":true}},{}],"edges":[{}]}}"#,
+                nodes.join(","),
+                edges.join(",")
+            );
+            crate::import::graph(text.as_bytes())
+        };
+        let diff = diff(&graph(&["1.9", "1.10"]), &graph(&["1.10.1", "1.11"]));
+        let mut upgrades: Vec<_> = diff
+            .upgrades
+            .iter()
+            .map(|(o, n)| (o.name.as_slice(), n.name.as_slice()))
+            .collect();
+        upgrades.sort_unstable();
+        assert_eq!(
+            upgrades,
+            vec![
+                (&b"foo-1.10"[..], &b"foo-1.11"[..]),
+                (b"foo-1.9", b"foo-1.10.1")
+            ]
+        );
+    }
+
+    #[test]
+    fn check_diff() {
+        let graph = |paths: &[(&str, u64)]| {
+            let mut nodes = vec![r#"{"id":0,"kind":"Link","path":"/r","root":true}"#.to_owned()];
+            let mut edges = Vec::new();
+            for (i, (path, size)) in paths.iter().enumerate() {
+                nodes.push(format!(
+                    r#"{{"id":{},"path":"/nix/store/{}","size":{}}}"#,
+                    i + 1,
+                    path,
+                    size
+                ));
+                edges.push(format!("[0,{}]", i + 1));
+            }
+            let text = format!(
+                r#"{{"nodes":[{}],"edges":[{}]}}"#,
+                nodes.join(","),
+                edges.join(",")
+            );
+            crate::import::graph(text.as_bytes())
+        };
+        let old = graph(&[
+            ("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-firefox-119.0", 100),
+            ("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.38", 10),
+            ("cccccccccccccccccccccccccccccccc-source", 5),
+            ("dddddddddddddddddddddddddddddddd-source", 5),
+            ("ffffffffffffffffffffffffffffffff-hello-2.12", 1),
+        ]);
+        let new = graph(&[
+            ("gggggggggggggggggggggggggggggggg-firefox-120.0", 120),
+            ("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.38", 10),
+            ("hhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhh-source", 7),
+            ("iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-hello-2.12", 1),
+            ("jjjjjjjjjjjjjjjjjjjjjjjjjjjjjjjj-vim-9.0", 30),
+        ]);
+        let diff = diff(&old, &new);
+        assert_eq!((diff.old_size, diff.new_size), (121, 168));
+        let upgrades: Vec<_> = diff
+            .upgrades
+            .iter()
+            .map(|(o, n)| (o.name.as_slice(), n.name.as_slice()))
+            .collect();
+        assert_eq!(
+            upgrades,
+            vec![
+                (&b"firefox-119.0"[..], &b"firefox-120.0"[..]),
+                (b"hello-2.12", b"hello-2.12")
+            ]
+        );
+        let names = |entries: &[Entry]| -> Vec<Vec<u8>> {
+            entries.iter().map(|e| e.name.clone()).collect()
+        };
+        assert_eq!(
+            names(&diff.added),
+            vec![b"vim-9.0".to_vec(), b"source".to_vec()]
+        );
+        assert_eq!(
+            names(&diff.removed),
+            vec![b"source".to_vec(), b"source".to_vec()]
+        );
+    }
+
+    #[test]
+    fn check_blame() {
+        let text = |big: &str, size: u64| {
+            format!(
+                r#"{{"nodes":[
+                {{"id":1,"kind":"Link","path":"/system","root":true}},
+                {{"id":2,"kind":"Link","path":"/home","root":true}},
+                {{"id":3,"path":"/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-glibc-2.38","size":10}},
+                {{"id":4,"path":"/nix/store/{}","size":{}}},
+                {{"id":5,"path":"/nix/store/cccccccccccccccccccccccccccccccc-vim-9.0","size":7}}
+            ],"edges":[[1,3],[2,3],[1,4],[2,5]]}}"#,
+                big, size
+            )
+        };
+        let old = text("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-firefox-119.0", 100);
+        let old = crate::import::graph(old.as_bytes());
+        let new = text("dddddddddddddddddddddddddddddddd-firefox-120.0", 150);
+        let new = crate::import::graph(new.as_bytes());
+        let diff = diff(&old, &new);
+        let blame = blame(&old, &new, &diff);
+        assert_eq!(blame.len(), 1);
+        assert_eq!(blame[0].label, b"/system".to_vec());
+        assert_eq!((blame[0].old, blame[0].new), (100, 150));
+        assert_eq!(blame[0].culprits.len(), 1);
+        assert_eq!(blame[0].culprits[0].name, b"firefox-120.0".to_vec());
+        // the other way round, /system shrinks, with no one to blame
+        let blame = super::blame(&new, &old, &super::diff(&new, &old));
+        assert_eq!(blame.len(), 1);
+        assert_eq!((blame[0].old, blame[0].new), (150, 100));
+        assert!(blame[0].culprits.is_empty());
+        let mut out = Vec::new();
+        render(&super::diff(&new, &old), &blame, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.ends_with("Grown gc-roots: 0\nShrunk gc-roots: 1\n\t-50 B\t/system\n"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn check_subtract() {
+        let a = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r","root":true},
+            {"id":2,"path":"/nix/store/0aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-top","size":1},
+            {"id":3,"path":"/nix/store/1bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-dep","size":2},
+            {"id":4,"path":"/nix/store/2ccccccccccccccccccccccccccccccc-shared","size":4}
+        ],"edges":[[1,2],[2,3],[2,4]]}"#;
+        let b = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/other","root":true},
+            {"id":2,"path":"/nix/store/2ccccccccccccccccccccccccccccccc-shared","size":4}
+        ],"edges":[[1,2]]}"#;
+        let res = subtract(&crate::import::graph(a), &crate::import::graph(b));
+        assert_eq!(res.graph.node_count(), 3);
+        assert_eq!(res.reachable_size(), 3);
+        assert_eq!(res.roots_name(), ["top".to_owned()].into());
+    }
+}
@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{self, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How many roots to list as top growers
+const TOP_GROWERS: usize = 5;
+
+/// What the digest remembers of a run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// size of live paths
+    pub total: u64,
+    /// for each root, the size which would be freed by removing only this root
+    pub roots: BTreeMap<Path, u64>,
+}
+
+impl Summary {
+    /// Summarizes a graph returned by `reduction::condense`.
+    pub fn new(di: &DepInfos) -> Self {
+        assert_eq!(di.metadata.reachable, Reachability::Connected);
+        let roots = di
+            .roots()
+            .filter_map(|idx| {
+                let node = &di.graph[idx];
+                node.description.path().map(|p| (p.clone(), node.size))
+            })
+            .collect();
+        Summary {
+            total: di.reachable_size(),
+            roots,
+        }
+    }
+
+    /// Reads a summary saved by `save`. Returns `None` if the file does not exist.
+    pub fn load(path: &path::Path) -> io::Result<Option<Self>> {
+        let f = match fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupted digest state");
+        let parse = |s: &[u8]| -> io::Result<u64> {
+            std::str::from_utf8(s)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(invalid)
+        };
+        let mut lines = io::BufReader::new(f).split(b'\n');
+        let total = parse(&lines.next().ok_or_else(invalid)??)?;
+        let mut roots = BTreeMap::new();
+        for line in lines {
+            let line = line?;
+            let tab = line.iter().position(|&c| c == b'\t').ok_or_else(invalid)?;
+            roots.insert(line[tab + 1..].to_vec(), parse(&line[..tab])?);
+        }
+        Ok(Some(Summary { total, roots }))
+    }
+
+    /// Saves the summary to `path` in a line based format.
+    pub fn save(&self, path: &path::Path) -> io::Result<()> {
+        let mut f = io::BufWriter::new(fs::File::create(path)?);
+        writeln!(f, "{}", self.total)?;
+        for (root, size) in &self.roots {
+            if root.contains(&b'\n') {
+                continue;
+            }
+            write!(f, "{}\t", size)?;
+            f.write_all(root)?;
+            f.write_all(b"\n")?;
+        }
+        f.flush()
+    }
+}
+
+/// Formats a size difference with an explicit sign
+pub fn delta(new: u64, old: u64) -> String {
+    if new >= old {
+        format!("+{}", ByteSize::b(new - old))
+    } else {
+        format!("-{}", ByteSize::b(old - new))
+    }
+}
+
+/// Writes a short plain text digest of `di`, a graph returned by `reduction::condense`.
+///
+/// `previous` is the summary of the last run along with its date, if any.
+/// Link roots older than `stale_after` are counted as stale. `overhead` is the space used by
+/// nix outside the store.
+pub fn render<W: Write>(
+    di: &DepInfos,
+    previous: Option<(&Summary, SystemTime)>,
+    stale_after: Duration,
+    overhead: &[crate::overhead::Item],
+    w: &mut W,
+) -> io::Result<()> {
+    let current = Summary::new(di);
+    write!(w, "Live store size: {}", ByteSize::b(current.total))?;
+    match previous {
+        Some((old, time)) => writeln!(
+            w,
+            " ({} since the last run{})",
+            delta(current.total, old.total),
+            LinkAge(Some(time))
+        )?,
+        None => writeln!(w, " (first run)")?,
+    }
+    crate::overhead::render(overhead, w)?;
+
+    let mut stale = 0;
+    let mut stale_size = 0;
+    for idx in di.roots() {
+        let node = &di.graph[idx];
+        if node.kind() != NodeKind::Link {
+            continue;
+        }
+        let age = node
+            .description
+            .path_as_os_str()
+            .and_then(|p| path::Path::new(p).symlink_metadata().ok())
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.elapsed().ok());
+        if matches!(age, Some(age) if age > stale_after) {
+            stale += 1;
+            stale_size += node.size;
+        }
+    }
+    writeln!(
+        w,
+        "Gc-roots: {}, of which {} older than {} days, retaining {} on their own",
+        current.roots.len(),
+        stale,
+        stale_after.as_secs() / (3600 * 24),
+        ByteSize::b(stale_size)
+    )?;
+
+    let mut by_growth: Vec<(NodeIndex, u64)> = di
+        .roots()
+        .filter_map(|idx| {
+            let node = &di.graph[idx];
+            let path = node.description.path()?;
+            let old = previous
+                .and_then(|(old, _)| old.roots.get(path).copied())
+                .unwrap_or(0);
+            if node.size > old {
+                Some((idx, old))
+            } else {
+                None
+            }
+        })
+        .collect();
+    by_growth.sort_by_key(|&(idx, old)| std::cmp::Reverse(di.graph[idx].size - old));
+    if previous.is_some() {
+        writeln!(w, "Top growers:")?;
+    } else {
+        writeln!(w, "Biggest gc-roots:")?;
+    }
+    if by_growth.is_empty() {
+        writeln!(w, "\tnone")?;
+    }
+    for (idx, old) in by_growth.into_iter().take(TOP_GROWERS) {
+        write!(w, "\t{}\t", delta(di.graph[idx].size, old))?;
+        w.write_all(&di.label(idx))?;
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+/// Prints the digest of `di` to stdout, comparing it with the summary saved in
+/// `state` (by default in the cache directory), and then updates `state`.
+///
+/// Each run also records the live size in a history next to `state`, and with `forecast`
+/// the digest ends with when the store is expected to fill its filesystem.
+pub fn run(
+    di: &DepInfos,
+    state: Option<PathBuf>,
+    stale_after: Duration,
+    overhead: &[crate::overhead::Item],
+    forecast: bool,
+) -> io::Result<()> {
+    let state = match state {
+        Some(path) => path,
+        None => crate::cache::cache_dir()?.join("digest"),
+    };
+    let mut history = state.clone().into_os_string();
+    history.push(".history");
+    let history = PathBuf::from(history);
+    let mut samples = crate::forecast::load(&history)?;
+    let sample = crate::forecast::Sample::now(di.reachable_size());
+    samples.push(sample);
+    let previous = Summary::load(&state)?;
+    let time = fs::metadata(&state).and_then(|m| m.modified()).ok();
+    let previous = match (&previous, time) {
+        (Some(summary), Some(time)) => Some((summary, time)),
+        _ => None,
+    };
+    {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        render(di, previous, stale_after, overhead, &mut handle)?;
+        if forecast {
+            let free = crate::forecast::free_space(&crate::archive::store_dir())?;
+            crate::forecast::render(&samples, free, &mut handle)?;
+        }
+    }
+    crate::forecast::append(&history, sample)?;
+    Summary::new(di).save(&state)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::digest::*;
+    use crate::selftest::generate_random;
+
+    #[test]
+    fn save_and_load() {
+        let di = crate::reduction::condense(generate_random(100, 3, false));
+        let summary = Summary::new(&di);
+        let path = std::env::temp_dir().join(format!("nix-du-digest-{}", std::process::id()));
+        summary.save(&path).unwrap();
+        let loaded = Summary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Some(summary));
+    }
+}
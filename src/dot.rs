@@ -4,23 +4,145 @@ use crate::depgraph;
 use bytesize::ByteSize;
 use petgraph::visit::IntoNodeReferences;
 use scarlet::colormap::ColorMap;
-use scarlet::material_colors::MaterialPrimary;
+use scarlet::material_colors::{MaterialPrimary, NeutralTone};
 use scarlet::{colormap::ListedColorMap, prelude::*};
 use std::io::{self, Write};
 
-pub fn render<W: Write>(dependencies: &depgraph::DepInfos, w: &mut W) -> io::Result<()> {
+/// Optional features of the output, several of which can be enabled with `--dot-style`
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Feature {
+    /// make the font size and the area of nodes grow with their size
+    Scale,
+    /// color nodes by the logarithm of their size, from the smallest to the biggest
+    Heatmap,
+    /// draw the nodes standing for several store paths as stacks of boxes, and store
+    /// derivations as notes
+    Shapes,
+    /// list the store paths of each node in its tooltip
+    Tooltips,
+}
+
+/// Options controlling the appearance of the output
+#[derive(Debug, Clone, Default)]
+pub struct Style {
+    /// make the font size grow with the logarithm of the size of the node
+    pub scale_labels: bool,
+    /// make the area of nodes grow with the logarithm of their size
+    pub scale_nodes: bool,
+    /// color nodes by the logarithm of their size instead of linearly
+    pub log_colors: bool,
+    /// draw the nodes standing for several store paths differently from single store paths
+    pub shapes: bool,
+    /// give each node a tooltip listing its store paths
+    pub tooltips: bool,
+    /// the number of store paths merged into each node to list under its label
+    pub examples: usize,
+    /// a value per node index to color nodes by, instead of their size
+    pub scores: Option<Vec<f64>>,
+    /// a size per node index, and the name of the metric it was measured by, to show and
+    /// scale nodes by instead of their own size
+    pub sizes: Option<(Vec<u64>, &'static str)>,
+    /// draw dashed the edges which are not references, instead of omitting them
+    pub synthetic_edges: bool,
+    /// color nodes by the user retaining them, instead of by size or score
+    pub owners: Option<crate::owners::Owners>,
+    /// with `owners`, draw the nodes each user retains alone in a cluster of this user
+    pub clusters: bool,
+}
+
+/// colors of users with `Style::owners`, in order
+const USER_COLORS: [MaterialPrimary; 10] = {
+    use scarlet::material_colors::{MaterialTone::Neutral, NeutralTone::W300};
+    use MaterialPrimary::*;
+    [
+        Red(Neutral(W300)),
+        Blue(Neutral(W300)),
+        Green(Neutral(W300)),
+        Amber(Neutral(W300)),
+        Purple(Neutral(W300)),
+        Cyan(Neutral(W300)),
+        DeepOrange(Neutral(W300)),
+        Lime(Neutral(W300)),
+        Pink(Neutral(W300)),
+        Indigo(Neutral(W300)),
+    ]
+};
+
+/// Returns the color of the `i`th user
+fn user_color(i: usize) -> RGBColor {
+    RGBColor::from_material_palette(USER_COLORS[i % USER_COLORS.len()])
+}
+
+impl Style {
+    /// Enables `features` on top of this style
+    pub fn with(mut self, features: &[Feature]) -> Self {
+        for feature in features {
+            match feature {
+                Feature::Scale => {
+                    self.scale_labels = true;
+                    self.scale_nodes = true;
+                }
+                Feature::Heatmap => self.log_colors = true,
+                Feature::Shapes => self.shapes = true,
+                Feature::Tooltips => self.tooltips = true,
+            }
+        }
+        self
+    }
+}
+
+/// font sizes used with `Style::scale_labels`
+const MIN_FONTSIZE: f64 = 10.;
+const MAX_FONTSIZE: f64 = 40.;
+/// widths of nodes in inches used with `Style::scale_nodes`, their height being two thirds
+const MIN_WIDTH: f64 = 0.75;
+const MAX_WIDTH: f64 = 4.;
+
+pub fn render<W: Write>(
+    dependencies: &depgraph::DepInfos,
+    style: &Style,
+    w: &mut W,
+) -> io::Result<()> {
     // compute color gradient
     // first, min and max
-    let mut min = dependencies.graph.raw_nodes()[0].weight.size;
-    let mut max = min;
-    for node in &dependencies.graph.raw_nodes()[1..] {
-        max = std::cmp::max(node.weight.size, max);
-        min = std::cmp::min(node.weight.size, min);
+    let size_of = |idx: petgraph::graph::NodeIndex| match &style.sizes {
+        Some((sizes, _)) => sizes[idx.index()],
+        None => dependencies.graph[idx].size,
+    };
+    let value = |idx: petgraph::graph::NodeIndex| match &style.scores {
+        Some(scores) => scores[idx.index()],
+        None => size_of(idx) as f64,
+    };
+    let mut min = f64::INFINITY;
+    let mut max = f64::NEG_INFINITY;
+    for idx in dependencies.graph.node_indices() {
+        max = max.max(value(idx));
+        min = min.min(value(idx));
     }
-    let span = (max - min) as f64;
+    let span = max - min;
+
+    // NaN scores get the lowest color
+    let scale = move |value: f64| {
+        if span > 0. {
+            ((value - min) / span).max(0.)
+        } else {
+            0.
+        }
+    };
 
-    let scale = move |size| (((size - min) as f64) / span);
+    let sizes = || dependencies.graph.node_indices().map(size_of);
+    let log_min = (sizes().min().unwrap_or(0).max(1) as f64).ln();
+    let log_span = (sizes().max().unwrap_or(0).max(1) as f64).ln() - log_min;
+    // between 0 for the smallest node and 1 for the biggest one
+    let log_offset = move |size: u64| {
+        if log_span > 0. {
+            ((size.max(1) as f64).ln() - log_min) / log_span
+        } else {
+            0.
+        }
+    };
 
+    let ids = crate::stableid::stable_ids(dependencies);
     let gradient = ListedColorMap::turbo();
     let textcolors: Vec<RGBColor> = [MaterialPrimary::White, MaterialPrimary::Black]
         .iter()
@@ -40,11 +162,25 @@ pub fn render<W: Write>(dependencies: &depgraph::DepInfos, w: &mut W) -> io::Res
         if idx == dependencies.root {
             continue;
         };
-        let size = ByteSize::b(node.size);
-        let offset = scale(node.size);
-        // make large node more visible in the color map
-        let offset = offset.sqrt();
-        let color: RGBColor = gradient.transform_single(offset);
+        let size = ByteSize::b(size_of(idx));
+        let color: RGBColor = match &style.owners {
+            Some(owners) => match owners.owner[idx.index()] {
+                Some(user) => user_color(user),
+                None if owners.shared.contains(idx.index()) => {
+                    RGBColor::from_material_palette(MaterialPrimary::Grey(NeutralTone::W500))
+                }
+                None => RGBColor::from_material_palette(MaterialPrimary::White),
+            },
+            None if style.log_colors && style.scores.is_none() => {
+                gradient.transform_single(log_offset(size_of(idx)))
+            }
+            None => {
+                let offset = scale(value(idx));
+                // make large node more visible in the color map
+                let offset = offset.sqrt();
+                gradient.transform_single(offset)
+            }
+        };
         let textcolor = textcolors
             .iter()
             .max_by_key(|c| (c.distance(&color) * 1000.) as u64)
@@ -52,25 +188,135 @@ pub fn render<W: Write>(dependencies: &depgraph::DepInfos, w: &mut W) -> io::Res
             .to_string();
         write!(
             w,
-            "N{}[color=\"{}\",fontcolor=\"{}\",label=\"",
+            "N{}[id=\"{}\",color=\"{}\",fontcolor=\"{}\",",
             idx.index(),
+            ids[idx.index()],
             color.to_string(),
             textcolor
         )?;
-        w.write_all(&node.name())?;
-        writeln!(w, " ({})\"];", size)?;
+        let offset = log_offset(size_of(idx));
+        if style.scale_labels {
+            let fontsize = MIN_FONTSIZE + offset * (MAX_FONTSIZE - MIN_FONTSIZE);
+            write!(w, "fontsize={:.1},", fontsize)?;
+        }
+        if style.scale_nodes {
+            let width = MIN_WIDTH + offset * (MAX_WIDTH - MIN_WIDTH);
+            write!(w, "width={:.2},height={:.2},", width, width * 2. / 3.)?;
+        }
+        if style.shapes && !node.members.is_empty() {
+            w.write_all(b"shape=box3d,")?;
+        } else if style.shapes && node.kind() == depgraph::NodeKind::Derivation {
+            w.write_all(b"shape=note,")?;
+        }
+        if style.tooltips {
+            let mut paths: Vec<&[u8]> = node.store_paths().into_iter().map(|(p, _)| p).collect();
+            if paths.is_empty() {
+                // gc-roots
+                paths.extend(node.description.path().map(Vec::as_slice));
+            }
+            w.write_all(b"tooltip=\"")?;
+            for (i, path) in paths.into_iter().enumerate() {
+                if i > 0 {
+                    w.write_all(b"\\n")?;
+                }
+                w.write_all(path)?;
+            }
+            w.write_all(b"\",")?;
+        }
+        w.write_all(b"label=\"")?;
+        w.write_all(&dependencies.label(idx))?;
+        match &style.sizes {
+            Some((_, metric)) => write!(w, " ({} {})", size, metric)?,
+            None => write!(w, " ({})", size)?,
+        }
+        let members = &node.members;
+        for (member, _) in members.iter().take(style.examples) {
+            w.write_all(b"\\n")?;
+            w.write_all(&depgraph::NodeDescription::Path(member.clone()).name())?;
+        }
+        if style.examples > 0 && members.len() > style.examples {
+            write!(w, "\\n(and {} more)", members.len() - style.examples)?;
+        }
+        w.write_all(b"\"];\n")?;
+    }
+    if let (Some(owners), true) = (&style.owners, style.clusters) {
+        for (i, user) in owners.users.iter().enumerate() {
+            write!(w, "subgraph cluster_user{} {{\nlabel=\"", i)?;
+            w.write_all(user.as_bytes())?;
+            w.write_all(b"\";\n")?;
+            for idx in dependencies.graph.node_indices() {
+                if idx != dependencies.root && owners.owner[idx.index()] == Some(i) {
+                    write!(w, "N{}; ", idx.index())?;
+                }
+            }
+            w.write_all(b"\n}\n")?;
+        }
+    }
+    if let Some(owners) = &style.owners {
+        w.write_all(b"subgraph cluster_users {\nlabel=\"users\";\n")?;
+        for (i, user) in owners.users.iter().enumerate() {
+            write!(w, "U{}[color=\"{}\",label=\"", i, user_color(i).to_string())?;
+            w.write_all(user.as_bytes())?;
+            w.write_all(b"\"];\n")?;
+        }
+        w.write_all(b"Ushared[color=\"")?;
+        let grey = RGBColor::from_material_palette(MaterialPrimary::Grey(NeutralTone::W500));
+        write!(w, "{}\",label=\"several users\"];\n}}\n", grey.to_string())?;
     }
     for edge in dependencies.graph.raw_edges() {
         if edge.source() == dependencies.root {
             continue;
         }
+        let attributes = match edge.weight {
+            depgraph::Edge::Reference => "",
+            _ if style.synthetic_edges => " [style=dashed]",
+            _ => continue,
+        };
         writeln!(
             w,
-            "N{} -> N{};",
+            "N{} -> N{}{};",
             edge.source().index(),
-            edge.target().index()
+            edge.target().index(),
+            attributes
         )?;
     }
     w.write_all(b"}\n")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::dot::*;
+
+    #[test]
+    fn check_features() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"path":"/nix/store/a-app","size":1000},
+            {"id":3,"path":"/nix/store/b-lib","size":10},
+            {"id":4,"kind":"Derivation","path":"/nix/store/d-lib.drv","size":1}
+        ],"edges":[[1,2],[2,3],[2,4]]}"#;
+        let di = crate::import::graph(text);
+        let mut di = crate::reduction::keep_reachable(di);
+        let app = crate::unlock::find_node(&di, "app").unwrap();
+        di.graph[app]
+            .members
+            .push((b"/nix/store/c-app-doc".to_vec(), 1));
+        let style = Style::default().with(&[Feature::Scale, Feature::Shapes, Feature::Tooltips]);
+        let mut out = Vec::new();
+        render(&di, &style, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let line = |name: &str| {
+            out.lines()
+                .find(|line| line.contains(&format!("label=\"{}", name)))
+                .unwrap()
+                .to_owned()
+        };
+        let app = line("app");
+        assert!(app.contains("fontsize=40.0,width=4.00,height=2.67,shape=box3d,"));
+        assert!(app.contains("tooltip=\"/nix/store/c-app-doc\\n/nix/store/a-app\""));
+        assert!(!line("lib (").contains("shape="));
+        assert!(line("lib.drv").contains("shape=note,"));
+        assert!(line("/r1").contains("fontsize=10.0,width=0.75,height=0.50,tooltip=\"/r1\""));
+    }
+}
@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Estimating what copying the closure of each gc-root to an archive, with `nix copy`, would
+//! transfer, to save closures before deleting them.
+//!
+//! The compressed size of a store path is the `downloadSize` of its narinfo in a
+//! substituter when it is there. Other paths are assumed to compress as well as those, or,
+//! if the substituter knows none of them, as well as a few paths compressed locally with
+//! `xz`.
+
+use crate::depgraph::*;
+use crate::preview;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How many queries to run at once against substituters, set by `--backend-jobs`
+static JOBS: AtomicUsize = AtomicUsize::new(4);
+/// How many store paths to ask a substituter about in one query, set by `--backend-batch`
+static BATCH: AtomicUsize = AtomicUsize::new(500);
+
+/// Sets how many queries to run at once against substituters, and how many store paths
+/// each asks about. High latency binary caches are much faster to query concurrently.
+pub fn set_concurrency(jobs: usize, batch: usize) {
+    JOBS.store(jobs.max(1), Ordering::Relaxed);
+    BATCH.store(batch.max(1), Ordering::Relaxed);
+}
+/// How many store paths to compress locally when the substituter knows none
+const SAMPLES: usize = 3;
+
+/// What exporting a closure would transfer
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Estimate {
+    /// uncompressed size of the NARs
+    pub nar: u64,
+    /// estimated size once compressed
+    pub compressed: u64,
+    /// number of paths whose compressed size is known from the substituter
+    pub known: usize,
+    /// number of paths whose compressed size is estimated
+    pub estimated: usize,
+}
+
+/// Returns the store paths of the graph
+fn store_paths(di: &DepInfos) -> Vec<(&[u8], u64)> {
+    di.graph
+        .node_weights()
+        .filter_map(|node| match &node.description {
+            NodeDescription::Path(p) | NodeDescription::Derivation(p) => {
+                Some((p.as_slice(), node.size))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Runs `query` on `chunk`, and when it fails, on each half of `chunk` in turn: older
+/// versions of nix fail when one of the paths is missing, and this finds the missing paths
+/// with a logarithmic number of queries each instead of querying every path alone.
+fn query_bisecting<F>(chunk: &[&[u8]], query: &F) -> BTreeMap<String, u64>
+where
+    F: Fn(&[&[u8]]) -> io::Result<BTreeMap<String, u64>>,
+{
+    match query(chunk) {
+        Ok(found) => found,
+        Err(_) if chunk.len() <= 1 => BTreeMap::new(),
+        Err(_) => {
+            let (left, right) = chunk.split_at(chunk.len() / 2);
+            let mut found = query_bisecting(left, query);
+            found.extend(query_bisecting(right, query));
+            found
+        }
+    }
+}
+
+/// Returns the compressed size of the `paths` known to `substituter`.
+pub fn compressed_sizes(paths: &[&[u8]], substituter: &str) -> BTreeMap<Path, u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let query = |paths: &[&[u8]]| {
+        let mut cmd = preview::nix();
+        cmd.args(["path-info", "--json", "--store", substituter]);
+        cmd.args(paths.iter().map(|p| std::ffi::OsStr::from_bytes(p)));
+        preview::output_of(&mut cmd)
+            .and_then(|out| preview::parse_path_info_field(&out, "downloadSize"))
+    };
+    let query_chunk = |chunk: &[&[u8]]| query_bisecting(chunk, &query);
+    let chunks: Vec<&[&[u8]]> = paths.chunks(BATCH.load(Ordering::Relaxed)).collect();
+    let found: Vec<BTreeMap<String, u64>> = match rayon::ThreadPoolBuilder::new()
+        .num_threads(JOBS.load(Ordering::Relaxed))
+        .build()
+    {
+        Ok(pool) => pool.install(|| chunks.par_iter().map(|&c| query_chunk(c)).collect()),
+        Err(_) => chunks.iter().map(|&c| query_chunk(c)).collect(),
+    };
+    found
+        .into_iter()
+        .flatten()
+        .map(|(p, size)| (p.into_bytes(), size))
+        .collect()
+}
+
+/// Returns the size of `path` once dumped as a NAR and compressed with `xz`.
+fn compress_locally(path: &[u8]) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let out = preview::output_of(
+        Command::new("sh")
+            .arg("-c")
+            .arg("nix-store --dump \"$1\" | xz -6 -c | wc -c")
+            .arg("sh")
+            .arg(std::ffi::OsStr::from_bytes(path)),
+    )?;
+    String::from_utf8_lossy(&out)
+        .trim()
+        .parse()
+        .map_err(io::Error::other)
+}
+
+/// Returns the ratio of compressed to NAR size to assume for paths unknown to the
+/// substituter, from those of `paths` it knows, or else by compressing a few of them.
+pub fn compression_ratio(paths: &[(&[u8], u64)], compressed: &BTreeMap<Path, u64>) -> f64 {
+    let (mut nar, mut small) = (0, 0);
+    for &(p, size) in paths {
+        if let Some(&c) = compressed.get(p) {
+            nar += size;
+            small += c;
+        }
+    }
+    if nar == 0 {
+        // evenly spaced paths, to get small and big ones
+        let step = (paths.len() / SAMPLES).max(1);
+        for &(p, size) in paths.iter().step_by(step).take(SAMPLES) {
+            match compress_locally(p) {
+                Ok(c) => {
+                    nar += size;
+                    small += c;
+                }
+                Err(e) => eprintln!("Could not compress {}: {}", String::from_utf8_lossy(p), e),
+            }
+        }
+    }
+    if nar == 0 {
+        1.
+    } else {
+        small as f64 / nar as f64
+    }
+}
+
+/// Estimates the cost of exporting the closure of each gc-root of `di`, given the compressed
+/// size of the paths known to the substituter, and the ratio to assume for the others.
+pub fn estimate(
+    di: &DepInfos,
+    compressed: &BTreeMap<Path, u64>,
+    ratio: f64,
+) -> Vec<(NodeIndex, Estimate)> {
+    let mut res: Vec<(NodeIndex, Estimate)> = di
+        .roots()
+        .map(|root| {
+            let mut estimate = Estimate::default();
+            let mut seen = BTreeSet::new();
+            let mut stack = vec![root];
+            while let Some(idx) = stack.pop() {
+                if !seen.insert(idx) {
+                    continue;
+                }
+                stack.extend(di.graph.neighbors(idx));
+                let node = &di.graph[idx];
+                if let NodeDescription::Path(p) | NodeDescription::Derivation(p) = &node.description
+                {
+                    estimate.nar += node.size;
+                    match compressed.get(p) {
+                        Some(&c) => {
+                            estimate.compressed += c;
+                            estimate.known += 1;
+                        }
+                        None => {
+                            estimate.compressed += (node.size as f64 * ratio) as u64;
+                            estimate.estimated += 1;
+                        }
+                    }
+                }
+            }
+            (root, estimate)
+        })
+        .collect();
+    res.sort_by_key(|(_, e)| std::cmp::Reverse(e.compressed));
+    res
+}
+
+/// Prints the estimated cost of exporting the closure of each gc-root, biggest first.
+pub fn render<W: Write>(di: &DepInfos, substituter: &str, w: &mut W) -> io::Result<()> {
+    let paths = store_paths(di);
+    let names: Vec<&[u8]> = paths.iter().map(|&(p, _)| p).collect();
+    let compressed = compressed_sizes(&names, substituter);
+    let ratio = compression_ratio(&paths, &compressed);
+    writeln!(
+        w,
+        "{} of {} store paths are known to {}, others are assumed to compress to {:.0}%.",
+        compressed.len(),
+        paths.len(),
+        substituter,
+        ratio * 100.
+    )?;
+    for (root, e) in estimate(di, &compressed, ratio) {
+        write!(
+            w,
+            "{}\t{}\t{}/{} estimated\t",
+            ByteSize::b(e.compressed),
+            ByteSize::b(e.nar),
+            e.estimated,
+            e.known + e.estimated
+        )?;
+        w.write_all(&di.label(root))?;
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::export::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn check_query_bisecting() {
+        let paths: Vec<Vec<u8>> = (0..64)
+            .map(|i| format!("/nix/store/{}", i).into_bytes())
+            .collect();
+        let paths: Vec<&[u8]> = paths.iter().map(Vec::as_slice).collect();
+        let missing: &[u8] = b"/nix/store/37";
+        let queries = Cell::new(0);
+        let query = |chunk: &[&[u8]]| {
+            queries.set(queries.get() + 1);
+            if chunk.contains(&missing) {
+                return Err(io::Error::other("missing path"));
+            }
+            Ok(chunk
+                .iter()
+                .map(|p| (String::from_utf8_lossy(p).into_owned(), 1))
+                .collect())
+        };
+        let found = query_bisecting(&paths, &query);
+        assert_eq!(found.len(), 63);
+        assert!(!found.contains_key("/nix/store/37"));
+        // two queries per halving, instead of one per path
+        assert_eq!(queries.get(), 1 + 2 * 6);
+    }
+
+    #[test]
+    fn check_estimate() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/a","size":100},
+            {"id":4,"path":"/nix/store/b","size":1000}
+        ],"edges":[[1,3],[2,3],[2,4]]}"#;
+        let di = crate::import::graph(text);
+        let compressed: BTreeMap<Path, u64> =
+            vec![(b"/nix/store/a".to_vec(), 25)].into_iter().collect();
+        let paths = store_paths(&di);
+        assert_eq!(compression_ratio(&paths, &compressed), 0.25);
+        let res = estimate(&di, &compressed, 0.5);
+        let res: Vec<(String, Estimate)> = res
+            .into_iter()
+            .map(|(idx, e)| (String::from_utf8_lossy(&di.label(idx)).into_owned(), e))
+            .collect();
+        assert_eq!(res.len(), 2);
+        assert!(res[0].0.contains("r2"));
+        assert_eq!(
+            res[0].1,
+            Estimate {
+                nar: 1100,
+                compressed: 525,
+                known: 1,
+                estimated: 1
+            }
+        );
+        assert_eq!(
+            res[1].1,
+            Estimate {
+                nar: 100,
+                compressed: 25,
+                known: 1,
+                estimated: 0
+            }
+        );
+    }
+}
@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! The dominator tree of the graph as flame graphs and treemaps draw it: the area of a node
+//! is the size freed by removing it, and contains the nodes freed with it.
+
+use crate::depgraph::*;
+use crate::json::write_string;
+use crate::tree::DominatorTree;
+use petgraph::prelude::NodeIndex;
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+/// the label of `idx`, or a name for the dummy root gathering the gc-roots
+fn label(di: &DepInfos, idx: NodeIndex) -> Cow<'_, [u8]> {
+    match di.graph[idx].kind() {
+        NodeKind::Dummy => Cow::Borrowed(b"{gc-roots}"),
+        _ => di.label(idx),
+    }
+}
+
+/// the name of `idx` in a folded stack, without the semicolons which separate frames and
+/// the line breaks which separate stacks
+fn frame(di: &DepInfos, idx: NodeIndex) -> String {
+    String::from_utf8_lossy(&label(di, idx))
+        .chars()
+        .map(|c| match c {
+            ';' => ':',
+            '\n' | '\r' => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+/// Writes the dominator tree of `di` as folded stacks, the input of `inferno-flamegraph`,
+/// `flamegraph.pl` and speedscope: one line per node, with the path from its gc-root to
+/// the node separated by semicolons, and the size of the node itself. The width of a frame
+/// of the flame graph is then the size freed by removing the node. Nodes of size 0 are
+/// omitted.
+pub fn render<W: Write>(di: &DepInfos, w: &mut W) -> io::Result<()> {
+    let tree = DominatorTree::new(di);
+    let mut stack: Vec<String> = Vec::new();
+    let mut todo = vec![(di.root, 0)];
+    while let Some((idx, depth)) = todo.pop() {
+        stack.truncate(depth);
+        // the dummy root is not a frame, so that each gc-root is at the bottom
+        let depth = if di.graph[idx].kind() == NodeKind::Dummy {
+            depth
+        } else {
+            stack.push(frame(di, idx));
+            let size = di.graph[idx].size;
+            if size > 0 {
+                writeln!(w, "{} {}", stack.join(";"), size)?;
+            }
+            depth + 1
+        };
+        // biggest first
+        todo.extend(
+            tree.children[idx.index()]
+                .iter()
+                .rev()
+                .map(|&child| (child, depth)),
+        );
+    }
+    Ok(())
+}
+
+fn render_node<W: Write>(
+    di: &DepInfos,
+    tree: &DominatorTree,
+    idx: NodeIndex,
+    w: &mut W,
+) -> io::Result<()> {
+    w.write_all(b"{\"name\":")?;
+    write_string(w, &label(di, idx))?;
+    write!(
+        w,
+        ",\"size\":{},\"retained\":{}",
+        di.graph[idx].size,
+        tree.retained[idx.index()]
+    )?;
+    let children = &tree.children[idx.index()];
+    if !children.is_empty() {
+        w.write_all(b",\"children\":[")?;
+        for (i, &child) in children.iter().enumerate() {
+            if i > 0 {
+                w.write_all(b",")?;
+            }
+            render_node(di, tree, child, w)?;
+        }
+        w.write_all(b"]")?;
+    }
+    w.write_all(b"}")
+}
+
+/// Writes the dominator tree of `di` as nested JSON objects, as `d3.hierarchy` and other
+/// treemap libraries read them: each node has a `name`, its own `size`, the size `retained`
+/// by it, which is freed by removing it, and the nodes freed with it as `children`, biggest
+/// first. The outermost object is the root of the graph.
+pub fn render_treemap<W: Write>(di: &DepInfos, w: &mut W) -> io::Result<()> {
+    let tree = DominatorTree::new(di);
+    render_node(di, &tree, di.root, w)?;
+    writeln!(w)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::flamegraph::*;
+
+    fn example() -> DepInfos {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/a-app","size":100},
+            {"id":4,"path":"/nix/store/b-lib;1","size":20},
+            {"id":5,"path":"/nix/store/c-glibc","size":4000},
+            {"id":6,"path":"/nix/store/d-tool","size":300}
+        ],"edges":[[1,3],[3,4],[3,5],[2,6],[6,5]]}"#;
+        let di = crate::import::graph(text);
+        crate::reduction::keep_reachable(di)
+    }
+
+    #[test]
+    fn check_render() {
+        let mut out = Vec::new();
+        render(&example(), &mut out).unwrap();
+        let mut lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        lines.sort_unstable();
+        assert_eq!(
+            lines,
+            vec![
+                "/r1;app 100",
+                "/r1;app;lib:1 20",
+                "/r2;tool 300",
+                "glibc 4000",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_render_treemap() {
+        let di = example();
+        let mut out = Vec::new();
+        render_treemap(&di, &mut out).unwrap();
+        let top = crate::json::parse(&out).unwrap();
+        assert_eq!(top.get("name").unwrap().as_str(), Some("{gc-roots}"));
+        assert_eq!(
+            top.get("retained").unwrap().as_u64(),
+            Some(di.reachable_size())
+        );
+        let children = top.get("children").unwrap().as_array().unwrap();
+        let names: Vec<&str> = children
+            .iter()
+            .map(|child| child.get("name").unwrap().as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["glibc", "/r2", "/r1"]);
+        assert_eq!(children[2].get("retained").unwrap().as_u64(), Some(120));
+    }
+}
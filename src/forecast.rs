@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Forecasting when the store fills its filesystem, from the live sizes recorded by
+//! previous digests.
+//!
+//! Growth is fitted as a straight line by least squares: stores grow in steps, at each
+//! upgrade, so a more elaborate model would mostly fit noise.
+
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path;
+use std::time::SystemTime;
+
+/// Forecasts need at least this many samples
+pub const MIN_SAMPLES: usize = 3;
+
+/// The live store size at some date
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// seconds since the epoch
+    pub time: u64,
+    /// size of live paths
+    pub total: u64,
+}
+
+impl Sample {
+    /// A sample of `total` taken now
+    pub fn now(total: u64) -> Self {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Sample { time, total }
+    }
+}
+
+/// Reads the samples appended by `append`, oldest first. Returns no sample if the file
+/// does not exist.
+pub fn load(path: &path::Path) -> io::Result<Vec<Sample>> {
+    let f = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupted store size history");
+    let mut res = Vec::new();
+    for line in io::BufReader::new(f).lines() {
+        let line = line?;
+        let (time, total) = line.split_once('\t').ok_or_else(invalid)?;
+        res.push(Sample {
+            time: time.parse().map_err(|_| invalid())?,
+            total: total.parse().map_err(|_| invalid())?,
+        });
+    }
+    res.sort_by_key(|s| s.time);
+    Ok(res)
+}
+
+/// Appends `sample` to the history in `path`, one `TIME\tTOTAL` line per sample.
+pub fn append(path: &path::Path, sample: Sample) -> io::Result<()> {
+    let mut f = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)?;
+    writeln!(f, "{}\t{}", sample.time, sample.total)
+}
+
+/// Returns the growth of the store in bytes per second, fitted by least squares, or
+/// `None` with fewer than `MIN_SAMPLES` samples or if they were all taken at once.
+pub fn growth(samples: &[Sample]) -> Option<f64> {
+    if samples.len() < MIN_SAMPLES {
+        return None;
+    }
+    let n = samples.len() as f64;
+    // relative to the first sample, to keep precision
+    let t0 = samples[0].time as f64;
+    let mean_t = samples.iter().map(|s| s.time as f64 - t0).sum::<f64>() / n;
+    let mean_s = samples.iter().map(|s| s.total as f64).sum::<f64>() / n;
+    let mut cov = 0.;
+    let mut var = 0.;
+    for s in samples {
+        let dt = s.time as f64 - t0 - mean_t;
+        cov += dt * (s.total as f64 - mean_s);
+        var += dt * dt;
+    }
+    if var == 0. {
+        None
+    } else {
+        Some(cov / var)
+    }
+}
+
+/// Returns the space available to unprivileged users on the filesystem of `path`
+pub fn free_space(path: &path::Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Returns in how many days `free` bytes are used at `growth` bytes per second, or `None`
+/// if the store does not grow.
+pub fn days_until_full(growth: f64, free: u64) -> Option<f64> {
+    if growth > 0. {
+        Some(free as f64 / growth / (24. * 3600.))
+    } else {
+        None
+    }
+}
+
+/// Writes the forecast for the history `samples` with `free` bytes left.
+pub fn render<W: Write>(samples: &[Sample], free: u64, w: &mut W) -> io::Result<()> {
+    write!(w, "Forecast: {} free, ", bytesize::ByteSize::b(free))?;
+    let growth = match growth(samples) {
+        Some(growth) => growth,
+        None => {
+            return writeln!(
+                w,
+                "not enough history yet ({} of {} runs)",
+                samples.len(),
+                MIN_SAMPLES
+            )
+        }
+    };
+    let per_day = growth * 24. * 3600.;
+    if per_day >= 0. {
+        write!(
+            w,
+            "growing by {}/day",
+            bytesize::ByteSize::b(per_day as u64)
+        )?;
+    } else {
+        write!(
+            w,
+            "shrinking by {}/day",
+            bytesize::ByteSize::b(-per_day as u64)
+        )?;
+    }
+    match days_until_full(growth, free) {
+        Some(days) => writeln!(w, ", full in {:.0} days", days),
+        None => writeln!(w),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::forecast::*;
+
+    #[test]
+    fn check_growth() {
+        let day = 24 * 3600;
+        let samples: Vec<Sample> = [(0, 1000), (day, 2100), (2 * day, 2900), (3 * day, 4000)]
+            .iter()
+            .map(|&(time, total)| Sample {
+                time: 1_700_000_000 + time,
+                total,
+            })
+            .collect();
+        let per_day = growth(&samples).unwrap() * day as f64;
+        assert!((per_day - 980.).abs() < 1e-6, "{}", per_day);
+        assert_eq!(growth(&samples[..2]), None);
+        let days = days_until_full(growth(&samples).unwrap(), 9800).unwrap();
+        assert!((days - 10.).abs() < 1e-6);
+        assert_eq!(days_until_full(-1., 9800), None);
+        let mut out = Vec::new();
+        render(&samples, 9800, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Forecast: 9.8 KB free, growing by 980 B/day, full in 10 days\n"
+        );
+        assert!(free_space(path::Path::new("/")).is_ok());
+    }
+
+    #[test]
+    fn append_and_load() {
+        let path = std::env::temp_dir().join(format!("nix-du-history-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(load(&path).unwrap(), vec![]);
+        let a = Sample { time: 2, total: 5 };
+        let b = Sample { time: 1, total: 7 };
+        append(&path, a).unwrap();
+        append(&path, b).unwrap();
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, vec![b, a]);
+    }
+}
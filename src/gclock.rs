@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+/// What to do when a garbage collection is running while we read the store
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum OnGc {
+    /// wait for the garbage collection to finish
+    Wait,
+    /// proceed anyway, but warn that sizes may be misleading
+    Warn,
+    /// exit with an error
+    Abort,
+}
+
+/// returns the path of the lock file nix holds exclusively while collecting garbage
+fn gc_lock_path() -> PathBuf {
+    let state_dir = std::env::var_os("NIX_STATE_DIR").unwrap_or_else(|| "/nix/var/nix".into());
+    PathBuf::from(state_dir).join("gc.lock")
+}
+
+/// opens the gc lock, or returns `None` if it does not exist (no gc ever ran)
+fn open_gc_lock() -> io::Result<Option<File>> {
+    match File::open(gc_lock_path()) {
+        Ok(f) => Ok(Some(f)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// takes a shared lock on `f`, like nix does before registering temporary roots.
+/// Returns `false` if `blocking` is false and someone holds an exclusive lock.
+fn lock_shared(f: &File, blocking: bool) -> io::Result<bool> {
+    let mut op = libc::LOCK_SH;
+    if !blocking {
+        op |= libc::LOCK_NB;
+    }
+    if unsafe { libc::flock(f.as_raw_fd(), op) } == 0 {
+        // we only wanted to know whether we could take the lock
+        unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_UN) };
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// Determines whether a garbage collection is currently running.
+///
+/// Fails when the gc lock is not readable, which is usually the case
+/// for unprivileged users of a multi-user installation.
+pub fn gc_in_progress() -> io::Result<bool> {
+    match open_gc_lock()? {
+        None => Ok(false),
+        Some(f) => lock_shared(&f, false).map(|free| !free),
+    }
+}
+
+/// Blocks until the running garbage collection (if any) is over.
+pub fn wait_for_gc() -> io::Result<()> {
+    if let Some(f) = open_gc_lock()? {
+        lock_shared(&f, true)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Writing the graph as GEXF 1.3, `--format gexf`, for Gephi, with the size of nodes as a
+//! numeric attribute.
+
+use crate::depgraph::*;
+use crate::render::{edges, write_xml_escaped, Renderer};
+use petgraph::visit::IntoNodeReferences;
+use std::io::{self, Write};
+
+/// The attributes of nodes, by id: their name and type
+const NODE_ATTRIBUTES: &[(&str, &str)] = &[
+    ("kind", "string"),
+    ("path", "string"),
+    ("size", "long"),
+    ("store_paths", "integer"),
+    ("root", "boolean"),
+];
+
+/// Renders the graph as GEXF
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Gexf {
+    /// also write the edges which are not references, with the label `synthetic`
+    pub synthetic: bool,
+}
+
+impl Renderer for Gexf {
+    fn render(&self, di: &DepInfos, w: &mut dyn Write) -> io::Result<()> {
+        let roots: fixedbitset::FixedBitSet = di.roots().map(|idx| idx.index()).collect();
+        writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(w, r#"<gexf xmlns="http://gexf.net/1.3" version="1.3">"#)?;
+        writeln!(w, r#"<graph defaultedgetype="directed">"#)?;
+        writeln!(w, r#"<attributes class="node">"#)?;
+        for (id, (name, kind)) in NODE_ATTRIBUTES.iter().enumerate() {
+            writeln!(
+                w,
+                r#"<attribute id="{}" title="{}" type="{}"/>"#,
+                id, name, kind
+            )?;
+        }
+        writeln!(w, "</attributes>\n<nodes>")?;
+        for (idx, node) in di.graph.node_references() {
+            if idx == di.root {
+                continue;
+            }
+            write!(w, r#"<node id="{}" label=""#, idx.index())?;
+            write_xml_escaped(w, &di.label(idx))?;
+            write!(
+                w,
+                r#""><attvalues><attvalue for="0" value="{:?}"/>"#,
+                node.kind()
+            )?;
+            if let Some(path) = node.description.path() {
+                w.write_all(br#"<attvalue for="1" value=""#)?;
+                write_xml_escaped(w, path)?;
+                w.write_all(br#""/>"#)?;
+            }
+            writeln!(
+                w,
+                r#"<attvalue for="2" value="{}"/><attvalue for="3" value="{}"/><attvalue for="4" value="{}"/></attvalues></node>"#,
+                node.size,
+                node.store_paths().len(),
+                roots.contains(idx.index())
+            )?;
+        }
+        writeln!(w, "</nodes>\n<edges>")?;
+        for (i, (source, target, synthetic)) in edges(di, self.synthetic).into_iter().enumerate() {
+            write!(
+                w,
+                r#"<edge id="{}" source="{}" target="{}""#,
+                i, source, target
+            )?;
+            if synthetic {
+                w.write_all(br#" label="synthetic""#)?;
+            }
+            writeln!(w, "/>")?;
+        }
+        writeln!(w, "</edges>\n</graph>\n</gexf>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gexf::*;
+
+    #[test]
+    fn check_render() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/home/alice/result","root":true},
+            {"id":2,"path":"/nix/store/a-hello","size":10}
+        ],"edges":[[1,2]]}"#;
+        let di = crate::import::graph(text);
+        let mut out = Vec::new();
+        Gexf::default().render(&di, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(r#"<attribute id="2" title="size" type="long"/>"#));
+        assert!(out
+            .contains(r#"<node id="2" label="hello"><attvalues><attvalue for="0" value="Path"/>"#));
+        assert!(out.contains(r#"<attvalue for="2" value="10"/>"#));
+        assert!(out.contains(r#"<edge id="0" source="1" target="2"/>"#));
+    }
+}
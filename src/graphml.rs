@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Writing the graph as GraphML, `--format graphml`, for yEd and Cytoscape, with the size
+//! of nodes as a numeric attribute.
+
+use crate::depgraph::*;
+use crate::render::{edges, write_xml_escaped, Renderer};
+use petgraph::visit::IntoNodeReferences;
+use std::io::{self, Write};
+
+/// The attributes of nodes: their id, name and type
+const NODE_KEYS: &[(&str, &str, &str)] = &[
+    ("d0", "label", "string"),
+    ("d1", "kind", "string"),
+    ("d2", "path", "string"),
+    ("d3", "size", "long"),
+    ("d4", "store_paths", "int"),
+    ("d5", "root", "boolean"),
+];
+
+/// Renders the graph as GraphML
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GraphMl {
+    /// also write the edges which are not references, with a `synthetic` attribute
+    pub synthetic: bool,
+}
+
+impl Renderer for GraphMl {
+    fn render(&self, di: &DepInfos, w: &mut dyn Write) -> io::Result<()> {
+        let roots: fixedbitset::FixedBitSet = di.roots().map(|idx| idx.index()).collect();
+        writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            w,
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+        )?;
+        for (id, name, kind) in NODE_KEYS {
+            writeln!(
+                w,
+                r#"<key id="{}" for="node" attr.name="{}" attr.type="{}"/>"#,
+                id, name, kind
+            )?;
+        }
+        writeln!(
+            w,
+            r#"<key id="e0" for="edge" attr.name="synthetic" attr.type="boolean"/>"#
+        )?;
+        writeln!(w, r#"<graph id="nix-du" edgedefault="directed">"#)?;
+        for (idx, node) in di.graph.node_references() {
+            if idx == di.root {
+                continue;
+            }
+            write!(w, r#"<node id="n{}"><data key="d0">"#, idx.index())?;
+            write_xml_escaped(w, &di.label(idx))?;
+            write!(w, r#"</data><data key="d1">{:?}</data>"#, node.kind())?;
+            if let Some(path) = node.description.path() {
+                w.write_all(br#"<data key="d2">"#)?;
+                write_xml_escaped(w, path)?;
+                w.write_all(b"</data>")?;
+            }
+            writeln!(
+                w,
+                r#"<data key="d3">{}</data><data key="d4">{}</data><data key="d5">{}</data></node>"#,
+                node.size,
+                node.store_paths().len(),
+                roots.contains(idx.index())
+            )?;
+        }
+        for (i, (source, target, synthetic)) in edges(di, self.synthetic).into_iter().enumerate() {
+            writeln!(
+                w,
+                r#"<edge id="e{}" source="n{}" target="n{}"><data key="e0">{}</data></edge>"#,
+                i, source, target, synthetic
+            )?;
+        }
+        writeln!(w, "</graph>\n</graphml>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graphml::*;
+
+    #[test]
+    fn check_render() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/home/alice/a&b","root":true},
+            {"id":2,"path":"/nix/store/a-hello","size":10}
+        ],"edges":[[1,2]]}"#;
+        let di = crate::import::graph(text);
+        let mut out = Vec::new();
+        GraphMl::default().render(&di, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            r#"<node id="n1"><data key="d0">/home/alice/a&amp;b</data><data key="d1">Link</data>"#
+        ));
+        assert!(out.contains(
+            r#"<data key="d3">10</data><data key="d4">1</data><data key="d5">false</data></node>"#
+        ));
+        assert!(out.contains(r#"<edge id="e0" source="n1" target="n2">"#));
+        assert!(out.ends_with("</graph>\n</graphml>\n"));
+    }
+}
@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Reading the store of an image, like a tarball built in CI or the mounted disk of a
+//! virtual machine, instead of the store of the running system.
+//!
+//! libnixstore opens the store of the image as a chroot store, `local?root=DIR`, where
+//! `DIR/nix/var/nix/db` is the database of the image. Of a tarball, only `nix/var/nix` is
+//! extracted, as the database has everything needed but deduplication.
+
+use std::io::{self, Read};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The magic number of qcow2 images
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+
+/// The root of the `/nix` tree of an image
+#[derive(Debug)]
+pub struct Image {
+    /// the directory containing `nix/var/nix`
+    pub root: PathBuf,
+    /// whether `root` was extracted from a tarball, and must be removed
+    extracted: bool,
+}
+
+impl Image {
+    /// Returns the URI under which libnixstore opens the store of the image.
+    pub fn store_uri(&self) -> String {
+        format!("local?root={}&read-only=true", self.root.display())
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        if self.extracted {
+            if let Err(e) = std::fs::remove_dir_all(&self.root) {
+                eprintln!("Could not remove {}: {}", self.root.display(), e);
+            }
+            crate::msg::keep_on_exit(&self.root);
+        }
+    }
+}
+
+/// Creates a new directory in the temporary directory, readable only by the user, to extract
+/// an image to. It is removed by `msg::exit` too.
+fn create_temp_dir() -> io::Result<PathBuf> {
+    let base = std::env::temp_dir();
+    for i in 0..1000 {
+        let dir = base.join(format!("nix-du-image-{}-{}", std::process::id(), i));
+        // fails if the directory exists, so that nothing else is ever removed
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => {
+                crate::msg::remove_on_exit(dir.clone());
+                return Ok(dir);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("could not create a new directory in {}", base.display()),
+    ))
+}
+
+/// Returns whether `root` contains the database of a store
+fn has_db(root: &Path) -> bool {
+    root.join("nix/var/nix/db/db.sqlite").exists()
+}
+
+/// Opens the image at `path`: a directory containing a `/nix` tree, like a mounted image,
+/// or a possibly compressed tarball.
+pub fn open(path: &Path) -> io::Result<Image> {
+    let not_found = || {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no nix/var/nix/db/db.sqlite", path.display()),
+        )
+    };
+    if path.is_dir() {
+        if !has_db(path) {
+            return Err(not_found());
+        }
+        return Ok(Image {
+            root: path.to_path_buf(),
+            extracted: false,
+        });
+    }
+    let mut magic = [0; 4];
+    std::fs::File::open(path)?.read_exact(&mut magic)?;
+    if magic == QCOW2_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is a disk image, mount it first, for example with \
+                 `guestmount -a {} -i --ro DIR`, and pass --image DIR",
+                path.display(),
+                path.display()
+            ),
+        ));
+    }
+    let image = Image {
+        root: create_temp_dir()?,
+        extracted: true,
+    };
+    // the tarball may have ./nix or nix
+    let out = Command::new("tar")
+        .arg("-xf")
+        .arg(path)
+        .arg("-C")
+        .arg(&image.root)
+        .args(["--wildcards", "--no-anchored", "nix/var/nix/*"])
+        .output()?;
+    if !out.status.success() {
+        return Err(io::Error::other(format!(
+            "could not extract {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    if !has_db(&image.root) {
+        return Err(not_found());
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::*;
+
+    #[test]
+    fn check_open() {
+        let dir = std::env::temp_dir().join(format!("nix-du-image-test-{}", std::process::id()));
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(tree.join("nix/var/nix/db")).unwrap();
+        std::fs::write(tree.join("nix/var/nix/db/db.sqlite"), b"").unwrap();
+        let image = open(&tree).unwrap();
+        assert_eq!(image.root, tree);
+        assert!(image.store_uri().starts_with("local?root="));
+        drop(image);
+        // a mounted image is never removed
+        assert!(has_db(&tree));
+
+        assert!(open(&dir).is_err());
+        let qcow2 = dir.join("disk.qcow2");
+        std::fs::write(&qcow2, b"QFI\xfb\0\0\0\x03").unwrap();
+        assert_eq!(
+            open(&qcow2).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_create_temp_dir() {
+        let first = create_temp_dir().unwrap();
+        std::fs::write(first.join("file"), b"").unwrap();
+        // an existing directory is never reused
+        let second = create_temp_dir().unwrap();
+        assert_ne!(first, second);
+        assert!(first.join("file").exists());
+        for dir in [first, second] {
+            std::fs::remove_dir_all(&dir).unwrap();
+            crate::msg::keep_on_exit(&dir);
+        }
+    }
+}
@@ -0,0 +1,375 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Reading graphs produced by `--format json` or by other tools.
+//!
+//! Such graphs are not trusted: store paths and sizes are checked, repeated paths are merged,
+//! and edges are checked to point to existing nodes. What could not be used as is ends up
+//! in a `Report`.
+
+use crate::depgraph::*;
+use crate::json::{self, Value};
+use enum_map::enum_map;
+use lazy_static::lazy_static;
+use petgraph::prelude::NodeIndex;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path;
+
+/// Inconsistencies found in an imported graph
+#[derive(Debug, Default)]
+pub struct Report {
+    /// paths of `Path` nodes which are not store paths
+    pub invalid_paths: Vec<Path>,
+    /// ids of nodes whose size is not a non negative integer, taken as 0
+    pub invalid_sizes: Vec<u64>,
+    /// paths of `Path` nodes of size 0, which is impossible for a real store path
+    pub empty_paths: Vec<Path>,
+    /// paths given to several nodes, which were merged
+    pub duplicates: Vec<Path>,
+    /// edges from or to a node which does not exist, which were dropped
+    pub dangling_edges: Vec<(u64, u64)>,
+    /// number of edges from a node to itself, which were turned into `self_reference`
+    pub self_references: usize,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.invalid_paths.is_empty()
+            && self.invalid_sizes.is_empty()
+            && self.empty_paths.is_empty()
+            && self.duplicates.is_empty()
+            && self.dangling_edges.is_empty()
+            && self.self_references == 0
+    }
+}
+
+/// Lists at most a few items of `items`
+fn write_list<T, F: Fn(&T) -> String>(
+    f: &mut fmt::Formatter,
+    what: &str,
+    items: &[T],
+    show: F,
+) -> fmt::Result {
+    const MAX: usize = 5;
+    if items.is_empty() {
+        return Ok(());
+    }
+    write!(f, "\n  {} {}:", items.len(), what)?;
+    for item in items.iter().take(MAX) {
+        write!(f, " {}", show(item))?;
+    }
+    if items.len() > MAX {
+        write!(f, " ...")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = |p: &Path| format!("«{}»", String::from_utf8_lossy(p));
+        write!(f, "the imported graph is inconsistent:")?;
+        write_list(f, "invalid store paths", &self.invalid_paths, path)?;
+        write_list(
+            f,
+            "invalid sizes, taken as 0, for nodes",
+            &self.invalid_sizes,
+            u64::to_string,
+        )?;
+        write_list(f, "store paths of size 0", &self.empty_paths, path)?;
+        write_list(f, "repeated paths, merged", &self.duplicates, path)?;
+        write_list(
+            f,
+            "edges to missing nodes, dropped",
+            &self.dangling_edges,
+            |(from, to)| format!("{}->{}", from, to),
+        )?;
+        if self.self_references > 0 {
+            write!(
+                f,
+                "\n  {} edges from a node to itself, dropped",
+                self.self_references
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// checks the syntax of a store path: `/store/dir/<32 chars of nix base32>-<name>`
+fn is_store_path(path: &[u8]) -> bool {
+    lazy_static! {
+        static ref STORE_PATH: regex::bytes::Regex = regex::bytes::Regex::new(
+            r"^/(?:[^/]+/)+[0-9abcdfghijklmnpqrsvwxyz]{32}-[a-zA-Z0-9+\-._?=]+$"
+        )
+        .expect("regex compilation failed");
+    }
+    STORE_PATH.is_match(path) && !path.ends_with(b"/.") && !path.ends_with(b"/..")
+}
+
+fn description(kind: &str, path: Option<Path>, id: u64) -> io::Result<NodeDescription> {
+    use self::NodeDescription::*;
+    let path = || {
+        path.clone()
+            .ok_or_else(|| invalid(format!("node {} of kind {} has no path", id, kind)))
+    };
+    Ok(match kind {
+        "Path" => Path(path()?),
+        "Link" => Link(path()?),
+        "Dummy" => Dummy,
+        "FilteredOut" => FilteredOut,
+        "Memory" => Memory(path()?),
+        "Temporary" => Temporary(path()?),
+        "Transient" => Transient,
+        "Shared" => Shared(path()?),
+        "Group" => Group(path()?),
+        "Other" => Other(path()?),
+        "System" => System(path()?),
+        "Common" => Common,
+        "Error" => Error(path()?),
+        "File" => File(path()?),
+        "Derivation" => Derivation(path()?),
+        "Package" => Package(path()?, 1),
+        _ => return Err(invalid(format!("node {} has unknown kind {}", id, kind))),
+    })
+}
+
+/// Builds a graph from a JSON document in the format of `--format json`.
+///
+/// Nodes marked `"root": true` are the gc roots; if none are, nodes without referrers
+/// are. A missing `kind` means a store path.
+pub fn from_json(value: &Value) -> io::Result<(DepInfos, Report)> {
+    let mut report = Report::default();
+    let nodes = value
+        .get("nodes")
+        .and_then(Value::as_array)
+        .ok_or_else(|| invalid("no list of nodes".into()))?;
+    let edges = match value.get("edges") {
+        None | Some(Value::Null) => &[][..],
+        Some(edges) => edges
+            .as_array()
+            .ok_or_else(|| invalid("edges are not a list".into()))?,
+    };
+
+    let mut graph = DepGraph::with_capacity(nodes.len() + 1, edges.len() + nodes.len());
+    let root = graph.add_node(DepNode::dummy());
+    // maps ids in the file to node indices
+    let mut ids: HashMap<u64, NodeIndex> = HashMap::with_capacity(nodes.len());
+    let mut by_path: HashMap<NodeDescription, NodeIndex> = HashMap::new();
+    let mut roots = Vec::new();
+    for node in nodes {
+        let id = node
+            .get("id")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| invalid("a node has no valid id".into()))?;
+        if ids.contains_key(&id) {
+            return Err(invalid(format!("several nodes have id {}", id)));
+        }
+        let kind = match node.get("kind") {
+            None | Some(Value::Null) => "Path",
+            Some(kind) => kind
+                .as_str()
+                .ok_or_else(|| invalid(format!("node {} has an invalid kind", id)))?,
+        };
+        let path = match node.get("path") {
+            None | Some(Value::Null) => None,
+            Some(path) => Some(
+                path.as_str()
+                    .ok_or_else(|| invalid(format!("node {} has an invalid path", id)))?
+                    .as_bytes()
+                    .to_vec(),
+            ),
+        };
+        let description = description(kind, path, id)?;
+        let size = match node.get("size") {
+            None | Some(Value::Null) => 0,
+            Some(size) => size.as_u64().unwrap_or_else(|| {
+                report.invalid_sizes.push(id);
+                0
+            }),
+        };
+        let is_root = matches!(node.get("root"), Some(Value::Bool(true)));
+        let system = node
+            .get("system")
+            .and_then(Value::as_str)
+            .map(|s| s.as_bytes().to_vec());
+        let age = node
+            .get("age")
+            .and_then(Value::as_u64)
+            .map(|secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+
+        if description.path().is_some() {
+            if let Some(&idx) = by_path.get(&description) {
+                report.duplicates.push(description.path().unwrap().clone());
+                ids.insert(id, idx);
+                if is_root && !roots.contains(&idx) {
+                    roots.push(idx);
+                }
+                continue;
+            }
+        }
+        if let NodeDescription::Path(p) = &description {
+            if !is_store_path(p) {
+                report.invalid_paths.push(p.clone());
+            } else if size == 0 {
+                report.empty_paths.push(p.clone());
+            }
+        }
+        let idx = graph.add_node(DepNode {
+            description: description.clone(),
+            size,
+            system,
+            age,
+            ..DepNode::dummy()
+        });
+        if description.path().is_some() {
+            by_path.insert(description, idx);
+        }
+        ids.insert(id, idx);
+        if is_root {
+            roots.push(idx);
+        }
+    }
+
+    for edge in edges {
+        let pair = edge
+            .as_array()
+            .filter(|e| e.len() == 2 || e.len() == 3)
+            .and_then(|e| Some((e[0].as_u64()?, e[1].as_u64()?)))
+            .ok_or_else(|| invalid("an edge is not a pair of ids".into()))?;
+        // written by --include-synthetic
+        let provenance = match edge.as_array().and_then(|e| e.get(2)) {
+            None => Edge::Reference,
+            Some(p) if p.as_str() == Some(Edge::Synthetic.name()) => Edge::Synthetic,
+            Some(_) => return Err(invalid("unknown provenance of an edge".into())),
+        };
+        match (ids.get(&pair.0), ids.get(&pair.1)) {
+            (Some(&from), Some(&to)) if from == to => {
+                report.self_references += 1;
+                graph[from].self_reference = true;
+            }
+            (Some(&from), Some(&to)) => {
+                // merging duplicates may have made this edge redundant
+                merge_edge(&mut graph, from, to, provenance);
+            }
+            _ => report.dangling_edges.push(pair),
+        }
+    }
+    record_degrees(&mut graph);
+
+    if roots.is_empty() {
+        roots = graph
+            .node_indices()
+            .filter(|&idx| idx != root && graph[idx].referrers == 0)
+            .collect();
+    }
+    for idx in roots {
+        graph.add_edge(root, idx, Edge::Root);
+    }
+    let mut di = DepInfos {
+        graph,
+        root,
+        metadata: SizeMetadata {
+            reachable: Reachability::Disconnected,
+            dedup: DedupAwareness::Unaware,
+            size: enum_map! { _ => enum_map!{ _ => None }},
+        },
+        annotations: Default::default(),
+    };
+    di.record_metadata();
+    Ok((di, report))
+}
+
+/// Reads the graph in the JSON file `path`.
+pub fn load(path: &path::Path) -> io::Result<(DepInfos, Report)> {
+    let data = std::fs::read(path)?;
+    from_json(&json::parse(&data)?)
+}
+
+/// Builds the graph of the JSON document `text`, which tests trust to be valid.
+#[cfg(test)]
+pub(crate) fn graph(text: &[u8]) -> DepInfos {
+    from_json(&json::parse(text).unwrap()).unwrap().0
+}
+
+/// Returns the node of `di` whose store path, or link, is `path`.
+#[cfg(test)]
+pub(crate) fn node(di: &DepInfos, path: &str) -> NodeIndex {
+    di.graph
+        .node_indices()
+        .find(|&idx| match di.graph[idx].description.path() {
+            Some(p) => p.as_slice() == path.as_bytes(),
+            None => false,
+        })
+        .unwrap_or_else(|| panic!("no node {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::import::*;
+
+    #[test]
+    fn check_import() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/nix/var/nix/gcroots/a","size":0,"root":true},
+            {"id":2,"path":"/nix/store/0123456789abcdfghijklmnpqrsvwxyz-foo","size":10},
+            {"id":3,"path":"/nix/store/0123456789abcdfghijklmnpqrsvwxyz-foo","size":10},
+            {"id":4,"path":"/nix/store/not-a-hash","size":-1},
+            {"id":5,"path":"/nix/store/zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz-bar","size":0}
+        ],"edges":[[1,2],[1,3],[2,4],[4,4],[2,5],[5,6]]}"#;
+        let (di, report) = from_json(&json::parse(text).unwrap()).unwrap();
+        assert_eq!(di.graph.node_count(), 5);
+        assert_eq!(di.roots().count(), 1);
+        assert_eq!(report.duplicates.len(), 1);
+        assert_eq!(
+            report.invalid_paths,
+            vec![b"/nix/store/not-a-hash".to_vec()]
+        );
+        assert_eq!(report.invalid_sizes, vec![4]);
+        assert_eq!(report.empty_paths.len(), 1);
+        assert_eq!(report.dangling_edges, vec![(5, 6)]);
+        assert_eq!(report.self_references, 1);
+        assert_eq!(di.self_references(), 1);
+        // the edge from 1 to 3 is the edge from 1 to 2
+        assert_eq!(di.graph.edge_count(), 1 + 3);
+        let foo = di
+            .graph
+            .node_indices()
+            .find(|&idx| di.graph[idx].size == 10)
+            .unwrap();
+        assert_eq!(di.graph[foo].referrers, 1);
+        assert_eq!(di.graph[foo].references, 2);
+        assert_eq!(di.reachable_size(), 10);
+        assert!(!report.is_empty());
+        assert!(
+            from_json(&json::parse(b"{\"nodes\":[{\"id\":1,\"kind\":\"Nope\"}]}").unwrap())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn check_import_files() {
+        // a project directory owning two result links
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"File","path":"/home/alice/src/project","root":true},
+            {"id":2,"kind":"Link","path":"/home/alice/src/project/result"},
+            {"id":3,"kind":"Link","path":"/home/alice/src/project/result-doc"},
+            {"id":4,"path":"/nix/store/0123456789abcdfghijklmnpqrsvwxyz-foo","size":10},
+            {"id":5,"path":"/nix/store/0123456789abcdfghijklmnpqrsvwxyz-foo-doc","size":1}
+        ],"edges":[[1,2],[1,3],[2,4],[3,5]]}"#;
+        let (di, report) = from_json(&json::parse(text).unwrap()).unwrap();
+        assert!(report.is_empty(), "{}", report);
+        assert_eq!(
+            di.roots_name(),
+            ["/home/alice/src/project".to_owned()]
+                .iter()
+                .cloned()
+                .collect()
+        );
+        let di = crate::reduction::condense(di);
+        assert_eq!(di.reachable_size(), 11);
+    }
+}
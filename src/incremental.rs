@@ -0,0 +1,400 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Differential condensation: reusing the equivalence classes of the last run for the
+//! gc-roots whose closure did not change, so that re-runs on a mostly static store only walk
+//! the closures of the few gc-roots which changed.
+//!
+//! Store paths are immutable, so the closure of a gc-root is unchanged when it refers to the
+//! same paths as last time, and no path of its closure was added since. Paths which were not
+//! live during the last run are the added ones; removed paths need no special care, as a
+//! gc-root refers to a removed path only if its references changed, or through an added path.
+//! Links between paths deduplicated by store optimisation do change, so graphs with `Shared`
+//! nodes are condensed from scratch.
+
+use crate::depgraph::*;
+use crate::reduction::{hash, quotient, seed};
+use petgraph::prelude::NodeIndex;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+const MAGIC: &str = "nix-du classes v1";
+
+/// The equivalence classes of a condensed graph, as saved between runs
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Classes {
+    /// the path of each gc-root, and the paths it refers to directly, sorted
+    roots: Vec<(Path, Vec<Path>)>,
+    /// the gc-roots of each class, as sorted indices into `roots`
+    classes: Vec<Vec<usize>>,
+    /// the class of each live path, as index into `classes`
+    class_of: HashMap<Path, usize>,
+}
+
+/// Returns the path of `root` and the paths it refers to, sorted, or `None` if it has no
+/// path, like the node gathering transient roots.
+fn references(di: &DepInfos, root: NodeIndex) -> Option<(Path, Vec<Path>)> {
+    let path = di.graph[root].description.path()?.clone();
+    let mut refs: Vec<Path> = di
+        .graph
+        .neighbors(root)
+        .filter_map(|idx| di.graph[idx].description.path().cloned())
+        .collect();
+    refs.sort_unstable();
+    Some((path, refs))
+}
+
+impl Classes {
+    /// Returns the classes of `condensed`, the result of condensing a graph whose gc-roots
+    /// had the references `roots`, as returned by `references`.
+    fn of(roots: Vec<(Path, Vec<Path>)>, condensed: &DepInfos) -> Self {
+        let index: HashMap<&Path, usize> = roots
+            .iter()
+            .enumerate()
+            .map(|(i, (path, _))| (path, i))
+            .collect();
+        let n = condensed.graph.node_count();
+        let mut reached: Vec<Vec<usize>> = vec![Vec::new(); n];
+        let mut dfs = petgraph::visit::Dfs::empty(&condensed.graph);
+        for idx in condensed.graph.node_indices() {
+            let node = &condensed.graph[idx];
+            let mut contained: Vec<usize> = node
+                .description
+                .path()
+                .into_iter()
+                .chain(node.members.iter().map(|(path, _)| path))
+                .filter_map(|path| index.get(path).copied())
+                .collect();
+            if contained.is_empty() {
+                continue;
+            }
+            contained.sort_unstable();
+            dfs.reset(&condensed.graph);
+            dfs.move_to(idx);
+            while let Some(nx) = dfs.next(&condensed.graph) {
+                reached[nx.index()].extend_from_slice(&contained);
+            }
+        }
+        let mut class_of = HashMap::new();
+        for idx in condensed.graph.node_indices() {
+            reached[idx.index()].sort_unstable();
+            if idx == condensed.root {
+                continue;
+            }
+            let node = &condensed.graph[idx];
+            if let Some(path) = node.description.path() {
+                class_of.insert(path.clone(), idx.index());
+            }
+            for (path, _) in &node.members {
+                class_of.insert(path.clone(), idx.index());
+            }
+        }
+        Classes {
+            roots,
+            classes: reached,
+            class_of,
+        }
+    }
+
+    /// Reads classes saved by `save`. Returns `None` if the file does not exist.
+    pub fn load(path: &std::path::Path) -> io::Result<Option<Self>> {
+        let f = match fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupted classes file");
+        let mut res = Classes::default();
+        let mut lines = io::BufReader::new(f).split(b'\n');
+        if lines.next().transpose()?.as_deref() != Some(MAGIC.as_bytes()) {
+            return Err(invalid());
+        }
+        for line in lines {
+            let line = line?;
+            let tab = line.iter().position(|&c| c == b'\t').ok_or_else(invalid)?;
+            let (tag, value) = (&line[..tab], &line[tab + 1..]);
+            match tag {
+                b"root" => res.roots.push((value.to_vec(), Vec::new())),
+                b"ref" => res
+                    .roots
+                    .last_mut()
+                    .ok_or_else(invalid)?
+                    .1
+                    .push(value.to_vec()),
+                b"class" => {
+                    let roots = std::str::from_utf8(value).map_err(|_| invalid())?;
+                    let roots: Result<Vec<usize>, _> =
+                        roots.split_whitespace().map(str::parse).collect();
+                    res.classes.push(roots.map_err(|_| invalid())?);
+                }
+                b"path" => {
+                    let class = res.classes.len().checked_sub(1).ok_or_else(invalid)?;
+                    res.class_of.insert(value.to_vec(), class);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        let roots = res.roots.len();
+        if res.classes.iter().flatten().any(|&i| i >= roots) {
+            return Err(invalid());
+        }
+        Ok(Some(res))
+    }
+
+    /// Saves the classes to `path`. Paths containing a newline cannot be saved, in which case
+    /// the file is removed, so that the next run does not use the classes of an older run.
+    pub fn save(&self, path: &std::path::Path) -> io::Result<()> {
+        let mut all_paths = self
+            .roots
+            .iter()
+            .flat_map(|(root, refs)| std::iter::once(root).chain(refs))
+            .chain(self.class_of.keys());
+        if all_paths.any(|p| p.contains(&b'\n')) {
+            return match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let mut by_class: Vec<Vec<&Path>> = vec![Vec::new(); self.classes.len()];
+        for (p, &class) in &self.class_of {
+            by_class[class].push(p);
+        }
+        let mut f = io::BufWriter::new(fs::File::create(path)?);
+        writeln!(f, "{}", MAGIC)?;
+        for (root, refs) in &self.roots {
+            f.write_all(b"root\t")?;
+            f.write_all(root)?;
+            f.write_all(b"\n")?;
+            for r in refs {
+                f.write_all(b"ref\t")?;
+                f.write_all(r)?;
+                f.write_all(b"\n")?;
+            }
+        }
+        for (roots, mut paths) in self.classes.iter().zip(by_class) {
+            let roots: Vec<String> = roots.iter().map(usize::to_string).collect();
+            writeln!(f, "class\t{}", roots.join(" "))?;
+            paths.sort_unstable();
+            for p in paths {
+                f.write_all(b"path\t")?;
+                f.write_all(p)?;
+                f.write_all(b"\n")?;
+            }
+        }
+        f.flush()
+    }
+}
+
+/// Returns, by node index, whether a node of `di` may be reached by a gc-root whose closure
+/// changed since `previous`: the nodes whose path was not live then, and the nodes
+/// depending on them.
+fn dirty(di: &DepInfos, previous: &Classes) -> fixedbitset::FixedBitSet {
+    let mut res = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    let mut stack: Vec<NodeIndex> = di
+        .graph
+        .node_indices()
+        .filter(|&idx| match di.graph[idx].description.path() {
+            Some(path) => !previous.class_of.contains_key(path),
+            None => true,
+        })
+        .collect();
+    while let Some(idx) = stack.pop() {
+        if res.put(idx.index()) {
+            continue;
+        }
+        stack.extend(
+            di.graph
+                .neighbors_directed(idx, petgraph::Direction::Incoming),
+        );
+    }
+    res
+}
+
+/// Same as `reduction::condense`, but reuses the classes of `previous`, if any, for the
+/// gc-roots whose closure did not change. Returns the condensed graph, its classes, to be
+/// reused by the next run, and how many gc-roots the closure of which was walked.
+pub fn condense(di: DepInfos, previous: Option<&Classes>) -> (DepInfos, Classes, usize) {
+    let roots: Vec<NodeIndex> = di.roots().collect();
+    let refs: Vec<Option<(Path, Vec<Path>)>> =
+        roots.iter().map(|&root| references(&di, root)).collect();
+    let empty = Classes::default();
+    // store optimisation links unchanged paths, and graphs rooted in a store path have no
+    // links to compare
+    let previous = match previous {
+        Some(previous)
+            if di.graph[di.root].kind() == NodeKind::Dummy
+                && di
+                    .graph
+                    .node_weights()
+                    .all(|node| node.kind() != NodeKind::Shared) =>
+        {
+            previous
+        }
+        _ => &empty,
+    };
+
+    let dirty = dirty(&di, previous);
+    let previous_index: HashMap<&Path, usize> = previous
+        .roots
+        .iter()
+        .enumerate()
+        .map(|(i, (path, _))| (path, i))
+        .collect();
+    // the node of each gc-root of the last run whose closure did not change
+    let mut kept: Vec<Option<NodeIndex>> = vec![None; previous.roots.len()];
+    let mut changed = Vec::new();
+    for (&root, refs) in roots.iter().zip(&refs) {
+        let unchanged = match refs {
+            Some((path, refs)) if !dirty.contains(root.index()) => match previous_index.get(path) {
+                Some(&i) if &previous.roots[i].1 == refs => {
+                    kept[i] = Some(root);
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+        if !unchanged {
+            changed.push(root);
+        }
+    }
+
+    // the hash of the set of roots of a node, as in `condense_relative_to`, except that the
+    // unchanged roots come first, in the order of their node index
+    let start_hash = seed(&di);
+    let labels: Vec<u128> = previous
+        .classes
+        .iter()
+        .map(|class| {
+            let mut reaching: Vec<NodeIndex> = class.iter().filter_map(|&i| kept[i]).collect();
+            reaching.sort_unstable();
+            reaching.into_iter().fold(start_hash, hash)
+        })
+        .collect();
+    let mut classes: Vec<u128> = di
+        .graph
+        .node_weights()
+        .map(|node| {
+            match node
+                .description
+                .path()
+                .and_then(|path| previous.class_of.get(path))
+            {
+                Some(&class) => labels[class],
+                None => start_hash,
+            }
+        })
+        .collect();
+    classes[di.root.index()] = hash(start_hash, "root");
+    for &root in &changed {
+        let mut bfs = petgraph::visit::Bfs::new(&di.graph, root);
+        while let Some(nx) = bfs.next(&di.graph) {
+            classes[nx.index()] = hash(classes[nx.index()], root);
+        }
+    }
+
+    let condensed = quotient(di, &classes);
+    let classes = Classes::of(refs.into_iter().flatten().collect(), &condensed);
+    (condensed, classes, changed.len())
+}
+
+/// Condenses `di` with the classes saved by the last run on the current store, and saves
+/// the new ones in their place. Returns the condensed graph, and how many gc-roots the
+/// closure of which was walked.
+pub fn run(di: DepInfos) -> io::Result<(DepInfos, usize)> {
+    let state = crate::cache::classes_file()?;
+    let previous = Classes::load(&state)?;
+    let (condensed, classes, changed) = condense(di, previous.as_ref());
+    classes.save(&state)?;
+    Ok((condensed, changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::incremental::*;
+    use std::collections::BTreeSet;
+
+    /// the paths of each node of a condensed graph
+    fn partition(di: &DepInfos) -> BTreeSet<BTreeSet<Path>> {
+        di.graph
+            .node_indices()
+            .filter(|&idx| idx != di.root)
+            .map(|idx| {
+                let node = &di.graph[idx];
+                node.description
+                    .path()
+                    .cloned()
+                    .into_iter()
+                    .chain(node.members.iter().map(|(p, _)| p.clone()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn check_incremental_condense() {
+        let before = crate::import::graph(
+            br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"kind":"Link","path":"/r3","root":true},
+            {"id":4,"path":"/nix/store/a","size":10},
+            {"id":5,"path":"/nix/store/b","size":100},
+            {"id":6,"path":"/nix/store/c","size":1000},
+            {"id":7,"path":"/nix/store/d","size":5}
+        ],"edges":[[1,4],[4,6],[2,5],[5,6],[3,7],[7,6]]}"#,
+        );
+        let (condensed, classes, changed) = condense(before.clone(), None);
+        assert_eq!(changed, 3);
+        assert_eq!(
+            partition(&condensed),
+            partition(&crate::reduction::condense(before))
+        );
+
+        // r2 now refers to a, d came with a new path e, and r4 is new
+        let after = crate::import::graph(
+            br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"kind":"Link","path":"/r3","root":true},
+            {"id":4,"path":"/nix/store/a","size":10},
+            {"id":5,"path":"/nix/store/b","size":100},
+            {"id":6,"path":"/nix/store/c","size":1000},
+            {"id":7,"path":"/nix/store/d","size":5},
+            {"id":8,"path":"/nix/store/e","size":1},
+            {"id":9,"kind":"Link","path":"/r4","root":true}
+        ],"edges":[[1,4],[4,6],[2,4],[5,6],[3,7],[7,6],[7,8],[9,5]]}"#,
+        );
+        let (condensed, _, changed) = condense(after.clone(), Some(&classes));
+        // only r1 is unchanged
+        assert_eq!(changed, 3);
+        let expected = crate::reduction::condense(after);
+        assert_eq!(partition(&condensed), partition(&expected));
+        assert_eq!(condensed.graph.edge_count(), expected.graph.edge_count());
+    }
+
+    #[test]
+    fn check_incremental_condense_random() {
+        for _ in 0..50 {
+            let di = crate::selftest::generate_random(62, 5, false);
+            let (_, classes, _) = condense(di.clone(), None);
+            let (condensed, _, _) = condense(di.clone(), Some(&classes));
+            assert_eq!(
+                partition(&condensed),
+                partition(&crate::reduction::condense(di))
+            );
+        }
+    }
+
+    #[test]
+    fn check_save_load() {
+        let di = crate::selftest::generate_random(30, 3, false);
+        let (_, classes, _) = condense(di, None);
+        let dir = std::env::temp_dir().join(format!("nix-du-classes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        classes.save(&dir.join("classes")).unwrap();
+        assert_eq!(Classes::load(&dir.join("classes")).unwrap(), Some(classes));
+        assert_eq!(Classes::load(&dir.join("missing")).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
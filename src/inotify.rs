@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Waiting for changes of directories with inotify.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::time::Duration;
+
+/// What makes a watched directory changed: entries created, removed, renamed or written.
+const EVENTS: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF;
+
+/// An inotify instance
+pub struct Inotify {
+    fd: RawFd,
+}
+
+impl Inotify {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify { fd })
+    }
+
+    /// Watches the entries of the directory `path`. Watching a directory twice is harmless.
+    pub fn watch(&self, path: &Path) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), EVENTS) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Waits at most `timeout` for changes of the watched directories, and returns whether
+    /// there were any. Pending events are consumed.
+    pub fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+            0 => return Ok(false),
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                return if e.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(e)
+                };
+            }
+            _ => (),
+        }
+        let mut buf = [0u8; 4096];
+        loop {
+            let n =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                return if e.kind() == io::ErrorKind::WouldBlock {
+                    Ok(true)
+                } else {
+                    Err(e)
+                };
+            }
+        }
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inotify::*;
+
+    #[test]
+    fn check_wait() {
+        let dir = std::env::temp_dir().join(format!("nix-du-inotify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let inotify = Inotify::new().unwrap();
+        inotify.watch(&dir).unwrap();
+        assert!(!inotify.wait(Duration::from_millis(10)).unwrap());
+        std::os::unix::fs::symlink("/nowhere", dir.join("result")).unwrap();
+        assert!(inotify.wait(Duration::from_secs(5)).unwrap());
+        // events were consumed
+        assert!(!inotify.wait(Duration::from_millis(10)).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
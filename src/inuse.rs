@@ -0,0 +1,207 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Finding the gc-roots whose deletion would let the garbage collector remove store paths
+//! in active use: paths used by running processes, as listed in memory and temporary
+//! roots, and the paths of the commands of running systemd services.
+//!
+//! A root is in use when it is the only gc-root, other than transient ones, keeping such a
+//! path alive. Deleting it does not break the running process right away, as its transient
+//! roots protect what it uses, but the next garbage collection after it exits, or the next
+//! restart of the service, does.
+
+use crate::depgraph::*;
+use petgraph::prelude::NodeIndex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path as FsPath;
+use std::process::Command;
+
+/// Why a gc-root is in use
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reason {
+    /// a running process uses a path: the path of its memory or temporary root
+    Process(Path),
+    /// a running systemd service runs a path: the name of the unit
+    Service(String),
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Reason::Process(root) => {
+                write!(f, "used by a process ({})", String::from_utf8_lossy(root))
+            }
+            Reason::Service(unit) => write!(f, "run by the service {}", unit),
+        }
+    }
+}
+
+/// Returns the store paths of the part of `text` which follows `store`.
+fn store_paths_in(text: &str, store: &FsPath) -> Vec<Path> {
+    let store = format!("{}/", store.display());
+    let mut res = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(&store) {
+        let path = &rest[start..];
+        let end = path[store.len()..]
+            .find(|c: char| c == '/' || c == ';' || c == ' ' || c == '}' || c.is_whitespace())
+            .map_or(path.len(), |end| end + store.len());
+        if end > store.len() {
+            res.push(path.as_bytes()[..end].to_vec());
+        }
+        rest = &path[end..];
+    }
+    res
+}
+
+/// Parses the output of `systemctl show --property=Id,ActiveState,ExecStart` into the store
+/// paths run by each active unit.
+pub fn parse_systemctl_show(text: &str, store: &FsPath) -> Vec<(String, Path)> {
+    let mut res = Vec::new();
+    for unit in text.split("\n\n") {
+        let mut id = None;
+        let mut active = false;
+        let mut paths = Vec::new();
+        for line in unit.lines() {
+            if let Some(value) = line.strip_prefix("Id=") {
+                id = Some(value.to_owned());
+            } else if let Some(value) = line.strip_prefix("ActiveState=") {
+                active = value == "active" || value == "reloading";
+            } else if let Some(value) = line.strip_prefix("ExecStart=") {
+                paths.extend(store_paths_in(value, store));
+            }
+        }
+        if let (Some(id), true) = (id, active) {
+            res.extend(paths.into_iter().map(|p| (id.clone(), p)));
+        }
+    }
+    res
+}
+
+/// Returns the store paths run by active systemd services, of the system and of the user,
+/// with the name of the unit. Empty without systemd.
+pub fn service_paths() -> Vec<(String, Path)> {
+    let store = crate::archive::store_dir();
+    let mut res = Vec::new();
+    for user in [false, true] {
+        let mut cmd = Command::new("systemctl");
+        if user {
+            cmd.arg("--user");
+        }
+        cmd.args(["show", "--property=Id,ActiveState,ExecStart", "*"]);
+        if let Ok(out) = cmd.output() {
+            if out.status.success() {
+                res.extend(parse_systemctl_show(
+                    &String::from_utf8_lossy(&out.stdout),
+                    &store,
+                ));
+            }
+        }
+    }
+    res
+}
+
+/// Returns the gc-roots of `di` in use, given the store paths run by `services`. `di` must
+/// not be condensed, so that the paths used by processes are the children of transient
+/// roots.
+pub fn roots_in_use(di: &DepInfos, services: &[(String, Path)]) -> BTreeMap<Path, Reason> {
+    let mut used: Vec<(NodeIndex, Reason)> = Vec::new();
+    let mut transient = Vec::new();
+    let mut dfs = petgraph::visit::Dfs::new(&di.graph, di.root);
+    while let Some(idx) = dfs.next(&di.graph) {
+        let node = &di.graph[idx];
+        if node.kind().is_transient() || node.kind() == NodeKind::Transient {
+            transient.push(idx);
+        }
+    }
+    for &idx in &transient {
+        for child in di.graph.neighbors(idx) {
+            if !transient.contains(&child) {
+                let root = di.graph[idx]
+                    .description
+                    .path()
+                    .cloned()
+                    .unwrap_or_default();
+                used.push((child, Reason::Process(root)));
+            }
+        }
+    }
+    let services: BTreeMap<&[u8], &str> = services
+        .iter()
+        .map(|(unit, path)| (path.as_slice(), unit.as_str()))
+        .collect();
+    for idx in di.graph.node_indices() {
+        if let NodeDescription::Path(path) = &di.graph[idx].description {
+            if let Some(unit) = services.get(path.as_slice()) {
+                used.push((idx, Reason::Service(unit.to_string())));
+            }
+        }
+    }
+    let mut res = BTreeMap::new();
+    let mut checked = BTreeSet::new();
+    for (idx, reason) in used {
+        if !checked.insert(idx) {
+            continue;
+        }
+        let keepers: Vec<NodeIndex> = crate::unlock::roots_of(di, idx)
+            .into_iter()
+            .filter(|&root| !transient.contains(&root))
+            .collect();
+        if let [root] = keepers[..] {
+            if let NodeDescription::Link(path) = &di.graph[root].description {
+                res.entry(path.clone()).or_insert(reason);
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inuse::*;
+
+    #[test]
+    fn check_parse_systemctl_show() {
+        let text = "Id=foo.service\nActiveState=active\n\
+            ExecStart={ path=/nix/store/aaaa-unit-script-foo/bin/foo ; argv[]=/nix/store/aaaa-unit-script-foo/bin/foo --flag=/nix/store/bbbb-conf ; ignore_errors=no }\n\n\
+            Id=bar.service\nActiveState=inactive\nExecStart={ path=/nix/store/cccc-bar/bin/bar ; }\n\n\
+            Id=baz.socket\nActiveState=active\nExecStart=\n";
+        let res = parse_systemctl_show(text, FsPath::new("/nix/store"));
+        let paths: Vec<(&str, &[u8])> = res
+            .iter()
+            .map(|(unit, path)| (unit.as_str(), path.as_slice()))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("foo.service", &b"/nix/store/aaaa-unit-script-foo"[..]),
+                ("foo.service", &b"/nix/store/aaaa-unit-script-foo"[..]),
+                ("foo.service", &b"/nix/store/bbbb-conf"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_roots_in_use() {
+        // the process uses a, kept by r1 alone, and b, kept by r1 and r2. The service runs
+        // c, kept by r3 alone.
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"kind":"Link","path":"/r3","root":true},
+            {"id":4,"kind":"Memory","path":"/proc/1","root":true},
+            {"id":5,"path":"/nix/store/a","size":10},
+            {"id":6,"path":"/nix/store/b","size":100},
+            {"id":7,"path":"/nix/store/c","size":5}
+        ],"edges":[[1,5],[1,6],[2,6],[3,7],[4,5],[4,6]]}"#;
+        let di = crate::import::graph(text);
+        let services = vec![("c.service".to_owned(), b"/nix/store/c".to_vec())];
+        let res = roots_in_use(&di, &services);
+        assert_eq!(
+            res.into_iter().collect::<Vec<_>>(),
+            vec![
+                (b"/r1".to_vec(), Reason::Process(b"/proc/1".to_vec())),
+                (b"/r3".to_vec(), Reason::Service("c.service".to_owned())),
+            ]
+        );
+    }
+}
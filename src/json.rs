@@ -0,0 +1,637 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph;
+use petgraph::visit::IntoNodeReferences;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+
+/// Writes `s` as a JSON string. Invalid UTF-8 is replaced by U+FFFD.
+pub fn write_string<W: Write>(w: &mut W, s: &[u8]) -> io::Result<()> {
+    w.write_all(b"\"")?;
+    for c in String::from_utf8_lossy(s).chars() {
+        match c {
+            '"' => w.write_all(b"\\\"")?,
+            '\\' => w.write_all(b"\\\\")?,
+            '\n' => w.write_all(b"\\n")?,
+            '\t' => w.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    w.write_all(b"\"")
+}
+
+/// Writes the store paths a node stands for, grouped by package name, biggest first. With
+/// `lod` at least 3, each package lists its store paths.
+fn write_packages<W: Write>(node: &depgraph::DepNode, lod: usize, w: &mut W) -> io::Result<()> {
+    let mut packages: BTreeMap<Vec<u8>, (u64, Vec<_>)> = BTreeMap::new();
+    for (path, size) in node.store_paths() {
+        let name = depgraph::NodeDescription::Path(path.to_vec())
+            .name()
+            .into_owned();
+        let package = packages
+            .entry(crate::diff::split_name(&name).0.to_vec())
+            .or_default();
+        package.0 += size;
+        package.1.push((path, size));
+    }
+    let mut packages: Vec<_> = packages.into_iter().collect();
+    packages.sort_by_key(|(_, (size, _))| std::cmp::Reverse(*size));
+    w.write_all(b",\"packages\":[")?;
+    for (i, (name, (size, mut paths))) in packages.into_iter().enumerate() {
+        if i > 0 {
+            w.write_all(b",")?;
+        }
+        w.write_all(b"{\"name\":")?;
+        write_string(w, &name)?;
+        write!(w, ",\"size\":{},\"count\":{}", size, paths.len())?;
+        if lod >= 3 {
+            paths.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+            w.write_all(b",\"paths\":[")?;
+            for (j, (path, size)) in paths.into_iter().enumerate() {
+                if j > 0 {
+                    w.write_all(b",")?;
+                }
+                w.write_all(b"{\"path\":")?;
+                write_string(w, path)?;
+                write!(w, ",\"size\":{}}}", size)?;
+            }
+            w.write_all(b"]")?;
+        }
+        w.write_all(b"}")?;
+    }
+    w.write_all(b"]")
+}
+
+/// Writes the graph as a JSON object with a `nodes` list and an `edges` list of
+/// `[source id, target id]` pairs. Like in the dot output, the root is omitted, and
+/// its children are marked with `"root": true`. Nodes whose system is known have a `system`,
+/// and gc-roots whose age is known an `age`, when they were created in seconds since the epoch.
+/// Edges which are not references are omitted.
+pub fn render<W: Write>(dependencies: &depgraph::DepInfos, w: &mut W) -> io::Result<()> {
+    render_with(dependencies, &Options::default(), w)
+}
+
+/// How the store paths merged into each node are listed by `render_with`
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Members {
+    /// one {"node", "path", "size"} object per store path
+    Full,
+    /// one [node, name, size] array per store path, its name relative to `store_dir`
+    Compact,
+}
+
+/// What `render_with` writes in addition to `render`
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// levels of detail: with 2, each node has a list of the packages it stands for, with
+    /// their size and number of store paths, and with 3, the store paths of each package, so
+    /// that viewers can expand nodes
+    pub lod: usize,
+    /// write the edges which are not references as `[source id, target id, "synthetic"]`
+    pub synthetic: bool,
+    /// list the store paths of all nodes in a `members` table, each referring to the id of
+    /// its node
+    pub members: Option<Members>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            lod: 1,
+            synthetic: false,
+            members: None,
+        }
+    }
+}
+
+/// Writes the `members` table of `render_with`, its store paths sorted. With `store_dir`,
+/// in the compact form and relative to this directory.
+fn write_members<W: Write>(
+    dependencies: &depgraph::DepInfos,
+    store_dir: Option<&[u8]>,
+    w: &mut W,
+) -> io::Result<()> {
+    if let Some(dir) = store_dir {
+        w.write_all(b",\"store_dir\":")?;
+        write_string(w, dir)?;
+    }
+    w.write_all(b",\"members\":[")?;
+    let mut first = true;
+    for (idx, node) in dependencies.graph.node_references() {
+        if idx == dependencies.root {
+            continue;
+        }
+        let mut paths = node.store_paths();
+        paths.sort_unstable();
+        for (path, size) in paths {
+            if !first {
+                w.write_all(b",")?;
+            }
+            first = false;
+            match store_dir {
+                None => {
+                    write!(w, "\n{{\"node\":{},\"path\":", idx.index())?;
+                    write_string(w, path)?;
+                    write!(w, ",\"size\":{}}}", size)?;
+                }
+                Some(dir) => {
+                    let name = path
+                        .strip_prefix(dir)
+                        .and_then(|name| name.strip_prefix(b"/"))
+                        .unwrap_or(path);
+                    write!(w, "\n[{},", idx.index())?;
+                    write_string(w, name)?;
+                    write!(w, ",{}]", size)?;
+                }
+            }
+        }
+    }
+    w.write_all(b"\n]")
+}
+
+/// Like `render`, with the additional data of `options`.
+pub fn render_with<W: Write>(
+    dependencies: &depgraph::DepInfos,
+    options: &Options,
+    w: &mut W,
+) -> io::Result<()> {
+    let (lod, synthetic) = (options.lod, options.synthetic);
+    let roots: fixedbitset::FixedBitSet = dependencies.roots().map(|idx| idx.index()).collect();
+    let ids = crate::stableid::stable_ids(dependencies);
+    w.write_all(b"{\"nodes\":[")?;
+    let mut first = true;
+    for (idx, node) in dependencies.graph.node_references() {
+        if idx == dependencies.root {
+            continue;
+        }
+        if !first {
+            w.write_all(b",")?;
+        }
+        first = false;
+        write!(
+            w,
+            "\n{{\"id\":{},\"stable_id\":\"{}\",\"kind\":\"{:?}\",\"label\":",
+            idx.index(),
+            ids[idx.index()],
+            node.kind()
+        )?;
+        write_string(w, &dependencies.label(idx))?;
+        w.write_all(b",\"path\":")?;
+        match node.description.path() {
+            Some(path) => write_string(w, path)?,
+            None => w.write_all(b"null")?,
+        }
+        write!(
+            w,
+            ",\"size\":{},\"referrers\":{},\"references\":{},\"self_reference\":{},\"root\":{}",
+            node.size,
+            node.referrers,
+            node.references,
+            node.self_reference,
+            roots.contains(idx.index())
+        )?;
+        if let Some(system) = &node.system {
+            w.write_all(b",\"system\":")?;
+            write_string(w, system)?;
+        }
+        if let Some(Ok(age)) = node.age.map(|t| t.duration_since(std::time::UNIX_EPOCH)) {
+            write!(w, ",\"age\":{}", age.as_secs())?;
+        }
+        if lod >= 2 {
+            write_packages(node, lod, w)?;
+        }
+        w.write_all(b"}")?;
+    }
+    w.write_all(b"\n],\"edges\":[")?;
+    let mut first = true;
+    for edge in dependencies.graph.raw_edges() {
+        if edge.source() == dependencies.root || (edge.weight.is_synthetic() && !synthetic) {
+            continue;
+        }
+        if !first {
+            w.write_all(b",")?;
+        }
+        first = false;
+        write!(w, "\n[{},{}", edge.source().index(), edge.target().index())?;
+        if edge.weight.is_synthetic() {
+            write!(w, ",\"{}\"", edge.weight.name())?;
+        }
+        w.write_all(b"]")?;
+    }
+    w.write_all(b"\n]")?;
+    match options.members {
+        None => (),
+        Some(Members::Full) => write_members(dependencies, None, w)?,
+        Some(Members::Compact) => {
+            let dir = crate::archive::store_dir();
+            write_members(dependencies, Some(dir.as_os_str().as_bytes()), w)?
+        }
+    }
+    w.write_all(b"}\n")
+}
+
+/// Writes `value` as JSON
+pub fn write_value<W: Write>(w: &mut W, value: &Value) -> io::Result<()> {
+    match value {
+        Value::Null => w.write_all(b"null"),
+        Value::Bool(b) => write!(w, "{}", b),
+        Value::Number(n) => w.write_all(n.as_bytes()),
+        Value::String(s) => write_string(w, s.as_bytes()),
+        Value::Array(values) => {
+            w.write_all(b"[")?;
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    w.write_all(b",")?;
+                }
+                write_value(w, value)?;
+            }
+            w.write_all(b"]")
+        }
+        Value::Object(fields) => {
+            w.write_all(b"{")?;
+            for (i, (key, value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    w.write_all(b",")?;
+                }
+                write_string(w, key.as_bytes())?;
+                w.write_all(b":")?;
+                write_value(w, value)?;
+            }
+            w.write_all(b"}")
+        }
+    }
+}
+
+/// A parsed JSON value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    /// the number as written, to keep integers exact
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// returns the value of `key` if this is an object containing it
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid JSON at byte {}: {}", self.pos, msg),
+        )
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.data.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, token: &[u8]) -> io::Result<()> {
+        if self.data[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {}", String::from_utf8_lossy(token))))
+        }
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        self.skip_whitespace();
+        let res = match self.data.get(self.pos) {
+            None => return Err(self.error("unexpected end")),
+            Some(b'n') => self.expect(b"null").map(|_| Value::Null)?,
+            Some(b't') => self.expect(b"true").map(|_| Value::Bool(true))?,
+            Some(b'f') => self.expect(b"false").map(|_| Value::Bool(false))?,
+            Some(b'"') => Value::String(self.string()?),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.data.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        items.push(self.value()?);
+                        self.skip_whitespace();
+                        match self.data.get(self.pos) {
+                            Some(b',') => self.pos += 1,
+                            Some(b']') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            _ => return Err(self.error("expected , or ]")),
+                        }
+                    }
+                }
+                Value::Array(items)
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.data.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.skip_whitespace();
+                        self.expect(b":")?;
+                        fields.push((key, self.value()?));
+                        self.skip_whitespace();
+                        match self.data.get(self.pos) {
+                            Some(b',') => self.pos += 1,
+                            Some(b'}') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            _ => return Err(self.error("expected , or }")),
+                        }
+                    }
+                }
+                Value::Object(fields)
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') =
+                    self.data.get(self.pos)
+                {
+                    self.pos += 1;
+                }
+                let n = String::from_utf8_lossy(&self.data[start..self.pos]).into_owned();
+                if n.parse::<f64>().is_err() {
+                    return Err(self.error("invalid number"));
+                }
+                Value::Number(n)
+            }
+            Some(_) => return Err(self.error("unexpected character")),
+        };
+        Ok(res)
+    }
+
+    fn hex4(&mut self) -> io::Result<u32> {
+        let digits = self
+            .data
+            .get(self.pos..self.pos + 4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.expect(b"\"")?;
+        let mut res = Vec::new();
+        loop {
+            let c = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let c = *self
+                        .data
+                        .get(self.pos)
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    let unescaped = match c {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&code) && self.expect(b"\\u").is_ok() {
+                                let low = self.hex4()?;
+                                code =
+                                    0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00));
+                            }
+                            std::char::from_u32(code).unwrap_or('\u{fffd}')
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut buf = [0; 4];
+                    res.extend_from_slice(unescaped.encode_utf8(&mut buf).as_bytes());
+                }
+                c => res.push(c),
+            }
+        }
+        String::from_utf8(res).map_err(|_| self.error("invalid UTF-8"))
+    }
+}
+
+/// Parses a JSON document.
+pub fn parse(data: &[u8]) -> io::Result<Value> {
+    let mut parser = Parser { data, pos: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != data.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json::*;
+
+    #[test]
+    fn check_parse() {
+        let value = parse(
+            r#" {"a": [1, -2.5e3, "x\"é\ud83d\ude00"], "b": {}, "c": [true, null]} "#.as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            value.get("a").unwrap().as_array().unwrap()[0].as_u64(),
+            Some(1)
+        );
+        assert_eq!(
+            value.get("a").unwrap().as_array().unwrap()[2].as_str(),
+            Some("x\"é😀")
+        );
+        assert_eq!(value.get("b"), Some(&Value::Object(Vec::new())));
+        assert_eq!(
+            value.get("c").unwrap().as_array().unwrap()[0].as_bool(),
+            Some(true)
+        );
+        let mut written = Vec::new();
+        write_value(&mut written, &value).unwrap();
+        assert_eq!(parse(&written).unwrap(), value);
+        assert!(parse(b"[1,]").is_err());
+        assert!(parse(b"{\"a\" 1}").is_err());
+        assert!(parse(b"\"abc").is_err());
+    }
+
+    #[test]
+    fn check_render_lod() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r","root":true},
+            {"id":2,"path":"/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-firefox-120.0","size":10},
+            {"id":3,"path":"/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.38","size":15},
+            {"id":4,"path":"/nix/store/cccccccccccccccccccccccccccccccc-glibc-2.38-bin","size":5}
+        ],"edges":[[1,2],[2,3],[2,4]]}"#;
+        let di = crate::import::graph(text);
+        let di = crate::reduction::condense(di);
+        let mut out = Vec::new();
+        let options = Options {
+            lod: 3,
+            ..Options::default()
+        };
+        render_with(&di, &options, &mut out).unwrap();
+        let doc = parse(&out).unwrap();
+        let nodes = doc.get("nodes").unwrap().as_array().unwrap();
+        assert_eq!(nodes.len(), 1);
+        let packages = nodes[0].get("packages").unwrap().as_array().unwrap();
+        let summary: Vec<_> = packages
+            .iter()
+            .map(|p| {
+                (
+                    p.get("name").unwrap().as_str().unwrap(),
+                    p.get("size").unwrap().as_u64().unwrap(),
+                    p.get("paths").unwrap().as_array().unwrap().len(),
+                )
+            })
+            .collect();
+        assert_eq!(summary, vec![("glibc", 20, 2), ("firefox", 10, 1)]);
+
+        let mut out = Vec::new();
+        render(&di, &mut out).unwrap();
+        let doc = parse(&out).unwrap();
+        let nodes = doc.get("nodes").unwrap().as_array().unwrap();
+        assert_eq!(nodes[0].get("packages"), None);
+    }
+
+    #[test]
+    fn check_render_synthetic_edges() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Memory","path":"/proc/1","root":true},
+            {"id":2,"path":"/nix/store/a","size":1}
+        ],"edges":[[1,2]]}"#;
+        let di = crate::import::graph(text);
+        let di = crate::reduction::merge_transient_roots(di);
+        let edges = |synthetic| {
+            let mut out = Vec::new();
+            let options = Options {
+                synthetic,
+                ..Options::default()
+            };
+            render_with(&di, &options, &mut out).unwrap();
+            let doc = parse(&out).unwrap();
+            doc.get("edges").unwrap().as_array().unwrap().to_vec()
+        };
+        // the edge from the node merging transient roots to /proc/1
+        assert_eq!(edges(false).len(), 1);
+        let all = edges(true);
+        assert_eq!(all.len(), 2);
+        assert!(all
+            .iter()
+            .any(|e| e.as_array().unwrap().get(2).and_then(Value::as_str) == Some("synthetic")));
+        // it can be imported back
+        let mut out = Vec::new();
+        let options = Options {
+            synthetic: true,
+            ..Options::default()
+        };
+        render_with(&di, &options, &mut out).unwrap();
+        assert!(crate::import::from_json(&parse(&out).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn check_render_members() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r","root":true},
+            {"id":2,"path":"/nix/store/bbbb-b","size":10},
+            {"id":3,"path":"/nix/store/aaaa-a","size":15}
+        ],"edges":[[1,2],[1,3]]}"#;
+        let di = crate::import::graph(text);
+        let di = crate::reduction::condense(di);
+        let mut out = Vec::new();
+        render_with(
+            &di,
+            &Options {
+                members: Some(Members::Full),
+                ..Options::default()
+            },
+            &mut out,
+        )
+        .unwrap();
+        let doc = parse(&out).unwrap();
+        let members = doc.get("members").unwrap().as_array().unwrap();
+        let paths: Vec<_> = members
+            .iter()
+            .map(|m| m.get("path").unwrap().as_str().unwrap())
+            .collect();
+        assert_eq!(paths, vec!["/nix/store/aaaa-a", "/nix/store/bbbb-b"]);
+        let node = members[0].get("node").unwrap().as_u64().unwrap();
+        assert!(
+            doc.get("nodes").unwrap().as_array().unwrap()[0]
+                .get("id")
+                .unwrap()
+                .as_u64()
+                == Some(node)
+        );
+
+        let mut out = Vec::new();
+        write_members(&di, Some(b"/nix/store"), &mut out).unwrap();
+        let doc = parse(&[b"{\"a\":0".to_vec(), out, b"}".to_vec()].concat()).unwrap();
+        assert_eq!(doc.get("store_dir").unwrap().as_str(), Some("/nix/store"));
+        let members = doc.get("members").unwrap().as_array().unwrap();
+        let first = members[0].as_array().unwrap();
+        assert_eq!(first[1].as_str(), Some("aaaa-a"));
+        assert_eq!(first[2].as_u64(), Some(15));
+    }
+}
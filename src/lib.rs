@@ -0,0 +1,106 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! The library behind the `nix-du` binary, to analyse what takes space in a nix store from
+//! other programs without parsing the output of `nix-du`.
+//!
+//! The entry points are:
+//!  * [`DepInfos::read_from_store`], reading the graph of references of a store and its
+//!    gc-roots, or [`import::from_json`] and [`snapshot::load`] for saved graphs;
+//!  * the reductions of [`reduction`], like [`reduction::condense`], which merges the store
+//!    paths retained by the same gc-roots, and [`reduction::keep`], which hides nodes;
+//!  * the renderers implementing [`Renderer`], like [`dot::Style`], [`graphml::GraphMl`]
+//!    and [`gexf::Gexf`].
+//!
+//! ```no_run
+//! use nix_du::{reduction, DepInfos, Renderer};
+//!
+//! let di = DepInfos::read_from_store(Vec::new(), None, false, false, 1).unwrap();
+//! let di = reduction::condense(reduction::merge_transient_roots(di));
+//! let di = reduction::keep(di, |node| node.size > 100_000_000);
+//! nix_du::graphml::GraphMl::default()
+//!     .render(&di, &mut std::io::stdout())
+//!     .unwrap();
+//! ```
+//!
+//! The callbacks through which the C++ adapter around libnixstore fills the graph, and its
+//! bindings, are only public with the `ffi` feature.
+
+#[macro_use]
+pub mod msg;
+pub mod annotations;
+pub mod anonymize;
+pub mod archive;
+pub mod attrs;
+#[cfg(feature = "ffi")]
+pub mod bindings;
+#[cfg(not(feature = "ffi"))]
+mod bindings;
+pub mod bugreport;
+pub mod buildtime;
+pub mod cache;
+pub mod check;
+pub mod compare;
+pub mod csv;
+pub mod daemon;
+pub mod depgraph;
+pub mod diff;
+pub mod digest;
+pub mod dot;
+pub mod export;
+pub mod flamegraph;
+pub mod forecast;
+pub mod gclock;
+pub mod gexf;
+pub mod graphml;
+pub mod image;
+pub mod import;
+pub mod incremental;
+#[cfg(target_os = "linux")]
+pub mod inotify;
+pub mod inuse;
+pub mod json;
+pub mod locate;
+pub mod logs;
+pub mod manifest;
+pub mod memstats;
+pub mod metric;
+pub mod nixos;
+pub mod opt;
+pub mod origins;
+pub mod overhead;
+pub mod owners;
+pub mod plan;
+pub mod preview;
+pub mod procinfo;
+pub mod protect;
+pub mod reduction;
+pub mod render;
+pub mod repl;
+pub mod rewrite;
+pub mod sample;
+pub mod score;
+#[cfg(any(test, feature = "selftest"))]
+pub mod selftest;
+pub mod services;
+pub mod simulate;
+pub mod snapshot;
+pub mod sqlite;
+pub mod stableid;
+#[cfg(feature = "store-selftest")]
+pub mod storetest;
+pub mod text;
+pub mod timeline;
+pub mod toolchains;
+pub mod tree;
+pub mod tui;
+pub mod unlock;
+pub mod verify;
+pub mod watch;
+pub mod why;
+
+pub use crate::depgraph::{DepInfos, DepNode, NodeDescription};
+pub use crate::render::Renderer;
+
+/// The callbacks of the adapter around libnixstore, for other adapters
+#[cfg(feature = "ffi")]
+pub use crate::depgraph::adapter::{register_edge, register_node, register_root};
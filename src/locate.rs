@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Finding which node of a condensed graph stands for a store path given only the beginning
+//! of its hash, as copied from an error message.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::io::{self, Write};
+
+/// Returns whether the base name of `path` starts with `prefix`
+fn matches(path: &[u8], prefix: &[u8]) -> bool {
+    let base = match path.iter().rposition(|&c| c == b'/') {
+        Some(i) => &path[i + 1..],
+        None => path,
+    };
+    base.starts_with(prefix)
+}
+
+/// Returns the nodes of `di` standing for a store path whose hash starts with `prefix`,
+/// along with the matching paths. `prefix` may also be a full path.
+pub fn locate(di: &DepInfos, prefix: &str) -> Vec<(NodeIndex, Vec<Path>)> {
+    let prefix = prefix.trim_end_matches('/');
+    let prefix = prefix.rsplit('/').next().unwrap_or(prefix).as_bytes();
+    let mut res = Vec::new();
+    for idx in di.graph.node_indices() {
+        let node = &di.graph[idx];
+        let own = match &node.description {
+            NodeDescription::Path(path) => Some(path),
+            _ => None,
+        };
+        let found: Vec<Path> = own
+            .into_iter()
+            .chain(node.members.iter().map(|(path, _)| path))
+            .filter(|path| matches(path, prefix))
+            .cloned()
+            .collect();
+        if !found.is_empty() {
+            res.push((idx, found));
+        }
+    }
+    res
+}
+
+/// Prints the nodes standing for the store paths whose hash starts with `prefix`, and the
+/// gc-roots retaining them.
+pub fn render<W: Write>(di: &DepInfos, prefix: &str, w: &mut W) -> io::Result<()> {
+    let label = |idx: NodeIndex| String::from_utf8_lossy(&di.label(idx)).into_owned();
+    let found = locate(di, prefix);
+    if found.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no live store path has a hash starting with «{}»", prefix),
+        ));
+    }
+    for (idx, paths) in found {
+        for path in paths {
+            w.write_all(&path)?;
+            writeln!(w)?;
+        }
+        let roots = crate::unlock::roots_of(di, idx);
+        writeln!(
+            w,
+            "  is part of {} ({}), kept alive by {} gc-roots:",
+            label(idx),
+            ByteSize::b(di.graph[idx].size),
+            roots.len()
+        )?;
+        for root in roots {
+            writeln!(w, "    {}", label(root))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::locate::*;
+
+    #[test]
+    fn check_locate() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r","root":true},
+            {"id":2,"path":"/nix/store/0aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-top","size":1},
+            {"id":3,"path":"/nix/store/1bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-dep","size":1}
+        ],"edges":[[1,2],[2,3]]}"#;
+        let di = crate::import::graph(text);
+        let di = crate::reduction::condense(di);
+        let found = locate(&di, "1bbb");
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0].1,
+            vec![b"/nix/store/1bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-dep".to_vec()]
+        );
+        // everything is merged into the root
+        assert_eq!(di.graph[found[0].0].kind(), NodeKind::Link);
+        assert_eq!(locate(&di, "/nix/store/0aaa").len(), 1);
+        assert!(locate(&di, "2").is_empty());
+    }
+}
@@ -0,0 +1,333 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Finding the build logs and `.drv` files of derivations whose outputs are all garbage.
+//!
+//! Nix keeps the log of each build in `/nix/var/log/nix/drvs`, and never removes it. A log is
+//! only of use while an output of its derivation is alive. Likewise, a `.drv` file kept
+//! alive while none of its outputs is will only be used to build them again.
+//!
+//! The outputs of a derivation are read from its `.drv` file. When it was deleted, an output
+//! is assumed alive if a live store path has the name of the derivation, so that in doubt
+//! logs are kept.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+/// A build log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Log {
+    /// the file of the log
+    pub file: PathBuf,
+    /// the path of the derivation built
+    pub drv: Path,
+    /// the space used by the file on disk
+    pub size: u64,
+}
+
+/// Returns the build logs in `log_dir`, usually `/nix/var/log/nix`, of the derivations of
+/// the store `store`. They are stored as `drvs/XX/REST.drv`, possibly compressed, where
+/// `XXREST.drv` is the name of the derivation.
+pub fn find_logs(log_dir: &std::path::Path, store: &std::path::Path) -> Vec<Log> {
+    let mut res = Vec::new();
+    let walker = walkdir::WalkDir::new(log_dir.join("drvs"))
+        .min_depth(2)
+        .max_depth(2);
+    for entry in walker.into_iter().filter_map(Result::ok) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let prefix = match entry.path().parent().and_then(|dir| dir.file_name()) {
+            Some(prefix) => prefix.as_bytes(),
+            None => continue,
+        };
+        let rest = entry.file_name().as_bytes();
+        let rest = rest.strip_suffix(b".bz2").unwrap_or(rest);
+        let mut drv = store.as_os_str().as_bytes().to_vec();
+        drv.push(b'/');
+        drv.extend_from_slice(prefix);
+        drv.extend_from_slice(rest);
+        res.push(Log {
+            file: entry.path().to_path_buf(),
+            drv,
+            size: crate::overhead::disk_usage(entry.path()),
+        });
+    }
+    res
+}
+
+/// Returns the output paths listed in the contents of a `.drv` file, in the ATerm format
+/// `Derive([("out","/nix/store/...","",""),...],...)`. The path of an output is `None` if
+/// unknown, as for content addressed derivations, whose outputs are only known once built.
+pub fn parse_outputs(drv: &[u8]) -> Option<Vec<Option<Path>>> {
+    let mut rest = drv.strip_prefix(b"Derive([")?;
+    let mut outputs = Vec::new();
+    while let Some(tuple) = rest.strip_prefix(b"(\"") {
+        // the name of the output, then its path
+        let name_end = tuple.iter().position(|&c| c == b'"')?;
+        let path = tuple[name_end..].strip_prefix(b"\",\"")?;
+        let path_end = path.iter().position(|&c| c == b'"')?;
+        // empty for the outputs of content addressed derivations
+        let output = &path[..path_end];
+        outputs.push(Some(output.to_vec()).filter(|output| !output.is_empty()));
+        let tuple_end = path.iter().position(|&c| c == b')')?;
+        rest = &path[tuple_end + 1..];
+        rest = rest.strip_prefix(b",").unwrap_or(rest);
+    }
+    Some(outputs)
+}
+
+/// What `analyse` found
+#[derive(Debug, Default)]
+pub struct Report {
+    /// all the build logs
+    pub logs: Vec<Log>,
+    /// the logs of derivations whose outputs are all garbage, as indices into `logs`
+    pub orphaned_logs: Vec<usize>,
+    /// the `.drv` files whose outputs are all garbage, and whether they are alive
+    pub orphaned_drvs: Vec<(NodeIndex, bool)>,
+}
+
+/// Finds which of `logs` and of the `.drv` files of `di`, a graph of the whole store which
+/// is not condensed, belong to derivations whose outputs are all garbage. `read_drv` returns
+/// the contents of a `.drv` file, if it can be read.
+pub fn analyse<F: Fn(&[u8]) -> Option<Vec<u8>>>(
+    di: &DepInfos,
+    logs: Vec<Log>,
+    read_drv: F,
+) -> Report {
+    let mut live = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    let mut dfs = petgraph::visit::Dfs::new(&di.graph, di.root);
+    while let Some(idx) = dfs.next(&di.graph) {
+        live.insert(idx.index());
+    }
+    let by_path: HashMap<&[u8], NodeIndex> = di
+        .graph
+        .node_indices()
+        .filter_map(|idx| match &di.graph[idx].description {
+            NodeDescription::Path(path) | NodeDescription::Derivation(path) => {
+                Some((path.as_slice(), idx))
+            }
+            _ => None,
+        })
+        .collect();
+    let live_names: BTreeSet<std::borrow::Cow<[u8]>> = live
+        .ones()
+        .map(NodeIndex::new)
+        .filter(|&idx| di.graph[idx].kind() == NodeKind::Path)
+        .map(|idx| di.graph[idx].name())
+        .collect();
+    let is_live = |path: &[u8]| match by_path.get(path) {
+        Some(idx) => live.contains(idx.index()),
+        None => false,
+    };
+    // whether all the outputs of the derivation `drv` are garbage, if known
+    let orphaned = |drv: &[u8]| -> Option<bool> {
+        let outputs = parse_outputs(&read_drv(drv)?)?;
+        let outputs: Vec<Path> = outputs.into_iter().collect::<Option<_>>()?;
+        Some(!outputs.is_empty() && !outputs.iter().any(|p| is_live(p)))
+    };
+
+    let mut report = Report::default();
+    for idx in di.graph.node_indices() {
+        let node = &di.graph[idx];
+        if let NodeDescription::Path(path) | NodeDescription::Derivation(path) = &node.description {
+            if path.ends_with(b".drv") && orphaned(path) == Some(true) {
+                report.orphaned_drvs.push((idx, live.contains(idx.index())));
+            }
+        }
+    }
+    for (i, log) in logs.iter().enumerate() {
+        let is_orphaned = if by_path.contains_key(log.drv.as_slice()) {
+            orphaned(&log.drv) == Some(true)
+        } else {
+            // the outputs of hello-2.12.drv are named hello-2.12, hello-2.12-man...
+            let name = NodeDescription::Path(log.drv.clone()).name().into_owned();
+            let stem = name.strip_suffix(b".drv").unwrap_or(&name);
+            !live_names
+                .range::<[u8], _>((Bound::Included(stem), Bound::Unbounded))
+                .next()
+                .is_some_and(|live| live.starts_with(stem))
+        };
+        if is_orphaned {
+            report.orphaned_logs.push(i);
+        }
+    }
+    report.logs = logs;
+    report
+}
+
+/// Quotes `s` for a POSIX shell. Its bytes are kept as they are, even if not UTF-8.
+pub fn shell_quote(s: &[u8]) -> Vec<u8> {
+    let mut res = vec![b'\''];
+    for &c in s {
+        if c == b'\'' {
+            res.extend(br"'\''");
+        } else {
+            res.push(c);
+        }
+    }
+    res.push(b'\'');
+    res
+}
+
+/// Prints how much space the logs and `.drv` files of `report` use, or with `commands`, the
+/// shell commands removing them. `.drv` files kept alive by gc-roots cannot be removed.
+pub fn render<W: Write>(
+    di: &DepInfos,
+    report: &Report,
+    log_dir: &std::path::Path,
+    commands: bool,
+    w: &mut W,
+) -> io::Result<()> {
+    if commands {
+        for &i in &report.orphaned_logs {
+            let file = report.logs[i].file.as_os_str().as_bytes();
+            w.write_all(b"rm -f -- ")?;
+            w.write_all(&shell_quote(file))?;
+            writeln!(w)?;
+        }
+        for &(idx, alive) in &report.orphaned_drvs {
+            if let (false, Some(path)) = (alive, di.graph[idx].description.path()) {
+                w.write_all(b"nix-store --delete ")?;
+                w.write_all(&shell_quote(path))?;
+                writeln!(w)?;
+            }
+        }
+        return Ok(());
+    }
+    let total = |sizes: &mut dyn Iterator<Item = u64>| {
+        let (count, size) = sizes.fold((0, 0), |(count, size), s| (count + 1, size + s));
+        format!("{} in {} files", ByteSize::b(size), count)
+    };
+    writeln!(
+        w,
+        "Build logs in {}: {}",
+        log_dir.join("drvs").display(),
+        total(&mut report.logs.iter().map(|log| log.size))
+    )?;
+    writeln!(
+        w,
+        "\tof derivations whose outputs are all garbage: {}",
+        total(&mut report.orphaned_logs.iter().map(|&i| report.logs[i].size))
+    )?;
+    writeln!(
+        w,
+        "Derivations whose outputs are all garbage: {}",
+        total(
+            &mut report
+                .orphaned_drvs
+                .iter()
+                .map(|&(idx, _)| di.graph[idx].size)
+        )
+    )?;
+    writeln!(
+        w,
+        "\tkept alive by gc-roots: {}",
+        total(
+            &mut report
+                .orphaned_drvs
+                .iter()
+                .filter(|&&(_, alive)| alive)
+                .map(|&(idx, _)| di.graph[idx].size)
+        )
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logs::*;
+
+    #[test]
+    fn check_parse_outputs() {
+        let drv = br#"Derive([("man","/nix/store/aaaa-hello-2.12-man","",""),("out","/nix/store/bbbb-hello-2.12","","")],[],[],"x86_64-linux","/bin/sh",[],[])"#;
+        assert_eq!(
+            parse_outputs(drv),
+            Some(vec![
+                Some(b"/nix/store/aaaa-hello-2.12-man".to_vec()),
+                Some(b"/nix/store/bbbb-hello-2.12".to_vec())
+            ])
+        );
+        let ca = br#"Derive([("out","","r:sha256",""),("dev","","r:sha256","")],[],[],"x86_64-linux","/bin/sh",[],[])"#;
+        assert_eq!(parse_outputs(ca), Some(vec![None, None]));
+        assert_eq!(parse_outputs(b"garbage"), None);
+    }
+
+    /// With `build_deps`, the `.drv` files are `Derivation` nodes, as `--build-deps` reads them.
+    fn analyse_example(build_deps: bool) {
+        // hello is alive, its drv is dead; the drv of foo is alive but not foo
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r","root":true},
+            {"id":2,"path":"/nix/store/aaaa-hello-2.12","size":10},
+            {"id":3,"path":"/nix/store/bbbb-hello-2.12.drv","size":1},
+            {"id":4,"path":"/nix/store/cccc-foo-1.0","size":100},
+            {"id":5,"path":"/nix/store/dddd-foo-1.0.drv","size":2},
+            {"id":6,"kind":"Link","path":"/s","root":true},
+            {"id":7,"path":"/nix/store/gggg-baz-1.drv","size":3}
+        ],"edges":[[1,2],[6,5]]}"#;
+        let mut di = crate::import::graph(text);
+        if build_deps {
+            for node in di.graph.node_weights_mut() {
+                node.description = node.description.clone().with_derivation();
+            }
+            assert_eq!(
+                di.graph[crate::import::node(&di, "/nix/store/bbbb-hello-2.12.drv")].kind(),
+                NodeKind::Derivation
+            );
+        }
+        let read_drv = |path: &[u8]| -> Option<Vec<u8>> {
+            let output: &[u8] = match path {
+                b"/nix/store/bbbb-hello-2.12.drv" => b"/nix/store/aaaa-hello-2.12",
+                b"/nix/store/dddd-foo-1.0.drv" => b"/nix/store/cccc-foo-1.0",
+                // content addressed, so its outputs are unknown and it is kept
+                b"/nix/store/gggg-baz-1.drv" => b"",
+                _ => return None,
+            };
+            Some([&b"Derive([(\"out\",\""[..], output, b"\",\"\",\"\")],[])"].concat())
+        };
+        let log = |drv: &[u8]| Log {
+            file: PathBuf::from(String::from_utf8_lossy(drv).into_owned()),
+            drv: drv.to_vec(),
+            size: 1000,
+        };
+        let logs = vec![
+            log(b"/nix/store/bbbb-hello-2.12.drv"),
+            log(b"/nix/store/dddd-foo-1.0.drv"),
+            // deleted derivations of something alive, and of something dead
+            log(b"/nix/store/eeee-hello-2.12.drv"),
+            log(b"/nix/store/ffff-bar-3.drv"),
+            log(b"/nix/store/gggg-baz-1.drv"),
+        ];
+        let report = analyse(&di, logs, read_drv);
+        assert_eq!(report.orphaned_logs, vec![1, 3]);
+        assert_eq!(report.orphaned_drvs.len(), 1);
+        let (idx, alive) = report.orphaned_drvs[0];
+        assert!(alive);
+        assert_eq!(
+            di.graph[idx].description.path().unwrap().as_slice(),
+            b"/nix/store/dddd-foo-1.0.drv"
+        );
+        let mut out = Vec::new();
+        render(&di, &report, "/log".as_ref(), true, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "rm -f -- '/nix/store/dddd-foo-1.0.drv'\nrm -f -- '/nix/store/ffff-bar-3.drv'\n"
+        );
+    }
+
+    #[test]
+    fn check_analyse() {
+        analyse_example(false);
+    }
+
+    #[test]
+    fn check_analyse_build_deps() {
+        analyse_example(true);
+    }
+}
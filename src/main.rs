@@ -1,25 +1,17 @@
 // SPDX-License-Identifier: LGPL-3.0
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use enum_map::enum_map;
 
-#[macro_use]
-pub mod msg;
-pub mod bindings;
-pub mod depgraph;
-pub mod dot;
-pub mod opt;
-pub mod reduction;
-use crate::msg::*;
 use bytesize::ByteSize;
-use std::ffi::OsString;
+use nix_du::msg::*;
+use nix_du::*;
+use std::ffi::{OsStr, OsString};
 use std::io;
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 
-/* so that these functions are available in libnix_adepter.a */
-pub use crate::depgraph::{register_edge, register_node};
-
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 enum StatOpts {
     Full,
     Alive,
@@ -27,9 +19,61 @@ enum StatOpts {
 
 type OptLevel = Option<StatOpts>;
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+enum Format {
+    /// a graph in the dot format of graphviz
+    Dot,
+    /// the dominator tree as indented text, with the size freed by removing each node
+    Tree,
+    /// nodes and edges as a JSON object
+    Json,
+    /// one line per node, with its size and number of referrers and references
+    Csv,
+    /// GraphML, for yEd and Cytoscape
+    Graphml,
+    /// GEXF, for Gephi
+    Gexf,
+    /// a table of the N biggest nodes, -n or 20, with the gc-roots retaining them
+    Text,
+    /// the size freed by removing each gc-root over its creation date, as a Vega-Lite plot
+    Timeline,
+    /// the dominator tree as folded stacks, for inferno, flamegraph.pl and speedscope
+    Flamegraph,
+    /// the dominator tree as nested JSON objects, for treemaps
+    Treemap,
+    /// a JUnit XML report of the assertions passed with --check, for CI pipelines
+    Junit,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+enum ColorBy {
+    /// the size of the node, or its score with --score-expr
+    Size,
+    /// the user whose gc-roots retain the node, grey if several users do
+    User,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+enum Backend {
+    /// libnixstore, which opens any store and finds all gc-roots
+    Libnixstore,
+    /// the database of the local store, read directly, which only finds the gc-roots which
+    /// are links
+    Sqlite,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+enum GroupBy {
+    /// the platform the store paths were built for, read from their derivation
+    System,
+    /// the gc-root which is the only one to keep the store paths alive, or else a node for
+    /// the store paths shared by several gc-roots, so that sizes sum to the live size
+    MinimalRoot,
+}
+
 fn print_stats<W: io::Write>(w: &mut W, g: &depgraph::DepInfos) -> io::Result<()> {
-    use crate::depgraph::DedupAwareness::*;
-    use crate::depgraph::Reachability::*;
+    use nix_du::depgraph::DedupAwareness::*;
+    use nix_du::depgraph::Reachability::*;
     let size = &g.metadata.size;
     let best = enum_map! {
         what => size[Aware][what].as_ref().or_else(|| size[Unaware][what].as_ref())
@@ -94,6 +138,9 @@ or with a user wide profile:
 #[derive(Parser, Debug)]
 #[clap(version, about, long_about = LONG_ABOUT)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Hide nodes below this size (a unit should be specified: -s=50MB)
     #[clap(short = 's', long, value_name = "SIZE")]
     min_size: Option<ByteSize>,
@@ -102,65 +149,662 @@ struct Args {
     #[clap(short = 'n', long, value_name = "N", conflicts_with = "min_size")]
     nodes: Option<u32>,
 
+    /// Keep each in memory and temporary gc-root apart, instead of one node per process
+    /// holding them and one `{transient}` node for those of unknown processes
+    #[clap(long)]
+    expand_transient: bool,
+
+    /// Only keep the N biggest nodes under each gc-root, and merge the others into one node
+    #[clap(long, value_name = "N")]
+    top_per_root: Option<usize>,
+
+    /// Only keep the nodes which gc-roots older than AGE retain, and no younger one, like
+    /// forgotten `result` links. AGE is a number followed by h, d, w, m (30 days) or y, like
+    /// 30d. Running processes count as young.
+    #[clap(long, value_name = "AGE", value_parser = parse_age, conflicts_with = "root")]
+    older_than: Option<std::time::Duration>,
+
+    /// Instead of drawing the graph, print how much deleting the gc-roots ROOT would free,
+    /// and the biggest store paths freed (as many as --nodes, 20 by default)
+    #[clap(long, value_name = "ROOT", num_args = 1.., conflicts_with = "root")]
+    simulate_delete: Vec<std::path::PathBuf>,
+
+    /// Only keep the nodes whose name or store path matches REGEX, like `-source$`. Can be
+    /// repeated, to keep the nodes matching any of them.
+    #[clap(long, value_name = "REGEX")]
+    filter: Vec<String>,
+
+    /// Hide the nodes whose name or store path matches REGEX, like `-doc$`. Can be repeated.
+    #[clap(long, value_name = "REGEX")]
+    exclude: Vec<String>,
+
+    /// Warn when the output has more than N edges
+    #[clap(long, value_name = "N", default_value_t = 2000)]
+    max_edges: usize,
+
+    /// Hide more small nodes until the output has no more edges than --max-edges
+    #[clap(long)]
+    auto_simplify: bool,
+
     /// Consider the dependencies of PATH instead of all gc roots
     #[clap(short = 'r', long, value_name = "PATH")]
     root: Option<PathBuf>,
 
+    /// Consider the union of the closures of PATHS instead of all gc roots, like --root with
+    /// several paths
+    #[clap(value_name = "PATHS")]
+    paths: Vec<PathBuf>,
+
+    /// Merge all gc-roots whose path starts with PREFIX into one node. Can be repeated.
+    #[clap(long, value_name = "PREFIX")]
+    group_roots_by: Vec<PathBuf>,
+
+    /// Instead of drawing the graph, print the size freed by removing each gc-root alone,
+    /// biggest first
+    #[clap(long, conflicts_with_all = &["format", "output", "interactive"])]
+    root_sizes: bool,
+
+    /// Do not merge the generations of each profile, like the `system-N-link`, into one node
+    #[clap(long)]
+    no_group_generations: bool,
+
+    /// Instead of grouping store paths by the gc-roots depending on them, group them by
+    /// KEY. `system` needs reading all derivations from the store, which is slow.
+    #[clap(
+        long,
+        value_name = "KEY",
+        value_enum,
+        conflicts_with = "condense_relative_to"
+    )]
+    group_by: Option<GroupBy>,
+
+    /// Merge the nodes of the versions and outputs of a package, like all `ghc-9.*` or all
+    /// the outputs of glibc, into one node per package
+    #[clap(long)]
+    group_by_name: bool,
+
+    /// Only distinguish nodes by which gc-roots matching GLOB depend on them; other gc-roots
+    /// are treated as ordinary nodes. Can be repeated.
+    #[clap(long, value_name = "GLOB")]
+    condense_relative_to: Vec<String>,
+
+    /// Never merge nodes of different kinds, like a gc-root with the store paths only it
+    /// retains, so that each node is only a link or only store paths
+    #[clap(long, conflicts_with_all = &["group_by", "incremental"])]
+    kind_pure: bool,
+
+    /// Label gc-roots with the descriptions in FILE, made of lines `GLOB = DESCRIPTION`
+    #[clap(long, value_name = "FILE")]
+    annotations: Option<PathBuf>,
+
+    /// Label store paths with the attribute of NIXPKGS they are built from, like
+    /// haskellPackages.pandoc, found with nix eval. NIXPKGS is a Nix expression like a path.
+    #[clap(long, value_name = "NIXPKGS", num_args = 0..=1, default_missing_value = "<nixpkgs>")]
+    attr_names: Option<String>,
+
+    /// Mark store paths which took more than an hour to build as expensive to rebuild, and
+    /// tell in nix-du plan how long building again what each step frees takes. Build times
+    /// come from the build logs, and from FILE, builds as returned by the Hydra API in JSON
+    #[clap(long, value_name = "FILE", num_args = 0..=1)]
+    build_times: Option<Option<PathBuf>>,
+
+    /// Instead of writing the graph, browse it in the terminal like ncdu, and print the nodes
+    /// marked on exit
+    #[clap(short = 'i', long, conflicts_with_all = &["format", "output"])]
+    interactive: bool,
+
+    /// Output format
+    #[clap(long, value_name = "FORMAT", value_enum, default_value_t = Format::Dot)]
+    format: Format,
+
+    /// Write the graph in FORMAT to PATH, or to stdout if PATH is -, instead of --format to
+    /// stdout. Can be given several times to get several formats from one run.
+    #[clap(long, value_name = "FORMAT=PATH", value_parser = parse_output)]
+    output: Vec<(Format, PathBuf)>,
+
+    /// With --format junit, assert SUBJECT < LIMIT or SUBJECT <= LIMIT, where SUBJECT is
+    /// live-size, roots, root-closure or node-size, like `live-size < 50GB`. Can be repeated.
+    #[clap(long, value_name = "ASSERTION")]
+    check: Vec<String>,
+
+    /// With --format json, levels of detail: 2 lists the packages each node stands for, 3
+    /// also their store paths
+    #[clap(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=3))]
+    lod: u8,
+
+    /// With --format json, list every store path in a `members` table referring to the id of
+    /// its node, so that other tools can drill down without reading the store again
+    #[clap(long, value_name = "MODE", value_enum)]
+    members: Option<json::Members>,
+
+    /// Make the font size of nodes grow with their size
+    #[clap(long)]
+    scale_labels: bool,
+
+    /// Enable optional features of the dot output, separated by commas, like
+    /// `--dot-style scale,tooltips`
+    #[clap(long, value_name = "FEATURES", value_enum, value_delimiter = ',')]
+    dot_style: Vec<dot::Feature>,
+
+    /// Attribute gc-roots, and what only they retain, to users: the dot output is colored by
+    /// user with a cluster per user, and --format text prints the size each user retains
+    /// alone
+    #[clap(long)]
+    by_user: bool,
+
+    /// What the colors of nodes in the dot output stand for
+    #[clap(long, value_name = "WHAT", value_enum, default_value_t = ColorBy::Size)]
+    color_by: ColorBy,
+
+    /// List the names of up to N store paths merged into each node under its label
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    show_examples: usize,
+
+    /// Score nodes by an expression of `size`, `age`, `substitutable` and `roots`, to rank
+    /// them with `nix-du rank` and color them by score in the dot output
+    #[clap(long, value_name = "EXPR")]
+    score_expr: Option<String>,
+
+    /// How to measure the size of nodes, for -s, -n, --top-per-root, the dot output and
+    /// `nix-du plan`
+    #[clap(long, value_name = "METRIC", value_enum, default_value_t = metric::Metric::Own)]
+    metric: metric::Metric,
+
+    /// Binary cache checked for the `substitutable` variable of --score-expr, and for
+    /// --metric download
+    #[clap(long, value_name = "URL", default_value = "https://cache.nixos.org")]
+    score_substituter: String,
+
+    /// Number of queries sent at once to a remote store and to substituters, which speeds
+    /// up high latency ones. The local store is always read one query at a time
+    #[clap(long, value_name = "N", default_value_t = 4)]
+    backend_jobs: usize,
+
+    /// How to read the store
+    #[clap(long, value_name = "BACKEND", value_enum, default_value_t = Backend::Libnixstore, conflicts_with = "image")]
+    backend: Backend,
+
+    /// Number of threads measuring deduplication with -O and computing the quotient graph,
+    /// one per core by default
+    #[clap(short = 'j', long, value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Number of store paths each query to a substituter asks about
+    #[clap(long, value_name = "N", default_value_t = 500)]
+    backend_batch: usize,
+
+    /// Report on stderr the size retained by the store paths whose name matches REGEX, and
+    /// its growth since the last run. Can be repeated.
+    #[clap(long, value_name = "REGEX")]
+    watch_package: Vec<String>,
+
+    /// Exit with code 2 when a --watch-package grew by more than SIZE since the last run
+    #[clap(long, value_name = "SIZE", default_value = "10MB")]
+    watch_threshold: ByteSize,
+
     /// Dump the unaltered graph read from store to the file passed as argument. Intended for debugging.
     #[clap(long, value_name = "FILE")]
     dump: Option<PathBuf>,
 
+    /// Save the graph read from the store to FILE, to be reused with --load-graph
+    #[clap(long, value_name = "FILE")]
+    save_graph: Option<PathBuf>,
+
+    /// Read the graph from FILE, saved by --save-graph, instead of the store
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["root", "dump", "opt_level"])]
+    load_graph: Option<PathBuf>,
+
+    /// Read the graph from FILE, in the format of --format json, instead of the store.
+    /// Store paths and sizes are checked and inconsistencies reported.
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["root", "dump", "opt_level", "load_graph"])]
+    import_graph: Option<PathBuf>,
+
+    /// Read the store of an image instead of the running system: a directory containing a
+    /// /nix tree, like a mounted VM image, or a tarball
+    #[clap(long, value_name = "PATH", conflicts_with_all = &["load_graph", "import_graph", "opt_level"])]
+    image: Option<PathBuf>,
+
+    /// Read the store at URI instead of the one of the running system, like
+    /// `ssh-ng://builder` or `local?root=/mnt`, as `nix path-info --store` does. --root and
+    /// PATHS are then paths of this store.
+    #[clap(long, value_name = "URI", conflicts_with_all = &["image", "load_graph", "import_graph", "opt_level"])]
+    store: Option<String>,
+
+    /// Tell store derivations (`.drv` files) from other store paths, and with
+    /// `keep-outputs = true` account for all the outputs they retain, which are the
+    /// build-time dependencies of what is built from them
+    #[clap(long, conflicts_with_all = &["image", "load_graph", "import_graph"])]
+    build_deps: bool,
+
+    /// Save the graph read from the store to PATH, by default in `$XDG_CACHE_HOME/nix-du`,
+    /// and reuse it while the store is not garbage collected or added to, only reading the
+    /// gc-roots again
+    #[clap(long, value_name = "PATH", num_args = 0..=1, conflicts_with_all = &["root", "paths", "store", "image", "load_graph", "import_graph"])]
+    cache: Option<Option<PathBuf>>,
+
+    /// Rename the paths starting with FROM to start with TO instead, to analyse a store
+    /// mounted elsewhere, like /mnt/nix/store=/nix/store, under its own names. Paths which
+    /// end up the same are merged. Can be repeated
+    #[clap(long, value_name = "FROM=TO", value_parser = parse_rewrite)]
+    rewrite_prefix: Vec<(PathBuf, PathBuf)>,
+
+    /// Replace store hashes, user names and the paths of gc-roots by stable pseudonyms in all
+    /// outputs, including --dump and --save-graph, to share a graph publicly
+    #[clap(long, conflicts_with = "annotations")]
+    anonymize: bool,
+
+    /// Also export the edges which are not references, like those from the node merging
+    /// transient roots to each of them, and list such merged roots as one in nix-du unlock
+    #[clap(long)]
+    include_synthetic: bool,
+
     /// whether to take store optimisation into account: 0: no, 1: live paths, 2: all paths (default autodetect)
     #[clap(short='O', long, value_name="N", value_parser = ["0", "1", "2", "auto"])]
     opt_level: Option<String>,
 
+    /// What to do if a garbage collection is running, as sizes read meanwhile are misleading
+    #[clap(long, value_name = "ACTION", value_enum, default_value_t = gclock::OnGc::Warn)]
+    on_gc: gclock::OnGc,
+
+    /// What the size of a store path is: the size of its NAR registered in the store, the
+    /// apparent size of its files, or the space allocated to them on disk, which is the only
+    /// one to reflect compression and copy-on-write on filesystems like zfs and btrfs
+    #[clap(long, value_name = "MODE", value_enum, default_value_t = opt::SizeMode::Nar, conflicts_with_all = &["store", "image", "load_graph", "import_graph"])]
+    size_mode: opt::SizeMode,
+
+    /// Report on stderr the memory used after each phase: resident set size, and estimated
+    /// size of the graph, of the store paths and of the condensation
+    #[clap(long)]
+    memory_stats: bool,
+
+    /// Reuse the equivalence classes computed by the last run with --incremental for the
+    /// gc-roots whose closure did not change, to condense faster a mostly static store.
+    /// Only for the store of the running system.
+    #[clap(long, conflicts_with_all = &["store", "image", "load_graph", "import_graph"])]
+    incremental: bool,
+
+    /// Forget the flags of the last run. Without flags, nix-du reuses the flags of the last run.
+    #[clap(long)]
+    fresh: bool,
+
     /// Don't print informationnal messages on stderr
     #[clap(short = 'q', long)]
     quiet: bool,
+
+    /// Print how long each phase took, and the size of the graph after it, on stderr
+    #[clap(short = 'v', long, conflicts_with = "quiet")]
+    verbose: bool,
 }
 
-fn main() {
-    let args = Args::parse();
+impl Args {
+    /// Whether the store read is not the one of the running system, so that its gc-roots,
+    /// processes and logs are not on this machine
+    fn foreign_store(&self) -> bool {
+        self.image.is_some() || self.store.is_some()
+    }
+}
 
-    let optlevel: Option<OptLevel> = match args.opt_level.as_ref().map(String::as_str) {
-        Some("0") => Some(None),
-        Some("1") => Some(Some(StatOpts::Alive)),
-        Some("2") => Some(Some(StatOpts::Full)),
-        Some("auto") | None => None,
-        _ => unreachable!(),
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the invariants of graph reductions on random graphs instead of reading the store
+    #[cfg(feature = "selftest")]
+    Selftest {
+        /// Number of random graphs to check
+        #[clap(long, value_name = "N", default_value_t = 40)]
+        iterations: u32,
+
+        /// Number of nodes of each random graph
+        #[clap(long, value_name = "N", default_value_t = 250, value_parser = clap::value_parser!(u32).range(2..))]
+        size: u32,
+
+        /// Instead, fill a scratch store in DIR with fixtures, and check the graph read back
+        /// and the whole pipeline on it
+        #[cfg(feature = "store-selftest")]
+        #[clap(long, value_name = "DIR")]
+        with_store: Option<PathBuf>,
+    },
+    /// Print a short plain text summary of the store, suitable for cron mails
+    Digest {
+        /// File remembering the previous run, to report growth [default: ~/.cache/nix-du/digest]
+        #[clap(long, value_name = "FILE")]
+        state: Option<PathBuf>,
+
+        /// Age in days after which a gc-root is counted as stale
+        #[clap(long, value_name = "DAYS", default_value_t = 90)]
+        stale_days: u64,
+
+        /// Also forecast when the store fills its filesystem, from the sizes recorded by
+        /// previous digests
+        #[clap(long)]
+        forecast: bool,
+    },
+    /// Compare the live store paths with a graph saved with --save-graph: what was upgraded,
+    /// added and removed, and which gc-roots grew or shrank
+    Diff {
+        /// The graph saved with --save-graph to compare with
+        old: PathBuf,
+        /// A graph saved with --save-graph to compare instead of the store
+        new: Option<PathBuf>,
+    },
+    /// Show the live store paths of a graph saved with --save-graph which are not live in
+    /// another one, in the output format
+    Subtract {
+        /// The graph saved with --save-graph whose paths are shown
+        a: PathBuf,
+        /// The graph saved with --save-graph whose paths are hidden
+        b: PathBuf,
+    },
+    /// Compare the closures of the profiles of two users: what is shared and what is not
+    CompareProfiles {
+        /// Name of the first user, or path to a profile
+        user1: String,
+        /// Name of the second user, or path to a profile
+        user2: String,
+    },
+    /// Write a tar archive with the anonymized graph, statistics, timings and versions, to
+    /// attach to a bug report against nix-du
+    BugReport {
+        /// Where to write the archive
+        #[clap(long, value_name = "FILE", default_value = "nix-du-bug-report.tar")]
+        output: PathBuf,
+    },
+    /// Choose which gc-roots to delete: print how much deleting more and more roots frees
+    Plan {
+        /// Tell how many roots must be deleted to free at least SIZE
+        #[clap(long, value_name = "SIZE")]
+        free: Option<ByteSize>,
+
+        /// Delete at most N roots
+        #[clap(long, value_name = "N", default_value_t = 10)]
+        max_roots: usize,
+
+        /// Also consider deleting gc-roots in use by running processes or services
+        #[clap(long)]
+        allow_in_use: bool,
+
+        /// Also consider deleting the gc-roots of the current and booted systems, the newest
+        /// generation of each profile, and gc-roots younger than a day
+        #[clap(long)]
+        no_default_protections: bool,
+
+        /// Print the plan as a shell script deleting its roots, up to freeing --free if given
+        #[clap(long)]
+        emit_commands: bool,
+    },
+    /// Tell which gc-roots keep a store path alive, and through which nodes
+    Unlock {
+        /// The store path, a link to it, or its name without hash
+        path: String,
+    },
+    /// Print every gc-root keeping a store path alive, with a chain of references from each
+    Why {
+        /// The store path, a link to it, or its name without hash
+        path: String,
+    },
+    /// Tell how much installing something would add to the store, before building it
+    Preview {
+        /// A store path, a derivation, or an installable like nixpkgs#hello
+        installable: String,
+
+        /// Where to look up paths which are not in the store
+        #[clap(long, value_name = "URL", default_value = "https://cache.nixos.org")]
+        substituter: String,
+    },
+    /// Estimate the compressed size of the closure of each gc-root, to plan copying them to
+    /// an archive with `nix copy` before deleting them
+    ExportCost {
+        /// Binary cache whose narinfo files give the compressed size of the paths it has
+        #[clap(long, value_name = "URL", default_value = "https://cache.nixos.org")]
+        substituter: String,
+    },
+    /// Find which node and gc-roots retain the store paths whose hash starts with a prefix
+    Locate {
+        /// The beginning of the hash, or of the store path
+        prefix: String,
+    },
+    /// Keep the graph of the store up to date in memory and answer queries about it on a
+    /// unix socket
+    Daemon {
+        /// Path of the socket [default: $XDG_RUNTIME_DIR/nix-du.sock]
+        #[clap(long, value_name = "FILE")]
+        socket: Option<PathBuf>,
+
+        /// Read the store again after this many seconds even if no change was noticed
+        #[clap(long, value_name = "SECONDS", default_value_t = 3600)]
+        refresh: u64,
+
+        /// Run this shell command when the live size changes, with the sizes in bytes before
+        /// and after in $NIX_DU_PREVIOUS_SIZE and $NIX_DU_LIVE_SIZE
+        #[clap(long, value_name = "COMMAND")]
+        on_change: Option<String>,
+    },
+    /// Ask a running nix-du daemon: size, roots [N], growers [N], locate HASHPREFIX, unlock
+    /// PATH, updated
+    Query {
+        /// Path of the socket [default: $XDG_RUNTIME_DIR/nix-du.sock]
+        #[clap(long, value_name = "FILE")]
+        socket: Option<PathBuf>,
+
+        /// The request
+        #[clap(required = true)]
+        request: Vec<String>,
+    },
+    /// Copy the closure of a gc-root to another store, like `file:///backup`, with `nix copy`,
+    /// and then optionally remove the gc-root
+    Archive {
+        /// The gc-root, a symlink to the store
+        root: PathBuf,
+
+        /// The store to copy to
+        #[clap(long, value_name = "URL")]
+        to: String,
+
+        /// Remove the gc-root once copied
+        #[clap(long)]
+        remove: bool,
+    },
+    /// Write the store paths of the closure of a gc-root, with their NAR size and hash, as
+    /// JSON
+    Manifest {
+        /// The gc-root, or any path leading to the store
+        root: PathBuf,
+
+        /// Where to write the manifest [default: stdout]
+        #[clap(short = 'o', long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Check the references, sizes and closures of N random store paths against
+    /// `nix-store --query`, and report discrepancies
+    VerifyAgainstNix {
+        /// Number of store paths to check
+        #[clap(default_value_t = 50)]
+        n: usize,
+    },
+    /// List the N nodes with the highest --score-expr, the best things to get rid of.
+    /// Variables are `size` in bytes, `age` in days of the youngest gc-root retaining the
+    /// node, `substitutable` the fraction of the node a substituter has, and `roots` the
+    /// number of gc-roots retaining it. Operators are + - * / ^, and functions ln, log2,
+    /// sqrt, min and max. [default score: size * (1 + age / 30) * (1 + substitutable)]
+    Rank {
+        /// Number of nodes to print
+        #[clap(default_value_t = 20)]
+        n: usize,
+    },
+    /// Print N live store paths sampled with a probability proportional to their size
+    SamplePaths {
+        /// Number of store paths to print
+        #[clap(default_value_t = 20)]
+        n: usize,
+    },
+    /// Read the graph once and explore it interactively: filter size>SIZE, top [N],
+    /// expand NAME, why NAME, export FILE...
+    Repl,
+    /// Report the size of build logs and .drv files of derivations whose outputs are all
+    /// garbage
+    Logs {
+        /// Print the shell commands removing them instead
+        #[clap(long)]
+        emit_commands: bool,
+    },
+    /// List toolchains (gcc, clang, rustc...) of which several versions are kept alive, and
+    /// the gc-roots responsible
+    Toolchains {
+        /// Ignore toolchains whose closure is smaller than SIZE
+        #[clap(long, value_name = "SIZE", default_value = "100MB")]
+        min_closure: ByteSize,
+    },
+    /// Tell which systemd services keep store paths alive through their running processes
+    Services,
+    /// Suggest changes to the NixOS configuration which would save space, like removing a
+    /// package from environment.systemPackages
+    Suggest {
+        /// The NixOS system
+        #[clap(long, value_name = "PATH", default_value = "/run/current-system")]
+        system: PathBuf,
+        /// Number of suggestions to print
+        #[clap(short = 'n', value_name = "N", default_value_t = 10)]
+        count: usize,
+    },
+    /// Tell which binary cache can provide each store path again, and what exists nowhere
+    /// else and deserves archiving before deleting it
+    Origins {
+        /// Binary cache to check, in order; can be repeated [default: the substituters nix
+        /// is configured with]
+        #[clap(long = "substituter", value_name = "URL")]
+        substituters: Vec<String>,
+        /// Number of nodes to list
+        #[clap(short = 'n', value_name = "N", default_value_t = 20)]
+        count: usize,
+    },
+}
+
+/// Parses the argument of `--output`, `FORMAT=PATH`
+fn parse_output(s: &str) -> Result<(Format, PathBuf), String> {
+    use clap::ValueEnum;
+    let (format, path) = s
+        .split_once('=')
+        .ok_or_else(|| "expected FORMAT=PATH".to_owned())?;
+    let format = Format::from_str(format, true)?;
+    if format == Format::Junit {
+        return Err("junit is only available with --format".to_owned());
+    }
+    Ok((format, PathBuf::from(path)))
+}
+
+/// Parses the argument of --rewrite-prefix
+fn parse_rewrite(s: &str) -> Result<(PathBuf, PathBuf), String> {
+    let (from, to) = s
+        .split_once('=')
+        .ok_or_else(|| "expected FROM=TO".to_owned())?;
+    if !from.starts_with('/') || !to.starts_with('/') {
+        return Err("prefixes must be absolute paths".to_owned());
+    }
+    Ok((PathBuf::from(from), PathBuf::from(to)))
+}
+
+/// Parses the argument of --older-than, like `30d`
+fn parse_age(s: &str) -> Result<std::time::Duration, String> {
+    let (number, unit) = s.split_at(s.len() - s.chars().last().map_or(0, char::len_utf8));
+    let hours = match unit {
+        "h" => 1,
+        "d" => 24,
+        "w" => 24 * 7,
+        "m" => 24 * 30,
+        "y" => 24 * 365,
+        _ => return Err("expected a number followed by h, d, w, m or y".to_owned()),
     };
-    let root: Option<OsString> = args.root.as_ref().map(|path| {
-        let path_buf = PathBuf::from(path).canonicalize().unwrap_or_else(|err| {
-            die!(
-                1,
-                "Could not canonicalize path «{}»: {}",
-                path.display(),
-                err
-            )
-        });
-        OsString::from(path_buf)
-    });
-    let dumpfile: Option<(std::fs::File, &PathBuf)> = args.dump.as_ref().map(|path| {
-        let f = std::fs::File::create(path).unwrap_or_else(|err| {
-            die!(1, "Could not open dump file «{}»: {}", path.display(), err)
-        });
-        (f, path)
-    });
+    let number: u64 = number
+        .parse()
+        .map_err(|e| format!("invalid number {:?}: {}", number, e))?;
+    Ok(std::time::Duration::from_secs(number * hours * 3600))
+}
 
-    set_quiet(args.quiet);
+/// Returns a size such that approximately `n` of the nodes measuring `sizes` are at least
+/// this big
+fn size_threshold(sizes: &[u64], n: usize) -> u64 {
+    if n >= sizes.len() {
+        return 0;
+    }
+    let mut sizes = sizes.to_vec();
+    sizes.sort_unstable();
+    sizes[sizes.len().saturating_sub(n)]
+}
 
-    /**************************************
-     * end argument parsing               *
-     **************************************/
+/// Reads the dependency graph from the store and takes store optimisation into account,
+/// as requested by `args`.
+fn read_store(
+    args: &Args,
+    root: Vec<OsString>,
+    dumpfile: Option<(std::fs::File, &PathBuf)>,
+    optlevel: Option<OptLevel>,
+) -> depgraph::DepInfos {
+    match gclock::gc_in_progress() {
+        // a garbage collection of the running system does not change the image
+        _ if args.foreign_store() => (),
+        Ok(false) => (),
+        Ok(true) => match args.on_gc {
+            gclock::OnGc::Abort => die!(
+                1,
+                "A garbage collection is running, aborting. Pass --on-gc=warn to proceed anyway."
+            ),
+            gclock::OnGc::Warn => {
+                eprintln!("Warning: a garbage collection is running, sizes will be misleading.")
+            }
+            gclock::OnGc::Wait => {
+                msg!("Waiting for the running garbage collection to finish... ");
+                gclock::wait_for_gc()
+                    .unwrap_or_else(|e| die!(1, "Could not wait for garbage collection: {}", e));
+                msg!("done\n");
+            }
+        },
+        Err(e) => msg!(
+            "Could not determine whether a garbage collection is running: {}\n",
+            e
+        ),
+    }
 
-    msg!("Reading dependency graph from store... ");
-    let mut g = depgraph::DepInfos::read_from_store(root)
-        .unwrap_or_else(|res| die!(res, "Could not read from store"));
+    let mut g = match &args.cache {
+        Some(file) => read_cached(args, file.as_ref()),
+        None => read_raw(args, root),
+    };
     msg!(
-        "{} nodes, {} edges read.\n",
+        "{} nodes, {} edges read, {} self-references ignored.\n",
         g.graph.node_count(),
-        g.graph.edge_count()
+        g.graph.edge_count(),
+        g.self_references()
     );
+    let read_errors = g.read_errors();
+    if !read_errors.is_empty() {
+        eprintln!(
+            "Warning: {} store paths could not be read, their references are missing:",
+            read_errors.len()
+        );
+        const SHOWN: usize = 5;
+        for message in read_errors.iter().take(SHOWN) {
+            eprintln!("\t{}", String::from_utf8_lossy(message));
+        }
+        if read_errors.len() > SHOWN {
+            eprintln!("\tand {} more", read_errors.len() - SHOWN);
+        }
+    }
+    if !args.foreign_store() {
+        procinfo::record_processes(&mut g);
+    }
+    for (process, size) in procinfo::pinned_by_processes(&g) {
+        if !args.foreign_store()
+            && process.running >= procinfo::LONG_RUNNING
+            && size >= procinfo::LARGE_CLOSURE
+        {
+            eprintln!(
+                "Warning: process {} ({}) has been running for {} and keeps {} alive \
+                 through temporary roots. If it is stuck, killing it frees them.",
+                process.pid,
+                process.command,
+                procinfo::human_duration(process.running),
+                ByteSize::b(size)
+            );
+        }
+    }
 
     /*************************************
      * handling of --dump
@@ -168,12 +812,25 @@ fn main() {
 
     if let Some((mut f, path)) = dumpfile {
         msg!("Dumping dependency graph to {}...", path.display());
-        dot::render(&g, &mut f)
+        let anonymized;
+        let dumped = if args.anonymize {
+            anonymized = {
+                let mut g = g.clone();
+                anonymize::anonymize(&mut g);
+                g
+            };
+            &anonymized
+        } else {
+            &g
+        };
+        dot::render(dumped, &dot::Style::default(), &mut f)
             .unwrap_or_else(|err| die!(1, "Could not dump dependency graph: {}", err));
         drop(f);
         msg!(" done\n");
     }
 
+    g = collapse_cycles(g);
+
     /******************
      * handling or -O *
      ******************/
@@ -189,40 +846,1021 @@ fn main() {
         Ok(Some(false)) => None,
     });
 
-    if let Some(statopts) = optlevel {
-        if statopts == StatOpts::Alive {
-            // drop dead paths
-            g = reduction::keep_reachable(g);
-        }
+    if optlevel == Some(StatOpts::Alive) {
+        // drop dead paths
+        g = reduction::keep_reachable(g);
+    }
 
+    if args.size_mode != opt::SizeMode::Nar {
+        msg!("Measuring the files of store paths...\n");
+        opt::measure_sizes(&mut g, args.size_mode).unwrap_or_else(|e| {
+            eprintln!(
+                "Warning: could not measure store paths, sizes are NAR sizes: {}",
+                e
+            )
+        });
+    }
+
+    if optlevel.is_some() {
         msg!(
             "Looking for optimized paths... (this could take a long time, pass option -O0 to skip)\n"
         );
-        opt::refine_optimized_store(&mut g)
+        opt::refine_optimized_store(&mut g, args.size_mode)
             .unwrap_or_else(|e| eprintln!("Could not unoptimize {:?}", e));
     }
+    g
+}
+
+/// Reads the unaltered dependency graph from the store, with the backend requested by `args`.
+fn read_raw(args: &Args, root: Vec<OsString>) -> depgraph::DepInfos {
+    msg!("Reading dependency graph from store...\n");
+    let with_systems = args.group_by == Some(GroupBy::System);
+    match args.backend {
+        Backend::Libnixstore => {
+            let store = args.store.as_ref().map(OsStr::new);
+            depgraph::DepInfos::read_from_store(
+                root,
+                store,
+                with_systems,
+                args.build_deps,
+                args.backend_jobs,
+            )
+            .unwrap_or_else(|e| die!(e.code, "Could not read from store: {}", e))
+        }
+        Backend::Sqlite => {
+            if with_systems || args.store.is_some() || args.build_deps {
+                die!(
+                    1,
+                    "--group-by system, --store and --build-deps need --backend libnixstore"
+                );
+            }
+            depgraph::DepInfos::read_from_db(root)
+                .unwrap_or_else(|e| die!(1, "Could not read the database of the store: {}", e))
+        }
+    }
+}
+
+/// Handling of `--cache`: reuses the graph saved in `file`, or the default file, if the
+/// store did not change since, reading only its gc-roots again, and otherwise reads the
+/// store and saves its graph.
+fn read_cached(args: &Args, file: Option<&PathBuf>) -> depgraph::DepInfos {
+    let file = match file {
+        Some(file) => file.clone(),
+        None => match cache::default_graph_file() {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Warning: could not locate the cache of the graph: {}", e);
+                return read_raw(args, vec![]);
+            }
+        },
+    };
+    let options = format!(
+        "backend {:?} build-deps {} systems {}",
+        args.backend,
+        args.build_deps,
+        args.group_by == Some(GroupBy::System)
+    );
+    let key = match cache::graph_key(&options) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("Warning: could not tell whether the store changed: {}", e);
+            return read_raw(args, vec![]);
+        }
+    };
+    let cached = cache::load_graph(&file, &key).unwrap_or_else(|e| {
+        eprintln!(
+            "Warning: could not load the graph cached in {}: {}",
+            file.display(),
+            e
+        );
+        None
+    });
+    if let Some(g) = cached {
+        msg!("Reusing the graph cached in {}...\n", file.display());
+        let state_dir = PathBuf::from(
+            std::env::var_os("NIX_STATE_DIR").unwrap_or_else(|| "/nix/var/nix".into()),
+        );
+        let links = depgraph::find_link_roots(
+            &[state_dir.join("gcroots"), state_dir.join("profiles")],
+            &archive::store_dir(),
+        );
+        match cache::restate_roots(g, &links, |pid| procinfo::process(pid).is_some()) {
+            Some(g) => return g,
+            None => msg!("The cache is stale, some gc-roots are not in it.\n"),
+        }
+    }
+    let g = read_raw(args, vec![]);
+    if let Err(e) = cache::save_graph(&g, &file, &key) {
+        eprintln!(
+            "Warning: could not cache the graph in {}: {}",
+            file.display(),
+            e
+        );
+    }
+    g
+}
+
+/// Merges the cycles of `g`, warning about them
+fn collapse_cycles(g: depgraph::DepInfos) -> depgraph::DepInfos {
+    let (g, cycles) = reduction::collapse_cycles(g);
+    if cycles > 0 {
+        eprintln!(
+            "Warning: the dependency graph has {} cycles, each was merged into one node.",
+            cycles
+        );
+    }
+    g
+}
+
+/// Reads the dependency graph from the store or from the file given by `--load-graph`
+/// or `--import-graph`, and saves it if requested by `args`.
+fn read_graph(
+    args: &Args,
+    root: Vec<OsString>,
+    dumpfile: Option<(std::fs::File, &PathBuf)>,
+    optlevel: Option<OptLevel>,
+) -> depgraph::DepInfos {
+    let mut g = match (&args.load_graph, &args.import_graph) {
+        (None, None) => read_store(args, root, dumpfile, optlevel),
+        (None, Some(path)) => {
+            msg!("Importing dependency graph from {}... ", path.display());
+            let (g, report) = import::load(path).unwrap_or_else(|e| {
+                die!(1, "Could not import graph from «{}»: {}", path.display(), e)
+            });
+            msg!(
+                "{} nodes, {} edges read.\n",
+                g.graph.node_count(),
+                g.graph.edge_count()
+            );
+            if !report.is_empty() {
+                eprintln!("Warning: {}", report);
+            }
+            collapse_cycles(g)
+        }
+        (Some(path), _) => {
+            msg!("Loading dependency graph from {}... ", path.display());
+            let g = snapshot::load(path).unwrap_or_else(|e| {
+                die!(1, "Could not load graph from «{}»: {}", path.display(), e)
+            });
+            msg!(
+                "{} nodes, {} edges read.\n",
+                g.graph.node_count(),
+                g.graph.edge_count()
+            );
+            g
+        }
+    };
+
+    if !args.rewrite_prefix.is_empty() {
+        let rules: Vec<_> = args
+            .rewrite_prefix
+            .iter()
+            .map(|(from, to)| {
+                (
+                    from.as_os_str().as_bytes().to_vec(),
+                    to.as_os_str().as_bytes().to_vec(),
+                )
+            })
+            .collect();
+        g = rewrite::rewrite_prefixes(g, &rules);
+    }
+
+    if args.anonymize {
+        anonymize::anonymize(&mut g);
+    }
+
+    if let Some(path) = &args.save_graph {
+        msg!("Saving dependency graph to {}...", path.display());
+        snapshot::save(&g, path)
+            .unwrap_or_else(|e| die!(1, "Could not save graph to «{}»: {}", path.display(), e));
+        msg!(" done\n");
+    }
 
     noisy!({
         let stderr = io::stderr();
         let mut handle = stderr.lock();
         print_stats(&mut handle, &g).expect("could not write to stderr");
     });
+    g
+}
+
+fn main() {
+    let mut argv: Vec<OsString> = std::env::args_os().collect();
+    let mut reused = None;
+    if argv.len() == 1 {
+        match cache::load_flags() {
+            Ok(Some(flags)) if !flags.is_empty() => reused = Some(flags),
+            Ok(_) => (),
+            Err(e) => eprintln!("Could not read the flags of the last run: {}", e),
+        }
+    }
+    let args = match &reused {
+        None => Args::parse_from(&argv),
+        Some(flags) => {
+            argv.extend(flags.iter().cloned());
+            Args::try_parse_from(&argv).unwrap_or_else(|e| {
+                eprintln!("Ignoring the invalid flags of the last run: {}", e);
+                reused = None;
+                Args::parse_from(&argv[..1])
+            })
+        }
+    };
+
+    let optlevel: Option<OptLevel> = match args.opt_level.as_ref().map(String::as_str) {
+        Some("0") => Some(None),
+        Some("1") => Some(Some(StatOpts::Alive)),
+        Some("2") => Some(Some(StatOpts::Full)),
+        Some("auto") | None => None,
+        _ => unreachable!(),
+    };
+    // nix knows nothing of store optimisation, and the files of a tarball are not extracted
+    let optlevel = match args.command {
+        Some(Command::VerifyAgainstNix { .. })
+        | Some(Command::ExportCost { .. })
+        | Some(Command::Manifest { .. }) => Some(None),
+        _ if args.foreign_store() => Some(None),
+        _ => optlevel,
+    };
+    let _image = args.image.as_ref().map(|path| {
+        let image = image::open(path)
+            .unwrap_or_else(|e| die!(1, "Could not open image {}: {}", path.display(), e));
+        std::env::set_var("NIX_REMOTE", image.store_uri());
+        image
+    });
+    depgraph::set_local_roots(!args.foreign_store());
+    let roots: Vec<&PathBuf> = match &args.command {
+        Some(Command::Manifest { root, .. }) => {
+            if args.root.is_some() {
+                die!(1, "nix-du manifest does not support --root");
+            }
+            vec![root]
+        }
+        _ => args.root.iter().chain(&args.paths).collect(),
+    };
+    let root: Vec<OsString> = roots
+        .into_iter()
+        .map(|path| {
+            // the paths of another store need not exist here
+            if args.store.is_some() {
+                return OsString::from(path);
+            }
+            let path_buf = PathBuf::from(path).canonicalize().unwrap_or_else(|err| {
+                die!(
+                    1,
+                    "Could not canonicalize path «{}»: {}",
+                    path.display(),
+                    err
+                )
+            });
+            OsString::from(path_buf)
+        })
+        .collect();
+    let dumpfile: Option<(std::fs::File, &PathBuf)> = args.dump.as_ref().map(|path| {
+        let f = std::fs::File::create(path).unwrap_or_else(|err| {
+            die!(1, "Could not open dump file «{}»: {}", path.display(), err)
+        });
+        (f, path)
+    });
+
+    let annotations = match &args.annotations {
+        None => annotations::Annotations::default(),
+        Some(path) => annotations::Annotations::load(path).unwrap_or_else(|err| {
+            die!(
+                1,
+                "Could not read annotations «{}»: {}",
+                path.display(),
+                err
+            )
+        }),
+    };
+
+    let score_expr = args.score_expr.as_ref().map(|text| {
+        score::Expr::parse(text)
+            .unwrap_or_else(|e| die!(1, "Invalid --score-expr «{}»: {}", text, e))
+    });
+
+    let reference_roots: Vec<regex::bytes::Regex> = args
+        .condense_relative_to
+        .iter()
+        .map(|glob| {
+            annotations::compile_glob(glob)
+                .unwrap_or_else(|err| die!(1, "Invalid glob «{}»: {}", glob, err))
+        })
+        .collect();
+
+    let assertions: Vec<check::Assertion> = args
+        .check
+        .iter()
+        .map(|text| {
+            check::Assertion::parse(text)
+                .unwrap_or_else(|e| die!(1, "Invalid assertion «{}»: {}", text, e))
+        })
+        .collect();
+    if (args.format == Format::Junit) == assertions.is_empty() {
+        die!(1, "--check and --format junit must be used together");
+    }
+    if (args.format == Format::Junit) && !args.output.is_empty() {
+        die!(1, "--format junit cannot be used with --output");
+    }
+    // the formats to write, and where, stdout if None
+    let outputs: Vec<(Format, Option<&PathBuf>)> = if args.output.is_empty() {
+        vec![(args.format, None)]
+    } else {
+        args.output
+            .iter()
+            .map(|(format, path)| (*format, Some(path).filter(|p| p.as_os_str() != "-")))
+            .collect()
+    };
+    if args.members.is_some() && !outputs.iter().any(|&(f, _)| f == Format::Json) {
+        die!(1, "--members needs --format json");
+    }
+
+    let regexes = |patterns: &[String]| -> Vec<regex::bytes::Regex> {
+        patterns
+            .iter()
+            .map(|pattern| {
+                regex::bytes::Regex::new(pattern)
+                    .unwrap_or_else(|err| die!(1, "Invalid regex «{}»: {}", pattern, err))
+            })
+            .collect()
+    };
+    let watched = regexes(&args.watch_package);
+    let included = regexes(&args.filter);
+    let excluded = regexes(&args.exclude);
+    if !watched.is_empty() && args.command.is_some() {
+        die!(1, "--watch-package cannot be used with a command");
+    }
+    if !args.paths.is_empty() && args.command.is_some() {
+        die!(
+            1,
+            "PATHS cannot be used with a command, pass --root instead"
+        );
+    }
+    if args.interactive && args.command.is_some() {
+        die!(1, "--interactive cannot be used with a command");
+    }
+    if args.root_sizes && args.command.is_some() {
+        die!(1, "--root-sizes cannot be used with a command");
+    }
+
+    set_quiet(args.quiet);
+    set_verbose(args.verbose);
+    let mut phase_start = std::time::Instant::now();
+    export::set_concurrency(args.backend_jobs, args.backend_batch);
+    if let Some(jobs) = args.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs.max(1))
+            .build_global()
+            .unwrap_or_else(|e| die!(1, "Could not start {} threads: {}", jobs, e));
+    }
+
+    if let Some(flags) = &reused {
+        let flags: Vec<_> = flags.iter().map(|f| f.to_string_lossy()).collect();
+        eprintln!(
+            "Note: reusing the flags of the last run: {}. Pass --fresh to forget them.",
+            flags.join(" ")
+        );
+    } else if args.fresh {
+        cache::forget_flags()
+            .unwrap_or_else(|e| eprintln!("Could not forget the flags of the last run: {}", e));
+    } else if args.command.is_none()
+        && argv.len() > 1
+        && args.store.is_none()
+        && args.image.is_none()
+    {
+        // the flags are remembered per store, and runs on another store would clobber them
+        cache::save_flags(&argv[1..])
+            .unwrap_or_else(|e| msg!("Could not remember the flags of this run: {}\n", e));
+    }
+
+    #[cfg(feature = "selftest")]
+    if let Some(Command::Selftest {
+        iterations, size, ..
+    }) = &args.command
+    {
+        #[cfg(feature = "store-selftest")]
+        if let Some(Command::Selftest {
+            with_store: Some(dir),
+            ..
+        }) = &args.command
+        {
+            storetest::run(dir).unwrap_or_else(|e| die!(1, "Selftest failed: {}", e));
+            return;
+        }
+        selftest::run(*iterations, *size);
+        return;
+    }
+
+    if let Some(Command::Query { socket, request }) = &args.command {
+        let socket = socket
+            .clone()
+            .unwrap_or_else(|| daemon::default_socket().unwrap_or_else(|e| die!(1, "{}", e)));
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        daemon::query(&socket, &request.join(" "), &mut handle)
+            .unwrap_or_else(|e| die!(1, "{}", e));
+        return;
+    }
+
+    if let Some(Command::Archive { root, to, remove }) = &args.command {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        archive::run(root, to, *remove, &mut handle)
+            .unwrap_or_else(|e| die!(1, "Could not archive {}: {}", root.display(), e));
+        return;
+    }
+
+    if let Some(Command::Daemon {
+        socket,
+        refresh,
+        on_change,
+    }) = &args.command
+    {
+        let socket = socket
+            .clone()
+            .unwrap_or_else(|| daemon::default_socket().unwrap_or_else(|e| die!(1, "{}", e)));
+        let refresh = std::time::Duration::from_secs(*refresh);
+        let res = daemon::run(&socket, refresh, on_change.as_deref(), || {
+            let mut g = read_graph(&args, root.clone(), None, optlevel);
+            g.annotations = annotations.clone();
+            reduction::condense(reduction::merge_transient_roots(g))
+        });
+        res.unwrap_or_else(|e| die!(1, "{}", e));
+        return;
+    }
+
+    /**************************************
+     * end argument parsing               *
+     **************************************/
+
+    let mut timings = bugreport::Timings::default();
+    let load = |path: &PathBuf| {
+        snapshot::load(path)
+            .unwrap_or_else(|e| die!(1, "Could not load «{}»: {}", path.display(), e))
+    };
+    let mut g = timings.time("reading the graph", || match &args.command {
+        Some(Command::Subtract { a, b }) => diff::subtract(&load(a), &load(b)),
+        Some(Command::Diff { new: Some(new), .. }) => load(new),
+        _ => read_graph(&args, root, dumpfile, optlevel),
+    });
+    g.annotations = annotations;
+    if let Some(nixpkgs) = &args.attr_names {
+        msg!("Looking up the attributes of {}... ", nixpkgs);
+        match attrs::attr_names(&g, nixpkgs) {
+            Ok(attrs) => {
+                msg!("{} store paths found\n", attrs.len());
+                g.annotations.extend_exact(attrs);
+            }
+            Err(e) => eprintln!("Warning: could not look up attributes: {}", e),
+        }
+    }
+    let build_times = match &args.build_times {
+        None => std::collections::BTreeMap::new(),
+        Some(file) => {
+            // the logs of an image or of the machine a graph was saved on are not here
+            let mut times = if !args.foreign_store()
+                && args.load_graph.is_none()
+                && args.import_graph.is_none()
+            {
+                let logs = logs::find_logs(&overhead::log_dir(), &archive::store_dir());
+                buildtime::local_build_times(
+                    &logs,
+                    |drv| std::fs::read(std::ffi::OsStr::from_bytes(drv)).ok(),
+                    buildtime::log_duration,
+                )
+            } else {
+                std::collections::BTreeMap::new()
+            };
+            if let Some(file) = file {
+                let hydra = std::fs::read(file).and_then(|data| buildtime::parse_hydra(&data));
+                match hydra {
+                    Ok(hydra) => times.extend(hydra),
+                    Err(e) => die!(
+                        1,
+                        "Could not read build times from «{}»: {}",
+                        file.display(),
+                        e
+                    ),
+                }
+            }
+            msg!("Build times of {} store paths found\n", times.len());
+            let markers: Vec<_> = buildtime::markers(&times)
+                .into_iter()
+                .map(|(path, marker)| match g.annotations.lookup(&path) {
+                    Some(existing) => {
+                        let marker = format!("{}, {}", existing, marker);
+                        (path, marker)
+                    }
+                    None => (path, marker),
+                })
+                .collect();
+            g.annotations.extend_exact(markers);
+            times
+        }
+    };
+    let mut memory = if args.memory_stats {
+        Some(memstats::MemoryStats::default())
+    } else {
+        None
+    };
+    let mut record = |phase, g: &depgraph::DepInfos| {
+        if verbose() {
+            eprintln!(
+                "[{:.2}s] {}: {} nodes, {} edges",
+                phase_start.elapsed().as_secs_f64(),
+                phase,
+                g.graph.node_count(),
+                g.graph.edge_count()
+            );
+            phase_start = std::time::Instant::now();
+        }
+        if let Some(memory) = &mut memory {
+            memory.record(phase, g);
+        }
+    };
+    record("reading the graph", &g);
+    let regressions = if watched.is_empty() {
+        0
+    } else {
+        watch::run(
+            &g,
+            &watched,
+            args.watch_threshold.as_u64(),
+            &mut io::stderr(),
+        )
+        .unwrap_or_else(|e| die!(1, "Could not watch packages: {}", e))
+    };
+
+    if let Some(Command::BugReport { output }) = &args.command {
+        if !args.anonymize {
+            anonymize::anonymize(&mut g);
+        }
+        let mut snapshot = Vec::new();
+        snapshot::write(&g, &mut snapshot).expect("writing to memory cannot fail");
+        let mut stats = format!(
+            "{} nodes, {} edges, {} self-references\n",
+            g.graph.node_count(),
+            g.graph.edge_count(),
+            g.self_references()
+        )
+        .into_bytes();
+        print_stats(&mut stats, &g).expect("writing to memory cannot fail");
+        let condensed = timings.time("condensing", || {
+            reduction::condense(reduction::merge_transient_roots(g))
+        });
+        let reduced = timings.time("transitive reduction", || {
+            reduction::transitive_reduction(condensed)
+        });
+        stats.extend_from_slice(
+            format!(
+                "after condensing and transitive reduction: {} nodes, {} edges\n",
+                reduced.graph.node_count(),
+                reduced.graph.edge_count()
+            )
+            .as_bytes(),
+        );
+        let files = [
+            (
+                "nix-du-bug-report/versions.txt",
+                bugreport::versions().into_bytes(),
+            ),
+            ("nix-du-bug-report/stats.txt", stats),
+            (
+                "nix-du-bug-report/timings.txt",
+                timings.render().into_bytes(),
+            ),
+            ("nix-du-bug-report/graph.nixdu", snapshot),
+        ];
+        let res = std::fs::File::create(output).and_then(|f| {
+            let mut w = io::BufWriter::new(f);
+            bugreport::write_tar(&mut w, &files)?;
+            io::Write::flush(&mut w)
+        });
+        res.unwrap_or_else(|e| die!(3, "Could not write «{}»: {}", output.display(), e));
+        eprintln!(
+            "Wrote {}. The graph is anonymized, but check the archive before sharing it; \
+             it can be read with nix-du --load-graph.",
+            output.display()
+        );
+        return;
+    }
+
+    if let Some(Command::CompareProfiles { user1, user2 }) = &args.command {
+        if args.root.is_some() || args.anonymize {
+            die!(
+                1,
+                "nix-du compare-profiles does not support --root nor --anonymize"
+            );
+        }
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        compare::render(&g, [user1, user2], &mut handle)
+            .unwrap_or_else(|e| die!(1, "Could not compare profiles: {}", e));
+        return;
+    }
+
+    if let Some(Command::Diff { old, .. }) = &args.command {
+        let old = load(old);
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        let changes = diff::diff(&old, &g);
+        let blame = diff::blame(&old, &g, &changes);
+        diff::render(&changes, &blame, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::Plan {
+        free,
+        max_roots,
+        allow_in_use,
+        no_default_protections,
+        emit_commands,
+    }) = &args.command
+    {
+        if args.root.is_some() {
+            die!(1, "nix-du plan does not support --root");
+        }
+        let metric = args.metric.implementation(&args.score_substituter);
+        if !metric.additive() {
+            die!(
+                1,
+                "nix-du plan needs an additive --metric, not {}",
+                metric.name()
+            );
+        }
+        let in_use = inuse::roots_in_use(&g, &inuse::service_paths());
+        let defaults = if *no_default_protections {
+            std::collections::BTreeMap::new()
+        } else {
+            protect::default_protections(&g, &protect::system_paths(), protect::link_age)
+        };
+        g = reduction::merge_transient_roots(g);
+        msg!("Computing quotient graph...\n");
+        g = reduction::condense(g);
+        let sizes = metric.sizes(&g);
+        let rebuild = if args.build_times.is_some() {
+            buildtime::rebuild_times(&g, &build_times)
+        } else {
+            Vec::new()
+        };
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        plan::render(
+            &g,
+            &sizes,
+            *max_roots,
+            free.map(|s| s.as_u64()),
+            &in_use,
+            *allow_in_use,
+            &defaults,
+            &rebuild,
+            *emit_commands,
+            &mut handle,
+        )
+        .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if !args.simulate_delete.is_empty() {
+        let deleted: Vec<_> = args
+            .simulate_delete
+            .iter()
+            .map(|root| simulate::find_root(&g, root).unwrap_or_else(|e| die!(1, "{}", e)))
+            .collect();
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        simulate::render(
+            &g,
+            &deleted,
+            args.nodes.map_or(20, |n| n as usize),
+            &mut handle,
+        )
+        .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::Unlock { path }) = &args.command {
+        g = reduction::merge_transient_roots(g);
+        let target = unlock::find_node(&g, path).unwrap_or_else(|e| die!(1, "{}", e));
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        unlock::render(&g, target, args.include_synthetic, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::Why { path }) = &args.command {
+        if args.root.is_some() {
+            die!(1, "nix-du why does not support --root");
+        }
+        let target = unlock::find_node(&g, path).unwrap_or_else(|e| die!(1, "{}", e));
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        why::render(&g, target, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::Repl) = &args.command {
+        msg!("Computing quotient graph... ");
+        let mut session = repl::Session::new(g);
+        msg!("done, type help for a list of commands\n");
+        let stdin = io::stdin();
+        let prompt = if unsafe { libc::isatty(0) } != 0 {
+            Some("nix-du> ")
+        } else {
+            None
+        };
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        repl::run(&mut session, stdin.lock(), prompt, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::Logs { emit_commands }) = &args.command {
+        if args.root.is_some() || args.foreign_store() {
+            die!(
+                1,
+                "nix-du logs does not support --root, --image nor --store"
+            );
+        }
+        let log_dir = overhead::log_dir();
+        let logs = logs::find_logs(&log_dir, &archive::store_dir());
+        let report = logs::analyse(&g, logs, |drv| {
+            std::fs::read(std::ffi::OsStr::from_bytes(drv)).ok()
+        });
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        logs::render(&g, &report, &log_dir, *emit_commands, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::Toolchains { min_closure }) = &args.command {
+        if args.root.is_some() {
+            die!(1, "nix-du toolchains does not support --root");
+        }
+        g = reduction::merge_transient_roots(g);
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        toolchains::render(&g, min_closure.as_u64(), &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::Services) = &args.command {
+        if args.root.is_some() || args.foreign_store() {
+            die!(
+                1,
+                "nix-du services does not support --root, --image nor --store"
+            );
+        }
+        let services = services::pinned_by_units(&g, procinfo::unit_of);
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        services::render(&services, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::Suggest { system, count }) = &args.command {
+        if args.root.is_some() {
+            die!(1, "nix-du suggest does not support --root");
+        }
+        let system = nixos::find_system(&g, system)
+            .unwrap_or_else(|e| die!(1, "Could not find the NixOS system: {}", e));
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        nixos::render(&g, system, *count, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::Preview {
+        installable,
+        substituter,
+    }) = &args.command
+    {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        preview::render(&g, installable, substituter, &mut handle)
+            .unwrap_or_else(|e| die!(1, "Could not preview «{}»: {}", installable, e));
+        return;
+    }
+
+    if let Some(Command::ExportCost { substituter }) = &args.command {
+        if args.root.is_some() {
+            die!(1, "nix-du export-cost does not support --root");
+        }
+        g = reduction::merge_transient_roots(g);
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        export::render(&g, substituter, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::Locate { prefix }) = &args.command {
+        g = reduction::merge_transient_roots(g);
+        msg!("Computing quotient graph...\n");
+        g = reduction::condense(g);
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        locate::render(&g, prefix, &mut handle).unwrap_or_else(|e| die!(1, "{}", e));
+        return;
+    }
+
+    if let Some(Command::Manifest { root, output }) = &args.command {
+        use std::os::unix::ffi::OsStrExt;
+        let root = root.as_os_str().as_bytes();
+        let res = match output {
+            None => manifest::render(&g, root, &mut io::stdout().lock()),
+            Some(path) => std::fs::File::create(path).and_then(|f| {
+                let mut w = io::BufWriter::new(f);
+                manifest::render(&g, root, &mut w)?;
+                io::Write::flush(&mut w)
+            }),
+        };
+        res.unwrap_or_else(|e| die!(3, "Could not write the manifest: {}", e));
+        return;
+    }
+
+    if let Some(Command::VerifyAgainstNix { n }) = &args.command {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        let found = verify::render(&g, *n, &mut handle)
+            .unwrap_or_else(|e| die!(1, "Could not verify the graph: {}", e));
+        if found > 0 {
+            msg::exit(2);
+        }
+        return;
+    }
+
+    if let Some(Command::Rank { n }) = &args.command {
+        g = reduction::merge_transient_roots(g);
+        msg!("Computing quotient graph...\n");
+        g = reduction::condense(g);
+        let expr = score_expr.unwrap_or_else(|| {
+            score::Expr::parse(score::DEFAULT_EXPR).expect("invalid default score")
+        });
+        let substitutable = score::substitutable(&g, &expr, &args.score_substituter);
+        let scores = score::scores(&g, &expr, &substitutable);
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        score::render(&g, &scores, *n, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::Origins {
+        substituters,
+        count,
+    }) = &args.command
+    {
+        if args.root.is_some() {
+            die!(1, "nix-du origins does not support --root");
+        }
+        let substituters = if substituters.is_empty() {
+            origins::configured_substituters()
+        } else {
+            substituters.clone()
+        };
+        g = reduction::merge_transient_roots(g);
+        msg!("Computing quotient graph...\n");
+        g = reduction::condense(g);
+        let origins = origins::origins(&score::store_paths(&g), &substituters);
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        origins::render(&g, &substituters, &origins, *count, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    if let Some(Command::SamplePaths { n }) = &args.command {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        sample::render(&g, *n, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    let foreign_store = args.foreign_store();
+    if let Some(Command::Digest {
+        state,
+        stale_days,
+        forecast,
+    }) = args.command
+    {
+        if args.root.is_some() {
+            die!(1, "nix-du digest does not support --root");
+        }
+        g = reduction::merge_transient_roots(g);
+        msg!("Computing quotient graph...\n");
+        g = reduction::condense(g);
+        let stale_after = std::time::Duration::from_secs(stale_days * 3600 * 24);
+        // the database and logs of an image or of the machine a graph was saved on are not here
+        let overhead = if !foreign_store && args.load_graph.is_none() && args.import_graph.is_none()
+        {
+            overhead::measure(&std::env::temp_dir())
+        } else {
+            Vec::new()
+        };
+        digest::run(&g, state, stale_after, &overhead, forecast)
+            .unwrap_or_else(|e| die!(3, "Could not write digest: {}", e));
+        return;
+    }
 
     /*******************
      * graph reduction *
      *******************/
 
-    g = reduction::merge_transient_roots(g);
-    msg!("Computing quotient graph... ");
-    g = reduction::condense(g);
+    if !args.expand_transient {
+        // the pids of another machine say nothing about the processes here
+        let foreign_store = args.foreign_store();
+        g = reduction::group_transient_roots(g, |pid| {
+            if foreign_store {
+                None
+            } else {
+                procinfo::process(pid)
+            }
+        });
+        g = reduction::merge_transient_roots(g);
+        record("merging transient roots", &g);
+    }
+    if let Some(age) = args.older_than {
+        g = reduction::keep_older_than(g, age, std::time::SystemTime::now());
+    }
+    let prefixes: Vec<depgraph::Path> = args
+        .group_roots_by
+        .iter()
+        .map(|prefix| prefix.as_os_str().as_bytes().to_vec())
+        .collect();
+    if args.by_user || args.color_by == ColorBy::User {
+        owners::tag_users(&mut g);
+    }
+    g = reduction::group_roots(g, &prefixes);
+    if !args.no_group_generations {
+        g = reduction::group_generations(g);
+    }
+    if args.root_sizes {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        tree::render_root_sizes(&g, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+    msg!("Computing quotient graph...\n");
+    g = if args.group_by == Some(GroupBy::System) {
+        collapse_cycles(reduction::group_by_system(g))
+    } else if args.group_by == Some(GroupBy::MinimalRoot) {
+        collapse_cycles(reduction::group_by_minimal_root(g))
+    } else if reference_roots.is_empty() && args.incremental {
+        let (g, changed) = incremental::run(g)
+            .unwrap_or_else(|e| die!(1, "Could not condense incrementally: {}", e));
+        msg!("{} gc-roots changed since the last run... ", changed);
+        g
+    } else if reference_roots.is_empty() && args.kind_pure {
+        reduction::condense_kind_pure(g)
+    } else if reference_roots.is_empty() {
+        reduction::condense(g)
+    } else {
+        let is_reference = |node: &depgraph::DepNode| match node.description.path() {
+            Some(path) => reference_roots.iter().any(|glob| glob.is_match(path)),
+            None => false,
+        };
+        if args.kind_pure {
+            reduction::condense_kind_pure_relative_to(g, is_reference)
+        } else {
+            reduction::condense_relative_to(g, is_reference)
+        }
+    };
+
+    record("condensation", &g);
+
+    if args.group_by_name {
+        // merging the nodes of a package may make cycles, which are not worth warning about
+        g = reduction::collapse_cycles(reduction::group_by_name(g)).0;
+        record("grouping by package name", &g);
+    }
 
+    if args.format == Format::Junit {
+        // filters would merge nodes and change closures
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        let failures = check::render_junit(&g, &assertions, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        if failures > 0 || regressions > 0 {
+            msg::exit(2);
+        }
+        return;
+    }
+
+    let metric = args.metric.implementation(&args.score_substituter);
     let mut min_size = args.min_size.map(|s| s.as_u64()).unwrap_or(0);
     if let Some(n_nodes) = args.nodes {
-        if (n_nodes as usize) < g.graph.node_count() {
-            let mut sizes: Vec<u64> = g.graph.raw_nodes().iter().map(|n| n.weight.size).collect();
-            sizes.sort_unstable();
-            min_size = sizes[sizes.len().saturating_sub(n_nodes as usize)] as u64;
-        }
+        min_size = size_threshold(&metric.sizes(&g), n_nodes as usize);
     }
 
     /*******************
@@ -230,27 +1868,160 @@ fn main() {
      *******************/
 
     if min_size > 0 {
-        g = reduction::keep(g, |d: &depgraph::DepNode| d.size >= min_size);
+        let sizes = metric.sizes(&g);
+        g = reduction::keep_by_index(g, |idx, _| sizes[idx.index()] >= min_size);
+    }
+    if !included.is_empty() || !excluded.is_empty() {
+        g = reduction::keep_matching(g, &included, &excluded);
+    }
+    if let Some(n) = args.top_per_root {
+        let sizes = metric.sizes(&g);
+        g = reduction::top_per_root_by(g, n, &sizes);
+    }
+    // the dot output of other graphs is transitively reduced on its own
+    let reduced = args.auto_simplify || outputs.iter().all(|&(f, _)| f == Format::Dot);
+    if reduced {
+        g = reduction::transitive_reduction(g);
     }
+    if g.graph.edge_count() > args.max_edges {
+        if args.auto_simplify {
+            while g.graph.edge_count() > args.max_edges {
+                let nodes = g.graph.node_count();
+                let sizes = metric.sizes(&g);
+                min_size = min_size.max(size_threshold(&sizes, nodes / 2));
+                g = reduction::keep_by_index(g, |idx, _| sizes[idx.index()] >= min_size);
+                g = reduction::transitive_reduction(g);
+                if g.graph.node_count() >= nodes {
+                    break;
+                }
+            }
+            msg!(
+                "Simplified the graph as with -s {} to stay below {} edges.\n",
+                ByteSize::b(min_size),
+                args.max_edges
+            );
+        } else {
+            eprintln!(
+                "Warning: the graph has {} edges and may be hard to render. \
+                 Hide small nodes with -s SIZE or -n N, or pass --auto-simplify.",
+                g.graph.edge_count()
+            );
+        }
+    }
+    record("filtering", &g);
     msg!(
         "{} nodes, {} edges.\n",
         g.graph.node_count(),
         g.graph.edge_count()
     );
 
-    let g = reduction::transitive_reduction(g);
-
     /*******************
      * output handling *
      *******************/
 
-    {
+    if args.interactive {
+        let marked = tui::run(&g, &console::Term::stdout())
+            .unwrap_or_else(|e| die!(3, "While browsing the graph: {}", e));
         let stdout = io::stdout();
         let mut handle = stdout.lock();
-        match dot::render(&g, &mut handle) {
-            Ok(_) => (),
-            Err(ref x) if x.kind() == io::ErrorKind::BrokenPipe => (),
-            Err(x) => die!(3, "While writing to stdout: {}", x),
+        tui::render_marked(&g, &marked, &mut handle)
+            .unwrap_or_else(|e| die!(3, "While writing to stdout: {}", e));
+        return;
+    }
+
+    let render = |format: Format, mut handle: &mut dyn io::Write| -> io::Result<()> {
+        match format {
+            Format::Dot => {
+                let reduced_graph;
+                let g = if reduced {
+                    &g
+                } else {
+                    reduced_graph = reduction::transitive_reduction(g.clone());
+                    &reduced_graph
+                };
+                let scores = score_expr.as_ref().map(|expr| {
+                    let substitutable = score::substitutable(g, expr, &args.score_substituter);
+                    let mut by_index = vec![0.; g.graph.node_count()];
+                    for (idx, score) in score::scores(g, expr, &substitutable) {
+                        by_index[idx.index()] = score;
+                    }
+                    by_index
+                });
+                let style = dot::Style {
+                    scale_labels: args.scale_labels,
+                    examples: args.show_examples,
+                    scores,
+                    sizes: match args.metric {
+                        metric::Metric::Own => None,
+                        _ => Some((metric.sizes(g), metric.name())),
+                    },
+                    synthetic_edges: args.include_synthetic,
+                    owners: match args.color_by {
+                        ColorBy::Size if !args.by_user => None,
+                        _ => Some(owners::owners(g, owners::user_of_root)),
+                    },
+                    clusters: args.by_user,
+                    ..dot::Style::default()
+                }
+                .with(&args.dot_style);
+                style.render(g, handle)
+            }
+            Format::Tree => tree::render(&g, &mut handle),
+            Format::Json => {
+                let options = json::Options {
+                    lod: args.lod as usize,
+                    synthetic: args.include_synthetic,
+                    members: args.members,
+                };
+                json::render_with(&g, &options, &mut handle)
+            }
+            Format::Csv => csv::render(&g, &mut handle),
+            Format::Graphml => graphml::GraphMl {
+                synthetic: args.include_synthetic,
+            }
+            .render(&g, handle),
+            Format::Gexf => gexf::Gexf {
+                synthetic: args.include_synthetic,
+            }
+            .render(&g, handle),
+            Format::Text if args.by_user => {
+                let owners = owners::owners(&g, owners::user_of_root);
+                owners::render_user_sizes(&g, &owners, &mut handle)
+            }
+            Format::Text => text::render(&g, args.nodes.map_or(20, |n| n as usize), &mut handle),
+            Format::Timeline => timeline::render(&g, &mut handle),
+            Format::Flamegraph => flamegraph::render(&g, &mut handle),
+            Format::Treemap => flamegraph::render_treemap(&g, &mut handle),
+            Format::Junit => unreachable!("junit is rendered before filtering"),
         }
+    };
+    for &(format, path) in &outputs {
+        match path {
+            None => {
+                let stdout = io::stdout();
+                let mut handle = stdout.lock();
+                match render(format, &mut handle) {
+                    Ok(_) => (),
+                    Err(ref x) if x.kind() == io::ErrorKind::BrokenPipe => (),
+                    Err(x) => die!(3, "While writing to stdout: {}", x),
+                }
+            }
+            Some(path) => {
+                let res = std::fs::File::create(path).and_then(|f| {
+                    let mut f = io::BufWriter::new(f);
+                    render(format, &mut f)?;
+                    io::Write::flush(&mut f)
+                });
+                res.unwrap_or_else(|e| die!(3, "While writing to «{}»: {}", path.display(), e));
+            }
+        }
+    }
+    if let Some(memory) = &memory {
+        memory
+            .render(&mut io::stderr())
+            .unwrap_or_else(|e| die!(3, "While writing to stderr: {}", e));
+    }
+    if regressions > 0 {
+        msg::exit(2);
     }
 }
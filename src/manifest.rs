@@ -0,0 +1,131 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Writing the inventory of the closure of a gc-root as JSON: every store path with its NAR
+//! size and hash, for backup or compliance tooling.
+//!
+//! The closure and sizes come from the graph, the hashes from `nix path-info`, as the graph
+//! has none. Paths `nix path-info` does not know have a null hash.
+
+use crate::depgraph::*;
+use crate::json;
+use crate::preview;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// How many store paths to ask `nix path-info` about at once
+const CHUNK: usize = 500;
+
+/// Returns the NAR hash of `paths`, as reported by `nix path-info`.
+pub fn nar_hashes(paths: &[&[u8]]) -> io::Result<BTreeMap<Path, String>> {
+    use std::os::unix::ffi::OsStrExt;
+    let mut res = BTreeMap::new();
+    for chunk in paths.chunks(CHUNK) {
+        let mut cmd = preview::nix();
+        cmd.args(["path-info", "--json"]);
+        cmd.args(chunk.iter().map(|p| std::ffi::OsStr::from_bytes(p)));
+        let out = preview::output_of(&mut cmd)?;
+        let hashes =
+            preview::parse_path_info_with(&out, "narHash", |v| v.as_str().map(str::to_owned))?;
+        res.extend(hashes.into_iter().map(|(p, hash)| (p.into_bytes(), hash)));
+    }
+    Ok(res)
+}
+
+/// Writes the manifest of the closure of `di`, read with the gc-root `root` as root, given
+/// the hash of its paths.
+pub fn write<W: Write>(
+    di: &DepInfos,
+    root: &[u8],
+    hashes: &BTreeMap<Path, String>,
+    w: &mut W,
+) -> io::Result<()> {
+    let mut paths: Vec<(&[u8], u64)> = di
+        .graph
+        .node_weights()
+        .flat_map(DepNode::store_paths)
+        .collect();
+    paths.sort_unstable();
+    paths.dedup();
+    let total: u64 = paths.iter().map(|&(_, size)| size).sum();
+    w.write_all(b"{\"root\":")?;
+    json::write_string(w, root)?;
+    write!(
+        w,
+        ",\"count\":{},\"size\":{},\"paths\":[",
+        paths.len(),
+        total
+    )?;
+    for (i, (path, size)) in paths.into_iter().enumerate() {
+        if i > 0 {
+            w.write_all(b",")?;
+        }
+        w.write_all(b"\n{\"path\":")?;
+        json::write_string(w, path)?;
+        write!(w, ",\"narSize\":{},\"narHash\":", size)?;
+        match hashes.get(path) {
+            Some(hash) => json::write_string(w, hash.as_bytes())?,
+            None => w.write_all(b"null")?,
+        }
+        w.write_all(b"}")?;
+    }
+    w.write_all(b"\n]}\n")
+}
+
+/// Queries the hashes of the store paths of `di` and writes its manifest.
+pub fn render<W: Write>(di: &DepInfos, root: &[u8], w: &mut W) -> io::Result<()> {
+    let paths: Vec<&[u8]> = di
+        .graph
+        .node_weights()
+        .flat_map(DepNode::store_paths)
+        .map(|(p, _)| p)
+        .collect();
+    let hashes = nar_hashes(&paths)?;
+    write(di, root, &hashes, w)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json::Value;
+    use crate::manifest::*;
+
+    #[test]
+    fn check_write() {
+        let text = br#"{"nodes":[
+            {"id":1,"path":"/nix/store/r","size":1},
+            {"id":2,"path":"/nix/store/b","size":100},
+            {"id":3,"path":"/nix/store/a","size":10}
+        ],"edges":[[1,2],[1,3],[3,2]]}"#;
+        let di = crate::import::graph(text);
+        let hashes: BTreeMap<Path, String> =
+            vec![(b"/nix/store/a".to_vec(), "sha256-aaaa".to_owned())]
+                .into_iter()
+                .collect();
+        let mut out = Vec::new();
+        write(&di, b"/home/u/result", &hashes, &mut out).unwrap();
+        let manifest = json::parse(&out).unwrap();
+        assert_eq!(
+            manifest.get("root").and_then(Value::as_str),
+            Some("/home/u/result")
+        );
+        assert_eq!(manifest.get("size").and_then(Value::as_u64), Some(111));
+        let paths = manifest.get("paths").and_then(Value::as_array).unwrap();
+        let paths: Vec<(&str, u64, Option<&str>)> = paths
+            .iter()
+            .map(|p| {
+                (
+                    p.get("path").and_then(Value::as_str).unwrap(),
+                    p.get("narSize").and_then(Value::as_u64).unwrap(),
+                    p.get("narHash").and_then(Value::as_str),
+                )
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("/nix/store/a", 10, Some("sha256-aaaa")),
+                ("/nix/store/b", 100, None),
+                ("/nix/store/r", 1, None),
+            ]
+        );
+    }
+}
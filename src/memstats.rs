@@ -0,0 +1,156 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Measuring memory usage at the boundaries of the phases of a run, for `--memory-stats`.
+//!
+//! The resident set size is read from `/proc/self/statm`, and the size of the main data
+//! structures is estimated from their capacity, so that users of machines with little memory
+//! know which phase to avoid.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use std::io::{self, Write};
+use std::mem::size_of;
+
+/// Estimated sizes of the data structures of a graph, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sizes {
+    /// the node and edge lists
+    pub graph: u64,
+    /// the store paths and names, including the members of merged nodes
+    pub paths: u64,
+    /// the per-node data allocated by `reduction::condense`: a 128 bit class label, and
+    /// the visited bitset of a traversal
+    pub condensation: u64,
+}
+
+/// Estimates the memory used by `di`.
+pub fn sizes(di: &DepInfos) -> Sizes {
+    let (nodes, edges) = di.graph.capacity();
+    let graph = nodes * size_of::<petgraph::graph::Node<DepNode>>()
+        + edges * size_of::<petgraph::graph::Edge<Edge>>();
+    let paths: usize = di
+        .graph
+        .node_weights()
+        .map(|node| {
+            let own = match &node.description {
+                NodeDescription::Path(p)
+                | NodeDescription::Link(p)
+                | NodeDescription::Memory(p)
+                | NodeDescription::Temporary(p)
+                | NodeDescription::Shared(p)
+                | NodeDescription::Group(p)
+                | NodeDescription::Generations(p, _)
+                | NodeDescription::Other(p) => p.capacity(),
+                _ => 0,
+            };
+            own + node.members.capacity() * size_of::<(Path, u64)>()
+                + node
+                    .members
+                    .iter()
+                    .map(|(p, _)| p.capacity())
+                    .sum::<usize>()
+        })
+        .sum();
+    let n = di.graph.node_count();
+    Sizes {
+        graph: graph as u64,
+        paths: paths as u64,
+        condensation: (n * size_of::<u128>() + n.div_ceil(8)) as u64,
+    }
+}
+
+/// Returns the resident set size of this process, if it can be known.
+pub fn rss() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size <= 0 {
+        return None;
+    }
+    Some(pages * page_size as u64)
+}
+
+/// Memory usage at a phase boundary
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// what was just done
+    pub phase: &'static str,
+    pub rss: Option<u64>,
+    pub sizes: Sizes,
+    pub nodes: usize,
+    pub edges: usize,
+}
+
+/// Memory usage over a run
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStats {
+    pub samples: Vec<Sample>,
+}
+
+impl MemoryStats {
+    /// Records the memory usage after `phase`, which left the graph `di`.
+    pub fn record(&mut self, phase: &'static str, di: &DepInfos) {
+        self.samples.push(Sample {
+            phase,
+            rss: rss(),
+            sizes: sizes(di),
+            nodes: di.graph.node_count(),
+            edges: di.graph.edge_count(),
+        });
+    }
+
+    /// Prints one line per phase, and the peak resident set size.
+    pub fn render<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let b = |x: u64| ByteSize::b(x).to_string();
+        let rss = |s: &Sample| s.rss.map_or_else(|| "?".to_owned(), b);
+        writeln!(
+            w,
+            "{:<24}{:>12}{:>12}{:>12}{:>14}{:>10}{:>10}",
+            "phase", "rss", "graph", "paths", "condensation", "nodes", "edges"
+        )?;
+        for s in &self.samples {
+            writeln!(
+                w,
+                "{:<24}{:>12}{:>12}{:>12}{:>14}{:>10}{:>10}",
+                s.phase,
+                rss(s),
+                b(s.sizes.graph),
+                b(s.sizes.paths),
+                b(s.sizes.condensation),
+                s.nodes,
+                s.edges
+            )?;
+        }
+        if let Some(peak) = self.samples.iter().filter_map(|s| s.rss).max() {
+            writeln!(w, "peak rss: {}", b(peak))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memstats::*;
+
+    #[test]
+    fn check_record() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r","root":true},
+            {"id":2,"path":"/nix/store/0aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-top","size":1},
+            {"id":3,"path":"/nix/store/1bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-dep","size":1}
+        ],"edges":[[1,2],[2,3]]}"#;
+        let di = crate::import::graph(text);
+        let mut stats = MemoryStats::default();
+        stats.record("read", &di);
+        let di = crate::reduction::condense(di);
+        stats.record("condensation", &di);
+        let (before, after) = (&stats.samples[0], &stats.samples[1]);
+        assert!(before.sizes.paths >= 2 * 44);
+        // the paths are moved to the members of the root
+        assert!(after.sizes.paths >= 2 * 44);
+        assert!(after.nodes < before.nodes);
+        let mut out = Vec::new();
+        stats.render(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().lines().count() >= 3);
+    }
+}
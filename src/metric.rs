@@ -0,0 +1,303 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! The ways of measuring the size of a node, chosen with `--metric`, and used by filters,
+//! sorting, labels and planners alike.
+//!
+//! Some metrics are additive: the size of a set of nodes is the sum of the sizes of its
+//! members, which planners need to add up what deleting roots frees. Others, like the size
+//! of the closure, already count the nodes below, and summing them counts nodes several
+//! times.
+
+use crate::depgraph::*;
+use crate::tree::DominatorTree;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+/// A way of measuring the size of the nodes of a graph
+pub trait SizeMetric {
+    /// The name of the metric, shown next to sizes measured by it
+    fn name(&self) -> &'static str;
+
+    /// Whether the size of a set of nodes is the sum of their sizes
+    fn additive(&self) -> bool {
+        true
+    }
+
+    /// Returns the size of each node of `di`, by node index
+    fn sizes(&self, di: &DepInfos) -> Vec<u64>;
+}
+
+/// The size of the store paths a node stands for, as stored in the graph
+pub struct OwnSize;
+
+impl SizeMetric for OwnSize {
+    fn name(&self) -> &'static str {
+        "own"
+    }
+
+    fn sizes(&self, di: &DepInfos) -> Vec<u64> {
+        di.graph.node_weights().map(|node| node.size).collect()
+    }
+}
+
+/// The size of a node and of all nodes it depends on
+pub struct ClosureSize;
+
+impl SizeMetric for ClosureSize {
+    fn name(&self) -> &'static str {
+        "closure"
+    }
+
+    fn additive(&self) -> bool {
+        false
+    }
+
+    fn sizes(&self, di: &DepInfos) -> Vec<u64> {
+        let mut dfs = petgraph::visit::Dfs::empty(&di.graph);
+        di.graph
+            .node_indices()
+            .map(|idx| {
+                dfs.reset(&di.graph);
+                dfs.move_to(idx);
+                let mut size = 0;
+                while let Some(idx) = dfs.next(&di.graph) {
+                    size += di.graph[idx].size;
+                }
+                size
+            })
+            .collect()
+    }
+}
+
+/// The size freed by removing a node: its own, and that of the nodes only it makes
+/// reachable from the root
+pub struct RetainedSize;
+
+impl SizeMetric for RetainedSize {
+    fn name(&self) -> &'static str {
+        "retained"
+    }
+
+    fn additive(&self) -> bool {
+        false
+    }
+
+    fn sizes(&self, di: &DepInfos) -> Vec<u64> {
+        DominatorTree::new(di).retained
+    }
+}
+
+/// The size of a node where each file deduplicated by store optimisation is shared evenly
+/// by the nodes referring to it, instead of being counted apart in a `Shared` node. Without
+/// store optimisation, the same as `OwnSize`.
+pub struct DedupAwareSize;
+
+impl SizeMetric for DedupAwareSize {
+    fn name(&self) -> &'static str {
+        "dedup-aware"
+    }
+
+    fn sizes(&self, di: &DepInfos) -> Vec<u64> {
+        let mut res: Vec<u64> = di.graph.node_weights().map(|node| node.size).collect();
+        for idx in di.graph.node_indices() {
+            if di.graph[idx].kind() != NodeKind::Shared {
+                continue;
+            }
+            let parents: Vec<_> = di
+                .graph
+                .neighbors_directed(idx, petgraph::Direction::Incoming)
+                .filter(|&parent| parent != di.root)
+                .collect();
+            if parents.is_empty() {
+                continue;
+            }
+            let size = res[idx.index()];
+            let share = size / parents.len() as u64;
+            // the remainder of the division goes to the parent of lowest index
+            let first = *parents.iter().min().expect("no parents");
+            res[first.index()] += size - share * parents.len() as u64;
+            for parent in parents {
+                res[parent.index()] += share;
+            }
+            res[idx.index()] = 0;
+        }
+        res
+    }
+}
+
+/// The size of a node once compressed, as downloaded from a substituter: the
+/// `downloadSize` of the paths it knows, and an estimate for the others
+pub struct DownloadSize {
+    /// the binary cache queried
+    pub substituter: String,
+    /// the compressed size of the paths already queried, `None` for those unknown to the
+    /// substituter, so that measuring each filtered graph does not query them again
+    queried: RefCell<BTreeMap<Path, Option<u64>>>,
+    /// the ratio assumed for unknown paths, from the first graph measured
+    ratio: Cell<Option<f64>>,
+}
+
+impl DownloadSize {
+    pub fn new(substituter: &str) -> Self {
+        DownloadSize {
+            substituter: substituter.to_owned(),
+            queried: RefCell::new(BTreeMap::new()),
+            ratio: Cell::new(None),
+        }
+    }
+}
+
+impl SizeMetric for DownloadSize {
+    fn name(&self) -> &'static str {
+        "download"
+    }
+
+    fn sizes(&self, di: &DepInfos) -> Vec<u64> {
+        let paths: Vec<(&[u8], u64)> = di
+            .graph
+            .node_weights()
+            .flat_map(DepNode::store_paths)
+            .collect();
+        let mut queried = self.queried.borrow_mut();
+        let missing: Vec<&[u8]> = paths
+            .iter()
+            .map(|&(p, _)| p)
+            .filter(|&p| !queried.contains_key(p))
+            .collect();
+        if !missing.is_empty() {
+            let found = crate::export::compressed_sizes(&missing, &self.substituter);
+            for p in missing {
+                queried.insert(p.to_vec(), found.get(p).copied());
+            }
+        }
+        let compressed: BTreeMap<Path, u64> = paths
+            .iter()
+            .filter_map(|&(p, _)| Some((p.to_vec(), queried[p]?)))
+            .collect();
+        let ratio = match self.ratio.get() {
+            Some(ratio) => ratio,
+            None => {
+                let ratio = crate::export::compression_ratio(&paths, &compressed);
+                self.ratio.set(Some(ratio));
+                ratio
+            }
+        };
+        di.graph
+            .node_weights()
+            .map(|node| download_size(node, &compressed, ratio))
+            .collect()
+    }
+}
+
+/// Returns the download size of `node`, given the compressed size of the paths known to the
+/// substituter, and the ratio to assume for the remainder of its size.
+fn download_size(node: &DepNode, compressed: &BTreeMap<Path, u64>, ratio: f64) -> u64 {
+    let (mut known_nar, mut known) = (0, 0);
+    for (path, size) in node.store_paths() {
+        if let Some(&c) = compressed.get(path) {
+            known_nar += size;
+            known += c;
+        }
+    }
+    known + (node.size.saturating_sub(known_nar) as f64 * ratio) as u64
+}
+
+/// The metrics which can be chosen on the command line
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Metric {
+    /// the size of the store paths of the node
+    Own,
+    /// the size of the node and everything it depends on
+    Closure,
+    /// the size freed by removing the node
+    Retained,
+    /// the own size, with files deduplicated by store optimisation shared by their users
+    DedupAware,
+    /// the compressed size, from the narinfo files of a substituter
+    Download,
+}
+
+impl Metric {
+    /// Returns the implementation of the metric. `substituter` is the binary cache queried
+    /// by `Download`.
+    pub fn implementation(self, substituter: &str) -> Box<dyn SizeMetric> {
+        match self {
+            Metric::Own => Box::new(OwnSize),
+            Metric::Closure => Box::new(ClosureSize),
+            Metric::Retained => Box::new(RetainedSize),
+            Metric::DedupAware => Box::new(DedupAwareSize),
+            Metric::Download => Box::new(DownloadSize::new(substituter)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metric::*;
+
+    #[test]
+    fn check_metrics() {
+        // a and b share a file deduplicated by store optimisation
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r","root":true},
+            {"id":2,"path":"/nix/store/a","size":10},
+            {"id":3,"path":"/nix/store/b","size":100},
+            {"id":4,"kind":"Shared","path":"file","size":7}
+        ],"edges":[[1,2],[2,3],[2,4],[3,4]]}"#;
+        let di = crate::import::graph(text);
+        let by_path = |sizes: Vec<u64>| -> BTreeMap<String, u64> {
+            di.graph
+                .node_indices()
+                .filter(|&idx| idx != di.root)
+                .map(|idx| {
+                    (
+                        String::from_utf8_lossy(di.graph[idx].description.path().unwrap())
+                            .into_owned(),
+                        sizes[idx.index()],
+                    )
+                })
+                .collect()
+        };
+        let own = by_path(OwnSize.sizes(&di));
+        let closure = by_path(ClosureSize.sizes(&di));
+        let retained = by_path(RetainedSize.sizes(&di));
+        let dedup = by_path(DedupAwareSize.sizes(&di));
+        let r = "/r";
+        assert_eq!(own[r], 0);
+        assert_eq!(closure[r], 117);
+        assert_eq!(retained[r], 117);
+        assert_eq!(closure["/nix/store/a"], 117);
+        assert_eq!(closure["/nix/store/b"], 107);
+        assert_eq!(retained["/nix/store/b"], 100);
+        assert_eq!(dedup["/nix/store/a"], 14);
+        assert_eq!(dedup["/nix/store/b"], 103);
+        assert_eq!(dedup.values().sum::<u64>(), own.values().sum::<u64>());
+        assert!(OwnSize.additive() && DedupAwareSize.additive());
+        assert!(!ClosureSize.additive() && !RetainedSize.additive());
+
+        let compressed: BTreeMap<Path, u64> =
+            vec![(b"/nix/store/a".to_vec(), 4)].into_iter().collect();
+        let a = &di.graph[crate::import::node(&di, "/nix/store/a")];
+        let b = &di.graph[crate::import::node(&di, "/nix/store/b")];
+        assert_eq!(download_size(a, &compressed, 0.5), 4);
+        assert_eq!(download_size(b, &compressed, 0.5), 50);
+
+        // the paths already queried are not queried again
+        let download = DownloadSize::new("https://cache.invalid");
+        for path in ["/r", "/nix/store/a", "/nix/store/b", "file"] {
+            let size = if path == "/nix/store/a" {
+                Some(4)
+            } else {
+                None
+            };
+            download
+                .queried
+                .borrow_mut()
+                .insert(path.as_bytes().to_vec(), size);
+        }
+        download.ratio.set(Some(0.5));
+        let sizes = by_path(download.sizes(&di));
+        assert_eq!(sizes["/nix/store/a"], 4);
+        assert_eq!(sizes["/nix/store/b"], 50);
+    }
+}
@@ -1,6 +1,10 @@
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 
 static QUIET: AtomicBool = AtomicBool::new(false);
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+static TEMPORARY: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
 
 pub fn quiet() -> bool {
     QUIET.load(Ordering::Relaxed)
@@ -10,11 +14,68 @@ pub fn set_quiet(x: bool) {
     QUIET.store(x, Ordering::Relaxed);
 }
 
+/// whether `-v` was specified, to print how long each phase takes
+pub fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+pub fn set_verbose(x: bool) {
+    VERBOSE.store(x, Ordering::Relaxed);
+}
+
+/// Returns a progress bar on stderr for `len` steps, or a counter of the `what` done if
+/// their number is not known in advance. It is hidden in quiet mode, and when stderr is
+/// not a terminal.
+pub fn progress(len: Option<u64>, what: &'static str) -> indicatif::ProgressBar {
+    if quiet() {
+        return indicatif::ProgressBar::hidden();
+    }
+    let bar = match len {
+        Some(len) => indicatif::ProgressBar::new(len).with_style(
+            indicatif::ProgressStyle::default_bar()
+                .template("{wide_bar} {percent:>3}% ETA {eta:>7}")
+                .expect("invalid template"),
+        ),
+        None => indicatif::ProgressBar::new_spinner().with_style(
+            indicatif::ProgressStyle::default_spinner()
+                .template("{spinner} {human_pos} {msg}")
+                .expect("invalid template"),
+        ),
+    };
+    // refresh only 3 times per second, otherwise it's slow
+    bar.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(3));
+    bar.with_message(what)
+}
+
+/// Registers `dir` to be removed by `exit`, which skips destructors
+pub fn remove_on_exit(dir: PathBuf) {
+    TEMPORARY.lock().expect("poisoned lock").push(dir);
+}
+
+/// Cancels `remove_on_exit(dir)`, once `dir` is removed otherwise
+pub fn keep_on_exit(dir: &Path) {
+    TEMPORARY
+        .lock()
+        .expect("poisoned lock")
+        .retain(|other| other != dir);
+}
+
+/// Removes the directories registered with `remove_on_exit`, and exits with `code`
+pub fn exit(code: i32) -> ! {
+    let dirs = std::mem::take(&mut *TEMPORARY.lock().expect("poisoned lock"));
+    for dir in dirs {
+        if let Err(e) = std::fs::remove_dir_all(&dir) {
+            eprintln!("Could not remove {}: {}", dir.display(), e);
+        }
+    }
+    std::process::exit(code)
+}
+
 /// only executes its argument if quiet mode is disabled.
 #[macro_export]
 macro_rules! noisy {
     ($x:block) => {
-        if !(quiet()) {
+        if !($crate::msg::quiet()) {
             $x
         }
     };
@@ -24,20 +85,19 @@ macro_rules! noisy {
 #[macro_export]
 macro_rules! msg {
     ($($arg:expr),+) => {
-        noisy!({
+        $crate::noisy!({
             eprint!($($arg),*);
         })
     }
 }
 
-/// like `eprintln!` but then calls exit(first argument).
+/// like `eprintln!` but then calls `msg::exit(first argument)`.
 #[macro_export]
 macro_rules! die {
     ($code:expr, $($arg:expr),+) => {
         {
             eprintln!($($arg),*);
-            use std::process::exit;
-            exit($code)
+            $crate::msg::exit($code)
         }
     }
 }
@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Suggesting changes to the NixOS configuration which would shrink the store, like
+//! "remove texlive from environment.systemPackages to save 3.1 GB".
+//!
+//! The closure of a NixOS system follows its options closely enough for heuristics:
+//!  * the references of its `system-path` are the packages of `environment.systemPackages`,
+//!    among which the `-man`, `-doc` and `-info` outputs come from `documentation.enable`;
+//!  * the references of its `system-units` are its systemd units, `unit-NAME.service` for
+//!    the service `NAME`.
+//!
+//! What a change saves is what it makes garbage, assuming the other NixOS generations are
+//! deleted too, as they keep the current configuration alive.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use fixedbitset::FixedBitSet;
+use petgraph::prelude::NodeIndex;
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Write};
+
+/// A change to the configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    /// what to do, like `remove hello from environment.systemPackages`
+    pub change: String,
+    /// the size it saves
+    pub saves: u64,
+}
+
+/// Returns the first node named `name` found by a breadth first search from `start`, at
+/// most `depth` references away.
+fn find_below(di: &DepInfos, start: NodeIndex, name: &[u8], depth: usize) -> Option<NodeIndex> {
+    let mut seen = BTreeSet::new();
+    let mut queue = VecDeque::from([(start, 0)]);
+    while let Some((idx, d)) = queue.pop_front() {
+        if !seen.insert(idx) {
+            continue;
+        }
+        if di.graph[idx].kind() == NodeKind::Path && di.graph[idx].name().as_ref() == name {
+            return Some(idx);
+        }
+        if d < depth {
+            queue.extend(di.graph.neighbors(idx).map(|n| (n, d + 1)));
+        }
+    }
+    None
+}
+
+/// Returns whether the gc-root `idx` is a NixOS generation other than the one of `system`
+fn is_other_generation(di: &DepInfos, idx: NodeIndex, system: NodeIndex) -> bool {
+    lazy_static::lazy_static! {
+        static ref GENERATION: regex::bytes::Regex =
+            regex::bytes::Regex::new(r"/profiles/system-[0-9]+-link$").unwrap();
+    }
+    match &di.graph[idx].description {
+        NodeDescription::Link(path) => {
+            GENERATION.is_match(path) && !di.graph.neighbors(idx).any(|n| n == system)
+        }
+        _ => false,
+    }
+}
+
+/// Returns the size of what stays alive, without other NixOS generations than `system`,
+/// when the references `removed` are removed.
+fn live_size(di: &DepInfos, system: NodeIndex, removed: &BTreeSet<(NodeIndex, NodeIndex)>) -> u64 {
+    let mut seen = FixedBitSet::with_capacity(di.graph.node_count());
+    let mut stack: Vec<NodeIndex> = di
+        .roots()
+        .filter(|&root| !is_other_generation(di, root, system))
+        .collect();
+    let mut size = 0;
+    while let Some(idx) = stack.pop() {
+        if seen.put(idx.index()) {
+            continue;
+        }
+        size += di.graph[idx].size;
+        stack.extend(
+            di.graph
+                .neighbors(idx)
+                .filter(|&n| !removed.contains(&(idx, n))),
+        );
+    }
+    size
+}
+
+/// Returns the suggestions for the NixOS system `system`, a node of `di`, a graph of the
+/// whole store, biggest savings first. Changes which save nothing are omitted.
+pub fn suggest(di: &DepInfos, system: NodeIndex) -> Vec<Suggestion> {
+    let mut changes: Vec<(String, BTreeSet<(NodeIndex, NodeIndex)>)> = Vec::new();
+    let name = |idx: NodeIndex| String::from_utf8_lossy(&di.graph[idx].name()).into_owned();
+    if let Some(system_path) = find_below(di, system, b"system-path", 1) {
+        let mut documentation = BTreeSet::new();
+        for package in di.graph.neighbors(system_path) {
+            let package_name = name(package);
+            let edge = (system_path, package);
+            if ["-man", "-doc", "-devdoc", "-info"]
+                .iter()
+                .any(|suffix| package_name.ends_with(suffix))
+            {
+                documentation.insert(edge);
+            } else {
+                changes.push((
+                    format!("remove {} from environment.systemPackages", package_name),
+                    BTreeSet::from([edge]),
+                ));
+            }
+        }
+        if !documentation.is_empty() {
+            changes.push(("set documentation.enable = false".to_owned(), documentation));
+        }
+    }
+    if let Some(units) = find_below(di, system, b"system-units", 3) {
+        for unit in di.graph.neighbors(units) {
+            let unit_name = name(unit);
+            if let Some(service) = unit_name
+                .strip_prefix("unit-")
+                .and_then(|n| n.strip_suffix(".service"))
+            {
+                let referrers = di
+                    .graph
+                    .neighbors_directed(unit, petgraph::Direction::Incoming)
+                    .map(|referrer| (referrer, unit))
+                    .collect();
+                changes.push((
+                    format!("disable the service {} ({}.service)", service, service),
+                    referrers,
+                ));
+            }
+        }
+    }
+    let base = live_size(di, system, &BTreeSet::new());
+    let mut res: Vec<Suggestion> = changes
+        .into_iter()
+        .map(|(change, removed)| Suggestion {
+            change,
+            saves: base - live_size(di, system, &removed),
+        })
+        .filter(|s| s.saves > 0)
+        .collect();
+    res.sort_by_key(|s| std::cmp::Reverse(s.saves));
+    res
+}
+
+/// Finds the node of the store path of the NixOS system `system`, usually
+/// `/run/current-system`.
+pub fn find_system(di: &DepInfos, system: &std::path::Path) -> io::Result<NodeIndex> {
+    use std::os::unix::ffi::OsStrExt;
+    let target = system.canonicalize()?;
+    let target = target.as_os_str().as_bytes();
+    di.graph
+        .node_indices()
+        .find(|&idx| match &di.graph[idx].description {
+            NodeDescription::Path(path) => path.as_slice() == target,
+            _ => false,
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a valid store path", system.display()),
+            )
+        })
+}
+
+/// Prints the `n` biggest suggestions for the system `system`.
+pub fn render<W: Write>(di: &DepInfos, system: NodeIndex, n: usize, w: &mut W) -> io::Result<()> {
+    let suggestions = suggest(di, system);
+    if suggestions.is_empty() {
+        return writeln!(w, "No suggestion: is this a NixOS system?");
+    }
+    for s in suggestions.iter().take(n) {
+        writeln!(w, "{} to save {}", s.change, ByteSize::b(s.saves))?;
+    }
+    writeln!(
+        w,
+        "These sizes assume that the other NixOS generations are deleted, for example with \
+         nix-collect-garbage -d."
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nixos::*;
+
+    #[test]
+    fn check_suggest() {
+        // texlive is only in systemPackages, hello is also in a user profile, the man
+        // pages only used by documentation, and foo.service runs bar
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/nix/var/nix/profiles/system-2-link","root":true},
+            {"id":2,"kind":"Link","path":"/nix/var/nix/profiles/system-1-link","root":true},
+            {"id":3,"kind":"Link","path":"/home/alice/.nix-profile","root":true},
+            {"id":4,"path":"/nix/store/a-nixos-system-2","size":1},
+            {"id":5,"path":"/nix/store/b-system-path","size":1},
+            {"id":6,"path":"/nix/store/c-texlive-2023","size":3000},
+            {"id":7,"path":"/nix/store/d-hello-2.12","size":50},
+            {"id":8,"path":"/nix/store/e-coreutils-9.1-man","size":20},
+            {"id":9,"path":"/nix/store/f-etc","size":1},
+            {"id":10,"path":"/nix/store/g-system-units","size":1},
+            {"id":11,"path":"/nix/store/h-unit-foo.service","size":1},
+            {"id":12,"path":"/nix/store/i-bar-1.0","size":400},
+            {"id":13,"path":"/nix/store/j-nixos-system-1","size":1}
+        ],"edges":[[1,4],[2,13],[13,6],[3,7],[4,5],[5,6],[5,7],[5,8],[4,9],[9,10],[10,11],[11,12]]}"#;
+        let di = crate::import::graph(text);
+        let system = crate::import::node(&di, "/nix/store/a-nixos-system-2");
+        let suggestions = suggest(&di, system);
+        let text: Vec<(&str, u64)> = suggestions
+            .iter()
+            .map(|s| (s.change.as_str(), s.saves))
+            .collect();
+        assert_eq!(
+            text,
+            vec![
+                ("remove texlive-2023 from environment.systemPackages", 3000),
+                ("disable the service foo (foo.service)", 401),
+                ("set documentation.enable = false", 20),
+            ]
+        );
+    }
+}
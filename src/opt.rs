@@ -4,6 +4,7 @@ use crate::msg::*;
 use dashmap::mapref::entry::Entry;
 use petgraph::prelude::NodeIndex;
 use rayon::prelude::*;
+use std::collections::HashSet;
 use std::io::Result;
 use std::iter::once;
 use std::os::unix::fs::MetadataExt;
@@ -12,6 +13,85 @@ use std::sync::Arc;
 use std::sync::RwLock;
 use walkdir::{DirEntryExt, WalkDir};
 
+/// What the size of a store path is
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum SizeMode {
+    /// the size of its NAR serialisation, registered in the database of the store
+    Nar,
+    /// the sum of the apparent sizes of its files, like `du --apparent-size`
+    Apparent,
+    /// the space allocated to its files, like `du`, the only one to tell the space used on
+    /// compressing or copy-on-write filesystems like zfs and btrfs
+    Disk,
+}
+
+impl SizeMode {
+    /// the size of a file with metadata `metadata`. NAR sizes count the apparent size of
+    /// files.
+    pub fn file_size(self, metadata: &std::fs::Metadata) -> u64 {
+        match self {
+            SizeMode::Nar | SizeMode::Apparent => metadata.len(),
+            SizeMode::Disk => metadata.blocks() * 512,
+        }
+    }
+}
+
+/// Replaces the size of the store paths of `di`, registered in the store, by their size
+/// in `mode`, measured on the files. Files hard linked within a store path are counted once.
+pub fn measure_sizes(di: &mut DepInfos, mode: SizeMode) -> Result<()> {
+    if mode == SizeMode::Nar {
+        return Ok(());
+    }
+    let progress = progress(Some(di.graph.node_count() as u64), "store paths");
+    let sizes = di
+        .graph
+        .raw_nodes()
+        .par_iter()
+        .map(|node| -> Result<Option<u64>> {
+            noisy!({
+                progress.inc(1);
+            });
+            // roots are not necessary readable
+            if !node.weight.kind().is_store_path() {
+                return Ok(None);
+            }
+            let path = std::path::Path::new(
+                node.weight
+                    .description
+                    .path_as_os_str()
+                    .expect("node with kind path without path"),
+            );
+            let metadata = path.symlink_metadata()?;
+            if metadata.file_type().is_symlink() {
+                return Ok(Some(mode.file_size(&metadata)));
+            }
+            let mut inodes = HashSet::new();
+            let mut size = 0;
+            for entry in WalkDir::new(path) {
+                let metadata = entry?.metadata()?;
+                if metadata.is_dir() || metadata.nlink() <= 1 || inodes.insert(metadata.ino()) {
+                    size += mode.file_size(&metadata);
+                }
+            }
+            Ok(Some(size))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    progress.finish_and_clear();
+    for (node, size) in di.graph.node_weights_mut().zip(sizes) {
+        if let Some(size) = size {
+            node.size = size;
+        }
+    }
+    // the sizes recorded so far are in another unit
+    for sizes in di.metadata.size.values_mut() {
+        for size in sizes.values_mut() {
+            *size = None;
+        }
+    }
+    di.record_metadata();
+    Ok(())
+}
+
 #[derive(Debug, Copy, Clone)]
 enum Owner {
     One(NodeIndex),
@@ -19,8 +99,8 @@ enum Owner {
 }
 
 /// Stats all the files in the store looking for hardlinked files
-/// and adapt the sizes of the nodes to take this into account.
-pub fn refine_optimized_store(di: &mut DepInfos) -> Result<()> {
+/// and adapt the sizes of the nodes, in `mode`, to take this into account.
+pub fn refine_optimized_store(di: &mut DepInfos, mode: SizeMode) -> Result<()> {
     // invariant:
     // forall visited file:
     // its inode is a key in inode_to_owner
@@ -34,17 +114,7 @@ pub fn refine_optimized_store(di: &mut DepInfos) -> Result<()> {
     let inode_to_owner = dashmap::DashMap::new();
 
     let indices = 0..di.graph.node_count();
-    let progress = if quiet() {
-        indicatif::ProgressBar::hidden()
-    } else {
-        indicatif::ProgressBar::new(di.graph.node_count() as u64).with_style(
-            indicatif::ProgressStyle::default_bar()
-                .template("{wide_bar} {percent:>3}% ETA {eta:>7}")
-                .expect("invalid template"),
-        )
-    };
-    // refresh only 3 times per second, otherwise it's slow
-    progress.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(3));
+    let progress = progress(Some(di.graph.node_count() as u64), "store paths");
     let locked_graph = Arc::new(RwLock::new(&mut di.graph));
     indices
         .into_par_iter()
@@ -59,7 +129,7 @@ pub fn refine_optimized_store(di: &mut DepInfos) -> Result<()> {
                 // scope where we borrow the graph
                 let weight = &graph[idx];
                 // roots are not necessary readable, and anyway they are symlinks
-                if weight.kind() != NodeKind::Path {
+                if !weight.kind().is_store_path() {
                     return Ok(());
                 }
                 let path = std::path::Path::new(
@@ -87,7 +157,7 @@ pub fn refine_optimized_store(di: &mut DepInfos) -> Result<()> {
                 // attempt to make the stat syscall without taking a write lock
                 let must_stat = matches!(inode_to_owner.get(&ino).map(|x| *x), Some(Owner::One(_)));
                 let filesize = if must_stat {
-                    Some(entry.metadata()?.len())
+                    Some(mode.file_size(&entry.metadata()?))
                 } else {
                     None
                 };
@@ -104,15 +174,16 @@ pub fn refine_optimized_store(di: &mut DepInfos) -> Result<()> {
                             Owner::One(n) => {
                                 // second time we see this inode;
                                 // let's create a "shared" node for these files
-                                let filesize =
-                                    filesize.unwrap_or_else(|| entry.metadata().unwrap().len());
+                                let filesize = filesize
+                                    .unwrap_or_else(|| mode.file_size(&entry.metadata().unwrap()));
                                 let mut graph = locked_graph.write().expect("poisoned lock");
                                 let name = graph[idx].name().into_owned();
                                 let new_node = graph.add_node(DepNode {
                                     description: NodeDescription::Shared(name),
                                     size: filesize,
+                                    ..DepNode::dummy()
                                 });
-                                graph.add_edge(n, new_node, ());
+                                graph.add_edge(n, new_node, Edge::Reference);
                                 let new_w = &mut graph[n];
                                 new_w.size -= filesize;
                                 *v = Owner::Several(new_node);
@@ -120,7 +191,7 @@ pub fn refine_optimized_store(di: &mut DepInfos) -> Result<()> {
                             }
                             Owner::Several(n) => (n, locked_graph.write().expect("poisoned lock")),
                         };
-                        graph.add_edge(idx, new_node, ());
+                        graph.add_edge(idx, new_node, Edge::Reference);
                         let filesize = graph[new_node].size;
                         let w = &mut graph[idx];
                         w.size -= filesize;
@@ -178,3 +249,41 @@ pub fn store_is_optimised(di: &DepInfos) -> Result<Option<bool>> {
     }
     Ok(Some(false))
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::opt::*;
+
+    #[test]
+    fn check_measure_sizes() {
+        let dir = std::env::temp_dir().join(format!("nix-du-opt-{}", std::process::id()));
+        let path = dir.join("aaaa-sparse");
+        std::fs::create_dir_all(&path).unwrap();
+        // a file with a hole, like a compressed one, takes less space than its size
+        std::fs::File::create(path.join("hole"))
+            .unwrap()
+            .set_len(1_000_000)
+            .unwrap();
+        std::fs::hard_link(path.join("hole"), path.join("same")).unwrap();
+        let text = format!(
+            r#"{{"nodes":[
+                {{"id":1,"kind":"Link","path":"/home/alice/result","root":true}},
+                {{"id":2,"path":"{}","size":5}}
+            ],"edges":[[1,2]]}}"#,
+            path.display()
+        );
+        let di = crate::import::graph(text.as_bytes());
+        let size = |mode| {
+            let mut di = di.clone();
+            measure_sizes(&mut di, mode).unwrap();
+            di.check_metadata();
+            di.reachable_size()
+        };
+        assert_eq!(size(SizeMode::Nar), 5);
+        // the hard link is counted once
+        let apparent = size(SizeMode::Apparent);
+        assert!((1_000_000..2_000_000).contains(&apparent));
+        assert!(size(SizeMode::Disk) < 1_000_000);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
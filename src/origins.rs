@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Finding which binary cache can provide each store path again, and what exists nowhere
+//! else: local builds which cannot be downloaded after deleting them, and deserve archiving
+//! first.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// The substituter used when nix cannot tell its configuration
+pub const DEFAULT_SUBSTITUTER: &str = "https://cache.nixos.org";
+
+/// Parses the output of `nix config show substituters`: URLs separated by spaces.
+pub fn parse_substituters(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|url| url.trim_end_matches('/').to_owned())
+        .collect()
+}
+
+/// Returns the substituters nix is configured with
+pub fn configured_substituters() -> Vec<String> {
+    let mut cmd = crate::preview::nix();
+    cmd.args(["config", "show", "substituters"]);
+    match crate::preview::output_of(&mut cmd) {
+        Ok(out) => parse_substituters(&String::from_utf8_lossy(&out)),
+        Err(_) => vec![DEFAULT_SUBSTITUTER.to_owned()],
+    }
+}
+
+/// Returns, for each store path of `paths` some of `substituters` have, the index of the
+/// first of them which has it. Each substituter is only asked for the paths the previous
+/// ones do not have.
+pub fn origins(paths: &[&[u8]], substituters: &[String]) -> BTreeMap<Path, usize> {
+    let mut res = BTreeMap::new();
+    for (i, substituter) in substituters.iter().enumerate() {
+        let missing: Vec<&[u8]> = paths
+            .iter()
+            .copied()
+            .filter(|&p| !res.contains_key(p))
+            .collect();
+        if missing.is_empty() {
+            break;
+        }
+        msg!(
+            "Querying {} for {} store paths...\n",
+            substituter,
+            missing.len()
+        );
+        for path in crate::export::compressed_sizes(&missing, substituter).into_keys() {
+            res.insert(path, i);
+        }
+    }
+    res
+}
+
+/// Returns whether the store path `path` could only be obtained again by building it, and
+/// no cache has it. Derivations are never in caches, but are cheap to instantiate again
+/// from the expressions they come from.
+fn local_only(path: &[u8], origins: &BTreeMap<Path, usize>) -> bool {
+    !path.ends_with(b".drv") && !origins.contains_key(path)
+}
+
+/// Returns, for each node of `di`, a condensed graph, the size of its store paths which no
+/// cache has, biggest first. Nodes with none are omitted.
+pub fn local_only_sizes(di: &DepInfos, origins: &BTreeMap<Path, usize>) -> Vec<(NodeIndex, u64)> {
+    let mut res: Vec<(NodeIndex, u64)> = di
+        .graph
+        .node_indices()
+        .map(|idx| {
+            let size = di.graph[idx]
+                .store_paths()
+                .into_iter()
+                .filter(|&(path, _)| local_only(path, origins))
+                .map(|(_, size)| size)
+                .sum();
+            (idx, size)
+        })
+        .filter(|&(_, size)| size > 0)
+        .collect();
+    res.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+    res
+}
+
+/// Prints how much each of `substituters` provides of `di`, a condensed graph, followed by
+/// the `n` nodes retaining the most space which exists nowhere else.
+pub fn render<W: Write>(
+    di: &DepInfos,
+    substituters: &[String],
+    origins: &BTreeMap<Path, usize>,
+    n: usize,
+    w: &mut W,
+) -> io::Result<()> {
+    let mut provided = vec![(0usize, 0u64); substituters.len()];
+    let mut nowhere = (0usize, 0u64);
+    for node in di.graph.node_weights() {
+        for (path, size) in node.store_paths() {
+            let entry = match origins.get(path) {
+                Some(&i) => &mut provided[i],
+                None if local_only(path, origins) => &mut nowhere,
+                None => continue,
+            };
+            entry.0 += 1;
+            entry.1 += size;
+        }
+    }
+    for (substituter, (count, size)) in substituters.iter().zip(provided) {
+        writeln!(
+            w,
+            "{} provides {} store paths, {}",
+            substituter,
+            count,
+            ByteSize::b(size)
+        )?;
+    }
+    writeln!(
+        w,
+        "{} store paths, {}, exist nowhere else:",
+        nowhere.0,
+        ByteSize::b(nowhere.1)
+    )?;
+    for (idx, size) in local_only_sizes(di, origins).into_iter().take(n) {
+        write!(w, "\t{}\t", ByteSize::b(size))?;
+        w.write_all(&di.label(idx))?;
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::origins::*;
+
+    #[test]
+    fn check_local_only() {
+        assert_eq!(
+            parse_substituters("https://cache.nixos.org/ https://foo.cachix.org\n"),
+            vec!["https://cache.nixos.org", "https://foo.cachix.org"]
+        );
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/aaaa-my-thesis","size":100},
+            {"id":4,"path":"/nix/store/bbbb-glibc","size":1000},
+            {"id":5,"path":"/nix/store/cccc-hello.drv","size":10},
+            {"id":6,"path":"/nix/store/dddd-private","size":50}
+        ],"edges":[[1,3],[1,4],[2,4],[2,5],[2,6]]}"#;
+        let di = crate::import::graph(text);
+        let di = crate::reduction::condense(di);
+        let origins: BTreeMap<Path, usize> = [
+            (b"/nix/store/bbbb-glibc".to_vec(), 0),
+            (b"/nix/store/dddd-private".to_vec(), 1),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let sizes: Vec<u64> = local_only_sizes(&di, &origins)
+            .into_iter()
+            .map(|(_, size)| size)
+            .collect();
+        assert_eq!(sizes, vec![100]);
+        let substituters = vec!["https://a".to_owned(), "https://b".to_owned()];
+        let mut out = Vec::new();
+        render(&di, &substituters, &origins, 5, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "https://a provides 1 store paths, 1.0 KB\nhttps://b provides 1 store paths, 50 B\n\
+             1 store paths, 100 B, exist nowhere else:\n\t100 B\t/r1\n"
+        );
+    }
+}
@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! The space nix uses outside the store: its database, build logs and the temporary
+//! directories of builds. It is not part of the graph, but is part of what `df` reports, so
+//! the digest lists it apart.
+
+use bytesize::ByteSize;
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Space used by nix outside the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    /// what the space is used for
+    pub what: &'static str,
+    /// where, as shown to the user
+    pub location: String,
+    /// the space used on disk
+    pub size: u64,
+}
+
+/// Returns the space used on disk by `path` and the files below it, counting hard linked
+/// files once, like `du`. Files which cannot be read are ignored.
+pub fn disk_usage(path: &Path) -> u64 {
+    let mut seen = BTreeSet::new();
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|m| m.nlink() <= 1 || m.is_dir() || seen.insert((m.dev(), m.ino())))
+        .map(|m| m.blocks() * 512)
+        .sum()
+}
+
+/// Returns the directory given by the environment variable `var`, or else `default`
+fn dir_from_env(var: &str, default: &str) -> PathBuf {
+    std::env::var_os(var)
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| default.into())
+        .into()
+}
+
+/// Returns the directory where nix keeps build logs
+pub fn log_dir() -> PathBuf {
+    dir_from_env("NIX_LOG_DIR", "/nix/var/log/nix")
+}
+
+/// Measures the database of nix, its build logs and the temporary directories of builds
+/// still present in `temp_dir`. Directories which do not exist are omitted.
+pub fn measure(temp_dir: &Path) -> Vec<Item> {
+    let state = dir_from_env("NIX_STATE_DIR", "/nix/var/nix");
+    let logs = log_dir();
+    let mut res = Vec::new();
+    for (what, dir) in [("database", state.join("db")), ("build logs", logs)] {
+        if dir.is_dir() {
+            res.push(Item {
+                what,
+                location: dir.display().to_string(),
+                size: disk_usage(&dir),
+            });
+        }
+    }
+    let builds: Vec<PathBuf> = match std::fs::read_dir(temp_dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name().as_bytes().starts_with(b"nix-build-"))
+            .map(|entry| entry.path())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    if !builds.is_empty() {
+        res.push(Item {
+            what: "temporary build directories",
+            location: temp_dir.join("nix-build-*").display().to_string(),
+            size: builds.iter().map(|dir| disk_usage(dir)).sum(),
+        });
+    }
+    res
+}
+
+/// Writes one line per item, with their total.
+pub fn render<W: Write>(items: &[Item], w: &mut W) -> io::Result<()> {
+    if items.is_empty() {
+        return Ok(());
+    }
+    let total: u64 = items.iter().map(|item| item.size).sum();
+    writeln!(
+        w,
+        "Used by nix outside the store, not counted above: {}",
+        ByteSize::b(total)
+    )?;
+    for item in items {
+        writeln!(
+            w,
+            "\t{} ({}): {}",
+            item.what,
+            item.location,
+            ByteSize::b(item.size)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::overhead::*;
+
+    #[test]
+    fn check_measure() {
+        let dir = std::env::temp_dir().join(format!("nix-du-overhead-{}", std::process::id()));
+        let build = dir.join("nix-build-hello-2.12.drv-0");
+        std::fs::create_dir_all(&build).unwrap();
+        std::fs::write(build.join("big"), vec![1u8; 100_000]).unwrap();
+        std::fs::hard_link(build.join("big"), build.join("same")).unwrap();
+        std::fs::create_dir_all(dir.join("unrelated")).unwrap();
+        std::fs::write(dir.join("unrelated").join("file"), vec![1u8; 100_000]).unwrap();
+        let items: Vec<Item> = measure(&dir)
+            .into_iter()
+            .filter(|item| item.what == "temporary build directories")
+            .collect();
+        assert_eq!(items.len(), 1);
+        // the hard link is counted once
+        assert!(items[0].size >= 100_000 && items[0].size < 200_000);
+        let mut out = Vec::new();
+        render(&items, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("nix-build-*"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
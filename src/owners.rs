@@ -0,0 +1,233 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Attributing gc-roots, and what they retain, to users, to see on a multi-user machine
+//! whose profiles keep what alive.
+//!
+//! The user of a gc-root is the one named in its path, like
+//! `/nix/var/nix/profiles/per-user/alice/profile-3-link` or `/home/alice/result`, or else
+//! the owner of the link.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use fixedbitset::FixedBitSet;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+
+/// Returns the name of the user of uid `uid` according to /etc/passwd
+fn name_of_uid(uid: u32) -> Option<String> {
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.get(2)?.parse::<u32>() {
+            Ok(id) if id == uid => Some(fields[0].to_owned()),
+            _ => None,
+        }
+    })
+}
+
+/// Returns the user named in the path of a gc-root, if any
+pub fn user_in_path(path: &[u8]) -> Option<String> {
+    lazy_static! {
+        static ref USER: regex::bytes::Regex =
+            regex::bytes::Regex::new(r"/(?:per-user|home|Users)/([^/]+)/").unwrap();
+    }
+    let user = USER.captures(path)?.get(1)?.as_bytes();
+    Some(String::from_utf8_lossy(user).into_owned())
+}
+
+/// Returns the user a gc-root belongs to, as tagged by `tag_users` or else found anew
+pub fn user_of_root(node: &DepNode) -> Option<String> {
+    if node.user.is_some() {
+        return node.user.clone();
+    }
+    let path = match &node.description {
+        NodeDescription::Link(path) => path,
+        NodeDescription::Generations(profile, _) => return user_in_path(profile),
+        _ => return None,
+    };
+    user_in_path(path).or_else(|| {
+        let metadata = std::path::Path::new(node.description.path_as_os_str()?)
+            .symlink_metadata()
+            .ok()?;
+        name_of_uid(metadata.uid())
+    })
+}
+
+/// Tags each gc-root of `di` with the user it belongs to, if known, so that the groups of
+/// gc-roots made afterwards can tell theirs.
+pub fn tag_users(di: &mut DepInfos) {
+    let roots: Vec<_> = di.roots().collect();
+    for root in roots {
+        let user = user_of_root(&di.graph[root]);
+        di.graph[root].user = user;
+    }
+}
+
+/// Returns the user all of `nodes` belong to, if they all belong to the same one
+pub fn common_user<'a, I: IntoIterator<Item = &'a DepNode>>(nodes: I) -> Option<String> {
+    let mut res: Option<&String> = None;
+    for node in nodes {
+        match (&node.user, res) {
+            (None, _) => return None,
+            (Some(user), Some(previous)) if user != previous => return None,
+            (Some(user), _) => res = Some(user),
+        }
+    }
+    res.cloned()
+}
+
+/// The users retaining each node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owners {
+    /// the users owning gc-roots, sorted
+    pub users: Vec<String>,
+    /// for each node index, the index in `users` of the only user retaining it, or `None`
+    /// if it is retained by several users or by gc-roots of no known user
+    pub owner: Vec<Option<usize>>,
+    /// for each node index, whether several users retain it
+    pub shared: FixedBitSet,
+}
+
+/// Finds which users retain each node of `di`, with `user_of` telling the user of each
+/// gc-root.
+pub fn owners<F: Fn(&DepNode) -> Option<String>>(di: &DepInfos, user_of: F) -> Owners {
+    let mut roots_of_user: BTreeMap<String, Vec<_>> = BTreeMap::new();
+    for root in di.roots() {
+        if let Some(user) = user_of(&di.graph[root]) {
+            roots_of_user.entry(user).or_default().push(root);
+        }
+    }
+    let n = di.graph.node_count();
+    let mut owner = vec![None; n];
+    let mut shared = FixedBitSet::with_capacity(n);
+    let users: Vec<String> = roots_of_user.keys().cloned().collect();
+    for (i, roots) in roots_of_user.into_values().enumerate() {
+        let mut seen = FixedBitSet::with_capacity(n);
+        let mut stack = roots;
+        while let Some(idx) = stack.pop() {
+            if seen.put(idx.index()) {
+                continue;
+            }
+            stack.extend(di.graph.neighbors(idx));
+            match owner[idx.index()] {
+                None if !shared.contains(idx.index()) => owner[idx.index()] = Some(i),
+                Some(j) if j != i => {
+                    owner[idx.index()] = None;
+                    shared.insert(idx.index());
+                }
+                _ => (),
+            }
+        }
+    }
+    Owners {
+        users,
+        owner,
+        shared,
+    }
+}
+
+/// Returns the size of the nodes of `di` reachable from its root which each user of `owners`
+/// retains alone, biggest first, followed by the size retained by several users and the one
+/// retained by no known user.
+pub fn user_sizes(di: &DepInfos, owners: &Owners) -> (Vec<(String, u64)>, u64, u64) {
+    let mut sizes = vec![0; owners.users.len()];
+    let (mut shared, mut unknown) = (0, 0);
+    let mut dfs = di.dfs();
+    while let Some(idx) = dfs.next(&di.graph) {
+        let size = di.graph[idx].size;
+        match owners.owner[idx.index()] {
+            Some(user) => sizes[user] += size,
+            None if owners.shared.contains(idx.index()) => shared += size,
+            None => unknown += size,
+        }
+    }
+    let mut res: Vec<(String, u64)> = owners.users.iter().cloned().zip(sizes).collect();
+    res.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    (res, shared, unknown)
+}
+
+/// Writes the size each user retains alone, one user per line, then the size several users
+/// retain and the one no known user does.
+pub fn render_user_sizes<W: Write>(di: &DepInfos, owners: &Owners, w: &mut W) -> io::Result<()> {
+    let (sizes, shared, unknown) = user_sizes(di, owners);
+    for (user, size) in sizes {
+        writeln!(w, "{}\t{}", ByteSize::b(size), user)?;
+    }
+    writeln!(w, "{}\tshared by several users", ByteSize::b(shared))?;
+    writeln!(w, "{}\tno known user", ByteSize::b(unknown))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::owners::*;
+
+    #[test]
+    fn check_owners() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/nix/var/nix/profiles/per-user/alice/profile-1-link","root":true},
+            {"id":2,"kind":"Link","path":"/home/bob/result","root":true},
+            {"id":3,"path":"/nix/store/a","size":10},
+            {"id":4,"path":"/nix/store/b","size":100},
+            {"id":5,"path":"/nix/store/c","size":1}
+        ],"edges":[[1,3],[2,4],[3,5],[4,5]]}"#;
+        let di = crate::import::graph(text);
+        let owners = owners(&di, |node| user_in_path(node.description.path()?));
+        assert_eq!(owners.users, vec!["alice".to_owned(), "bob".to_owned()]);
+        let of = |path| {
+            let idx = crate::import::node(&di, path);
+            (
+                owners.owner[idx.index()],
+                owners.shared.contains(idx.index()),
+            )
+        };
+        assert_eq!(of("/nix/store/a"), (Some(0), false));
+        assert_eq!(of("/nix/store/b"), (Some(1), false));
+        assert_eq!(of("/nix/store/c"), (None, true));
+        let di = crate::reduction::keep_reachable(di);
+        let owners = super::owners(&di, |node| user_in_path(node.description.path()?));
+        let mut out = Vec::new();
+        render_user_sizes(&di, &owners, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "100 B\tbob\n10 B\talice\n1 B\tshared by several users\n0 B\tno known user\n"
+        );
+    }
+
+    #[test]
+    fn check_group_users() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/nix/var/nix/profiles/per-user/alice/profile-1-link","root":true},
+            {"id":2,"kind":"Link","path":"/nix/var/nix/profiles/per-user/alice/profile-2-link","root":true},
+            {"id":3,"kind":"Link","path":"/home/bob/result","root":true},
+            {"id":4,"path":"/nix/store/a","size":10},
+            {"id":5,"path":"/nix/store/b","size":100}
+        ],"edges":[[1,4],[2,4],[3,5]]}"#;
+        let di = crate::import::graph(text);
+        let mut di = crate::reduction::keep_reachable(di);
+        tag_users(&mut di);
+        let di = crate::reduction::group_roots(di, &[b"/home/".to_vec()]);
+        let di = crate::reduction::group_generations(di);
+        let mut users: Vec<(String, Option<String>)> = di
+            .roots()
+            .map(|idx| {
+                (
+                    String::from_utf8_lossy(&di.label(idx)).into_owned(),
+                    di.graph[idx].user.clone(),
+                )
+            })
+            .collect();
+        users.sort();
+        assert_eq!(
+            users,
+            vec![
+                (
+                    "per-user/alice/profile (2 generations)".to_owned(),
+                    Some("alice".to_owned())
+                ),
+                ("{roots in /home/}".to_owned(), Some("bob".to_owned())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,528 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Planning which gc-roots to delete.
+//!
+//! Deleting a set of gc-roots frees the store paths only these roots depend on. Finding the
+//! best set of `k` roots is hard in general, so roots are chosen greedily: each step deletes
+//! the root freeing the most, given the roots already deleted. The sizes freed after each
+//! step form a Pareto front of the trade-off between the size freed and the number of roots
+//! deleted.
+
+use crate::depgraph::*;
+use crate::inuse::Reason;
+use bytesize::ByteSize;
+use fixedbitset::FixedBitSet;
+use petgraph::prelude::NodeIndex;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// One step of a plan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// the roots to delete at this step: usually one, but several if the first ones only
+    /// free something together with the last one
+    pub roots: Vec<NodeIndex>,
+    /// the number of roots deleted by this step and all previous ones
+    pub deleted: usize,
+    /// the size freed by this step and all previous ones
+    pub freed: u64,
+}
+
+/// Returns whether the user can delete the gc-root `idx`. Only symlinks can be removed:
+/// memory and temporary roots go away with the processes holding them.
+pub fn deletable(di: &DepInfos, idx: NodeIndex) -> bool {
+    di.graph[idx].kind() == NodeKind::Link
+}
+
+/// Returns the size, measured by `sizes`, which deleting roots cannot free, as it is kept
+/// alive by roots which are not `deletable` or are `protected`. The size kept by only one
+/// such root is attributed to it, the size kept by several of them to `None`. Biggest first.
+pub fn non_actionable<F: Fn(NodeIndex) -> bool>(
+    di: &DepInfos,
+    sizes: &[u64],
+    protected: F,
+) -> Vec<(Option<NodeIndex>, u64)> {
+    // the root keeping each node, or None if several do
+    let mut owner: Vec<Option<Option<NodeIndex>>> = vec![None; di.graph.node_count()];
+    for root in di.roots() {
+        if deletable(di, root) && !protected(root) {
+            continue;
+        }
+        let mut dfs = petgraph::visit::Dfs::new(&di.graph, root);
+        while let Some(idx) = dfs.next(&di.graph) {
+            owner[idx.index()] = match owner[idx.index()] {
+                None => Some(Some(root)),
+                Some(_) => Some(None),
+            };
+        }
+    }
+    let mut by_owner: BTreeMap<Option<NodeIndex>, u64> = BTreeMap::new();
+    for (i, owner) in owner.into_iter().enumerate() {
+        if let Some(owner) = owner {
+            *by_owner.entry(owner).or_default() += sizes[i];
+        }
+    }
+    let mut res: Vec<_> = by_owner.into_iter().filter(|&(_, size)| size > 0).collect();
+    res.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+    res
+}
+
+/// Computes greedily the order in which to delete at most `max_roots` roots of `di`.
+/// Roots which would not free anything alone are grouped with the next ones, so each step
+/// is on the Pareto front. Only roots which are `deletable` are chosen.
+pub fn greedy(di: &DepInfos, max_roots: usize) -> Vec<Step> {
+    let sizes: Vec<u64> = di.graph.node_weights().map(|node| node.size).collect();
+    greedy_protecting(di, max_roots, &sizes, |_| false)
+}
+
+/// Same as `greedy`, but the size of nodes is `sizes`, by node index, which must be
+/// additive, and the roots for which `protected` returns `true` are never chosen either,
+/// as if they were not deletable.
+pub fn greedy_protecting<F: Fn(NodeIndex) -> bool>(
+    di: &DepInfos,
+    max_roots: usize,
+    sizes: &[u64],
+    protected: F,
+) -> Vec<Step> {
+    let roots: Vec<NodeIndex> = di
+        .roots()
+        .filter(|&idx| deletable(di, idx) && !protected(idx))
+        .collect();
+    let n = di.graph.node_count();
+    // for each node, the set of candidate roots it is reachable from,
+    // and whether another root keeps it alive anyway
+    let mut keepers = vec![FixedBitSet::with_capacity(roots.len()); n];
+    let mut pinned = FixedBitSet::with_capacity(n);
+    for (i, &root) in roots.iter().enumerate() {
+        let mut dfs = petgraph::visit::Dfs::new(&di.graph, root);
+        while let Some(idx) = dfs.next(&di.graph) {
+            keepers[idx.index()].insert(i);
+        }
+    }
+    for root in di.roots() {
+        if !roots.contains(&root) {
+            let mut dfs = petgraph::visit::Dfs::new(&di.graph, root);
+            while let Some(idx) = dfs.next(&di.graph) {
+                pinned.insert(idx.index());
+            }
+        }
+    }
+
+    let mut deleted = FixedBitSet::with_capacity(roots.len());
+    let mut pending = Vec::new();
+    let mut freed = 0;
+    let mut steps = Vec::new();
+    while deleted.count_ones(..) < max_roots.min(roots.len()) {
+        // what deleting each root would free now, and its share of the nodes which need
+        // more deletions, to break ties
+        let mut gains = vec![(0u64, 0f64); roots.len()];
+        for (idx, keepers) in keepers.iter().enumerate() {
+            if pinned.contains(idx) || keepers.count_ones(..) == 0 {
+                continue;
+            }
+            let mut remaining = keepers.clone();
+            remaining.difference_with(&deleted);
+            let count = remaining.count_ones(..);
+            let size = sizes[idx];
+            for i in remaining.ones() {
+                if count == 1 {
+                    gains[i].0 += size;
+                }
+                gains[i].1 += size as f64 / count as f64;
+            }
+        }
+        let best = (0..roots.len())
+            .filter(|&i| !deleted.contains(i))
+            .max_by(|&a, &b| {
+                gains[a]
+                    .0
+                    .cmp(&gains[b].0)
+                    .then(gains[a].1.total_cmp(&gains[b].1))
+                    .then(b.cmp(&a))
+            });
+        let best = match best {
+            Some(best) if gains[best].1 > 0. => best,
+            _ => break,
+        };
+        deleted.insert(best);
+        pending.push(roots[best]);
+        if gains[best].0 == 0 {
+            // this root only frees something together with roots deleted later
+            continue;
+        }
+        freed += gains[best].0;
+        steps.push(Step {
+            roots: std::mem::take(&mut pending),
+            deleted: deleted.count_ones(..),
+            freed,
+        });
+    }
+    steps
+}
+
+/// Returns the nodes of `di` which deleting the roots `deleted` frees
+pub fn freed_by(di: &DepInfos, deleted: &[NodeIndex]) -> FixedBitSet {
+    let n = di.graph.node_count();
+    let mut alive = FixedBitSet::with_capacity(n);
+    alive.insert(di.root.index());
+    let mut stack: Vec<NodeIndex> = di.roots().filter(|r| !deleted.contains(r)).collect();
+    while let Some(idx) = stack.pop() {
+        if !alive.put(idx.index()) {
+            stack.extend(di.graph.neighbors(idx));
+        }
+    }
+    let mut freed = FixedBitSet::with_capacity(n);
+    freed.insert_range(..);
+    freed.difference_with(&alive);
+    freed
+}
+
+/// Returns whether the profile `profile` is a link to its generation `number`
+fn is_current_generation(profile: &[u8], number: u64) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let profile = std::path::Path::new(std::ffi::OsStr::from_bytes(profile));
+    let generation = match profile.file_name() {
+        Some(name) => format!("{}-{}-link", name.to_string_lossy(), number),
+        None => return false,
+    };
+    match std::fs::read_link(profile) {
+        Ok(target) => target.file_name() == Some(generation.as_ref()),
+        Err(_) => false,
+    }
+}
+
+/// Returns the shell command deleting the gc-root `idx`: `nix-env --delete-generations` for
+/// the generation of a profile, or else `rm` of the link. The current generation of a
+/// profile cannot be deleted, and gets a comment instead.
+pub fn delete_command(di: &DepInfos, idx: NodeIndex) -> Vec<u8> {
+    use crate::logs::shell_quote;
+    let path = di.graph[idx]
+        .description
+        .path()
+        .expect("deletable roots are links");
+    let mut res = Vec::new();
+    match crate::protect::generation(path) {
+        Some((profile, number)) if is_current_generation(profile, number) => {
+            res.extend(b"# not deleting the current generation of: ");
+            res.extend(shell_quote(profile));
+        }
+        Some((profile, number)) => {
+            res.extend(b"nix-env --profile ");
+            res.extend(shell_quote(profile));
+            res.extend(format!(" --delete-generations {}", number).into_bytes());
+        }
+        None => {
+            res.extend(b"rm -- ");
+            res.extend(shell_quote(path));
+        }
+    }
+    res
+}
+
+/// Prints the shell script deleting the roots of `steps`, up to the first step freeing
+/// `target` if given, with what each step frees, and collecting garbage.
+pub fn render_commands<W: Write>(
+    di: &DepInfos,
+    steps: &[Step],
+    target: Option<u64>,
+    w: &mut W,
+) -> io::Result<()> {
+    let steps = match target.and_then(|target| steps.iter().position(|s| s.freed >= target)) {
+        Some(last) => &steps[..=last],
+        None => steps,
+    };
+    writeln!(w, "#!/bin/sh\nset -e")?;
+    if steps.is_empty() {
+        return writeln!(w, "# Deleting gc-roots cannot free anything.");
+    }
+    let mut previous = 0;
+    for step in steps {
+        writeln!(
+            w,
+            "# frees {} ({} in total)",
+            ByteSize::b(step.freed - previous),
+            ByteSize::b(step.freed)
+        )?;
+        previous = step.freed;
+        for &idx in &step.roots {
+            w.write_all(&delete_command(di, idx))?;
+            writeln!(w)?;
+        }
+    }
+    writeln!(w, "nix-store --gc")
+}
+
+/// Returns the reason why the root `idx` is in use, if it is in `in_use`.
+fn in_use_reason<'a>(
+    di: &DepInfos,
+    idx: NodeIndex,
+    in_use: &'a BTreeMap<Path, Reason>,
+) -> Option<&'a Reason> {
+    match &di.graph[idx].description {
+        NodeDescription::Link(path) => in_use.get(path),
+        _ => None,
+    }
+}
+
+/// Prints the Pareto front of the trade-off between the size freed and the number of roots
+/// deleted, measuring nodes by `sizes`. If `target` is given, tells how many roots must be
+/// deleted to free at least this size. The roots in `in_use` are never deleted, unless
+/// `allow_in_use` is set; then they are marked as such. Neither are the link roots in
+/// `defaults`, like those of `protect::default_protections`, by path. If `rebuild`, the
+/// build time of each node in seconds, is not empty, each step tells how long building
+/// again what it frees takes. With `commands`, the plan is printed as a shell script by
+/// `render_commands` instead.
+#[allow(clippy::too_many_arguments)]
+pub fn render<W: Write>(
+    di: &DepInfos,
+    sizes: &[u64],
+    max_roots: usize,
+    target: Option<u64>,
+    in_use: &BTreeMap<Path, Reason>,
+    allow_in_use: bool,
+    defaults: &BTreeMap<Path, &str>,
+    rebuild: &[u64],
+    commands: bool,
+    w: &mut W,
+) -> io::Result<()> {
+    let label = |idx: NodeIndex| String::from_utf8_lossy(&di.label(idx)).into_owned();
+    let default_reason = |idx: NodeIndex| match &di.graph[idx].description {
+        NodeDescription::Link(path) => defaults.get(path),
+        _ => None,
+    };
+    let protected = |idx| {
+        (!allow_in_use && in_use_reason(di, idx, in_use).is_some()) || default_reason(idx).is_some()
+    };
+    if commands {
+        let steps = greedy_protecting(di, max_roots, sizes, protected);
+        return render_commands(di, &steps, target, w);
+    }
+    let by_default: Vec<NodeIndex> = di
+        .roots()
+        .filter(|&idx| default_reason(idx).is_some())
+        .collect();
+    if !by_default.is_empty() {
+        writeln!(
+            w,
+            "Not deleting {} gc-roots protected by default (pass --no-default-protections to \
+             consider them):",
+            by_default.len()
+        )?;
+        for idx in by_default {
+            let reason = default_reason(idx).expect("root is protected");
+            writeln!(w, "\t{}: {}", label(idx), reason)?;
+        }
+    }
+    let kept: Vec<NodeIndex> = di
+        .roots()
+        .filter(|&idx| {
+            !allow_in_use
+                && in_use_reason(di, idx, in_use).is_some()
+                && default_reason(idx).is_none()
+        })
+        .collect();
+    if !kept.is_empty() {
+        writeln!(
+            w,
+            "Not deleting {} gc-roots in use (pass --allow-in-use to consider them):",
+            kept.len()
+        )?;
+        for idx in kept {
+            let reason = in_use_reason(di, idx, in_use).expect("root is in use");
+            writeln!(w, "\t{}: {}", label(idx), reason)?;
+        }
+    }
+    let pinned = non_actionable(di, sizes, protected);
+    if !pinned.is_empty() {
+        let total: u64 = pinned.iter().map(|&(_, size)| size).sum();
+        writeln!(
+            w,
+            "{} is kept alive by gc-roots which cannot be deleted, and is not counted below:",
+            ByteSize::b(total)
+        )?;
+        for (owner, size) in pinned {
+            match owner {
+                Some(idx) => writeln!(w, "\t{}\t{}", ByteSize::b(size), label(idx))?,
+                None => writeln!(w, "\t{}\tshared by several of them", ByteSize::b(size))?,
+            }
+        }
+    }
+    let steps = greedy_protecting(di, max_roots, sizes, protected);
+    if steps.is_empty() {
+        writeln!(w, "Deleting gc-roots cannot free anything.")?;
+        return Ok(());
+    }
+    if rebuild.is_empty() {
+        writeln!(w, "roots\tfreed\tper root\troots to delete")?;
+    } else {
+        writeln!(w, "roots\tfreed\tper root\trebuild\troots to delete")?;
+    }
+    let mut deleted = Vec::new();
+    for step in &steps {
+        deleted.extend_from_slice(&step.roots);
+        let rebuild_time = if rebuild.is_empty() {
+            String::new()
+        } else {
+            let secs: u64 = freed_by(di, &deleted).ones().map(|i| rebuild[i]).sum();
+            format!(
+                "{}\t",
+                crate::procinfo::human_duration(std::time::Duration::from_secs(secs))
+            )
+        };
+        let labels: Vec<_> = step
+            .roots
+            .iter()
+            .map(|&idx| match in_use_reason(di, idx, in_use) {
+                Some(reason) => format!("{} (in use: {})", label(idx), reason),
+                None => label(idx),
+            })
+            .collect();
+        writeln!(
+            w,
+            "{}\t{}\t{}\t{}{}",
+            step.deleted,
+            ByteSize::b(step.freed),
+            ByteSize::b(step.freed / step.deleted as u64),
+            rebuild_time,
+            labels.join(", ")
+        )?;
+    }
+    let best_ratio = steps
+        .iter()
+        .max_by_key(|step| step.freed / step.deleted as u64)
+        .unwrap();
+    writeln!(
+        w,
+        "The most freed per root deleted is with the first {} roots.",
+        best_ratio.deleted
+    )?;
+    if let Some(target) = target {
+        match steps.iter().find(|step| step.freed >= target) {
+            Some(step) => writeln!(
+                w,
+                "Deleting the first {} roots frees at least {}.",
+                step.deleted,
+                ByteSize::b(target)
+            )?,
+            None => writeln!(
+                w,
+                "Could not find how to free {} with at most {} roots.",
+                ByteSize::b(target),
+                max_roots
+            )?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plan::*;
+
+    #[test]
+    fn check_greedy() {
+        // r1 alone keeps 10, r2 and r3 share 100, r3 alone keeps 5, the transient root
+        // keeps 1000 with r1
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"kind":"Link","path":"/r3","root":true},
+            {"id":4,"kind":"Memory","path":"/proc/1","root":true},
+            {"id":5,"path":"/nix/store/a","size":10},
+            {"id":6,"path":"/nix/store/b","size":100},
+            {"id":7,"path":"/nix/store/c","size":5},
+            {"id":8,"path":"/nix/store/d","size":1000}
+        ],"edges":[[1,5],[2,6],[3,6],[3,7],[1,8],[4,8]]}"#;
+        let di = crate::import::graph(text);
+        let steps = greedy(&di, 10);
+        let freed: Vec<u64> = steps.iter().map(|s| s.freed).collect();
+        assert_eq!(freed, vec![10, 15, 115]);
+        assert_eq!(steps[2].deleted, 3);
+        assert_eq!(greedy(&di, 2).len(), 2);
+        // when r3 is protected, it keeps b and c alive, so only r1 frees something; here
+        // measured by another metric which doubles sizes
+        let r3 = crate::import::node(&di, "/r3");
+        let sizes: Vec<u64> = di.graph.node_weights().map(|node| 2 * node.size).collect();
+        let freed: Vec<u64> = greedy_protecting(&di, 10, &sizes, |idx| idx == r3)
+            .iter()
+            .map(|s| s.freed)
+            .collect();
+        assert_eq!(freed, vec![20]);
+        // deleting roots can never free d, kept by the transient root, nor, when r3 is
+        // protected, b and c
+        let memory = di.roots().find(|&idx| !deletable(&di, idx)).unwrap();
+        let own: Vec<u64> = di.graph.node_weights().map(|node| node.size).collect();
+        assert_eq!(
+            non_actionable(&di, &own, |_| false),
+            vec![(Some(memory), 1000)]
+        );
+        assert_eq!(
+            non_actionable(&di, &own, |idx| idx == r3),
+            vec![(Some(memory), 1000), (Some(r3), 105)]
+        );
+        // deleting r1 frees r1 and a, but not d, and deleting r2 alone frees only r2
+        let r1 = crate::import::node(&di, "/r1");
+        let freed: Vec<u64> = freed_by(&di, &[r1]).ones().map(|i| own[i]).collect();
+        assert_eq!(freed, vec![0, 10]);
+        assert_eq!(freed_by(&di, &[r1, r3]).count_ones(..), 4);
+    }
+
+    #[test]
+    fn check_render_commands() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/nix/var/nix/profiles/system-3-link","root":true},
+            {"id":2,"kind":"Link","path":"/home/alice/it's a result","root":true},
+            {"id":3,"path":"/nix/store/a","size":100},
+            {"id":4,"path":"/nix/store/b","size":10}
+        ],"edges":[[1,3],[2,4]]}"#;
+        let di = crate::import::graph(text);
+        let steps = greedy(&di, 10);
+        let script = |target| {
+            let mut out = Vec::new();
+            render_commands(&di, &steps, target, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            script(None),
+            "#!/bin/sh\nset -e\n\
+             # frees 100 B (100 B in total)\n\
+             nix-env --profile '/nix/var/nix/profiles/system' --delete-generations 3\n\
+             # frees 10 B (110 B in total)\n\
+             rm -- '/home/alice/it'\\''s a result'\n\
+             nix-store --gc\n"
+        );
+        assert_eq!(script(Some(50)).matches("# frees").count(), 1);
+    }
+
+    #[test]
+    fn check_delete_command() {
+        use std::os::unix::ffi::OsStringExt;
+        let dir = std::env::temp_dir().join(format!("nix-du-plan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::os::unix::fs::symlink("profile-2-link", dir.join("profile")).unwrap();
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"kind":"Link","path":"/r3","root":true},
+            {"id":4,"path":"/nix/store/a","size":1}
+        ],"edges":[[1,4],[2,4],[3,4]]}"#;
+        let mut di = crate::import::graph(text);
+        let roots: Vec<NodeIndex> = di.roots().collect();
+        let generation = |number| {
+            let mut path = dir.join("profile").into_os_string().into_vec();
+            path.extend(format!("-{}-link", number).into_bytes());
+            path
+        };
+        di.graph[roots[0]].description = NodeDescription::Link(b"/home/alice/r\xe9sult".to_vec());
+        di.graph[roots[1]].description = NodeDescription::Link(generation(1));
+        di.graph[roots[2]].description = NodeDescription::Link(generation(2));
+        // the path is not altered, even if not UTF-8
+        assert_eq!(
+            delete_command(&di, roots[0]),
+            b"rm -- '/home/alice/r\xe9sult'".to_vec()
+        );
+        assert!(delete_command(&di, roots[1]).ends_with(b"' --delete-generations 1"));
+        assert!(delete_command(&di, roots[2]).starts_with(b"# "));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Estimating how much installing something would add to the store, before building it.
+//!
+//! The closure of the outputs is queried with `nix path-info`, from the local store if the
+//! outputs are already there, or else from the narinfo files of a substituter. Store paths
+//! which are already in the store do not cost anything.
+
+use crate::depgraph::*;
+use crate::json::{self, Value};
+use bytesize::ByteSize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::process::Command;
+
+/// Runs `cmd` and returns its standard output, or an error mentioning its standard error.
+pub fn output_of(cmd: &mut Command) -> io::Result<Vec<u8>> {
+    let out = cmd.output()?;
+    if out.status.success() {
+        Ok(out.stdout)
+    } else {
+        Err(io::Error::other(format!(
+            "{:?} failed with {}: {}",
+            cmd,
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )))
+    }
+}
+
+/// A `nix` command with the experimental CLI enabled
+pub fn nix() -> Command {
+    let mut cmd = Command::new("nix");
+    cmd.args(["--extra-experimental-features", "nix-command flakes"]);
+    cmd
+}
+
+/// Returns the output paths of `what`: a store path, a derivation, or an installable like
+/// `nixpkgs#hello`.
+pub fn outputs(what: &str) -> io::Result<Vec<String>> {
+    let out = if what.ends_with(".drv") {
+        output_of(Command::new("nix-store").args(["--query", "--outputs", what]))?
+    } else if what.starts_with('/') {
+        return Ok(vec![what.to_owned()]);
+    } else {
+        let mut out = output_of(nix().args(["eval", "--raw", &format!("{}.outPath", what)]))?;
+        out.push(b'\n');
+        out
+    };
+    Ok(String::from_utf8_lossy(&out)
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Parses the output of `nix path-info --json` into the NAR size of each path. Handles both
+/// the list of objects of nix < 2.19 and the object indexed by paths of later versions.
+pub fn parse_path_info(data: &[u8]) -> io::Result<BTreeMap<String, u64>> {
+    parse_path_info_field(data, "narSize")
+}
+
+/// Same as `parse_path_info`, but returns the integer `field` of each path, like
+/// `downloadSize` for paths of a binary cache. Invalid paths, and paths without `field`, are
+/// omitted, but all valid paths must have a `narSize`.
+pub fn parse_path_info_field(data: &[u8], field: &str) -> io::Result<BTreeMap<String, u64>> {
+    parse_path_info_with(data, field, Value::as_u64)
+}
+
+/// Same as `parse_path_info_field`, for a `field` of any type, converted by `convert`.
+pub fn parse_path_info_with<T, F: Fn(&Value) -> Option<T>>(
+    data: &[u8],
+    field: &str,
+    convert: F,
+) -> io::Result<BTreeMap<String, T>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected output of nix path-info",
+        )
+    };
+    let value = |info: &Value| match info.get(field) {
+        Some(v) => convert(v).map(Some).ok_or_else(invalid),
+        // newer versions of nix report invalid paths as null
+        None if field != "narSize" || *info == Value::Null => Ok(None),
+        None => Err(invalid()),
+    };
+    let mut res = BTreeMap::new();
+    match json::parse(data)? {
+        Value::Array(infos) => {
+            for info in &infos {
+                let path = info
+                    .get("path")
+                    .and_then(Value::as_str)
+                    .ok_or_else(invalid)?;
+                if let Some(v) = value(info)? {
+                    res.insert(path.to_owned(), v);
+                }
+            }
+        }
+        Value::Object(infos) => {
+            for (path, info) in &infos {
+                if let Some(v) = value(info)? {
+                    res.insert(path.clone(), v);
+                }
+            }
+        }
+        _ => return Err(invalid()),
+    }
+    Ok(res)
+}
+
+/// Returns the NAR size of each path in the closure of `paths`, from the local store, or
+/// from `substituter` if some of them are not valid locally.
+pub fn closure(paths: &[String], substituter: &str) -> io::Result<BTreeMap<String, u64>> {
+    let query = |store: Option<&str>| {
+        let mut cmd = nix();
+        cmd.args(["path-info", "--json", "--recursive"]);
+        if let Some(store) = store {
+            cmd.args(["--store", store]);
+        }
+        output_of(cmd.args(paths)).and_then(|out| parse_path_info(&out))
+    };
+    query(None).or_else(|_| query(Some(substituter)))
+}
+
+/// What installing some store paths would cost
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cost {
+    /// size and number of paths of the closure
+    pub closure: (u64, usize),
+    /// size and number of the paths of the closure which are not in the store yet
+    pub missing: (u64, usize),
+}
+
+/// Computes the cost of adding `closure` to the store of `di`.
+pub fn cost(di: &DepInfos, closure: &BTreeMap<String, u64>) -> Cost {
+    let present: BTreeSet<&[u8]> = di
+        .graph
+        .node_weights()
+        .filter_map(|node| match &node.description {
+            NodeDescription::Path(path) => Some(path.as_slice()),
+            _ => None,
+        })
+        .collect();
+    let mut res = Cost::default();
+    for (path, &size) in closure {
+        res.closure.0 += size;
+        res.closure.1 += 1;
+        if !present.contains(path.as_bytes()) {
+            res.missing.0 += size;
+            res.missing.1 += 1;
+        }
+    }
+    res
+}
+
+/// Prints what installing `what` would add to the store of `di`.
+pub fn render<W: Write>(di: &DepInfos, what: &str, substituter: &str, w: &mut W) -> io::Result<()> {
+    let outputs = outputs(what)?;
+    let closure = closure(&outputs, substituter).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "{}\nIt may not be substitutable from {}, and would have to be built.",
+                e, substituter
+            ),
+        )
+    })?;
+    let cost = cost(di, &closure);
+    writeln!(
+        w,
+        "Closure of {}: {} in {} store paths",
+        outputs.join(", "),
+        ByteSize::b(cost.closure.0),
+        cost.closure.1
+    )?;
+    writeln!(
+        w,
+        "Not yet in the store: {} in {} store paths",
+        ByteSize::b(cost.missing.0),
+        cost.missing.1
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::preview::*;
+
+    #[test]
+    fn check_cost() {
+        let old = br#"[{"path":"/nix/store/a","narSize":10},{"path":"/nix/store/b","narSize":5}]"#;
+        let new = br#"{"/nix/store/a":{"narSize":10},"/nix/store/b":{"narSize":5}}"#;
+        let closure = parse_path_info(old).unwrap();
+        assert_eq!(closure, parse_path_info(new).unwrap());
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r","root":true},
+            {"id":2,"path":"/nix/store/a","size":10}
+        ],"edges":[[1,2]]}"#;
+        let di = crate::import::graph(text);
+        assert_eq!(
+            cost(&di, &closure),
+            Cost {
+                closure: (15, 2),
+                missing: (5, 1)
+            }
+        );
+    }
+}
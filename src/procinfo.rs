@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! The processes owning memory and temporary roots, and for how long they have been
+//! running.
+//!
+//! A temporary root only lasts as long as its process, but a stuck `nix-build` can pin a
+//! large closure for days; killing it is then often the real fix for a full disk.
+
+use crate::depgraph::*;
+use petgraph::prelude::NodeIndex;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Processes running for longer than this are flagged
+pub const LONG_RUNNING: Duration = Duration::from_secs(24 * 3600);
+/// Processes pinning less than this are not flagged
+pub const LARGE_CLOSURE: u64 = 1_000_000_000;
+
+/// A running process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Process {
+    pub pid: u32,
+    /// the name of its executable, as in `/proc/PID/comm`
+    pub command: String,
+    /// how long it has been running
+    pub running: Duration,
+}
+
+/// Returns the pid of the process owning a transient root: `{temp:PID}`, or `/proc/PID/...`
+pub fn pid_of(description: &NodeDescription) -> Option<u32> {
+    let digits = match description {
+        NodeDescription::Temporary(path) => path.strip_prefix(b"{temp:")?.strip_suffix(b"}")?,
+        NodeDescription::Memory(path) => {
+            let rest = path.strip_prefix(b"/proc/")?;
+            &rest[..rest.iter().position(|&c| c == b'/')?]
+        }
+        _ => return None,
+    };
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// Returns the start time of a process, in clock ticks since boot, from the contents of
+/// `/proc/PID/stat`. Its second field, the command, may contain spaces and parentheses.
+pub fn parse_start_time(stat: &str) -> Option<u64> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    // rest starts with the third field, and the start time is the 22nd
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Looks up the process `pid`, if it is still running
+pub fn process(pid: u32) -> Option<Process> {
+    let dir = std::path::PathBuf::from(format!("/proc/{}", pid));
+    let start = parse_start_time(&std::fs::read_to_string(dir.join("stat")).ok()?)?;
+    let uptime = std::fs::read_to_string("/proc/uptime").ok()?;
+    let uptime: f64 = uptime.split_whitespace().next()?.parse().ok()?;
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks <= 0 {
+        return None;
+    }
+    let command = std::fs::read_to_string(dir.join("comm")).ok()?;
+    Some(Process {
+        pid,
+        command: command.trim_end().to_owned(),
+        running: Duration::from_secs_f64((uptime - start as f64 / ticks as f64).max(0.)),
+    })
+}
+
+/// Records in each memory and temporary root of `di` the process holding it, if it is still
+/// running. Only the store of the running system has its processes here.
+pub fn record_processes(di: &mut DepInfos) {
+    for node in di.graph.node_weights_mut() {
+        if let Some(process) = pid_of(&node.description).and_then(process) {
+            node.process = Some(format!(
+                "{}, running for {}",
+                process.command,
+                human_duration(process.running)
+            ));
+        }
+    }
+}
+
+/// Returns the systemd unit of a process from the contents of `/proc/PID/cgroup`: the
+/// innermost service or scope of its cgroup, like `hydra-evaluator.service` in
+/// `0::/system.slice/hydra-evaluator.service`.
+pub fn parse_unit(cgroup: &str) -> Option<String> {
+    let line = cgroup
+        .lines()
+        .find(|line| line.starts_with("0::"))
+        .or_else(|| cgroup.lines().find(|line| line.contains(":name=systemd:")))?;
+    let (_, path) = line.rsplit_once(':')?;
+    path.rsplit('/')
+        .find(|unit| unit.ends_with(".service") || unit.ends_with(".scope"))
+        .map(str::to_owned)
+}
+
+/// Looks up the systemd unit of the process `pid`
+pub fn unit_of(pid: u32) -> Option<String> {
+    parse_unit(&std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?)
+}
+
+/// Returns the size of the union of the closures of `roots`
+pub fn closure_size(di: &DepInfos, roots: Vec<NodeIndex>) -> u64 {
+    let mut seen = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    let mut stack = roots;
+    let mut size = 0;
+    while let Some(idx) = stack.pop() {
+        if !seen.put(idx.index()) {
+            size += di.graph[idx].size;
+            stack.extend(di.graph.neighbors(idx));
+        }
+    }
+    size
+}
+
+/// Formats a duration as `5m`, `3h` or `2d`
+pub fn human_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs < 3600 {
+        format!("{}m", secs / 60)
+    } else if secs < 24 * 3600 {
+        format!("{}h", secs / 3600)
+    } else {
+        format!("{}d", secs / (24 * 3600))
+    }
+}
+
+/// Returns the processes owning the transient roots of `di`, with the size of the union of
+/// the closures of their roots, sorted by decreasing size. Processes which are no longer
+/// running are omitted.
+pub fn pinned_by_processes(di: &DepInfos) -> Vec<(Process, u64)> {
+    let mut roots: BTreeMap<u32, Vec<NodeIndex>> = BTreeMap::new();
+    for idx in di.graph.node_indices() {
+        if let Some(pid) = pid_of(&di.graph[idx].description) {
+            roots.entry(pid).or_default().push(idx);
+        }
+    }
+    let mut res: Vec<(Process, u64)> = roots
+        .into_iter()
+        .filter_map(|(pid, roots)| Some((process(pid)?, closure_size(di, roots))))
+        .collect();
+    res.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::procinfo::*;
+
+    #[test]
+    fn check_pid_of() {
+        use NodeDescription::*;
+        assert_eq!(pid_of(&Temporary(b"{temp:1234}".to_vec())), Some(1234));
+        assert_eq!(pid_of(&Memory(b"/proc/42/maps".to_vec())), Some(42));
+        assert_eq!(pid_of(&Memory(b"{lsof}".to_vec())), None);
+        assert_eq!(pid_of(&Path(b"/proc/42/maps".to_vec())), None);
+    }
+
+    #[test]
+    fn check_parse_start_time() {
+        let stat = "1234 (nix (build) x) S 1 1234 1234 0 -1 4194560 100 0 0 0 5 3 0 0 20 0 1 0 \
+                    987654 12345 678 18446744073709551615";
+        assert_eq!(parse_start_time(stat), Some(987654));
+        let me = process(std::process::id()).unwrap();
+        assert!(me.running < LONG_RUNNING);
+        assert_eq!(human_duration(Duration::from_secs(3 * 24 * 3600 + 5)), "3d");
+    }
+
+    #[test]
+    fn check_record_processes() {
+        let text = format!(
+            r#"{{"nodes":[
+                {{"id":1,"kind":"Temporary","path":"{{temp:{}}}","root":true}},
+                {{"id":2,"path":"/nix/store/a","size":1}}
+            ],"edges":[[1,2]]}}"#,
+            std::process::id()
+        );
+        let mut di = crate::import::graph(text.as_bytes());
+        let temp = di.roots().next().unwrap();
+        // labels do not look processes up
+        assert_eq!(
+            di.graph[temp].name().as_ref(),
+            di.graph[temp].description.name().as_ref()
+        );
+        record_processes(&mut di);
+        let name = String::from_utf8(di.graph[temp].name().into_owned()).unwrap();
+        assert!(name.contains(", running for "), "{}", name);
+    }
+
+    #[test]
+    fn check_parse_unit() {
+        assert_eq!(
+            parse_unit("0::/system.slice/hydra-evaluator.service\n").as_deref(),
+            Some("hydra-evaluator.service")
+        );
+        assert_eq!(
+            parse_unit("0::/user.slice/user-1000.slice/session-2.scope").as_deref(),
+            Some("session-2.scope")
+        );
+        assert_eq!(
+            parse_unit("12:cpu:/\n1:name=systemd:/system.slice/nix-daemon.service/build")
+                .as_deref(),
+            Some("nix-daemon.service")
+        );
+        assert_eq!(parse_unit("0::/"), None);
+    }
+}
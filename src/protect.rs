@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! The gc-roots which planning never deletes by default, so that following a plan blindly
+//! cannot remove the ability to roll back:
+//!  * those of the current and of the booted NixOS system;
+//!  * the newest generation of each profile, like `/nix/var/nix/profiles/system-42-link`
+//!    among the `system-N-link`;
+//!  * those created less than a day ago, likely still being worked with.
+
+use crate::depgraph::*;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
+use std::time::Duration;
+
+/// Roots younger than this are protected
+pub const RECENT: Duration = Duration::from_secs(24 * 3600);
+
+/// The links to the NixOS systems whose roots are protected
+pub const SYSTEMS: &[&str] = &["/run/current-system", "/run/booted-system"];
+
+/// Returns the store paths of the NixOS systems of `SYSTEMS` which exist here
+pub fn system_paths() -> Vec<Path> {
+    SYSTEMS
+        .iter()
+        .filter_map(|link| std::fs::canonicalize(link).ok())
+        .map(|path| path.as_os_str().as_bytes().to_vec())
+        .collect()
+}
+
+/// Returns how long ago the link of a gc-root was created, if it is a link of known age
+pub fn link_age(node: &DepNode) -> Option<Duration> {
+    if node.kind() != NodeKind::Link {
+        return None;
+    }
+    node.age?.elapsed().ok()
+}
+
+/// Splits the path of a profile generation, like `/nix/var/nix/profiles/system-42-link`,
+/// into its profile and its number
+pub fn generation(path: &[u8]) -> Option<(&[u8], u64)> {
+    lazy_static! {
+        static ref GENERATION: regex::bytes::Regex =
+            regex::bytes::Regex::new(r"^(.*)-([0-9]+)-link$").unwrap();
+    }
+    let captures = GENERATION.captures(path)?;
+    let number = std::str::from_utf8(captures.get(2)?.as_bytes())
+        .ok()?
+        .parse()
+        .ok()?;
+    Some((captures.get(1)?.as_bytes(), number))
+}
+
+/// Returns the link roots of `di`, a graph as read from the store, protected by default,
+/// by path, with the reason why. `systems` are the store paths of the NixOS systems to
+/// protect, and `age` tells how old a root is.
+pub fn default_protections<F: Fn(&DepNode) -> Option<Duration>>(
+    di: &DepInfos,
+    systems: &[Path],
+    age: F,
+) -> BTreeMap<Path, &'static str> {
+    let mut res = BTreeMap::new();
+    // for each profile, its newest generation
+    let mut newest: BTreeMap<&[u8], (u64, &Path)> = BTreeMap::new();
+    for idx in di.roots() {
+        let node = &di.graph[idx];
+        let path = match &node.description {
+            NodeDescription::Link(path) => path,
+            _ => continue,
+        };
+        if di.graph.neighbors(idx).any(
+            |n| matches!(&di.graph[n].description, NodeDescription::Path(p) if systems.contains(p)),
+        ) {
+            res.insert(path.clone(), "current or booted system");
+        } else if matches!(age(node), Some(age) if age < RECENT) {
+            res.insert(path.clone(), "created less than a day ago");
+        }
+        if let Some((profile, number)) = generation(path) {
+            let entry = newest.entry(profile).or_insert((number, path));
+            if number > entry.0 {
+                *entry = (number, path);
+            }
+        }
+    }
+    for (_, path) in newest.into_values() {
+        res.entry(path.clone())
+            .or_insert("newest generation of its profile");
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protect::*;
+
+    #[test]
+    fn check_default_protections() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/nix/var/nix/profiles/system-9-link","root":true},
+            {"id":2,"kind":"Link","path":"/nix/var/nix/profiles/system-10-link","root":true},
+            {"id":3,"kind":"Link","path":"/nix/var/nix/profiles/system-8-link","root":true},
+            {"id":4,"kind":"Link","path":"/home/alice/result","root":true},
+            {"id":5,"kind":"Link","path":"/home/alice/old-result","root":true},
+            {"id":6,"path":"/nix/store/a-nixos-system-9","size":1},
+            {"id":7,"path":"/nix/store/b-nixos-system-10","size":1},
+            {"id":8,"path":"/nix/store/c-nixos-system-8","size":1},
+            {"id":9,"path":"/nix/store/d-hello","size":1}
+        ],"edges":[[1,6],[2,7],[3,8],[4,9],[5,9]]}"#;
+        let di = crate::import::graph(text);
+        let protections = default_protections(
+            &di,
+            &[b"/nix/store/a-nixos-system-9".to_vec()],
+            |node| match node.description.path()?.as_slice() {
+                b"/home/alice/result" => Some(Duration::from_secs(60)),
+                _ => Some(Duration::from_secs(7 * 24 * 3600)),
+            },
+        );
+        let protections: Vec<(&[u8], &str)> = protections
+            .iter()
+            .map(|(path, &reason)| (path.as_slice(), reason))
+            .collect();
+        assert_eq!(
+            protections,
+            vec![
+                (&b"/home/alice/result"[..], "created less than a day ago"),
+                (
+                    &b"/nix/var/nix/profiles/system-10-link"[..],
+                    "newest generation of its profile"
+                ),
+                (
+                    &b"/nix/var/nix/profiles/system-9-link"[..],
+                    "current or booted system"
+                ),
+            ]
+        );
+        assert_eq!(
+            generation(b"/a/profile-3-link"),
+            Some((&b"/a/profile"[..], 3))
+        );
+        assert_eq!(generation(b"/a/profile"), None);
+    }
+}
@@ -2,9 +2,13 @@
 
 use std::collections;
 use std::hash::Hasher;
+use std::time::{Duration, SystemTime};
 use std::{self, hash::Hash};
 
+use fixedbitset::FixedBitSet;
+use petgraph::prelude::NodeIndex;
 use petgraph::visit::{DfsPostOrder, EdgeFiltered, EdgeRef, IntoEdgeReferences};
+use rayon::prelude::*;
 
 use crate::depgraph::*;
 
@@ -27,17 +31,189 @@ pub fn merge_transient_roots(mut di: DepInfos) -> DepInfos {
 
     let fake_root_idx = di.graph.add_node(DepNode {
         description: NodeDescription::Transient,
-        size: 0,
+        ..DepNode::dummy()
     });
-    di.graph.add_edge(di.root, fake_root_idx, ());
+    di.graph.add_edge(di.root, fake_root_idx, Edge::Root);
     for idx in targets {
         let edx = di.graph.find_edge(di.root, idx).unwrap();
         di.graph.remove_edge(edx);
-        di.graph.add_edge(fake_root_idx, idx, ());
+        di.graph.add_edge(fake_root_idx, idx, Edge::Synthetic);
     }
     di
 }
 
+/// Merges the in memory and temporary roots of each process `process` finds running into one
+/// root per process, so that what they retain is shown as held by it. The temporary roots
+/// of a nix-daemon worker are those of the client session it serves. The roots of unknown
+/// processes are left for `merge_transient_roots`.
+/// noop if the graph is rooted in a fs node
+pub fn group_transient_roots<F: Fn(u32) -> Option<crate::procinfo::Process>>(
+    mut di: DepInfos,
+    process: F,
+) -> DepInfos {
+    if di.graph[di.root].kind() != NodeKind::Dummy {
+        return di;
+    }
+
+    let mut by_pid: collections::BTreeMap<u32, Vec<NodeIndex>> = collections::BTreeMap::new();
+    for idx in di.roots() {
+        if !di.graph[idx].kind().is_transient() {
+            continue;
+        }
+        if let Some(pid) = crate::procinfo::pid_of(&di.graph[idx].description) {
+            by_pid.entry(pid).or_default().push(idx);
+        }
+    }
+    for (pid, targets) in by_pid {
+        let process = match process(pid) {
+            Some(process) => process,
+            None => continue,
+        };
+        let label = if process.command == "nix-daemon" {
+            format!("nix-daemon session (pid {})", pid)
+        } else {
+            format!("{} (pid {})", process.command, pid)
+        };
+        let group = di.graph.add_node(DepNode {
+            description: NodeDescription::Process(label.into_bytes()),
+            ..DepNode::dummy()
+        });
+        di.graph.add_edge(di.root, group, Edge::Root);
+        for idx in targets {
+            let edx = di.graph.find_edge(di.root, idx).unwrap();
+            di.graph.remove_edge(edx);
+            di.graph.add_edge(group, idx, Edge::Synthetic);
+        }
+    }
+    di
+}
+
+/// Whether `path` is `prefix` or inside the directory `prefix`: `/home/al` contains
+/// `/home/al/result` but not `/home/alice`.
+fn is_under(path: &[u8], prefix: &[u8]) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    std::path::Path::new(std::ffi::OsStr::from_bytes(path))
+        .starts_with(std::ffi::OsStr::from_bytes(prefix))
+}
+
+/// Merges the link roots whose path is in one of the directories `prefixes` into one root
+/// per prefix. When several prefixes match, the longest one is chosen.
+/// noop if the graph is rooted in a fs node
+pub fn group_roots(mut di: DepInfos, prefixes: &[Path]) -> DepInfos {
+    if di.graph[di.root].kind() != NodeKind::Dummy {
+        return di;
+    }
+
+    let targets: Vec<(NodeIndex, usize)> = di
+        .roots()
+        .filter_map(|idx| {
+            let path = match &di.graph[idx].description {
+                NodeDescription::Link(path) => path,
+                _ => return None,
+            };
+            prefixes
+                .iter()
+                .enumerate()
+                .filter(|(_, prefix)| is_under(path, prefix))
+                .max_by_key(|(_, prefix)| prefix.len())
+                .map(|(i, _)| (idx, i))
+        })
+        .collect();
+
+    // the node of each prefix, created on demand
+    let mut groups: Vec<Option<NodeIndex>> = vec![None; prefixes.len()];
+    for (idx, i) in targets {
+        let group = *groups[i].get_or_insert_with(|| {
+            let group = di.graph.add_node(DepNode {
+                description: NodeDescription::Group(prefixes[i].clone()),
+                ..DepNode::dummy()
+            });
+            di.graph.add_edge(di.root, group, Edge::Root);
+            group
+        });
+        let edx = di.graph.find_edge(di.root, idx).unwrap();
+        di.graph.remove_edge(edx);
+        di.graph.add_edge(group, idx, Edge::Synthetic);
+        di.graph[group].age = di.graph[group].age.max(di.graph[idx].age);
+    }
+    for group in groups.into_iter().flatten() {
+        let members = di.graph.neighbors(group).map(|idx| &di.graph[idx]);
+        di.graph[group].user = crate::owners::common_user(members);
+    }
+    di
+}
+
+/// Merges the link roots which are generations of the same profile, like
+/// `/nix/var/nix/profiles/system-42-link`, into one root per profile, when it has several.
+/// noop if the graph is rooted in a fs node
+pub fn group_generations(mut di: DepInfos) -> DepInfos {
+    if di.graph[di.root].kind() != NodeKind::Dummy {
+        return di;
+    }
+
+    let mut profiles: collections::BTreeMap<Path, Vec<NodeIndex>> = collections::BTreeMap::new();
+    for idx in di.roots() {
+        if let NodeDescription::Link(path) = &di.graph[idx].description {
+            if let Some((profile, _)) = crate::protect::generation(path) {
+                profiles.entry(profile.to_vec()).or_default().push(idx);
+            }
+        }
+    }
+
+    for (profile, generations) in profiles {
+        if generations.len() < 2 {
+            continue;
+        }
+        let group = di.graph.add_node(DepNode {
+            description: NodeDescription::Generations(profile, generations.len() as u32),
+            ..DepNode::dummy()
+        });
+        di.graph.add_edge(di.root, group, Edge::Root);
+        for idx in generations {
+            let edx = di.graph.find_edge(di.root, idx).unwrap();
+            di.graph.remove_edge(edx);
+            di.graph.add_edge(group, idx, Edge::Synthetic);
+            di.graph[group].age = di.graph[group].age.max(di.graph[idx].age);
+        }
+        let members = di.graph.neighbors(group).map(|idx| &di.graph[idx]);
+        di.graph[group].user = crate::owners::common_user(members);
+    }
+    di
+}
+
+/// Retains only the nodes which gc-roots older than `age` at `now` retain, and no other
+/// gc-root. Gc-roots of unknown age, like running processes, count as young.
+/// noop if the graph is rooted in a fs node
+pub fn keep_older_than(mut di: DepInfos, age: Duration, now: SystemTime) -> DepInfos {
+    if di.graph[di.root].kind() != NodeKind::Dummy {
+        return di;
+    }
+
+    let is_young = |node: &DepNode| match node.age.map(|t| now.duration_since(t)) {
+        Some(Ok(elapsed)) => elapsed < age,
+        _ => true,
+    };
+    // marks what young gc-roots retain
+    let mut dfs = petgraph::visit::Dfs::empty(&di.graph);
+    let young: Vec<NodeIndex> = di.roots().filter(|&idx| is_young(&di.graph[idx])).collect();
+    for root in young {
+        dfs.move_to(root);
+        while dfs.next(&di.graph).is_some() {}
+    }
+    let retained = dfs.discovered;
+    di.graph
+        .retain_edges(|g, edx| !retained.contains(g.edge_endpoints(edx).unwrap().1.index()));
+    let mut di = keep_reachable(di);
+    // what young gc-roots retain is no longer reachable
+    for sizes in di.metadata.size.values_mut() {
+        for size in sizes.values_mut() {
+            *size = None;
+        }
+    }
+    di.record_metadata();
+    di
+}
+
 /// Transitive reduction
 ///
 /// Handles cycles by removing back edges first, then doing tred on the resulting dag, and then
@@ -85,22 +261,21 @@ pub fn transitive_reduction(mut di: DepInfos) -> DepInfos {
     // ... the back edges and edges to roots
     for e in di.graph.edge_references() {
         if is_back_edge(e) || e.source() == di.root {
-            new.add_edge(e.source(), e.target(), ());
+            new.add_edge(e.source(), e.target(), *e.weight());
         }
     }
     // ... and the edges of tred
     for e in tred.edge_references() {
-        new.add_edge(
-            toposort[e.source() as usize],
-            toposort[e.target() as usize],
-            (),
-        );
+        let (from, to) = (toposort[e.source() as usize], toposort[e.target() as usize]);
+        // tred only keeps edges of the graph
+        let edge = di.graph.find_edge(from, to).expect("tred added an edge");
+        new.add_edge(from, to, di.graph[edge]);
     }
     std::mem::swap(&mut di.graph, &mut new);
     di
 }
 
-fn hash(state: u128, value: impl std::hash::Hash + Copy) -> u128 {
+pub(crate) fn hash(state: u128, value: impl std::hash::Hash + Copy) -> u128 {
     let mut hasher = std::collections::hash_map::DefaultHasher::default();
     state.hash(&mut hasher);
     ("first", value).hash(&mut hasher);
@@ -147,39 +322,135 @@ fn test_hash_larger() {
 /// equivalence class which have a corresponding edge in `G`.
 ///
 /// Complexity: with n vertices, m edges and r roots:
-/// * n+m in space
-/// * (n+m)*r in time
+/// * n+m+n*r/8 bytes in space
+/// * (n+m)*r/64 in time, as sets of roots are propagated as bitsets in one pass, then
+///   hashed on all cores
 ///
 /// Expected simplification: as I write theses lines, on my store (`NixOS`, 37G)
 /// * before: n=37594, m=262914
 /// * after `condense`: n=61, m=211
-pub fn condense(mut di: DepInfos) -> DepInfos {
-    // I don't like non-deterministic algorithms. they are a nightmare to debug.
-    // But we rely on the hash of roots behaving like a random variable.
-    // So we seed the hash with the graph.
-    // Unfortunately, petgraph::Graph does not implement Hash, so let's do it
-    // by hand.
-    // hashing nodes is enough, if edges change then some store paths must also change.
-    let mut start_hash = 0;
-    for node in di.graph.raw_nodes() {
-        start_hash = hash(start_hash, &node.weight);
+pub fn condense(di: DepInfos) -> DepInfos {
+    condense_relative_to(di, |_| true)
+}
+
+/// Same as `condense`, but `roots(v)` only contains the roots for which `is_reference`
+/// returns `true`. Other roots are treated as ordinary nodes, and the nodes which
+/// no chosen root depends on are gathered in one node.
+pub fn condense_relative_to<T: Fn(&DepNode) -> bool>(di: DepInfos, is_reference: T) -> DepInfos {
+    let classes = root_classes(&di, is_reference);
+    quotient(di, &classes)
+}
+
+/// Same as `condense`, but only merges nodes of the same kind, so that a gc-root is never
+/// merged with the store paths only it retains, which hides which part is the deletable
+/// link.
+pub fn condense_kind_pure(di: DepInfos) -> DepInfos {
+    condense_kind_pure_relative_to(di, |_| true)
+}
+
+/// Same as `condense_relative_to`, but only merges nodes of the same kind, like
+/// `condense_kind_pure`.
+pub fn condense_kind_pure_relative_to<T: Fn(&DepNode) -> bool>(
+    di: DepInfos,
+    is_reference: T,
+) -> DepInfos {
+    let mut classes = root_classes(&di, is_reference);
+    for (class, node) in classes.iter_mut().zip(di.graph.node_weights()) {
+        *class = hash(*class, node.kind() as u8);
     }
+    quotient(di, &classes)
+}
 
+/// Returns the hash of the set of roots for which `is_reference` returns `true` depending
+/// on each node, by node index, as described in `condense`.
+fn root_classes<T: Fn(&DepNode) -> bool>(di: &DepInfos, is_reference: T) -> Vec<u128> {
+    let start_hash = seed(di);
     let mut classes: Vec<u128> = vec![start_hash; di.graph.node_count()];
+    // the root must not share its class with nodes no chosen root depends on
+    classes[di.root.index()] = hash(start_hash, "root");
+
+    // the chosen roots, and the position of each in `roots`
+    let mut roots: Vec<NodeIndex> = Vec::new();
+    let mut position = vec![None; di.graph.node_count()];
+    for root in di.roots() {
+        if position[root.index()].is_none() && is_reference(&di.graph[root]) {
+            position[root.index()] = Some(roots.len());
+            roots.push(root);
+        }
+    }
+
+    // compute the set of roots depending on each strongly connected component, as a bitset
+    // of positions: tarjan_scc returns them in reverse topological order, so iterating
+    // backwards, the sets of all the components referring to one are final when it is
+    // reached, and it only remains to add them to the components it refers to.
+    let sccs = petgraph::algo::tarjan_scc(&di.graph);
+    let mut component = vec![0; di.graph.node_count()];
+    for (i, scc) in sccs.iter().enumerate() {
+        for idx in scc {
+            component[idx.index()] = i;
+        }
+    }
+    let mut sets = vec![FixedBitSet::with_capacity(roots.len()); sccs.len()];
+    let progress = crate::msg::progress(Some(sccs.len() as u64), "nodes");
+    for (i, scc) in sccs.iter().enumerate().rev() {
+        progress.inc(1);
+        for idx in scc {
+            if let Some(p) = position[idx.index()] {
+                sets[i].insert(p);
+            }
+        }
+        if sets[i].count_ones(..) == 0 {
+            continue;
+        }
+        let set = std::mem::take(&mut sets[i]);
+        for idx in scc {
+            for child in di.graph.neighbors(*idx) {
+                let j = component[child.index()];
+                if j != i {
+                    sets[j].union_with(&set);
+                }
+            }
+        }
+        sets[i] = set;
+    }
+    progress.finish_and_clear();
 
     // label each node with the set of roots that depend on it
     // actually we don't label each node with a set of roots indices, which would take too much
     // memory, but with the hash of this set. The probability of collision is then bounded by
     // the birthday paradox with (number of nodes) people and 2^128 days. It's very low :)
-    for root in di.roots() {
-        let mut bfs = petgraph::visit::Bfs::new(&di.graph, root);
-        while let Some(nx) = bfs.next(&di.graph) {
-            // importantly roots are visited in the same order on each node, so that the hash is
-            // equal for the same set of roots
-            classes[nx.index()] ^= hash(classes[nx.index()], root);
+    let root = di.root.index();
+    classes.par_iter_mut().enumerate().for_each(|(i, class)| {
+        if i == root {
+            return;
+        }
+        // importantly roots are hashed in the same order for each node, so that the hash is
+        // equal for the same set of roots
+        for p in sets[component[i]].ones() {
+            *class ^= hash(*class, roots[p]);
         }
+    });
+    classes
+}
+
+/// Returns the value the hashes of the sets of roots of `condense` start from.
+pub(crate) fn seed(di: &DepInfos) -> u128 {
+    // I don't like non-deterministic algorithms. they are a nightmare to debug.
+    // But we rely on the hash of roots behaving like a random variable.
+    // So we seed the hash with the graph.
+    // Unfortunately, petgraph::Graph does not implement Hash, so let's do it
+    // by hand.
+    // hashing nodes is enough, if edges change then some store paths must also change.
+    let mut start_hash = 0;
+    for node in di.graph.raw_nodes() {
+        start_hash = hash(start_hash, &node.weight);
     }
+    start_hash
+}
 
+/// Merges the nodes of `di` reachable from the root which have the same value in
+/// `classes`, by node index, as described in `condense`.
+pub(crate) fn quotient(mut di: DepInfos, classes: &[u128]) -> DepInfos {
     let mut bfs = petgraph::visit::Bfs::new(&di.graph, di.root);
 
     // now remove spurious elements from the original graph.
@@ -198,24 +469,42 @@ pub fn condense(mut di: DepInfos) -> DepInfos {
             new_graph.add_node(w)
         });
         let new_w = &mut new_graph[*new_node];
+        // the description of the representative was moved, and is now Dummy
+        let old_w = &mut di.graph[idx];
+        match std::mem::replace(&mut old_w.description, NodeDescription::Dummy) {
+            NodeDescription::Path(p)
+            | NodeDescription::Derivation(p)
+            | NodeDescription::Link(p)
+            | NodeDescription::Memory(p)
+            | NodeDescription::Temporary(p) => new_w.members.push((p, old_w.size)),
+            _ => (),
+        }
+        new_w.members.append(&mut old_w.members);
         new_w.size = new_w.size + di.graph[idx].size;
+        new_w.referrers += di.graph[idx].referrers;
+        new_w.references += di.graph[idx].references;
+        new_w.self_reference |= di.graph[idx].self_reference;
     }
 
     let new_root = new_ids[&classes[di.root.index()]];
     // keep edges
     for edge in di.graph.raw_edges() {
-        let from = new_ids[&classes[edge.source().index()]];
-        if from == new_root && edge.source() != di.root {
-            // this node is unreachable, so it falls into the equivalence class of the root
+        if !bfs.discovered.contains(edge.source().index()) {
+            // this node is unreachable
             continue;
         };
+        let from = new_ids[&classes[edge.source().index()]];
         let to = new_ids[&classes[edge.target().index()]];
         debug_assert_ne!(to, new_root);
         if from == to {
             // keep the graph acyclic
+            // this edge is now internal to the node, and so is its reference
+            let w = &mut new_graph[to];
+            w.referrers = w.referrers.saturating_sub(1);
+            w.references = w.references.saturating_sub(1);
             continue;
         }
-        new_graph.update_edge(from, to, ());
+        merge_edge(&mut new_graph, from, to, edge.weight);
     }
 
     di.graph = new_graph;
@@ -224,6 +513,292 @@ pub fn condense(mut di: DepInfos) -> DepInfos {
     di
 }
 
+/// Returns the platform a node should be attributed to: its `system`, and the target of the
+/// cross compilation, if the name of the store path starts with a target triple like
+/// `aarch64-unknown-linux-gnu-`. Empty if unknown.
+fn system_hint(node: &DepNode) -> Path {
+    lazy_static::lazy_static! {
+        static ref TRIPLE: regex::bytes::Regex =
+            regex::bytes::Regex::new(r"^([a-z0-9_]+)-(?:unknown|pc|apple|w64)-([a-z]+)(?:-[a-z0-9]+)?-")
+                .expect("regex compilation failed");
+    }
+    let mut system = node.system.clone().unwrap_or_default();
+    if node.kind() == NodeKind::Path {
+        if let Some(c) = TRIPLE.captures(&node.name()) {
+            if system.is_empty() {
+                system.extend_from_slice(b"unknown");
+            }
+            system.extend_from_slice(b", cross to ");
+            system.extend_from_slice(&c[1]);
+            system.push(b'-');
+            system.extend_from_slice(&c[2]);
+        }
+    }
+    system
+}
+
+/// Merges all the nodes reachable from the root which are not roots into one node per
+/// platform, as given by `system_hint`, so that the size of the store paths built for each
+/// platform is visible. Nodes of unknown system are merged together as well.
+/// Unlike `condense`, the result may have cycles, when paths built for one platform refer
+/// to paths of another one which refer back to the first platform.
+pub fn group_by_system(mut di: DepInfos) -> DepInfos {
+    let mut new_graph = DepGraph::new();
+    let mut new_ids = vec![NodeIndex::end(); di.graph.node_count()];
+    let mut systems: collections::BTreeMap<Path, NodeIndex> = collections::BTreeMap::new();
+    let mut roots = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    roots.insert(di.root.index());
+    if di.graph[di.root].kind() == NodeKind::Dummy {
+        roots.extend(di.roots().map(|idx| idx.index()));
+    }
+
+    let mut bfs = petgraph::visit::Bfs::new(&di.graph, di.root);
+    while let Some(idx) = bfs.next(&di.graph) {
+        let w = &mut di.graph[idx];
+        if roots.contains(idx.index()) || w.kind().is_gc_root() {
+            let mut new_w = DepNode::dummy();
+            std::mem::swap(&mut new_w, w);
+            new_ids[idx.index()] = new_graph.add_node(new_w);
+            continue;
+        }
+        let system = system_hint(w);
+        let new_node = *systems.entry(system).or_insert_with_key(|system| {
+            new_graph.add_node(DepNode {
+                description: NodeDescription::System(system.clone()),
+                system: if system.is_empty() {
+                    None
+                } else {
+                    Some(system.clone())
+                },
+                ..DepNode::dummy()
+            })
+        });
+        let new_w = &mut new_graph[new_node];
+        new_w.size += w.size;
+        new_w.referrers += w.referrers;
+        new_w.references += w.references;
+        new_w.self_reference |= w.self_reference;
+        new_ids[idx.index()] = new_node;
+    }
+
+    for edge in di.graph.raw_edges() {
+        if !bfs.discovered.contains(edge.source().index()) {
+            continue;
+        }
+        let from = new_ids[edge.source().index()];
+        let to = new_ids[edge.target().index()];
+        if from == to {
+            let w = &mut new_graph[to];
+            w.referrers = w.referrers.saturating_sub(1);
+            w.references = w.references.saturating_sub(1);
+            continue;
+        }
+        merge_edge(&mut new_graph, from, to, edge.weight);
+    }
+
+    di.root = new_ids[di.root.index()];
+    di.graph = new_graph;
+    di.metadata.reachable = Reachability::Connected;
+    di
+}
+
+/// Returns the name of a package without its version and output, cut like `nix-env` parses
+/// names, before the first dash not followed by a letter: `glibc` for `glibc-2.38-44-bin`.
+pub fn package_name(name: &[u8]) -> &[u8] {
+    let name = name.strip_suffix(b".drv").unwrap_or(name);
+    let end = name
+        .windows(2)
+        .position(|w| w[0] == b'-' && !w[1].is_ascii_alphabetic())
+        .unwrap_or(name.len());
+    &name[..end]
+}
+
+/// Merges the nodes of store paths of `di` which are not gc-roots and whose names are those
+/// of the same package, as given by `package_name`, into one `Package` node per package, so
+/// that the versions and outputs of a package are shown together. Derivations are only
+/// merged with derivations. The store paths of the nodes merged are kept as members.
+/// Like `group_by_system`, the result may have cycles.
+pub fn group_by_name(mut di: DepInfos) -> DepInfos {
+    let mut packages: collections::BTreeMap<(bool, Path), Vec<NodeIndex>> =
+        collections::BTreeMap::new();
+    for idx in di.graph.node_indices() {
+        let node = &di.graph[idx];
+        if idx == di.root || !node.kind().is_store_path() {
+            continue;
+        }
+        let key = (
+            node.kind() == NodeKind::Derivation,
+            package_name(&node.description.name()).to_vec(),
+        );
+        packages.entry(key).or_default().push(idx);
+    }
+
+    let mut new_graph = DepGraph::with_capacity(di.graph.node_count(), di.graph.edge_count());
+    let mut new_ids = vec![NodeIndex::end(); di.graph.node_count()];
+    for ((_, name), nodes) in packages {
+        if nodes.len() < 2 {
+            continue;
+        }
+        let mut new_w = DepNode {
+            description: NodeDescription::Package(name, nodes.len() as u32),
+            ..DepNode::dummy()
+        };
+        for &idx in &nodes {
+            let w = &mut di.graph[idx];
+            let members: u64 = w.members.iter().map(|(_, size)| size).sum();
+            if let Some(path) = w.description.path() {
+                new_w
+                    .members
+                    .push((path.clone(), w.size.saturating_sub(members)));
+            }
+            new_w.members.append(&mut w.members);
+            new_w.size += w.size;
+            new_w.referrers += w.referrers;
+            new_w.references += w.references;
+            new_w.self_reference |= w.self_reference;
+        }
+        let new_node = new_graph.add_node(new_w);
+        for idx in nodes {
+            new_ids[idx.index()] = new_node;
+        }
+    }
+    for idx in di.graph.node_indices() {
+        if new_ids[idx.index()] == NodeIndex::end() {
+            let mut new_w = DepNode::dummy();
+            std::mem::swap(&mut new_w, &mut di.graph[idx]);
+            new_ids[idx.index()] = new_graph.add_node(new_w);
+        }
+    }
+
+    for edge in di.graph.raw_edges() {
+        let from = new_ids[edge.source().index()];
+        let to = new_ids[edge.target().index()];
+        if from == to {
+            let w = &mut new_graph[to];
+            w.referrers = w.referrers.saturating_sub(1);
+            w.references = w.references.saturating_sub(1);
+            continue;
+        }
+        merge_edge(&mut new_graph, from, to, edge.weight);
+    }
+
+    di.root = new_ids[di.root.index()];
+    di.graph = new_graph;
+    di
+}
+
+/// Merges each node reachable from the root into the gc-root which dominates it, if any,
+/// and the other ones into one `Common` node, so that the sizes of the gc-roots and of the
+/// `Common` node sum to the live size. A gc-root is a child of the root.
+/// Like `group_by_system`, the result may have cycles, when the `Common` node refers to a
+/// gc-root which refers to the `Common` node.
+pub fn group_by_minimal_root(mut di: DepInfos) -> DepInfos {
+    let dominators = petgraph::algo::dominators::simple_fast(&di.graph, di.root);
+    let mut new_graph = DepGraph::new();
+    let mut new_ids = vec![NodeIndex::end(); di.graph.node_count()];
+    let mut roots = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    roots.extend(di.roots().map(|idx| idx.index()));
+    let mut common = None;
+
+    // dominators are reached before the nodes they dominate
+    let mut bfs = petgraph::visit::Bfs::new(&di.graph, di.root);
+    while let Some(idx) = bfs.next(&di.graph) {
+        if idx == di.root || roots.contains(idx.index()) {
+            let mut new_w = DepNode::dummy();
+            std::mem::swap(&mut new_w, &mut di.graph[idx]);
+            new_ids[idx.index()] = new_graph.add_node(new_w);
+            continue;
+        }
+        let parent = dominators
+            .immediate_dominator(idx)
+            .expect("reachable nodes have a dominator");
+        let new_node = if parent == di.root {
+            *common.get_or_insert_with(|| {
+                new_graph.add_node(DepNode {
+                    description: NodeDescription::Common,
+                    ..DepNode::dummy()
+                })
+            })
+        } else {
+            new_ids[parent.index()]
+        };
+        let w = &di.graph[idx];
+        let new_w = &mut new_graph[new_node];
+        new_w.size += w.size;
+        new_w.referrers += w.referrers;
+        new_w.references += w.references;
+        new_w.self_reference |= w.self_reference;
+        new_ids[idx.index()] = new_node;
+    }
+
+    for edge in di.graph.raw_edges() {
+        if !bfs.discovered.contains(edge.source().index()) {
+            continue;
+        }
+        let from = new_ids[edge.source().index()];
+        let to = new_ids[edge.target().index()];
+        if from == to {
+            let w = &mut new_graph[to];
+            w.referrers = w.referrers.saturating_sub(1);
+            w.references = w.references.saturating_sub(1);
+            continue;
+        }
+        merge_edge(&mut new_graph, from, to, edge.weight);
+    }
+
+    di.root = new_ids[di.root.index()];
+    di.graph = new_graph;
+    di.metadata.reachable = Reachability::Connected;
+    di
+}
+
+/// Merges each strongly connected component of the graph into one node, so that the
+/// result is acyclic. The store does not allow cycles, but imported graphs may have some.
+/// The node of a component takes the name of an arbitrary member.
+///
+/// Returns the new graph and the number of components of more than one node.
+pub fn collapse_cycles(mut di: DepInfos) -> (DepInfos, usize) {
+    let components = petgraph::algo::tarjan_scc(&di.graph);
+    let cycles = components.iter().filter(|c| c.len() > 1).count();
+    if cycles == 0 {
+        return (di, 0);
+    }
+
+    let mut new_graph = DepGraph::with_capacity(components.len(), di.graph.edge_count());
+    let mut new_ids = vec![NodeIndex::end(); di.graph.node_count()];
+    for component in &components {
+        let mut new_w = DepNode::dummy();
+        std::mem::swap(&mut new_w, &mut di.graph[component[0]]);
+        for &idx in &component[1..] {
+            let w = &di.graph[idx];
+            new_w.size += w.size;
+            new_w.referrers += w.referrers;
+            new_w.references += w.references;
+            new_w.self_reference |= w.self_reference;
+        }
+        let new_node = new_graph.add_node(new_w);
+        for &idx in component {
+            new_ids[idx.index()] = new_node;
+        }
+    }
+
+    for edge in di.graph.raw_edges() {
+        let from = new_ids[edge.source().index()];
+        let to = new_ids[edge.target().index()];
+        if from == to {
+            let w = &mut new_graph[to];
+            w.referrers = w.referrers.saturating_sub(1);
+            w.references = w.references.saturating_sub(1);
+            continue;
+        }
+        merge_edge(&mut new_graph, from, to, edge.weight);
+    }
+
+    di.root = new_ids[di.root.index()];
+    di.graph = new_graph;
+    (di, cycles)
+}
+
 /// Creates a new graph retaining only reachable nodes
 pub fn keep_reachable(mut di: DepInfos) -> DepInfos {
     let mut new_graph = DepGraph::new();
@@ -243,7 +818,7 @@ pub fn keep_reachable(mut di: DepInfos) -> DepInfos {
         if let (Some(&newfrom), Some(&newto)) =
             (new_ids.get(&edge.source()), new_ids.get(&edge.target()))
         {
-            new_graph.add_edge(newfrom, newto, ());
+            new_graph.add_edge(newfrom, newto, edge.weight);
         }
     }
 
@@ -263,7 +838,32 @@ pub fn keep_reachable(mut di: DepInfos) -> DepInfos {
 ///
 /// Requires that all nodes are reachable from the root.
 /// `assert_eq!(di.metadata.reachable, Reachability::Connected);`
-pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
+pub fn keep<T: Fn(&DepNode) -> bool>(di: DepInfos, filter: T) -> DepInfos {
+    keep_by_index(di, |_, node| filter(node))
+}
+
+/// Returns whether the name or the path of `node` matches `pattern`
+pub fn name_matches(node: &DepNode, pattern: &regex::bytes::Regex) -> bool {
+    pattern.is_match(&node.name())
+        || matches!(node.description.path(), Some(path) if pattern.is_match(path))
+}
+
+/// Same as `keep`, retaining the nodes whose name or path matches one of `include`, or any
+/// node if it is empty, and none of `exclude`.
+pub fn keep_matching(
+    di: DepInfos,
+    include: &[regex::bytes::Regex],
+    exclude: &[regex::bytes::Regex],
+) -> DepInfos {
+    keep(di, |node| {
+        (include.is_empty() || include.iter().any(|p| name_matches(node, p)))
+            && !exclude.iter().any(|p| name_matches(node, p))
+    })
+}
+
+/// Same as `keep`, but `filter` is also passed the index of the node, to look up sizes
+/// measured by a `metric::SizeMetric`.
+pub fn keep_by_index<T: Fn(NodeIndex, &DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
     assert_eq!(di.metadata.reachable, Reachability::Connected);
     let mut new_graph = DepGraph::new();
     // ids of nodes put in new_graph
@@ -274,7 +874,7 @@ pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
 
     // loop over nodes to see which we keep
     for idx in di.graph.node_indices() {
-        if idx == di.root || filter(&di.graph[idx]) {
+        if idx == di.root || filter(idx, &di.graph[idx]) {
             let mut new_w = DepNode::dummy();
             std::mem::swap(&mut di.graph[idx], &mut new_w);
             new_ids.insert(idx, new_graph.add_node(new_w));
@@ -335,7 +935,12 @@ pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
                             }
                             None => old_id.unwrap_or_else(|| new_ids[&old]),
                         };
-                        new_graph.add_edge(new, new2, ());
+                        // the edge bypasses the children which are not kept
+                        let edge = match di.graph.find_edge(old, idx) {
+                            Some(e) => di.graph[e],
+                            None => Edge::Synthetic,
+                        };
+                        new_graph.add_edge(new, new2, edge);
                     } else {
                         // this child is not kept
                         // absorb its size upstream
@@ -358,7 +963,7 @@ pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
     // we add edges to kept roots
     for id in di.roots() {
         if let Some(&nid) = new_ids.get(&id) {
-            new_graph.add_edge(new_root, nid, ());
+            new_graph.add_edge(new_root, nid, Edge::Root);
         }
     }
     // to keep the size unchanged, we create a dummy root with the remaining size
@@ -367,9 +972,10 @@ pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
         let fake_root = DepNode {
             description: NodeDescription::FilteredOut,
             size: remaining_size,
+            ..DepNode::dummy()
         };
         let id = new_graph.add_node(fake_root);
-        new_graph.add_edge(new_root, id, ());
+        new_graph.add_edge(new_root, id, Edge::Synthetic);
     }
 
     di.root = new_root;
@@ -378,152 +984,139 @@ pub fn keep<T: Fn(&DepNode) -> bool>(mut di: DepInfos, filter: T) -> DepInfos {
     di
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::depgraph::*;
-    use crate::reduction::*;
-    use enum_map::enum_map;
-    use petgraph::prelude::NodeIndex;
-    use petgraph::visit::IntoNodeReferences;
-    use petgraph::visit::NodeRef;
-    use rand::distributions::WeightedIndex;
-    use rand::prelude::*;
-    use rand::Rng;
-    use std::collections::{self, BTreeMap, BTreeSet};
+/// Keeps at most `n` of the nodes reachable from each root, chosen by decreasing size, and
+/// merges the other ones into one `Other` node per root.
+///
+/// To keep the graph meaningful, all the nodes depending on a kept node are kept as well,
+/// and count towards the `n` nodes of the root being considered. A node which is not kept
+/// is merged into the `Other` node of the root with the lowest index among the roots it is
+/// reachable from, so that `Other` nodes never depend on each other in cycles.
+///
+/// Roots are always kept.
+/// Requires that all nodes are reachable from the root.
+/// `assert_eq!(di.metadata.reachable, Reachability::Connected);`
+pub fn top_per_root(di: DepInfos, n: usize) -> DepInfos {
+    let sizes: Vec<u64> = di.graph.node_weights().map(|node| node.size).collect();
+    top_per_root_by(di, n, &sizes)
+}
 
-    /// asserts that `transform` preserves
-    /// * the set of roots, by path
-    /// * reachable size
-    /// * the root, by path
-    fn check_invariants<T: Fn(DepInfos) -> DepInfos>(transform: T, di: DepInfos, same_roots: bool) {
-        let orig = di.clone();
-        orig.check_metadata();
-        let new = transform(di);
-        println!(
-            "OLD:\n{:?}\nNew:\n{:?}",
-            petgraph::dot::Dot::new(&orig.graph),
-            petgraph::dot::Dot::new(&new.graph)
-        );
-        new.check_metadata();
-        if same_roots {
-            assert_eq!(new.roots_name(), orig.roots_name(), "not the same roots");
-        }
-        assert_eq!(
-            new.reachable_size(),
-            orig.reachable_size(),
-            "not the same reachable size"
-        );
-        assert_eq!(
-            new.graph[new.root], orig.graph[orig.root],
-            "not the same root"
-        );
-        let _ = petgraph::algo::toposort(&new.graph, None).expect("the graph has a cycle");
-        assert_eq!(
-            new.graph
-                .neighbors_directed(new.root, petgraph::prelude::Incoming)
-                .count(),
-            0,
-            "incoming edges to root"
-        );
+/// Same as `top_per_root`, but nodes are chosen by decreasing `sizes`, by node index.
+pub fn top_per_root_by(mut di: DepInfos, n: usize, sizes: &[u64]) -> DepInfos {
+    assert_eq!(di.metadata.reachable, Reachability::Connected);
+    let mut roots: Vec<NodeIndex> = di.roots().collect();
+    roots.sort_unstable();
+    let mut kept = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    kept.insert(di.root.index());
+    for &root in &roots {
+        kept.insert(root.index());
     }
-    /// generates a random `DepInfos` where
-    /// * all derivations have a distinct path
-    /// * there are `size` derivations
-    /// * the expected average degree of the graph should be `avg_degree`
-    /// * the first 62 nodes have size `1<<index`
-    ///
-    /// if connected is true, forces the output to be reachable from the root
-    /// otherwise, it is random.
-    fn generate_random(size: u32, avg_degree: u32, connected: bool) -> DepInfos {
-        use self::NodeDescription::*;
-        assert!(avg_degree <= size - 1);
-        let choices = &[true, false];
-        let weights = &[avg_degree, size - 1 - avg_degree];
-        let wc = WeightedIndex::new(weights).unwrap();
-        let mut rng = rand::thread_rng();
-        let mut g: DepGraph = petgraph::graph::Graph::new();
-        let rooted = rng.gen();
-        for i in 0..size {
-            let path = i.to_string().into();
-            let description = if rooted || i > 4 || rng.gen() {
-                Path(path)
-            } else {
-                if rng.gen() {
-                    Memory(path)
-                } else {
-                    Temporary(path)
-                }
-            };
-            let size = if i < 62 {
-                1u64 << i
-            } else {
-                3 + 2 * (i as u64)
-            };
-            let w = DepNode { description, size };
-            g.add_node(w);
-        }
-        for i in 0..size {
-            for j in (i + 1)..size {
-                if choices[wc.sample(&mut rng)] && !g[NodeIndex::from(j)].kind().is_gc_root() {
-                    g.add_edge(NodeIndex::from(i), NodeIndex::from(j), ());
-                }
+
+    for &root in &roots {
+        let mut candidates = Vec::new();
+        let mut dfs = petgraph::visit::Dfs::new(&di.graph, root);
+        while let Some(idx) = dfs.next(&di.graph) {
+            if idx != root {
+                candidates.push(idx);
             }
         }
-        let mut metadata = SizeMetadata {
-            reachable: Reachability::Connected,
-            dedup: DedupAwareness::Unaware,
-            size: enum_map! { _ => enum_map!{ _ => None }},
-        };
-        let root = g.add_node(if rooted {
-            DepNode {
-                description: Path("root".into()),
-                size: 42,
+        candidates.sort_by_key(|&idx| std::cmp::Reverse(sizes[idx.index()]));
+        let mut count = 0;
+        for idx in candidates {
+            if count >= n {
+                break;
             }
-        } else {
-            DepNode::dummy()
-        });
-        for idx in g
-            .externals(petgraph::Direction::Incoming)
-            .collect::<Vec<_>>()
-        {
-            if !rooted && rng.gen() {
-                if g[idx].kind() == NodeKind::Path {
-                    let w = &mut g[idx].description;
-                    let mut temp = NodeDescription::Dummy;
-                    std::mem::swap(&mut temp, w);
-                    temp = match temp {
-                        Path(path) => Link(path),
-                        o => o,
-                    };
-                    std::mem::swap(&mut temp, w);
-                    assert_eq!(w.kind(), NodeKind::Link);
-                }
-            }
-            let make_reachable = connected || g[idx].kind().is_gc_root() || rng.gen();
-            if root != idx && make_reachable {
-                g.add_edge(root, idx, ());
+            if kept.contains(idx.index()) {
+                count += 1;
+                continue;
             }
-            if !make_reachable {
-                metadata.reachable = Reachability::Disconnected;
+            // the nodes not kept yet which depend on idx, including itself
+            let mut closure = vec![idx];
+            let mut seen = collections::BTreeSet::new();
+            seen.insert(idx);
+            let mut i = 0;
+            while i < closure.len() && count + closure.len() <= n {
+                for parent in di
+                    .graph
+                    .neighbors_directed(closure[i], petgraph::Direction::Incoming)
+                {
+                    if !kept.contains(parent.index()) && seen.insert(parent) {
+                        closure.push(parent);
+                    }
+                }
+                i += 1;
             }
-        }
-        let mut di = DepInfos {
-            graph: g,
-            root,
-            metadata,
-        };
-        // there may be edges from root to root
-        for i in di.roots().collect::<Vec<_>>() {
-            for j in di.roots().collect::<Vec<_>>() {
-                if j > i && choices[wc.sample(&mut rng)] {
-                    di.graph.add_edge(i, j, ());
+            if count + closure.len() <= n {
+                count += closure.len();
+                for idx in closure {
+                    kept.insert(idx.index());
                 }
             }
         }
-        let _ = petgraph::algo::toposort(&di.graph, None).expect("the random graph has a cycle");
-        di.record_metadata();
-        di
     }
+
+    // the root whose Other node each node will be merged into
+    let mut owner = vec![NodeIndex::end(); di.graph.node_count()];
+    let mut dfs = petgraph::visit::Dfs::empty(&di.graph);
+    for &root in &roots {
+        dfs.move_to(root);
+        while let Some(idx) = dfs.next(&di.graph) {
+            owner[idx.index()] = root;
+        }
+    }
+
+    let mut new_graph = DepGraph::new();
+    let mut new_ids = vec![NodeIndex::end(); di.graph.node_count()];
+    // Other nodes first, as the names of roots are needed
+    let mut others = collections::BTreeMap::new();
+    for idx in di.graph.node_indices() {
+        if kept.contains(idx.index()) {
+            continue;
+        }
+        let root = owner[idx.index()];
+        let other = *others.entry(root).or_insert_with(|| {
+            new_graph.add_node(DepNode {
+                description: NodeDescription::Other(di.label(root).into_owned()),
+                ..DepNode::dummy()
+            })
+        });
+        new_graph[other].size += di.graph[idx].size;
+        new_ids[idx.index()] = other;
+    }
+    for idx in di.graph.node_indices() {
+        if kept.contains(idx.index()) {
+            let mut new_w = DepNode::dummy();
+            std::mem::swap(&mut new_w, &mut di.graph[idx]);
+            new_ids[idx.index()] = new_graph.add_node(new_w);
+        }
+    }
+    for edge in di.graph.raw_edges() {
+        let from = new_ids[edge.source().index()];
+        let to = new_ids[edge.target().index()];
+        debug_assert!(
+            !kept.contains(edge.target().index()) || kept.contains(edge.source().index()),
+            "a kept node depends on a merged one"
+        );
+        if from != to {
+            merge_edge(&mut new_graph, from, to, edge.weight);
+        }
+    }
+
+    di.root = new_ids[di.root.index()];
+    di.graph = new_graph;
+    di
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depgraph::*;
+    use crate::reduction::*;
+    use crate::selftest::*;
+    use petgraph::prelude::NodeIndex;
+    use petgraph::visit::IntoNodeReferences;
+    use petgraph::visit::NodeRef;
+    use rand::Rng;
+    use std::collections::{self, BTreeMap, BTreeSet};
+
     fn size_to_old_nodes(drv: &DepNode) -> collections::BTreeSet<NodeIndex> {
         (0..62)
             .filter(|i| drv.size & (1u64 << i) != 0)
@@ -548,20 +1141,7 @@ mod tests {
     /// check that condense and keep preserve some invariants
     fn invariants() {
         for _ in 0..40 {
-            let di = generate_random(250, 10, false);
-            println!("testing merge_transient_roots");
-            check_invariants(merge_transient_roots, di.clone(), false);
-            println!("testing condense");
-            check_invariants(condense, di.clone(), true);
-            println!("testing keep_reachable");
-            check_invariants(keep_reachable, di.clone(), true);
-            println!("testing keep none");
-            let trimmed = keep_reachable(di);
-            check_invariants(|x| keep(x, |_| false), trimmed.clone(), false);
-            println!("testing keep all");
-            check_invariants(|x| keep(x, |_| true), trimmed.clone(), true);
-            println!("testing tred");
-            check_invariants(transitive_reduction, trimmed, true);
+            check_reductions(generate_random(250, 10, false));
         }
     }
     #[test]
@@ -614,6 +1194,62 @@ mod tests {
         }
     }
     #[test]
+    fn check_group_roots() {
+        let prefixes = vec![b"1".to_vec(), b"12".to_vec()];
+        for _ in 0..40 {
+            let old = generate_random(250, 10, false);
+            check_invariants(|x| group_roots(x, &prefixes), old.clone(), false);
+            let new = group_roots(old.clone(), &prefixes);
+            if old.graph[old.root].kind() != NodeKind::Dummy {
+                assert_eq!(old.graph.edge_count(), new.graph.edge_count());
+                continue;
+            }
+            for idx in new.roots() {
+                match &new.graph[idx].description {
+                    NodeDescription::Link(path) => {
+                        assert!(!is_under(path, b"1"), "{:?} was not grouped", path)
+                    }
+                    NodeDescription::Group(prefix) => {
+                        for child in new.graph.neighbors(idx) {
+                            let path = new.graph[child].description.path().unwrap();
+                            assert!(is_under(path, prefix));
+                            assert_eq!(
+                                is_under(path, b"12"),
+                                prefix == b"12",
+                                "{:?} is in the wrong group",
+                                path
+                            );
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    #[test]
+    fn check_group_roots_by_directory() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/home/al/result","root":true},
+            {"id":2,"kind":"Link","path":"/home/alice/result","root":true},
+            {"id":3,"path":"/nix/store/a","size":1},
+            {"id":4,"path":"/nix/store/b","size":1}
+        ],"edges":[[1,3],[2,4]]}"#;
+        let di = crate::import::graph(text);
+        let di = group_roots(di, &[b"/home/al".to_vec()]);
+        let mut roots: Vec<_> = di
+            .roots()
+            .map(|idx| di.graph[idx].description.clone())
+            .collect();
+        roots.sort_unstable();
+        assert_eq!(
+            roots,
+            vec![
+                NodeDescription::Link(b"/home/alice/result".to_vec()),
+                NodeDescription::Group(b"/home/al".to_vec()),
+            ]
+        );
+    }
+    #[test]
     fn check_keep_reachable() {
         for _ in 0..40 {
             let old = generate_random(150, 1, false);
@@ -648,6 +1284,32 @@ mod tests {
         }
     }
     #[test]
+    fn check_collapse_cycles() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..40 {
+            let old = generate_random(100, 3, false);
+            let (same, cycles) = collapse_cycles(old.clone());
+            assert_eq!(cycles, 0);
+            assert_eq!(same.graph.node_count(), old.graph.node_count());
+
+            let mut cyclic = old;
+            // the root is the last node, keep it without incoming edges
+            let n = cyclic.graph.node_count() - 1;
+            for _ in 0..20 {
+                let from = rng.gen_range(0..n);
+                let to = rng.gen_range(0..n);
+                if from != to {
+                    cyclic.graph.add_edge(
+                        NodeIndex::from(from as u32),
+                        NodeIndex::from(to as u32),
+                        Edge::Reference,
+                    );
+                }
+            }
+            check_invariants(|x| collapse_cycles(x).0, cyclic, false);
+        }
+    }
+    #[test]
     fn check_transitive_reduction() {
         let mut rng = rand::thread_rng();
         for _ in 0..40 {
@@ -656,8 +1318,11 @@ mod tests {
             for _ in 1..20 {
                 let from = rng.gen_range(1..old.graph.node_count());
                 let to = rng.gen_range(1..old.graph.node_count());
-                old.graph
-                    .add_edge(NodeIndex::from(from as u32), NodeIndex::from(to as u32), ());
+                old.graph.add_edge(
+                    NodeIndex::from(from as u32),
+                    NodeIndex::from(to as u32),
+                    Edge::Reference,
+                );
                 old.check_metadata();
             }
 
@@ -739,6 +1404,13 @@ mod tests {
                         );
                 }
                 nodes_image.insert(after);
+                // all the other store paths of the class are recorded as members
+                assert_eq!(
+                    drv.members.len() + 1,
+                    elements.len(),
+                    "members of {:?}",
+                    drv
+                );
                 // here check edges
                 for (idx2, drv2) in new.graph.node_references() {
                     let targets = size_to_old_nodes(drv2);
@@ -771,6 +1443,324 @@ mod tests {
             );
         }
     }
+    #[test]
+    fn check_root_classes() {
+        for _ in 0..40 {
+            let di = generate_random(120, 3, false);
+            // one search per root, like the definition of `condense`
+            let mut expected = vec![BTreeSet::new(); di.graph.node_count()];
+            for root in di.roots() {
+                let mut bfs = petgraph::visit::Bfs::new(&di.graph, root);
+                while let Some(nx) = bfs.next(&di.graph) {
+                    expected[nx.index()].insert(root);
+                }
+            }
+            expected[di.root.index()].insert(di.root);
+            let classes = root_classes(&di, |_| true);
+            for i in 0..classes.len() {
+                for j in 0..classes.len() {
+                    assert_eq!(
+                        classes[i] == classes[j],
+                        expected[i] == expected[j],
+                        "{} and {}",
+                        i,
+                        j
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_condense_relative_to() {
+        let is_reference = |drv: &DepNode| match drv.description.path() {
+            Some(p) => matches!(String::from_utf8_lossy(p).parse::<u32>(), Ok(i) if i & 1 == 0),
+            None => false,
+        };
+        for _ in 0..80 {
+            let old = generate_random(62, 10, false);
+            check_invariants(
+                |x| condense_relative_to(x, is_reference),
+                old.clone(),
+                false,
+            );
+            let new = condense_relative_to(old.clone(), is_reference);
+            let mut old_rev = old.graph.clone();
+            old_rev.reverse();
+            let references: BTreeSet<NodeIndex> = old
+                .roots()
+                .filter(|&idx| is_reference(&old.graph[idx]))
+                .collect();
+            let dependent_references = |idx| {
+                let mut dfs = petgraph::visit::Dfs::new(&old_rev, idx);
+                let mut res = BTreeSet::new();
+                while let Some(nx) = dfs.next(&old_rev) {
+                    if references.contains(&nx) {
+                        res.insert(nx);
+                    }
+                }
+                res
+            };
+            let mut images = BTreeSet::new();
+            for (idx, drv) in new.graph.node_references() {
+                if idx == new.root {
+                    continue;
+                }
+                let members = size_to_old_nodes(drv);
+                let image = dependent_references(*members.iter().next().unwrap());
+                for &member in &members {
+                    assert_eq!(dependent_references(member), image);
+                }
+                assert!(images.insert(image), "two nodes have the same class");
+            }
+        }
+    }
+    #[test]
+    fn check_condense_kind_pure() {
+        for _ in 0..40 {
+            let old = generate_random(62, 10, false);
+            check_invariants(condense_kind_pure, old.clone(), false);
+            let new = condense_kind_pure(merge_transient_roots(old.clone()));
+            let condensed = condense(merge_transient_roots(old.clone()));
+            assert!(new.graph.node_count() >= condensed.graph.node_count());
+            for (idx, drv) in new.graph.node_references() {
+                if idx == new.root {
+                    continue;
+                }
+                for member in size_to_old_nodes(drv) {
+                    assert_eq!(old.graph[member].kind(), drv.kind());
+                }
+            }
+        }
+    }
+    #[test]
+    fn check_top_per_root() {
+        for _ in 0..40 {
+            let old = condense(generate_random(250, 10, false));
+            check_invariants(|x| top_per_root(x, 3), old.clone(), true);
+            let new = top_per_root(old, 3);
+            for idx in new.graph.node_indices() {
+                if new.graph[idx].kind() == NodeKind::Other {
+                    assert!(new
+                        .graph
+                        .neighbors(idx)
+                        .all(|child| new.graph[child].kind() == NodeKind::Other));
+                }
+            }
+        }
+    }
+    #[test]
+    fn check_group_by_system() {
+        for _ in 0..40 {
+            let mut old = generate_random(250, 10, false);
+            let systems = [
+                None,
+                Some(b"x86_64-linux".to_vec()),
+                Some(b"aarch64-linux".to_vec()),
+            ];
+            for idx in old.graph.node_indices() {
+                old.graph[idx].system = systems[idx.index() % 3].clone();
+            }
+            let new = group_by_system(old.clone());
+            new.check_metadata();
+            // with a store path as root, its references are grouped as well
+            let dummy_root = old.graph[old.root].kind() == NodeKind::Dummy;
+            if dummy_root {
+                assert_eq!(new.roots_name(), old.roots_name());
+            }
+            assert_eq!(new.reachable_size(), old.reachable_size());
+            let mut dfs = old.dfs();
+            while dfs.next(&old.graph).is_some() {}
+            let reachable = dfs.discovered;
+            let old_roots: fixedbitset::FixedBitSet = old
+                .roots()
+                .filter(|_| dummy_root)
+                .map(|idx| idx.index())
+                .collect();
+            for node in new.graph.node_weights() {
+                if node.kind() != NodeKind::System {
+                    continue;
+                }
+                assert_eq!(node.kind(), NodeKind::System);
+                let expected: u64 = old
+                    .graph
+                    .node_references()
+                    .filter(|&(i, w)| {
+                        reachable.contains(i.index())
+                            && i != old.root
+                            && !old_roots.contains(i.index())
+                            && !w.kind().is_gc_root()
+                            && w.system == node.system
+                    })
+                    .map(|(_, w)| w.size)
+                    .sum();
+                assert_eq!(node.size, expected, "size of {:?}", node);
+            }
+        }
+    }
+    #[test]
+    fn check_condense_degrees() {
+        for _ in 0..80 {
+            let mut old = generate_random(62, 10, true);
+            record_degrees(&mut old.graph);
+            let new = condense(old.clone());
+            for (idx, drv) in new.graph.node_references() {
+                if idx == new.root {
+                    continue;
+                }
+                let members = size_to_old_nodes(drv);
+                let crossing = |inward: bool| {
+                    old.graph
+                        .raw_edges()
+                        .iter()
+                        .filter(|e| {
+                            members.contains(&e.target()) == inward
+                                && members.contains(&e.source()) != inward
+                        })
+                        .count() as u32
+                };
+                assert_eq!(drv.referrers, crossing(true), "referrers of {:?}", drv);
+                assert_eq!(drv.references, crossing(false), "references of {:?}", drv);
+            }
+        }
+    }
+    #[test]
+    fn check_group_by_minimal_root() {
+        for _ in 0..80 {
+            let old = generate_random(62, 10, true);
+            let new = group_by_minimal_root(old.clone());
+            assert_eq!(new.reachable_size(), old.reachable_size());
+            let oldroots: collections::BTreeSet<_> = old.roots().collect();
+            let tree = crate::tree::DominatorTree::new(&old);
+            for idx in new.roots() {
+                // the size of a gc-root is what removing it frees
+                let old_idx = *size_to_old_nodes(&new.graph[idx])
+                    .intersection(&oldroots)
+                    .next()
+                    .unwrap();
+                assert_eq!(new.graph[idx].size, tree.retained[old_idx.index()]);
+            }
+            let common = new
+                .graph
+                .node_indices()
+                .filter(|&idx| new.graph[idx].kind() == NodeKind::Common)
+                .count();
+            assert!(common <= 1);
+            assert_eq!(new.graph.node_count(), oldroots.len() + 1 + common);
+        }
+    }
+    #[test]
+    fn check_keep_matching() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/a-hello-2.12","size":10},
+            {"id":4,"path":"/nix/store/b-hello-2.12-doc","size":20},
+            {"id":5,"path":"/nix/store/c-nixpkgs-source","size":30}
+        ],"edges":[[1,3],[1,4],[2,5]]}"#;
+        let di = crate::import::graph(text);
+        let di = keep_reachable(di);
+        let re = |s: &str| regex::bytes::Regex::new(s).unwrap();
+        let names = |di: &DepInfos| {
+            let mut names: Vec<String> = di
+                .graph
+                .node_weights()
+                .filter(|node| node.kind() == NodeKind::Path)
+                .map(|node| String::from_utf8_lossy(&node.name()).into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        let sources = keep_matching(di.clone(), &[re("-source$")], &[]);
+        assert_eq!(names(&sources), vec!["nixpkgs-source"]);
+        assert_eq!(sources.reachable_size(), di.reachable_size());
+        let no_doc = keep_matching(di.clone(), &[], &[re("-doc$")]);
+        assert_eq!(names(&no_doc), vec!["hello-2.12", "nixpkgs-source"]);
+        // full paths match too
+        let by_path = keep_matching(di, &[re("^/nix/store/a-")], &[]);
+        assert_eq!(names(&by_path), vec!["hello-2.12"]);
+    }
+
+    #[test]
+    fn check_keep_older_than() {
+        const DAY: u64 = 24 * 3600;
+        let text = format!(
+            r#"{{"nodes":[
+            {{"id":1,"kind":"Link","path":"/home/alice/result","root":true,"age":{}}},
+            {{"id":2,"kind":"Link","path":"/home/alice/old-result","root":true,"age":{}}},
+            {{"id":3,"kind":"Memory","path":"/proc/42/maps","root":true}},
+            {{"id":4,"path":"/nix/store/a-hello","size":1}},
+            {{"id":5,"path":"/nix/store/b-glibc","size":10}},
+            {{"id":6,"path":"/nix/store/c-old-hello","size":100}},
+            {{"id":7,"path":"/nix/store/d-libfoo","size":1000}},
+            {{"id":8,"path":"/nix/store/e-firefox","size":10000}}
+        ],"edges":[[1,4],[4,5],[2,6],[6,5],[6,7],[2,8],[3,8]]}}"#,
+            99 * DAY,
+            10 * DAY
+        );
+        let di = crate::import::graph(text.as_bytes());
+        let now = std::time::UNIX_EPOCH + Duration::from_secs(100 * DAY);
+        let di = keep_older_than(di, Duration::from_secs(30 * DAY), now);
+        di.check_metadata();
+        let mut paths: Vec<String> = di
+            .graph
+            .node_weights()
+            .filter_map(|node| node.description.path())
+            .map(|path| String::from_utf8_lossy(path).into_owned())
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                "/home/alice/old-result",
+                "/nix/store/c-old-hello",
+                "/nix/store/d-libfoo"
+            ]
+        );
+        assert_eq!(di.reachable_size(), 1100);
+    }
+
+    #[test]
+    fn check_group_generations() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/nix/var/nix/profiles/system-1-link","root":true},
+            {"id":2,"kind":"Link","path":"/nix/var/nix/profiles/system-2-link","root":true},
+            {"id":3,"kind":"Link","path":"/nix/var/nix/profiles/per-user/alice/profile-7-link","root":true},
+            {"id":4,"kind":"Link","path":"/home/alice/result","root":true},
+            {"id":5,"path":"/nix/store/a-nixos-system-1","size":10},
+            {"id":6,"path":"/nix/store/b-nixos-system-2","size":20},
+            {"id":7,"path":"/nix/store/c-glibc","size":40},
+            {"id":8,"path":"/nix/store/d-hello","size":80}
+        ],"edges":[[1,5],[2,6],[5,7],[6,7],[3,8],[4,8]]}"#;
+        let di = crate::import::graph(text);
+        let di = group_generations(keep_reachable(di));
+        let mut roots: Vec<String> = di
+            .roots()
+            .map(|idx| String::from_utf8_lossy(&di.label(idx)).into_owned())
+            .collect();
+        roots.sort();
+        assert_eq!(
+            roots,
+            vec![
+                "/home/alice/result",
+                "generation 7 of alice's profile",
+                "system (2 generations)"
+            ]
+        );
+        let condensed = condense(di);
+        let group = condensed
+            .graph
+            .node_indices()
+            .find(|&idx| {
+                matches!(
+                    condensed.graph[idx].description,
+                    NodeDescription::Generations(..)
+                )
+            })
+            .unwrap();
+        assert_eq!(condensed.graph[group].size, 70);
+    }
+
     #[test]
     fn check_keep() {
         let filter_drv = |drv: &DepNode| {
@@ -924,4 +1914,91 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn check_group_transient_roots() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/home/alice/result","root":true},
+            {"id":2,"kind":"Temporary","path":"{temp:10}","root":true},
+            {"id":3,"kind":"Memory","path":"/proc/10/maps","root":true},
+            {"id":4,"kind":"Temporary","path":"{temp:11}","root":true},
+            {"id":5,"kind":"Temporary","path":"{temp:12}","root":true},
+            {"id":6,"kind":"Memory","path":"{lsof}","root":true},
+            {"id":7,"path":"/nix/store/a-hello","size":1}
+        ],"edges":[[1,7],[2,7],[3,7],[4,7],[5,7],[6,7]]}"#;
+        let di = crate::import::graph(text);
+        let process = |pid| {
+            let command = match pid {
+                10 => "nixos-rebuild",
+                11 => "nix-daemon",
+                _ => return None,
+            };
+            Some(crate::procinfo::Process {
+                pid,
+                command: command.to_owned(),
+                running: Duration::from_secs(60),
+            })
+        };
+        let di = merge_transient_roots(group_transient_roots(di, process));
+        let mut roots: Vec<(String, usize)> = di
+            .roots()
+            .filter(|&idx| di.graph[idx].kind() == NodeKind::Transient)
+            .map(|idx| {
+                (
+                    String::from_utf8_lossy(&di.graph[idx].name()).into_owned(),
+                    di.graph.neighbors(idx).count(),
+                )
+            })
+            .collect();
+        roots.sort();
+        assert_eq!(
+            roots,
+            vec![
+                ("held by: nix-daemon session (pid 11)".to_owned(), 1),
+                ("held by: nixos-rebuild (pid 10)".to_owned(), 2),
+                ("{transient}".to_owned(), 2),
+            ]
+        );
+        assert_eq!(di.roots().count(), 4);
+    }
+
+    #[test]
+    fn check_group_by_name() {
+        assert_eq!(package_name(b"glibc-2.38-44-bin"), b"glibc");
+        assert_eq!(package_name(b"xorg-server-21.1.8"), b"xorg-server");
+        assert_eq!(package_name(b"source"), b"source");
+        assert_eq!(package_name(b"hello-2.12.drv"), b"hello");
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":7,"kind":"Link","path":"/r3","root":true},
+            {"id":3,"path":"/nix/store/a-ghc-9.4.8","size":1000},
+            {"id":4,"path":"/nix/store/b-ghc-9.6.3","size":2000},
+            {"id":5,"path":"/nix/store/c-ghc-9.6.3-doc","size":10},
+            {"id":6,"path":"/nix/store/d-gmp-6.3.0","size":100}
+        ],"edges":[[1,3],[2,4],[4,5],[3,6],[4,6],[7,3],[7,4]]}"#;
+        let di = crate::import::graph(text);
+        let di = group_by_name(condense(di));
+        let mut nodes: Vec<(String, u64, usize)> = di
+            .graph
+            .node_weights()
+            .filter(|node| !node.kind().is_gc_root() && node.kind() != NodeKind::Dummy)
+            .map(|node| {
+                (
+                    String::from_utf8_lossy(&node.name()).into_owned(),
+                    node.size,
+                    node.store_paths().len(),
+                )
+            })
+            .collect();
+        nodes.sort();
+        assert_eq!(
+            nodes,
+            vec![
+                ("ghc (2 variants)".to_owned(), 3010, 3),
+                ("gmp-6.3.0".to_owned(), 100, 1),
+            ]
+        );
+        assert_eq!(di.reachable_size(), 3110);
+    }
 }
@@ -0,0 +1,51 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! What the renderers of the condensed graph to other tools have in common: `dot`,
+//! `graphml` and `gexf` write the same nodes, the root of the graph excepted, and the same
+//! edges, references and optionally synthetic ones.
+
+use crate::depgraph::*;
+use std::io::{self, Write};
+
+/// A format the condensed graph can be written in
+pub trait Renderer {
+    /// Writes `di` to `w`
+    fn render(&self, di: &DepInfos, w: &mut dyn Write) -> io::Result<()>;
+}
+
+impl Renderer for crate::dot::Style {
+    fn render(&self, di: &DepInfos, w: &mut dyn Write) -> io::Result<()> {
+        crate::dot::render(di, self, &mut &mut *w)
+    }
+}
+
+/// Writes `s` escaped for XML, invalid UTF-8 being replaced by U+FFFD
+pub fn write_xml_escaped<W: Write + ?Sized>(w: &mut W, s: &[u8]) -> io::Result<()> {
+    for c in String::from_utf8_lossy(s).chars() {
+        match c {
+            '<' => w.write_all(b"&lt;")?,
+            '>' => w.write_all(b"&gt;")?,
+            '&' => w.write_all(b"&amp;")?,
+            '"' => w.write_all(b"&quot;")?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    Ok(())
+}
+
+/// Returns the edges of `di` to write, as pairs of node indices, with whether they are
+/// synthetic: those not from the root of the graph, and only references unless `synthetic`
+pub fn edges(di: &DepInfos, synthetic: bool) -> Vec<(usize, usize, bool)> {
+    di.graph
+        .raw_edges()
+        .iter()
+        .filter(|e| e.source() != di.root && (synthetic || !e.weight.is_synthetic()))
+        .map(|e| {
+            (
+                e.source().index(),
+                e.target().index(),
+                e.weight.is_synthetic(),
+            )
+        })
+        .collect()
+}
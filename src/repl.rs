@@ -0,0 +1,256 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! An interactive prompt exploring a graph read once, so that trying filters does not read
+//! the store again each time.
+//!
+//! Commands are:
+//!  * `filter size>SIZE`, `filter name~REGEX`: hide the nodes smaller than `SIZE`, or whose
+//!    name does not match `REGEX`; `filter` alone shows the filters, `filter none` clears them
+//!  * `top [N]`: the `N` biggest nodes shown
+//!  * `expand NAME`: the store paths merged into the node of `NAME`
+//!  * `why NAME`: the gc-roots keeping `NAME` alive, like `nix-du unlock`
+//!  * `export FILE`: writes the nodes shown to `FILE`, as JSON if it ends with `.json`, or
+//!    else in the dot format
+//!  * `size`, `help`, and `quit`
+
+use crate::depgraph::*;
+use crate::reduction;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use regex::bytes::Regex;
+use std::io::{self, BufRead, Write};
+
+/// What is explored
+pub struct Session {
+    /// the graph, with transient roots merged, to tell why a path is alive
+    full: DepInfos,
+    /// the graph returned by `reduction::condense`
+    condensed: DepInfos,
+    /// nodes smaller than this are hidden
+    min_size: u64,
+    /// nodes whose name does not match are hidden
+    pattern: Option<Regex>,
+}
+
+impl Session {
+    /// Starts exploring `di`, a graph as read from the store
+    pub fn new(di: DepInfos) -> Self {
+        let full = reduction::merge_transient_roots(di);
+        let condensed = reduction::condense(full.clone());
+        Session {
+            full,
+            condensed,
+            min_size: 0,
+            pattern: None,
+        }
+    }
+
+    /// Returns the condensed graph without the nodes filters hide
+    fn view(&self) -> DepInfos {
+        let mut di = self.condensed.clone();
+        if self.min_size > 0 {
+            di = reduction::keep(di, |node| node.size >= self.min_size);
+        }
+        if let Some(pattern) = &self.pattern {
+            di = reduction::keep(di, |node| pattern.is_match(&node.name()));
+        }
+        di
+    }
+
+    /// Returns the node of the condensed graph labelled `query`, or which a store path of
+    /// this name was merged into
+    fn find(&self, query: &str) -> Option<NodeIndex> {
+        let di = &self.condensed;
+        let query = query.as_bytes();
+        di.graph.node_indices().find(|&idx| {
+            di.label(idx).as_ref() == query
+                || di.graph[idx]
+                    .store_paths()
+                    .iter()
+                    .any(|&(path, _)| NodeDescription::Path(path.to_vec()).name().as_ref() == query)
+        })
+    }
+
+    /// Describes the filters
+    fn filters(&self) -> String {
+        let mut res = Vec::new();
+        if self.min_size > 0 {
+            res.push(format!("size>{}", ByteSize::b(self.min_size)));
+        }
+        if let Some(pattern) = &self.pattern {
+            res.push(format!("name~{}", pattern));
+        }
+        if res.is_empty() {
+            "no filter".to_owned()
+        } else {
+            res.join(" ")
+        }
+    }
+
+    /// Runs the command `line`, writing its output or errors to `w`. Returns false if it
+    /// was `quit`.
+    pub fn execute<W: Write>(&mut self, line: &str, w: &mut W) -> io::Result<bool> {
+        let line = line.trim();
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+        match (command, arg) {
+            ("", _) => {}
+            ("quit" | "exit", _) => return Ok(false),
+            ("help", _) => writeln!(
+                w,
+                "Commands are: filter size>SIZE, filter name~REGEX, filter none, top [N], \
+                 expand NAME, why NAME, export FILE, size, quit"
+            )?,
+            ("size", _) => writeln!(w, "{}", ByteSize::b(self.condensed.reachable_size()))?,
+            ("filter", "") => writeln!(w, "{}", self.filters())?,
+            ("filter", "none") => {
+                self.min_size = 0;
+                self.pattern = None;
+            }
+            ("filter", filter) => {
+                if let Some(size) = filter.strip_prefix("size>") {
+                    match size.trim().parse::<ByteSize>() {
+                        Ok(size) => self.min_size = size.as_u64(),
+                        Err(e) => writeln!(w, "error: {}: {}", size, e)?,
+                    }
+                } else if let Some(pattern) = filter.strip_prefix("name~") {
+                    match Regex::new(pattern.trim()) {
+                        Ok(pattern) => self.pattern = Some(pattern),
+                        Err(e) => writeln!(w, "error: {}", e)?,
+                    }
+                } else {
+                    writeln!(w, "error: expected size>SIZE, name~REGEX or none")?
+                }
+            }
+            ("top", n) => {
+                let n = match n {
+                    "" => 10,
+                    n => match n.parse() {
+                        Ok(n) => n,
+                        Err(_) => {
+                            writeln!(w, "error: invalid number of nodes")?;
+                            return Ok(true);
+                        }
+                    },
+                };
+                let di = self.view();
+                let mut nodes: Vec<_> = di
+                    .graph
+                    .node_indices()
+                    .filter(|&idx| idx != di.root)
+                    .collect();
+                nodes.sort_by_key(|&idx| std::cmp::Reverse(di.graph[idx].size));
+                for idx in nodes.into_iter().take(n) {
+                    write!(w, "{}\t", ByteSize::b(di.graph[idx].size))?;
+                    w.write_all(&di.label(idx))?;
+                    writeln!(w)?;
+                }
+            }
+            ("expand", name) => match self.find(name) {
+                Some(idx) => {
+                    let mut paths = self.condensed.graph[idx].store_paths();
+                    paths.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+                    for (path, size) in paths {
+                        write!(w, "{}\t", ByteSize::b(size))?;
+                        w.write_all(path)?;
+                        writeln!(w)?;
+                    }
+                }
+                None => writeln!(w, "error: no node is or contains «{}»", name)?,
+            },
+            ("why", name) => match crate::unlock::find_node(&self.full, name) {
+                Ok(target) => crate::unlock::render(&self.full, target, false, w)?,
+                Err(e) => writeln!(w, "error: {}", e)?,
+            },
+            ("export", "") => writeln!(w, "error: expected a file name")?,
+            ("export", file) => {
+                let res = std::fs::File::create(file).and_then(|f| {
+                    let mut f = io::BufWriter::new(f);
+                    let di = self.view();
+                    if file.ends_with(".json") {
+                        crate::json::render_with(&di, &crate::json::Options::default(), &mut f)?;
+                    } else {
+                        let di = reduction::transitive_reduction(di);
+                        crate::dot::render(&di, &crate::dot::Style::default(), &mut f)?;
+                    }
+                    f.flush()
+                });
+                match res {
+                    Ok(()) => writeln!(w, "wrote {} with {}", file, self.filters())?,
+                    Err(e) => writeln!(w, "error: could not write {}: {}", file, e)?,
+                }
+            }
+            _ => writeln!(
+                w,
+                "error: unknown command «{}», type help for a list",
+                command
+            )?,
+        }
+        Ok(true)
+    }
+}
+
+/// Runs the commands read from `input` until it ends or `quit`, printing `prompt` before
+/// each if any.
+pub fn run<R: BufRead, W: Write>(
+    session: &mut Session,
+    input: R,
+    prompt: Option<&str>,
+    w: &mut W,
+) -> io::Result<()> {
+    let mut lines = input.lines();
+    loop {
+        if let Some(prompt) = prompt {
+            write!(w, "{}", prompt)?;
+            w.flush()?;
+        }
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        if !session.execute(&line, w)? {
+            return Ok(());
+        }
+        w.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repl::*;
+
+    #[test]
+    fn check_session() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/aaaa-firefox-120.0","size":1000},
+            {"id":4,"path":"/nix/store/bbbb-libfoo-1.0","size":10},
+            {"id":5,"path":"/nix/store/cccc-glibc-2.38","size":50}
+        ],"edges":[[1,3],[3,4],[3,5],[2,5]]}"#;
+        let di = crate::import::graph(text);
+        let mut session = Session::new(di);
+        let run = |session: &mut Session, input: &str| {
+            let mut out = Vec::new();
+            run(session, input.as_bytes(), None, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(run(&mut session, "size\n"), "1.1 KB\n");
+        assert_eq!(
+            run(&mut session, "top\n"),
+            "1.0 KB\t/r1\n50 B\tglibc-2.38\n0 B\t/r2\n"
+        );
+        assert_eq!(
+            run(&mut session, "expand firefox-120.0\n"),
+            "1.0 KB\t/nix/store/aaaa-firefox-120.0\n10 B\t/nix/store/bbbb-libfoo-1.0\n"
+        );
+        assert!(run(&mut session, "why glibc-2.38").contains("by 2 gc-roots"));
+        assert_eq!(
+            run(&mut session, "filter size>100B\nfilter\ntop\nquit\nsize\n"),
+            "size>100 B\n1.1 KB\t/r1\n"
+        );
+        assert!(run(&mut session, "frobnicate").starts_with("error: unknown command"));
+    }
+}
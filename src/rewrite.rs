@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Rewriting the prefix of paths, to analyse a copy of a store mounted elsewhere, like
+//! `/mnt/nix/store`, under the names it has on its machine, and compare it with local
+//! graphs.
+//!
+//! Store paths which end up with the same name stand for the same contents: they are merged
+//! into one node, which keeps the size of one of them.
+
+use crate::depgraph::*;
+use std::collections::HashMap;
+
+/// Returns `path` with its first prefix `from` of `rules` replaced by the matching `to`.
+/// Prefixes only match whole components: `/mnt` is a prefix of `/mnt/nix`, not of `/mnt2`.
+pub fn rewrite(path: &[u8], rules: &[(Path, Path)]) -> Option<Path> {
+    rules.iter().find_map(|(from, to)| {
+        let from = from.strip_suffix(b"/").unwrap_or(from);
+        let rest = path.strip_prefix(from)?;
+        if !rest.is_empty() && rest[0] != b'/' {
+            return None;
+        }
+        let to = to.strip_suffix(b"/").unwrap_or(to);
+        let mut res = to.to_vec();
+        res.extend_from_slice(rest);
+        Some(res)
+    })
+}
+
+/// Rewrites the paths of the store paths and gc-roots of `di` according to `rules`, as
+/// `FROM` and `TO` prefix pairs, merging the nodes which end up with the same path.
+pub fn rewrite_prefixes(di: DepInfos, rules: &[(Path, Path)]) -> DepInfos {
+    if rules.is_empty() {
+        return di;
+    }
+    let DepInfos {
+        graph: old,
+        root,
+        metadata,
+        annotations,
+    } = di;
+    let mut graph = DepGraph::with_capacity(old.node_count(), old.edge_count());
+    // for each node of `old`, its node in `graph`
+    let mut new_ids = Vec::with_capacity(old.node_count());
+    let mut by_path: HashMap<NodeDescription, _> = HashMap::new();
+    let mut merged = false;
+    for mut node in old.node_weights().cloned() {
+        match &mut node.description {
+            NodeDescription::Path(path) | NodeDescription::Link(path) => {
+                if let Some(new) = rewrite(path, rules) {
+                    *path = new;
+                }
+            }
+            _ => (),
+        }
+        for (path, _) in &mut node.members {
+            if let Some(new) = rewrite(path, rules) {
+                *path = new;
+            }
+        }
+        if node.description.path().is_some() {
+            if let Some(&idx) = by_path.get(&node.description) {
+                merged = true;
+                new_ids.push(idx);
+                continue;
+            }
+        }
+        let description = node.description.clone();
+        let idx = graph.add_node(node);
+        if description.path().is_some() {
+            by_path.insert(description, idx);
+        }
+        new_ids.push(idx);
+    }
+    for edge in old.raw_edges() {
+        let from = new_ids[edge.source().index()];
+        let to = new_ids[edge.target().index()];
+        if from == to {
+            graph[from].self_reference = true;
+        } else {
+            merge_edge(&mut graph, from, to, edge.weight);
+        }
+    }
+    let mut di = DepInfos {
+        graph,
+        root: new_ids[root.index()],
+        metadata,
+        annotations,
+    };
+    if merged {
+        record_degrees(&mut di.graph);
+        for sizes in di.metadata.size.values_mut() {
+            for size in sizes.values_mut() {
+                *size = None;
+            }
+        }
+        di.record_metadata();
+    }
+    di
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rewrite::*;
+
+    #[test]
+    fn check_rewrite() {
+        let rules = vec![(b"/mnt/nix/store/".to_vec(), b"/nix/store".to_vec())];
+        assert_eq!(
+            rewrite(b"/mnt/nix/store/aaaa-hello", &rules),
+            Some(b"/nix/store/aaaa-hello".to_vec())
+        );
+        assert_eq!(rewrite(b"/mnt/nix/storefoo", &rules), None);
+        assert_eq!(rewrite(b"/nix/store/aaaa-hello", &rules), None);
+    }
+
+    #[test]
+    fn check_rewrite_prefixes() {
+        // a graph mixing both prefixes, with hello under both names
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/mnt/nix/var/nix/gcroots/a","root":true},
+            {"id":2,"kind":"Link","path":"/nix/var/nix/gcroots/b","root":true},
+            {"id":3,"path":"/mnt/nix/store/aaaa-hello","size":100},
+            {"id":4,"path":"/nix/store/aaaa-hello","size":100},
+            {"id":5,"path":"/mnt/nix/store/bbbb-glibc","size":1000}
+        ],"edges":[[1,3],[2,4],[3,5]]}"#;
+        let di = crate::import::graph(text);
+        let rules = vec![(b"/mnt/nix".to_vec(), b"/nix".to_vec())];
+        let di = rewrite_prefixes(di, &rules);
+        di.check_metadata();
+        assert_eq!(di.graph.node_count(), 5);
+        assert_eq!(di.reachable_size(), 1100);
+        let hello = crate::import::node(&di, "/nix/store/aaaa-hello");
+        assert_eq!(di.graph[hello].referrers, 2);
+        assert_eq!(
+            di.roots_name(),
+            ["/nix/var/nix/gcroots/a", "/nix/var/nix/gcroots/b"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        );
+    }
+}
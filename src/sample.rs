@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Sampling live store paths with a probability proportional to their size, to get an idea
+//! of what the store is made of without reading a full report.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+
+/// A source of random numbers, hashing a counter with the random keys std draws for
+/// `HashMap`s, which is random enough for sampling without depending on `rand`.
+#[derive(Default)]
+pub struct Random {
+    keys: RandomState,
+    counter: u64,
+}
+
+impl Random {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.counter += 1;
+        let mut hasher = self.keys.build_hasher();
+        hasher.write_u64(self.counter);
+        hasher.finish()
+    }
+
+    /// a number in `(0, 1]`
+    pub fn unit(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns `n` distinct items of `items`, each picked with a probability proportional
+    /// to its positive `weight`, like reservoir sampling by Efraimidis and Spirakis: the `n`
+    /// items with the largest `unit()^(1/weight)` are picked. Returns all of them if there
+    /// are less than `n`, and with a constant weight, a uniform sample.
+    pub fn choose_weighted<T: Copy, F: Fn(T) -> f64>(
+        &mut self,
+        items: &[T],
+        n: usize,
+        weight: F,
+    ) -> Vec<T> {
+        let mut keyed: Vec<(f64, T)> = items
+            .iter()
+            .map(|&item| (self.unit().ln() / weight(item), item))
+            .collect();
+        keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+        keyed.into_iter().take(n).map(|(_, item)| item).collect()
+    }
+}
+
+/// Returns `n` distinct live store paths of `di`, sampled with a probability proportional to
+/// their size. Returns all of them if there are less than `n`.
+pub fn sample(di: &DepInfos, n: usize, rng: &mut Random) -> Vec<NodeIndex> {
+    let mut live = Vec::new();
+    let mut dfs = di.dfs();
+    while let Some(idx) = dfs.next(&di.graph) {
+        let node = &di.graph[idx];
+        if node.kind().is_store_path() && node.size > 0 {
+            live.push(idx);
+        }
+    }
+    let mut res = rng.choose_weighted(&live, n, |idx| di.graph[idx].size as f64);
+    res.sort_by_key(|&idx| std::cmp::Reverse(di.graph[idx].size));
+    res
+}
+
+/// Prints `n` live store paths sampled by `sample`, with their size and share of the live
+/// store size.
+pub fn render<W: Write>(di: &DepInfos, n: usize, w: &mut W) -> io::Result<()> {
+    let total = di.reachable_size().max(1);
+    for idx in sample(di, n, &mut Random::new()) {
+        let node = &di.graph[idx];
+        write!(
+            w,
+            "{}\t{:.2}%\t",
+            ByteSize::b(node.size),
+            node.size as f64 * 100. / total as f64
+        )?;
+        w.write_all(node.description.path().map_or(&[][..], |p| p))?;
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sample::*;
+    use crate::selftest::generate_random;
+
+    #[test]
+    fn check_sample() {
+        let mut rng = Random::new();
+        for _ in 0..20 {
+            let di = generate_random(62, 3, false);
+            let res = sample(&di, 10, &mut rng);
+            let mut distinct = res.clone();
+            distinct.sort();
+            distinct.dedup();
+            assert_eq!(distinct.len(), res.len());
+            assert!(res.len() <= 10);
+            for &idx in &res {
+                assert_eq!(di.graph[idx].kind(), NodeKind::Path);
+                assert!(di.graph[idx].size > 0);
+            }
+        }
+        // big paths are picked first
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r","root":true},
+            {"id":2,"path":"/nix/store/a","size":1},
+            {"id":3,"path":"/nix/store/b","size":1000000000}
+        ],"edges":[[1,2],[1,3]]}"#;
+        let di = crate::import::graph(text);
+        let res = sample(&di, 1, &mut rng);
+        assert_eq!(
+            di.graph[res[0]].description.path(),
+            Some(&b"/nix/store/b".to_vec())
+        );
+    }
+}
@@ -0,0 +1,402 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Ranking the nodes of a condensed graph by a score combining their size, age and
+//! substitutability, as given by `--score-expr`, to get a single list of the best things to
+//! get rid of.
+//!
+//! The expression is arithmetic (`+ - * / ^`, parentheses, `ln`, `log2`, `sqrt`, `min`,
+//! `max`) over these variables:
+//! * `size`: the size of the node, in bytes
+//! * `age`: the age in days of the youngest gc-root retaining the node, 0 if unknown
+//! * `substitutable`: the fraction of the size of the node which a substituter can provide
+//!   again, between 0 and 1
+//! * `roots`: the number of gc-roots retaining the node
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+/// The score used when `--score-expr` is not given: big, old and substitutable nodes first
+pub const DEFAULT_EXPR: &str = "size * (1 + age / 30) * (1 + substitutable)";
+
+/// A parsed score expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Variable(Variable),
+    Neg(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variable {
+    Size,
+    Age,
+    Substitutable,
+    Roots,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Ln,
+    Log2,
+    Sqrt,
+    Min,
+    Max,
+}
+
+/// The values of the variables for a node
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Metrics {
+    pub size: f64,
+    pub age: f64,
+    pub substitutable: f64,
+    pub roots: f64,
+}
+
+/// A recursive descent parser of expressions
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_spaces(&mut self) {
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_spaces();
+        self.input.get(self.pos).copied()
+    }
+
+    fn error<T>(&self, what: &str) -> Result<T, String> {
+        Err(format!("{} at offset {}", what, self.pos))
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(&format!("expected «{}»", c as char))
+        }
+    }
+
+    /// sum := product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut res = self.product()?;
+        while let Some(op @ (b'+' | b'-')) = self.peek() {
+            self.pos += 1;
+            res = Expr::Binary(op as char, Box::new(res), Box::new(self.product()?));
+        }
+        Ok(res)
+    }
+
+    /// product := power (('*' | '/') power)*
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut res = self.power()?;
+        while let Some(op @ (b'*' | b'/')) = self.peek() {
+            self.pos += 1;
+            res = Expr::Binary(op as char, Box::new(res), Box::new(self.power()?));
+        }
+        Ok(res)
+    }
+
+    /// power := unary ('^' power)?
+    fn power(&mut self) -> Result<Expr, String> {
+        let base = self.unary()?;
+        if self.peek() == Some(b'^') {
+            self.pos += 1;
+            return Ok(Expr::Binary('^', Box::new(base), Box::new(self.power()?)));
+        }
+        Ok(base)
+    }
+
+    /// unary := '-' unary | atom
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.atom()
+    }
+
+    /// atom := number | variable | function '(' sum (',' sum)* ')' | '(' sum ')'
+    fn atom(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                let res = self.sum()?;
+                self.expect(b')')?;
+                Ok(res)
+            }
+            Some(c) if c.is_ascii_digit() || c == b'.' => {
+                let start = self.pos;
+                while self
+                    .input
+                    .get(self.pos)
+                    .is_some_and(|&c| c.is_ascii_digit() || c == b'.')
+                {
+                    self.pos += 1;
+                }
+                let text = std::str::from_utf8(&self.input[start..self.pos]).unwrap();
+                match text.parse() {
+                    Ok(x) => Ok(Expr::Number(x)),
+                    Err(_) => self.error(&format!("invalid number «{}»", text)),
+                }
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while self
+                    .input
+                    .get(self.pos)
+                    .is_some_and(|&c| c.is_ascii_alphanumeric() || c == b'_')
+                {
+                    self.pos += 1;
+                }
+                let name = std::str::from_utf8(&self.input[start..self.pos]).unwrap();
+                let function = match name {
+                    "size" => return Ok(Expr::Variable(Variable::Size)),
+                    "age" => return Ok(Expr::Variable(Variable::Age)),
+                    "substitutable" => return Ok(Expr::Variable(Variable::Substitutable)),
+                    "roots" => return Ok(Expr::Variable(Variable::Roots)),
+                    "ln" => Function::Ln,
+                    "log2" => Function::Log2,
+                    "sqrt" => Function::Sqrt,
+                    "min" => Function::Min,
+                    "max" => Function::Max,
+                    _ => {
+                        self.pos = start;
+                        return self.error(&format!("unknown name «{}»", name));
+                    }
+                };
+                self.expect(b'(')?;
+                let mut args = vec![self.sum()?];
+                while self.peek() == Some(b',') {
+                    self.pos += 1;
+                    args.push(self.sum()?);
+                }
+                self.expect(b')')?;
+                let arity_ok = match function {
+                    Function::Min | Function::Max => !args.is_empty(),
+                    _ => args.len() == 1,
+                };
+                if !arity_ok {
+                    return self.error(&format!("wrong number of arguments to «{}»", name));
+                }
+                Ok(Expr::Call(function, args))
+            }
+            Some(_) => self.error("unexpected character"),
+            None => self.error("unexpected end of expression"),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            input: text.as_bytes(),
+            pos: 0,
+        };
+        let res = parser.sum()?;
+        if parser.peek().is_some() {
+            return parser.error("unexpected character");
+        }
+        Ok(res)
+    }
+
+    pub fn eval(&self, m: &Metrics) -> f64 {
+        match self {
+            Expr::Number(x) => *x,
+            Expr::Variable(Variable::Size) => m.size,
+            Expr::Variable(Variable::Age) => m.age,
+            Expr::Variable(Variable::Substitutable) => m.substitutable,
+            Expr::Variable(Variable::Roots) => m.roots,
+            Expr::Neg(e) => -e.eval(m),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(m), b.eval(m));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    _ => a.powf(b),
+                }
+            }
+            Expr::Call(f, args) => {
+                let mut values = args.iter().map(|e| e.eval(m));
+                match f {
+                    Function::Ln => values.next().unwrap_or(0.).ln(),
+                    Function::Log2 => values.next().unwrap_or(0.).log2(),
+                    Function::Sqrt => values.next().unwrap_or(0.).sqrt(),
+                    Function::Min => values.fold(f64::INFINITY, f64::min),
+                    Function::Max => values.fold(f64::NEG_INFINITY, f64::max),
+                }
+            }
+        }
+    }
+
+    /// Returns whether the expression uses `v`
+    pub fn uses(&self, v: Variable) -> bool {
+        match self {
+            Expr::Number(_) => false,
+            Expr::Variable(w) => *w == v,
+            Expr::Neg(e) => e.uses(v),
+            Expr::Binary(_, a, b) => a.uses(v) || b.uses(v),
+            Expr::Call(_, args) => args.iter().any(|e| e.uses(v)),
+        }
+    }
+}
+
+/// Returns the store paths of `di`, including the members of merged nodes.
+pub fn store_paths(di: &DepInfos) -> Vec<&[u8]> {
+    di.graph
+        .node_weights()
+        .flat_map(|node| node.store_paths().into_iter().map(|(p, _)| p))
+        .collect()
+}
+
+/// Computes the variables of the node `idx` of the condensed graph `di`. `substitutable`
+/// contains the store paths a substituter has.
+pub fn metrics(
+    di: &DepInfos,
+    idx: NodeIndex,
+    substitutable: &BTreeMap<Path, u64>,
+    now: SystemTime,
+) -> Metrics {
+    let node = &di.graph[idx];
+    let roots = crate::unlock::roots_of(di, idx);
+    let age = roots
+        .iter()
+        .map(|&root| {
+            di.graph[root]
+                .age
+                .and_then(|t| now.duration_since(t).ok())
+                .map_or(0., |d| d.as_secs_f64() / (24. * 3600.))
+        })
+        .fold(f64::INFINITY, f64::min);
+    let paths = node.store_paths();
+    let total: u64 = paths.iter().map(|(_, size)| size).sum();
+    let available: u64 = paths
+        .iter()
+        .filter(|(p, _)| substitutable.contains_key(*p))
+        .map(|(_, size)| size)
+        .sum();
+    Metrics {
+        size: node.size as f64,
+        age: if age.is_finite() { age } else { 0. },
+        substitutable: if total == 0 {
+            0.
+        } else {
+            available as f64 / total as f64
+        },
+        roots: roots.len() as f64,
+    }
+}
+
+/// Returns the score of each node of `di` but the root, highest first.
+pub fn scores(
+    di: &DepInfos,
+    expr: &Expr,
+    substitutable: &BTreeMap<Path, u64>,
+) -> Vec<(NodeIndex, f64)> {
+    let now = SystemTime::now();
+    let mut res: Vec<(NodeIndex, f64)> = di
+        .graph
+        .node_indices()
+        .filter(|&idx| idx != di.root)
+        .map(|idx| (idx, expr.eval(&metrics(di, idx, substitutable, now))))
+        .collect();
+    res.sort_by(|a, b| b.1.total_cmp(&a.1));
+    res
+}
+
+/// Returns the store paths that `substituter` has, if `expr` needs them.
+pub fn substitutable(di: &DepInfos, expr: &Expr, substituter: &str) -> BTreeMap<Path, u64> {
+    if expr.uses(Variable::Substitutable) {
+        crate::export::compressed_sizes(&store_paths(di), substituter)
+    } else {
+        BTreeMap::new()
+    }
+}
+
+/// Prints the `n` nodes with the highest score.
+pub fn render<W: Write>(
+    di: &DepInfos,
+    scores: &[(NodeIndex, f64)],
+    n: usize,
+    w: &mut W,
+) -> io::Result<()> {
+    for &(idx, score) in scores.iter().take(n) {
+        write!(w, "{:.3e}\t{}\t", score, ByteSize::b(di.graph[idx].size))?;
+        w.write_all(&di.label(idx))?;
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::score::*;
+
+    #[test]
+    fn check_parse() {
+        let m = Metrics {
+            size: 100.,
+            age: 60.,
+            substitutable: 0.5,
+            roots: 2.,
+        };
+        let eval = |text: &str| Expr::parse(text).unwrap().eval(&m);
+        assert_eq!(eval(DEFAULT_EXPR), 100. * 3. * 1.5);
+        assert_eq!(eval("1 + 2 * 3 - 4 / 2"), 5.);
+        assert_eq!(eval("2 ^ 3 ^ 2"), 512.);
+        assert_eq!(eval("-(size - 1)"), -99.);
+        assert_eq!(eval("max(roots, 3, age) * min(1, substitutable)"), 30.);
+        assert_eq!(eval("log2(size / 25) + sqrt(4)"), 4.);
+        assert!(Expr::parse("size +").is_err());
+        assert!(Expr::parse("bogus * 2").is_err());
+        assert!(Expr::parse("ln(1, 2)").is_err());
+        assert!(Expr::parse("(size").is_err());
+        assert!(Expr::parse(DEFAULT_EXPR)
+            .unwrap()
+            .uses(Variable::Substitutable));
+        assert!(!Expr::parse("size").unwrap().uses(Variable::Age));
+    }
+
+    #[test]
+    fn check_scores() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/0aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a","size":10},
+            {"id":4,"path":"/nix/store/1bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b","size":20}
+        ],"edges":[[1,3],[2,4]]}"#;
+        let di = crate::import::graph(text);
+        let di = crate::reduction::condense(di);
+        let available: BTreeMap<Path, u64> =
+            vec![(b"/nix/store/0aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a".to_vec(), 1)]
+                .into_iter()
+                .collect();
+        let by = |text: &str| -> Vec<f64> {
+            scores(&di, &Expr::parse(text).unwrap(), &available)
+                .into_iter()
+                .map(|(_, score)| score)
+                .collect()
+        };
+        assert_eq!(by("size"), vec![20., 10.]);
+        assert_eq!(by("substitutable"), vec![1., 0.]);
+        assert_eq!(by("roots"), vec![1., 1.]);
+    }
+}
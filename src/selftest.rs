@@ -0,0 +1,250 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::*;
+use crate::reduction::*;
+use enum_map::enum_map;
+use petgraph::prelude::NodeIndex;
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use rand::Rng;
+use std::time::{Duration, SystemTime};
+
+/// asserts that `transform` preserves
+/// * the set of roots, by path
+/// * reachable size
+/// * the root, by path
+pub fn check_invariants<T: Fn(DepInfos) -> DepInfos>(transform: T, di: DepInfos, same_roots: bool) {
+    let orig = di.clone();
+    orig.check_metadata();
+    let new = transform(di);
+    if cfg!(test) {
+        println!(
+            "OLD:\n{:?}\nNew:\n{:?}",
+            petgraph::dot::Dot::new(&orig.graph),
+            petgraph::dot::Dot::new(&new.graph)
+        );
+    }
+    if same_roots {
+        assert_eq!(new.roots_name(), orig.roots_name(), "not the same roots");
+    }
+    assert_eq!(
+        new.reachable_size(),
+        orig.reachable_size(),
+        "not the same reachable size"
+    );
+    check_structure(&orig, &new);
+}
+
+/// asserts that `transform`, which filters out part of the graph, preserves
+/// * the root, by path
+///
+/// and does not increase the reachable size
+pub fn check_filter<T: Fn(DepInfos) -> DepInfos>(transform: T, di: DepInfos) {
+    let orig = di.clone();
+    orig.check_metadata();
+    let new = transform(di);
+    assert!(
+        new.reachable_size() <= orig.reachable_size(),
+        "bigger reachable size"
+    );
+    check_structure(&orig, &new);
+}
+
+/// asserts that `new`, transformed from `orig`, has consistent metadata, the same root, and
+/// no cycle
+fn check_structure(orig: &DepInfos, new: &DepInfos) {
+    new.check_metadata();
+    assert_eq!(
+        new.graph[new.root], orig.graph[orig.root],
+        "not the same root"
+    );
+    let _ = petgraph::algo::toposort(&new.graph, None).expect("the graph has a cycle");
+    assert_eq!(
+        new.graph
+            .neighbors_directed(new.root, petgraph::prelude::Incoming)
+            .count(),
+        0,
+        "incoming edges to root"
+    );
+}
+
+/// generates a random `DepInfos` where
+/// * all derivations have a distinct path
+/// * there are `size` derivations
+/// * the expected average degree of the graph should be `avg_degree`
+/// * the first 62 nodes have size `1<<index`
+///
+/// if connected is true, forces the output to be reachable from the root
+/// otherwise, it is random.
+pub fn generate_random(size: u32, avg_degree: u32, connected: bool) -> DepInfos {
+    use self::NodeDescription::*;
+    assert!(avg_degree < size);
+    let choices = &[true, false];
+    let weights = &[avg_degree, size - 1 - avg_degree];
+    let wc = WeightedIndex::new(weights).unwrap();
+    let mut rng = rand::thread_rng();
+    let mut g: DepGraph = petgraph::graph::Graph::new();
+    let rooted = rng.gen();
+    for i in 0..size {
+        let path = i.to_string().into();
+        let description = if rooted || i > 4 || rng.gen() {
+            Path(path)
+        } else {
+            if rng.gen() {
+                Memory(path)
+            } else {
+                Temporary(path)
+            }
+        };
+        let size = if i < 62 {
+            1u64 << i
+        } else {
+            3 + 2 * (i as u64)
+        };
+        let w = DepNode {
+            description,
+            size,
+            ..DepNode::dummy()
+        };
+        g.add_node(w);
+    }
+    for i in 0..size {
+        for j in (i + 1)..size {
+            if choices[wc.sample(&mut rng)] && !g[NodeIndex::from(j)].kind().is_gc_root() {
+                g.add_edge(NodeIndex::from(i), NodeIndex::from(j), Edge::Reference);
+            }
+        }
+    }
+    let mut metadata = SizeMetadata {
+        reachable: Reachability::Connected,
+        dedup: DedupAwareness::Unaware,
+        size: enum_map! { _ => enum_map!{ _ => None }},
+    };
+    let root = g.add_node(if rooted {
+        DepNode {
+            description: Path("root".into()),
+            size: 42,
+            ..DepNode::dummy()
+        }
+    } else {
+        DepNode::dummy()
+    });
+    for idx in g
+        .externals(petgraph::Direction::Incoming)
+        .collect::<Vec<_>>()
+    {
+        if !rooted && rng.gen() && g[idx].kind() == NodeKind::Path {
+            let w = &mut g[idx].description;
+            let mut temp = NodeDescription::Dummy;
+            std::mem::swap(&mut temp, w);
+            temp = match temp {
+                Path(path) => Link(path),
+                o => o,
+            };
+            std::mem::swap(&mut temp, w);
+            assert_eq!(w.kind(), NodeKind::Link);
+        }
+        let make_reachable = connected || g[idx].kind().is_gc_root() || rng.gen();
+        if root != idx && make_reachable {
+            g.add_edge(root, idx, Edge::Root);
+        }
+        if !make_reachable {
+            metadata.reachable = Reachability::Disconnected;
+        }
+    }
+    let mut di = DepInfos {
+        graph: g,
+        root,
+        metadata,
+        annotations: Default::default(),
+    };
+    // there may be edges from root to root
+    for i in di.roots().collect::<Vec<_>>() {
+        for j in di.roots().collect::<Vec<_>>() {
+            if j > i && choices[wc.sample(&mut rng)] {
+                di.graph.add_edge(i, j, Edge::Reference);
+            }
+        }
+    }
+    let _ = petgraph::algo::toposort(&di.graph, None).expect("the random graph has a cycle");
+    di.record_metadata();
+    di
+}
+
+/// checks that all reductions preserve the invariants of `check_invariants` on `di`
+pub fn check_reductions(di: DepInfos) {
+    msg!("testing merge_transient_roots\n");
+    check_invariants(merge_transient_roots, di.clone(), false);
+    msg!("testing condense\n");
+    check_invariants(condense, di.clone(), true);
+    msg!("testing keep_reachable\n");
+    check_invariants(keep_reachable, di.clone(), true);
+    msg!("testing keep none\n");
+    let trimmed = keep_reachable(di.clone());
+    check_invariants(|x| keep(x, |_| false), trimmed.clone(), false);
+    msg!("testing keep all\n");
+    check_invariants(|x| keep(x, |_| true), trimmed.clone(), true);
+    msg!("testing tred\n");
+    check_invariants(transitive_reduction, trimmed.clone(), true);
+    msg!("testing keep_matching\n");
+    let pattern = |s: &str| regex::bytes::Regex::new(s).unwrap();
+    check_invariants(
+        |x| keep_matching(x, &[pattern("1")], &[pattern("3$")]),
+        trimmed.clone(),
+        false,
+    );
+    msg!("testing keep_older_than\n");
+    check_filter(
+        |x| keep_older_than(x, Duration::from_secs(30 * 86400), SystemTime::now()),
+        di.clone(),
+    );
+    msg!("testing group_roots\n");
+    let prefixes = [b"1".to_vec(), b"12".to_vec()];
+    check_invariants(|x| group_roots(x, &prefixes), di.clone(), false);
+    msg!("testing group_generations\n");
+    check_invariants(group_generations, di.clone(), false);
+    msg!("testing collapse_cycles\n");
+    check_invariants(|x| collapse_cycles(x).0, di.clone(), true);
+    msg!("testing condense_relative_to\n");
+    // the nodes with an even path are the references
+    let is_reference = |node: &DepNode| match node.description.path() {
+        Some(p) => matches!(String::from_utf8_lossy(p).parse::<u32>(), Ok(i) if i & 1 == 0),
+        None => false,
+    };
+    check_invariants(|x| condense_relative_to(x, is_reference), di.clone(), false);
+    msg!("testing condense_kind_pure\n");
+    check_invariants(condense_kind_pure, di.clone(), false);
+    let condensed = condense(di);
+    msg!("testing top_per_root\n");
+    check_invariants(|x| top_per_root(x, 3), condensed.clone(), true);
+    // these may create cycles, which nix-du collapses afterwards
+    msg!("testing group_by_name\n");
+    check_invariants(
+        |x| collapse_cycles(group_by_name(x)).0,
+        condensed.clone(),
+        false,
+    );
+    msg!("testing group_by_system\n");
+    check_invariants(
+        |x| collapse_cycles(group_by_system(x)).0,
+        condensed.clone(),
+        false,
+    );
+    msg!("testing group_by_minimal_root\n");
+    check_invariants(
+        |x| collapse_cycles(group_by_minimal_root(x)).0,
+        condensed,
+        false,
+    );
+}
+
+/// Runs `check_reductions` on `iterations` random graphs of `size` nodes.
+///
+/// Panics if an invariant does not hold.
+pub fn run(iterations: u32, size: u32) {
+    for i in 0..iterations {
+        msg!("graph {}/{}:\n", i + 1, iterations);
+        check_reductions(generate_random(size, 10.min(size - 1), false));
+    }
+    msg!("All invariants hold.\n");
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Attributing memory and temporary roots to the systemd units of the processes owning
+//! them, to see which services, like a long-running `hydra-evaluator`, keep closures alive.
+
+use crate::depgraph::*;
+use crate::procinfo;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// What a systemd unit keeps alive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// the unit, like `nix-daemon.service`, or `None` for processes outside any service
+    /// or scope
+    pub unit: Option<String>,
+    /// the processes of the unit owning transient roots
+    pub pids: Vec<u32>,
+    /// the size of the union of the closures of the roots of these processes
+    pub size: u64,
+}
+
+/// Returns what the systemd units keep alive through the transient roots of `di`, a graph
+/// as read from the store, with `unit_of` telling the unit of a process. Biggest first.
+pub fn pinned_by_units<F: Fn(u32) -> Option<String>>(di: &DepInfos, unit_of: F) -> Vec<Service> {
+    let mut by_unit: BTreeMap<Option<String>, (Vec<u32>, Vec<NodeIndex>)> = BTreeMap::new();
+    let mut units = BTreeMap::new();
+    for idx in di.graph.node_indices() {
+        if let Some(pid) = procinfo::pid_of(&di.graph[idx].description) {
+            let unit = units.entry(pid).or_insert_with(|| unit_of(pid)).clone();
+            let (pids, roots) = by_unit.entry(unit).or_default();
+            if !pids.contains(&pid) {
+                pids.push(pid);
+            }
+            roots.push(idx);
+        }
+    }
+    let mut res: Vec<Service> = by_unit
+        .into_iter()
+        .map(|(unit, (mut pids, roots))| {
+            pids.sort_unstable();
+            Service {
+                unit,
+                pids,
+                size: procinfo::closure_size(di, roots),
+            }
+        })
+        .collect();
+    res.sort_by_key(|s| std::cmp::Reverse(s.size));
+    res
+}
+
+/// Prints what each systemd unit keeps alive
+pub fn render<W: Write>(services: &[Service], w: &mut W) -> io::Result<()> {
+    if services.is_empty() {
+        return writeln!(w, "No process keeps store paths alive.");
+    }
+    writeln!(w, "size\tprocesses\tunit")?;
+    for service in services {
+        let pids: Vec<String> = service.pids.iter().map(u32::to_string).collect();
+        writeln!(
+            w,
+            "{}\t{}\t{}",
+            ByteSize::b(service.size),
+            pids.join(","),
+            service.unit.as_deref().unwrap_or("(outside systemd units)")
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::services::*;
+
+    #[test]
+    fn check_pinned_by_units() {
+        // the evaluator has two processes sharing b, the shell is in no unit
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Temporary","path":"{temp:10}","root":true},
+            {"id":2,"kind":"Memory","path":"/proc/11/maps","root":true},
+            {"id":3,"kind":"Memory","path":"/proc/12/exe","root":true},
+            {"id":4,"path":"/nix/store/a","size":100},
+            {"id":5,"path":"/nix/store/b","size":1000},
+            {"id":6,"path":"/nix/store/c","size":10}
+        ],"edges":[[1,4],[1,5],[2,5],[3,6]]}"#;
+        let di = crate::import::graph(text);
+        let services = pinned_by_units(&di, |pid| match pid {
+            10 | 11 => Some("hydra-evaluator.service".to_owned()),
+            _ => None,
+        });
+        assert_eq!(
+            services,
+            vec![
+                Service {
+                    unit: Some("hydra-evaluator.service".to_owned()),
+                    pids: vec![10, 11],
+                    size: 1100
+                },
+                Service {
+                    unit: None,
+                    pids: vec![12],
+                    size: 10
+                },
+            ]
+        );
+        let mut out = Vec::new();
+        render(&services, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "size\tprocesses\tunit\n1.1 KB\t10,11\thydra-evaluator.service\n\
+             10 B\t12\t(outside systemd units)\n"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Checking a cleanup plan before touching the store, with `--simulate-delete ROOT...`:
+//! the gc-roots named are removed from the graph as read from the store, and what is no
+//! longer reachable is what the garbage collector would free.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+
+/// Finds the gc-root of `di` whose link is `path`, made absolute but not resolved.
+pub fn find_root(di: &DepInfos, path: &std::path::Path) -> io::Result<NodeIndex> {
+    let path = std::path::absolute(path)?;
+    let path = path.as_os_str().as_bytes();
+    di.roots()
+        .find(|&idx| di.graph[idx].description.path().map(Vec::as_slice) == Some(path))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("«{}» is not a gc-root", String::from_utf8_lossy(path)),
+            )
+        })
+}
+
+/// Returns the store paths of `di` which deleting the gc-roots `deleted` would free, biggest
+/// first. Those which are already garbage are not counted.
+pub fn freed(di: &DepInfos, deleted: &[NodeIndex]) -> Vec<NodeIndex> {
+    let mut freed = crate::plan::freed_by(di, deleted);
+    freed.difference_with(&crate::plan::freed_by(di, &[]));
+    let mut res: Vec<NodeIndex> = freed
+        .ones()
+        .map(NodeIndex::new)
+        .filter(|&idx| di.graph[idx].kind().is_store_path())
+        .collect();
+    res.sort_by_key(|&idx| std::cmp::Reverse(di.graph[idx].size));
+    res
+}
+
+/// Prints how much deleting the gc-roots `deleted` of `di` would free, and the `n` biggest
+/// store paths freed.
+pub fn render<W: Write>(
+    di: &DepInfos,
+    deleted: &[NodeIndex],
+    n: usize,
+    w: &mut W,
+) -> io::Result<()> {
+    let freed = freed(di, deleted);
+    let total: u64 = freed.iter().map(|&idx| di.graph[idx].size).sum();
+    writeln!(
+        w,
+        "Deleting {} gc-roots would free {} in {} store paths",
+        deleted.len(),
+        ByteSize::b(total),
+        freed.len()
+    )?;
+    for &idx in freed.iter().take(n) {
+        write!(w, "{}\t", ByteSize::b(di.graph[idx].size))?;
+        w.write_all(
+            di.graph[idx]
+                .description
+                .path()
+                .map_or(&[][..], Vec::as_slice),
+        )?;
+        writeln!(w)?;
+    }
+    if freed.len() > n {
+        writeln!(w, "and {} smaller store paths", freed.len() - n)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulate::*;
+
+    #[test]
+    fn check_render() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/home/alice/result","root":true},
+            {"id":2,"kind":"Link","path":"/home/alice/other","root":true},
+            {"id":3,"path":"/nix/store/a-app","size":1000},
+            {"id":4,"path":"/nix/store/b-doc","size":10},
+            {"id":5,"path":"/nix/store/c-lib","size":100},
+            {"id":6,"path":"/nix/store/d-garbage","size":5}
+        ],"edges":[[1,3],[3,4],[3,5],[2,5]]}"#;
+        let di = crate::import::graph(text);
+        let root = find_root(&di, std::path::Path::new("/home/alice/result")).unwrap();
+        assert!(find_root(&di, std::path::Path::new("/nix/store/c-lib")).is_err());
+        let mut out = Vec::new();
+        render(&di, &[root], 1, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Deleting 1 gc-roots would free 1.0 KB in 2 store paths\n\
+             1.0 KB\t/nix/store/a-app\n\
+             and 1 smaller store paths\n"
+        );
+    }
+}
@@ -0,0 +1,410 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Saving the dependency graph to a file, and loading it back.
+//!
+//! The format is binary and little endian:
+//! * a header: `MAGIC`, the number of nodes, edges and node chunks, the index of the root,
+//!   the offset of edges and the metadata;
+//! * a table giving the offset and number of nodes of each chunk;
+//! * the chunks of nodes, each node being its kind, size, degrees, self reference flag,
+//!   its path prefixed by its length, its system prefixed by its length plus one, or 0
+//!   if unknown, and its age in seconds since the epoch, or `NO_SIZE` if unknown;
+//! * the edges, as pairs of `u32` node indices followed by their kind: 0 for a reference,
+//!   1 for an edge from the root and 2 for a synthetic edge.
+//!
+//! Chunks make it possible to decode nodes in parallel.
+
+use crate::depgraph::*;
+use enum_map::enum_map;
+use petgraph::prelude::NodeIndex;
+use rayon::prelude::*;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path;
+
+const MAGIC: &[u8; 16] = b"nix-du graph v4\n";
+/// size of an edge: two node indices and its kind
+const EDGE_LEN: usize = 4 + 4 + 1;
+/// number of nodes in a chunk
+const CHUNK: usize = 1 << 16;
+/// encodes `None` in the size metadata and in ages
+const NO_SIZE: u64 = u64::MAX;
+
+/// A read only memory mapping of a whole file
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn open(path: &path::Path) -> io::Result<Self> {
+        let f = fs::File::open(path)?;
+        let len = f.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(invalid());
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                f.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr, len })
+    }
+}
+
+impl std::ops::Deref for Mmap {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupted graph file")
+}
+
+/// Reads little endian integers from a byte slice
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).ok_or_else(invalid)?;
+        let res = self.data.get(self.pos..end).ok_or_else(invalid)?;
+        self.pos = end;
+        Ok(res)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn usize(&mut self) -> io::Result<usize> {
+        let n = self.u64()?;
+        if n > self.data.len() as u64 {
+            return Err(invalid());
+        }
+        Ok(n as usize)
+    }
+}
+
+fn kind_to_u8(kind: NodeKind) -> u8 {
+    use self::NodeKind::*;
+    match kind {
+        Path => 0,
+        Link => 1,
+        Dummy => 2,
+        FilteredOut => 3,
+        Memory => 4,
+        Temporary => 5,
+        Transient => 6,
+        Shared => 7,
+        Group => 8,
+        Other => 9,
+        System => 10,
+        Common => 11,
+        Error => 12,
+        File => 13,
+        Derivation => 14,
+        Package => 15,
+    }
+}
+
+fn write_node<W: Write>(w: &mut W, node: &DepNode) -> io::Result<()> {
+    w.write_all(&[kind_to_u8(node.kind())])?;
+    w.write_all(&node.size.to_le_bytes())?;
+    w.write_all(&node.referrers.to_le_bytes())?;
+    w.write_all(&node.references.to_le_bytes())?;
+    w.write_all(&[node.self_reference as u8])?;
+    let path = node.description.path().map_or(&[][..], |p| p.as_slice());
+    w.write_all(&(path.len() as u32).to_le_bytes())?;
+    w.write_all(path)?;
+    match &node.system {
+        None => w.write_all(&0u32.to_le_bytes())?,
+        Some(system) => {
+            w.write_all(&(system.len() as u32 + 1).to_le_bytes())?;
+            w.write_all(system)?;
+        }
+    }
+    let age = match node.age.map(|t| t.duration_since(std::time::UNIX_EPOCH)) {
+        Some(Ok(age)) => age.as_secs(),
+        _ => NO_SIZE,
+    };
+    w.write_all(&age.to_le_bytes())
+}
+
+fn read_node(r: &mut Reader) -> io::Result<DepNode> {
+    use self::NodeDescription::*;
+    let kind = r.u8()?;
+    let size = r.u64()?;
+    let referrers = r.u32()?;
+    let references = r.u32()?;
+    let self_reference = r.u8()? != 0;
+    let len = r.u32()? as usize;
+    let path = r.bytes(len)?.to_vec();
+    let system = match r.u32()? as usize {
+        0 => None,
+        len => Some(r.bytes(len - 1)?.to_vec()),
+    };
+    let age = match r.u64()? {
+        NO_SIZE => None,
+        secs => Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs)),
+    };
+    let description = match kind {
+        0 => Path(path),
+        1 => Link(path),
+        2 => Dummy,
+        3 => FilteredOut,
+        4 => Memory(path),
+        5 => Temporary(path),
+        6 => Transient,
+        7 => Shared(path),
+        8 => Group(path),
+        9 => Other(path),
+        10 => System(path),
+        11 => Common,
+        12 => Error(path),
+        13 => File(path),
+        14 => Derivation(path),
+        15 => Package(path, 1),
+        _ => return Err(invalid()),
+    };
+    Ok(DepNode {
+        description,
+        size,
+        referrers,
+        references,
+        self_reference,
+        system,
+        members: Vec::new(),
+        age,
+        user: None,
+        process: None,
+    })
+}
+
+/// Saves `di` to `path`. Annotations are not saved.
+pub fn save(di: &DepInfos, path: &path::Path) -> io::Result<()> {
+    let mut w = io::BufWriter::new(fs::File::create(path)?);
+    write(di, &mut w)?;
+    w.flush()
+}
+
+/// Writes `di` to `w` in the format read by `load`.
+pub fn write<W: Write>(di: &DepInfos, w: &mut W) -> io::Result<()> {
+    let nodes = di.graph.raw_nodes();
+    // encode chunks in parallel as well
+    let chunks: Vec<Vec<u8>> = nodes
+        .par_chunks(CHUNK)
+        .map(|chunk| {
+            let mut buf = Vec::new();
+            for node in chunk {
+                write_node(&mut buf, &node.weight)?;
+            }
+            Ok(buf)
+        })
+        .collect::<io::Result<_>>()?;
+
+    let header_len = MAGIC.len() + 5 * 8 + 2 + 4 * 8;
+    let table_len = chunks.len() * 16;
+    let edges_offset = header_len + table_len + chunks.iter().map(Vec::len).sum::<usize>();
+
+    w.write_all(MAGIC)?;
+    for n in [
+        nodes.len(),
+        di.graph.edge_count(),
+        chunks.len(),
+        di.root.index(),
+        edges_offset,
+    ] {
+        w.write_all(&(n as u64).to_le_bytes())?;
+    }
+    let reachable = match di.metadata.reachable {
+        Reachability::Connected => 0,
+        Reachability::Disconnected => 1,
+    };
+    let dedup = match di.metadata.dedup {
+        DedupAwareness::Aware => 0,
+        DedupAwareness::Unaware => 1,
+    };
+    w.write_all(&[reachable, dedup])?;
+    for dedup in [DedupAwareness::Aware, DedupAwareness::Unaware] {
+        for reachable in [Reachability::Connected, Reachability::Disconnected] {
+            let size = di.metadata.size[dedup][reachable].unwrap_or(NO_SIZE);
+            w.write_all(&size.to_le_bytes())?;
+        }
+    }
+
+    let mut offset = (header_len + table_len) as u64;
+    for (i, chunk) in chunks.iter().enumerate() {
+        let count = CHUNK.min(nodes.len() - i * CHUNK) as u64;
+        w.write_all(&offset.to_le_bytes())?;
+        w.write_all(&count.to_le_bytes())?;
+        offset += chunk.len() as u64;
+    }
+    for chunk in &chunks {
+        w.write_all(chunk)?;
+    }
+    for edge in di.graph.raw_edges() {
+        w.write_all(&(edge.source().index() as u32).to_le_bytes())?;
+        w.write_all(&(edge.target().index() as u32).to_le_bytes())?;
+        w.write_all(&[match edge.weight {
+            Edge::Reference => 0,
+            Edge::Root => 1,
+            Edge::Synthetic => 2,
+        }])?;
+    }
+    Ok(())
+}
+
+/// Loads a graph saved by `save`.
+pub fn load(path: &path::Path) -> io::Result<DepInfos> {
+    let map = Mmap::open(path)?;
+    let data: &[u8] = &map;
+    let mut r = Reader::new(data, 0);
+    if r.bytes(MAGIC.len())? != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a graph saved by nix-du",
+        ));
+    }
+    let node_count = r.usize()?;
+    let edge_count = r.usize()?;
+    let chunk_count = r.usize()?;
+    let root = r.usize()?;
+    let edges_offset = r.usize()?;
+    let reachable = match r.u8()? {
+        0 => Reachability::Connected,
+        1 => Reachability::Disconnected,
+        _ => return Err(invalid()),
+    };
+    let dedup = match r.u8()? {
+        0 => DedupAwareness::Aware,
+        1 => DedupAwareness::Unaware,
+        _ => return Err(invalid()),
+    };
+    let mut metadata = SizeMetadata {
+        reachable,
+        dedup,
+        size: enum_map! { _ => enum_map!{ _ => None }},
+    };
+    for dedup in [DedupAwareness::Aware, DedupAwareness::Unaware] {
+        for reachable in [Reachability::Connected, Reachability::Disconnected] {
+            let size = r.u64()?;
+            if size != NO_SIZE {
+                metadata.size[dedup][reachable] = Some(size);
+            }
+        }
+    }
+    let table = (0..chunk_count)
+        .map(|_| Ok((r.usize()?, r.usize()?)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let chunks = table
+        .par_iter()
+        .map(|&(offset, count)| {
+            let mut r = Reader::new(data, offset);
+            (0..count).map(|_| read_node(&mut r)).collect()
+        })
+        .collect::<io::Result<Vec<Vec<DepNode>>>>()?;
+    let edges_len = edge_count.checked_mul(EDGE_LEN).ok_or_else(invalid)?;
+    let edges = Reader::new(data, edges_offset).bytes(edges_len)?;
+    let edges: Vec<(u32, u32, u8)> = edges
+        .par_chunks_exact(EDGE_LEN)
+        .map(|e| {
+            let mut r = Reader::new(e, 0);
+            (r.u32().unwrap(), r.u32().unwrap(), r.u8().unwrap())
+        })
+        .collect();
+
+    let mut graph = DepGraph::with_capacity(node_count, edge_count);
+    for node in chunks.into_iter().flatten() {
+        graph.add_node(node);
+    }
+    if graph.node_count() != node_count || root >= node_count {
+        return Err(invalid());
+    }
+    for &(from, to, kind) in &edges {
+        if from as usize >= node_count || to as usize >= node_count {
+            return Err(invalid());
+        }
+        let edge = match kind {
+            0 => Edge::Reference,
+            1 => Edge::Root,
+            2 => Edge::Synthetic,
+            _ => return Err(invalid()),
+        };
+        graph.add_edge(from.into(), to.into(), edge);
+    }
+    Ok(DepInfos {
+        graph,
+        root: NodeIndex::new(root),
+        metadata,
+        annotations: Default::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::selftest::generate_random;
+    use crate::snapshot::*;
+
+    #[test]
+    fn save_and_load() {
+        let mut di = generate_random(300, 10, false);
+        // like the edge from an unreadable path to its error
+        di.graph
+            .add_edge(NodeIndex::new(1), NodeIndex::new(2), Edge::Synthetic);
+        let path = std::env::temp_dir().join(format!("nix-du-snapshot-{}", std::process::id()));
+        save(&di, &path).unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.root, di.root);
+        assert_eq!(loaded.metadata.reachable, di.metadata.reachable);
+        assert_eq!(loaded.metadata.size, di.metadata.size);
+        let nodes = |g: &DepGraph| g.node_weights().cloned().collect::<Vec<_>>();
+        assert_eq!(nodes(&loaded.graph), nodes(&di.graph));
+        let edges = |g: &DepGraph| {
+            g.raw_edges()
+                .iter()
+                .map(|e| (e.source(), e.target(), e.weight))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(edges(&loaded.graph), edges(&di.graph));
+    }
+}
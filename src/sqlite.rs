@@ -0,0 +1,644 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Reading the tables of a SQLite database, like the database of the store, without
+//! libsqlite. The file format is described at <https://www.sqlite.org/fileformat.html>;
+//! only what scanning the rows of a table needs is supported.
+//!
+//! The write-ahead log next to the database is read too, so that the transactions nix
+//! committed but did not checkpoint yet are seen. Nothing is locked: a transaction
+//! committed while reading may make reading fail, or be seen in part.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path;
+
+const MAGIC: &[u8] = b"SQLite format 3\0";
+/// The magic numbers of write-ahead logs, whose checksums are little and big endian
+const WAL_MAGIC_LE: u32 = 0x377f_0682;
+const WAL_MAGIC_BE: u32 = 0x377f_0683;
+
+/// The value of a column in a row
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(Vec<u8>),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// Returns the bytes of a text or a blob
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Text(b) | Value::Blob(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed database: {}", what),
+    )
+}
+
+fn be16(data: &[u8], at: usize) -> io::Result<usize> {
+    match data.get(at..at + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]]) as usize),
+        None => Err(corrupt("truncated page")),
+    }
+}
+
+fn be32(data: &[u8], at: usize) -> io::Result<u32> {
+    match data.get(at..at + 4) {
+        Some(b) => Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(corrupt("truncated page")),
+    }
+}
+
+/// Decodes the variable length integer at the start of `data`, and returns it with its
+/// length
+pub fn varint(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for i in 0..9 {
+        let byte = *data.get(i)?;
+        if i == 8 {
+            return Some(((value << 8) | byte as u64, 9));
+        }
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Decodes a record, the values of the columns of a row
+pub fn record(payload: &[u8]) -> Option<Vec<Value>> {
+    let (header_size, mut pos) = varint(payload)?;
+    let header_size = header_size as usize;
+    let mut types = Vec::new();
+    while pos < header_size {
+        let (serial_type, len) = varint(payload.get(pos..)?)?;
+        types.push(serial_type);
+        pos += len;
+    }
+    let mut body = header_size;
+    let mut res = Vec::with_capacity(types.len());
+    for serial_type in types {
+        let len = match serial_type {
+            0 | 8 | 9 => 0,
+            1..=4 => serial_type as usize,
+            5 => 6,
+            6 | 7 => 8,
+            10 | 11 => return None,
+            n => (n as usize - 12) / 2,
+        };
+        let data = payload.get(body..body + len)?;
+        body += len;
+        res.push(match serial_type {
+            0 => Value::Null,
+            8 => Value::Integer(0),
+            9 => Value::Integer(1),
+            1..=6 => {
+                // sign extend from the first byte
+                let first = data[0] as i8 as i64;
+                Value::Integer(data[1..].iter().fold(first, |v, &b| (v << 8) | b as i64))
+            }
+            7 => {
+                let mut bits = [0; 8];
+                bits.copy_from_slice(data);
+                Value::Real(f64::from_bits(u64::from_be_bytes(bits)))
+            }
+            n if n % 2 == 0 => Value::Blob(data.to_vec()),
+            _ => Value::Text(data.to_vec()),
+        });
+    }
+    Some(res)
+}
+
+/// Adds the checksum of `data` to `sums`, as the write-ahead log does
+fn wal_checksum(big_endian: bool, data: &[u8], sums: (u32, u32)) -> (u32, u32) {
+    let (mut s0, mut s1) = sums;
+    let word = |b: &[u8]| {
+        let b = [b[0], b[1], b[2], b[3]];
+        if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    };
+    for chunk in data.chunks_exact(8) {
+        s0 = s0.wrapping_add(word(&chunk[..4])).wrapping_add(s1);
+        s1 = s1.wrapping_add(word(&chunk[4..])).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+/// Returns the pages of the committed transactions of the write-ahead log `data`, by page
+/// number. Frames after the last valid commit are ignored.
+pub fn wal_pages(data: &[u8], page_size: usize) -> HashMap<u32, Vec<u8>> {
+    let mut pages = HashMap::new();
+    if data.len() < 32 {
+        return pages;
+    }
+    let big_endian = match be32(data, 0) {
+        Ok(WAL_MAGIC_BE) => true,
+        Ok(WAL_MAGIC_LE) => false,
+        _ => return pages,
+    };
+    if be32(data, 8).ok() != Some(page_size as u32) {
+        return pages;
+    }
+    let mut sums = wal_checksum(big_endian, &data[..24], (0, 0));
+    if (be32(data, 24).ok(), be32(data, 28).ok()) != (Some(sums.0), Some(sums.1)) {
+        return pages;
+    }
+    let salts = &data[16..24];
+    let mut pending = HashMap::new();
+    let mut offset = 32;
+    while let Some(frame) = data.get(offset..offset + 24 + page_size) {
+        let (header, page) = frame.split_at(24);
+        if &header[8..16] != salts {
+            break;
+        }
+        sums = wal_checksum(big_endian, &header[..8], sums);
+        sums = wal_checksum(big_endian, page, sums);
+        if (be32(header, 16).ok(), be32(header, 20).ok()) != (Some(sums.0), Some(sums.1)) {
+            break;
+        }
+        if let Ok(number) = be32(header, 0) {
+            pending.insert(number, page.to_vec());
+        }
+        // the size of the database after the transaction, only set on its last frame
+        if be32(header, 4).unwrap_or(0) != 0 {
+            pages.extend(pending.drain());
+        }
+        offset += frame.len();
+    }
+    pages
+}
+
+/// A table of a database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    /// the names of the columns, in order
+    pub columns: Vec<String>,
+    /// the first page of the table
+    root: u32,
+    /// the column which is the rowid, declared `INTEGER PRIMARY KEY`, if any
+    rowid: Option<usize>,
+}
+
+impl Table {
+    /// Returns the position of the column `name` among the columns of the table
+    pub fn column(&self, name: &str) -> io::Result<usize> {
+        self.columns
+            .iter()
+            .position(|c| c.eq_ignore_ascii_case(name))
+            .ok_or_else(|| corrupt(&format!("no column {}", name)))
+    }
+}
+
+/// Returns the columns of the table created by the statement `sql`, and which of them is
+/// the rowid.
+pub fn parse_columns(sql: &str) -> Option<(Vec<String>, Option<usize>)> {
+    let inner = &sql[sql.find('(')? + 1..sql.rfind(')')?];
+    let mut definitions = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in inner.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                definitions.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    definitions.push(&inner[start..]);
+    let mut columns = Vec::new();
+    let mut rowid = None;
+    for definition in definitions {
+        let words: Vec<String> = definition
+            .split_whitespace()
+            .map(|w| w.to_ascii_lowercase())
+            .collect();
+        match words.first().map(String::as_str) {
+            None | Some("primary" | "foreign" | "unique" | "check" | "constraint") => continue,
+            Some(_) => (),
+        }
+        if words.len() >= 4 && words[1] == "integer" && words[2] == "primary" && words[3] == "key" {
+            rowid = Some(columns.len());
+        }
+        let name = definition.split_whitespace().next()?;
+        columns.push(name.trim_matches(|c| "\"`[]".contains(c)).to_owned());
+    }
+    Some((columns, rowid))
+}
+
+/// An open database
+pub struct Database {
+    file: fs::File,
+    page_size: usize,
+    /// the size of pages without the bytes reserved for extensions
+    usable: usize,
+    /// the pages more recent in the write-ahead log than in the file
+    wal: HashMap<u32, Vec<u8>>,
+    /// the number of pages of the database, to detect loops in corrupted databases
+    page_count: usize,
+}
+
+impl Database {
+    /// Opens the database at `path` for reading
+    pub fn open(path: &path::Path) -> io::Result<Self> {
+        let file = fs::File::open(path)?;
+        let mut header = [0; 100];
+        file.read_exact_at(&mut header, 0)
+            .map_err(|_| corrupt("no header"))?;
+        if &header[..16] != MAGIC {
+            return Err(corrupt("not a SQLite database"));
+        }
+        let page_size = match be16(&header, 16)? {
+            1 => 65536,
+            n => n,
+        };
+        if page_size < 512 || !page_size.is_power_of_two() {
+            return Err(corrupt("invalid page size"));
+        }
+        let usable = page_size - header[20] as usize;
+        let mut wal_path = path.as_os_str().to_owned();
+        wal_path.push("-wal");
+        let wal = match fs::read(&wal_path) {
+            Ok(data) => wal_pages(&data, page_size),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let page_count = file.metadata()?.len() as usize / page_size + wal.len();
+        Ok(Database {
+            file,
+            page_size,
+            usable,
+            wal,
+            page_count,
+        })
+    }
+
+    /// Returns page `number`, counted from 1
+    fn page(&self, number: u32) -> io::Result<Vec<u8>> {
+        if let Some(page) = self.wal.get(&number) {
+            return Ok(page.clone());
+        }
+        if number == 0 {
+            return Err(corrupt("page 0"));
+        }
+        let mut page = vec![0; self.page_size];
+        self.file
+            .read_exact_at(&mut page, (number as u64 - 1) * self.page_size as u64)
+            .map_err(|_| corrupt("page out of the file"))?;
+        Ok(page)
+    }
+
+    /// Returns the payload of the cell at `offset` of the leaf page `page`, of `size` bytes,
+    /// reading overflow pages if it does not fit
+    fn payload(&self, page: &[u8], offset: usize, size: usize) -> io::Result<Vec<u8>> {
+        let usable = self.usable;
+        let max_local = usable - 35;
+        let local = if size <= max_local {
+            size
+        } else {
+            let min_local = (usable - 12) * 32 / 255 - 23;
+            let k = min_local + (size - min_local) % (usable - 4);
+            if k <= max_local {
+                k
+            } else {
+                min_local
+            }
+        };
+        let mut res = page
+            .get(offset..offset + local)
+            .ok_or_else(|| corrupt("truncated cell"))?
+            .to_vec();
+        let mut next = if local < size {
+            be32(page, offset + local)?
+        } else {
+            0
+        };
+        let mut hops = 0;
+        while res.len() < size {
+            hops += 1;
+            if next == 0 || hops > self.page_count {
+                return Err(corrupt("truncated overflow chain"));
+            }
+            let overflow = self.page(next)?;
+            next = be32(&overflow, 0)?;
+            let len = (size - res.len()).min(usable - 4);
+            res.extend_from_slice(&overflow[4..4 + len]);
+        }
+        Ok(res)
+    }
+
+    /// Calls `f` with the rowid and the values of each row of the table b-tree starting at
+    /// page `root`
+    fn scan<F: FnMut(i64, Vec<Value>) -> io::Result<()>>(
+        &self,
+        root: u32,
+        mut f: F,
+    ) -> io::Result<()> {
+        let mut stack = vec![root];
+        let mut visited = 0;
+        while let Some(number) = stack.pop() {
+            visited += 1;
+            if visited > self.page_count {
+                return Err(corrupt("loop in a b-tree"));
+            }
+            let page = self.page(number)?;
+            // the header of the database precedes that of the first page
+            let header = if number == 1 { 100 } else { 0 };
+            let cells = be16(&page, header + 3)?;
+            match page.get(header) {
+                // interior page
+                Some(0x05) => {
+                    stack.push(be32(&page, header + 8)?);
+                    for i in (0..cells).rev() {
+                        let offset = be16(&page, header + 12 + 2 * i)?;
+                        stack.push(be32(&page, offset)?);
+                    }
+                }
+                // leaf page
+                Some(0x0d) => {
+                    for i in 0..cells {
+                        let mut offset = be16(&page, header + 8 + 2 * i)?;
+                        let cell = || corrupt("truncated cell");
+                        let (size, len) =
+                            varint(page.get(offset..).ok_or_else(cell)?).ok_or_else(cell)?;
+                        offset += len;
+                        let (rowid, len) =
+                            varint(page.get(offset..).ok_or_else(cell)?).ok_or_else(cell)?;
+                        offset += len;
+                        let payload = self.payload(&page, offset, size as usize)?;
+                        let values = record(&payload).ok_or_else(|| corrupt("invalid record"))?;
+                        f(rowid as i64, values)?;
+                    }
+                }
+                _ => return Err(corrupt("not a table b-tree page")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the table `name`
+    pub fn table(&self, name: &str) -> io::Result<Table> {
+        let mut res = None;
+        // the schema is the table `sqlite_master`, rooted at the first page: its columns
+        // are the type of the object, its name, its table, its first page and its statement
+        self.scan(1, |_, values| {
+            if let [Value::Text(kind), Value::Text(n), _, Value::Integer(root), Value::Text(sql)] =
+                values.as_slice()
+            {
+                if kind == b"table" && n.eq_ignore_ascii_case(name.as_bytes()) {
+                    let sql = String::from_utf8_lossy(sql);
+                    let (columns, rowid) =
+                        parse_columns(&sql).ok_or_else(|| corrupt("invalid schema"))?;
+                    res = Some(Table {
+                        columns,
+                        root: *root as u32,
+                        rowid,
+                    });
+                }
+            }
+            Ok(())
+        })?;
+        res.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no table {}", name)))
+    }
+
+    /// Calls `f` with the values of the columns of each row of `table`. Rows written before
+    /// a column was added lack its value, which is then `Null`.
+    pub fn rows<F: FnMut(&[Value]) -> io::Result<()>>(
+        &self,
+        table: &Table,
+        mut f: F,
+    ) -> io::Result<()> {
+        self.scan(table.root, |rowid, mut values| {
+            values.resize(table.columns.len(), Value::Null);
+            if let Some(i) = table.rowid {
+                values[i] = Value::Integer(rowid);
+            }
+            f(&values)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sqlite::*;
+
+    #[test]
+    fn check_varint_and_record() {
+        assert_eq!(varint(&[0x05]), Some((5, 1)));
+        assert_eq!(varint(&[0x81, 0x00]), Some((128, 2)));
+        assert_eq!(varint(&[0xff; 9]), Some((u64::MAX, 9)));
+        assert_eq!(varint(&[0x81]), None);
+        // NULL, 0x0102 on 2 bytes, -1 on 1 byte, the text "ab", 1
+        let payload = [6, 0, 2, 1, 17, 9, 0x01, 0x02, 0xff, b'a', b'b'];
+        assert_eq!(
+            record(&payload),
+            Some(vec![
+                Value::Null,
+                Value::Integer(258),
+                Value::Integer(-1),
+                Value::Text(b"ab".to_vec()),
+                Value::Integer(1),
+            ])
+        );
+        assert_eq!(record(&payload[..9]), None);
+    }
+
+    #[test]
+    fn check_parse_columns() {
+        let sql =
+            "CREATE TABLE ValidPaths (\n    id integer primary key autoincrement not null,\n    \
+                   path text unique not null,\n    narSize integer,\n    ca text\n)";
+        assert_eq!(
+            parse_columns(sql),
+            Some((
+                vec![
+                    "id".to_owned(),
+                    "path".to_owned(),
+                    "narSize".to_owned(),
+                    "ca".to_owned()
+                ],
+                Some(0)
+            ))
+        );
+        let sql = "create table Refs (referrer integer not null, reference integer not null, \
+                   primary key (referrer, reference), foreign key (referrer) references \
+                   ValidPaths(id) on delete cascade)";
+        assert_eq!(
+            parse_columns(sql),
+            Some((vec!["referrer".to_owned(), "reference".to_owned()], None))
+        );
+    }
+
+    fn put_varint(value: u64, out: &mut Vec<u8>) {
+        let mut bytes = vec![(value & 0x7f) as u8];
+        let mut value = value >> 7;
+        while value > 0 {
+            bytes.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        bytes.reverse();
+        out.extend(bytes);
+    }
+
+    /// Encodes a record of NULLs, integers on 8 bytes and texts
+    fn encode(values: &[Value]) -> Vec<u8> {
+        let mut types = Vec::new();
+        let mut body = Vec::new();
+        for value in values {
+            match value {
+                Value::Null => types.push(0),
+                Value::Integer(i) => {
+                    types.push(6);
+                    body.extend(i.to_be_bytes());
+                }
+                Value::Text(t) => {
+                    put_varint(13 + 2 * t.len() as u64, &mut types);
+                    body.extend(t);
+                }
+                _ => unimplemented!(),
+            }
+        }
+        // small enough headers have a size of one byte
+        let mut res = vec![types.len() as u8 + 1];
+        res.extend(types);
+        res.extend(body);
+        res
+    }
+
+    /// Returns a table leaf page of `page_size` bytes whose header is at `header`, with
+    /// `cells`
+    fn leaf(page_size: usize, header: usize, cells: &[Vec<u8>]) -> Vec<u8> {
+        let mut page = vec![0; page_size];
+        let mut end = page_size;
+        page[header] = 0x0d;
+        page[header + 3..header + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+        for (i, cell) in cells.iter().enumerate() {
+            end -= cell.len();
+            page[end..end + cell.len()].copy_from_slice(cell);
+            let pointer = header + 8 + 2 * i;
+            page[pointer..pointer + 2].copy_from_slice(&(end as u16).to_be_bytes());
+        }
+        page[header + 5..header + 7].copy_from_slice(&(end as u16).to_be_bytes());
+        page
+    }
+
+    fn cell(rowid: u64, payload: &[u8]) -> Vec<u8> {
+        let mut res = Vec::new();
+        put_varint(payload.len() as u64, &mut res);
+        put_varint(rowid, &mut res);
+        res.extend(payload);
+        res
+    }
+
+    #[test]
+    fn check_read_table() {
+        let page_size = 512;
+        let text = |s: &str| Value::Text(s.as_bytes().to_vec());
+        let sql = "CREATE TABLE Sizes (id integer primary key, name text, size integer)";
+        let schema = encode(&[
+            text("table"),
+            text("Sizes"),
+            text("Sizes"),
+            Value::Integer(2),
+            text(sql),
+        ]);
+        let mut db = leaf(page_size, 100, &[cell(1, &schema)]);
+        db[..16].copy_from_slice(MAGIC);
+        db[16..18].copy_from_slice(&(page_size as u16).to_be_bytes());
+        // with 512 bytes pages, a payload of 557 bytes keeps 49 bytes in the page and the
+        // rest in one overflow page
+        let long = "x".repeat(557 - 5 - 8);
+        let big = encode(&[Value::Null, text(&long), Value::Integer(-3)]);
+        assert_eq!(big.len(), 557);
+        let mut overflowing = Vec::new();
+        put_varint(557, &mut overflowing);
+        put_varint(7, &mut overflowing);
+        overflowing.extend(&big[..49]);
+        overflowing.extend(3u32.to_be_bytes());
+        let old = encode(&[Value::Null, text("old")]);
+        db.extend(leaf(page_size, 0, &[cell(1, &old), overflowing]));
+        let mut overflow = vec![0; 4];
+        overflow.extend(&big[49..]);
+        db.extend(overflow);
+        assert_eq!(db.len(), 3 * page_size);
+
+        let path = std::env::temp_dir().join(format!("nix-du-sqlite-{}", std::process::id()));
+        fs::write(&path, &db).unwrap();
+        let database = Database::open(&path).unwrap();
+        let table = database.table("sizes").unwrap();
+        assert_eq!(table.column("size").unwrap(), 2);
+        assert!(database.table("other").is_err());
+        let mut rows = Vec::new();
+        database
+            .rows(&table, |values| {
+                rows.push(values.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Integer(1), text("old"), Value::Null],
+                vec![Value::Integer(7), text(&long), Value::Integer(-3)],
+            ]
+        );
+    }
+
+    #[test]
+    fn check_wal_pages() {
+        let page_size = 512;
+        let mut wal = Vec::new();
+        wal.extend(WAL_MAGIC_BE.to_be_bytes());
+        wal.extend(3_007_000u32.to_be_bytes());
+        wal.extend((page_size as u32).to_be_bytes());
+        wal.extend(0u32.to_be_bytes());
+        wal.extend(b"saltsalt");
+        let mut sums = wal_checksum(true, &wal, (0, 0));
+        wal.extend(sums.0.to_be_bytes());
+        wal.extend(sums.1.to_be_bytes());
+        // a committed transaction writing page 2, and one writing page 3 which is not
+        for (number, commit) in [(2u32, 1u32), (3, 0)].iter().cloned() {
+            let mut header = Vec::new();
+            header.extend(number.to_be_bytes());
+            header.extend(commit.to_be_bytes());
+            let page = vec![number as u8; page_size];
+            sums = wal_checksum(true, &header, sums);
+            sums = wal_checksum(true, &page, sums);
+            header.extend(b"saltsalt");
+            header.extend(sums.0.to_be_bytes());
+            header.extend(sums.1.to_be_bytes());
+            wal.extend(header);
+            wal.extend(page);
+        }
+        let pages = wal_pages(&wal, page_size);
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[&2], vec![2; page_size]);
+        // a corrupted frame invalidates the rest of the log
+        wal[32 + 24] ^= 1;
+        assert!(wal_pages(&wal, page_size).is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Identifiers of nodes which stay the same across runs, unlike node indices, so that
+//! external tools can track a node of the condensed graph from one run to the next.
+//!
+//! A node of the condensed graph is the set of store paths retained by exactly the same
+//! gc-roots, so its identifier is a hash of the paths of these gc-roots, and of its kind.
+//! It survives upgrades of its members, but not the addition or removal of one of these
+//! gc-roots. Nodes retained by no gc-root, and nodes sharing the same gc-roots, like those
+//! merged by filters, are told apart by a hash of their members instead.
+//!
+//! The hash is FNV-1a, which unlike the hasher of the standard library is guaranteed to give
+//! the same result with every version of rust.
+
+use crate::depgraph::*;
+use std::collections::BTreeMap;
+
+pub(crate) const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Hashes `bytes` into `state` with FNV-1a
+pub(crate) fn fnv(state: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(state, |h, &b| (h ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// Returns what identifies a node, independently of the gc-roots retaining it
+fn own_key(node: &DepNode) -> Vec<u8> {
+    match node.description.path() {
+        Some(path) => path.clone(),
+        None => node.name().into_owned(),
+    }
+}
+
+/// Returns the hash of the members of a node
+fn members_hash(node: &DepNode) -> u64 {
+    let mut paths: Vec<&[u8]> = node.store_paths().into_iter().map(|(p, _)| p).collect();
+    paths.sort_unstable();
+    let mut h = fnv(FNV_OFFSET, &own_key(node));
+    for path in paths {
+        h = fnv(fnv(h, b"\0"), path);
+    }
+    h
+}
+
+/// Returns the stable identifier of each node of `di`, by node index, as 16 hexadecimal
+/// digits. The identifier of the root of the graph is empty.
+pub fn stable_ids(di: &DepInfos) -> Vec<String> {
+    let n = di.graph.node_count();
+    // the combination of the hashes of the gc-roots reaching each node: a wrapping sum, as
+    // it does not depend on the order the roots are visited in
+    let mut reached = vec![0u64; n];
+    let mut count = vec![0u32; n];
+    for root in di.roots() {
+        let h = fnv(FNV_OFFSET, &own_key(&di.graph[root]));
+        let mut dfs = petgraph::visit::Dfs::new(&di.graph, root);
+        while let Some(idx) = dfs.next(&di.graph) {
+            reached[idx.index()] = reached[idx.index()].wrapping_add(h);
+            count[idx.index()] += 1;
+        }
+    }
+    let mut hashes: Vec<u64> = di
+        .graph
+        .node_indices()
+        .map(|idx| {
+            let node = &di.graph[idx];
+            if count[idx.index()] == 0 {
+                return members_hash(node);
+            }
+            let kind = format!("{:?}", node.kind());
+            let h = fnv(FNV_OFFSET, kind.as_bytes());
+            let h = fnv(h, &reached[idx.index()].to_le_bytes());
+            fnv(h, &count[idx.index()].to_le_bytes())
+        })
+        .collect();
+    let mut by_hash: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+    for (i, &h) in hashes.iter().enumerate() {
+        by_hash.entry(h).or_default().push(i);
+    }
+    for same in by_hash.values().filter(|same| same.len() > 1) {
+        for &i in same {
+            let members = members_hash(&di.graph.raw_nodes()[i].weight);
+            hashes[i] = fnv(hashes[i], &members.to_le_bytes());
+        }
+    }
+    hashes
+        .into_iter()
+        .enumerate()
+        .map(|(i, h)| {
+            if i == di.root.index() {
+                String::new()
+            } else {
+                format!("{:016x}", h)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stableid::*;
+
+    fn ids(text: &[u8]) -> BTreeMap<String, String> {
+        let di = crate::import::graph(text);
+        let di = crate::reduction::condense(di);
+        let ids = stable_ids(&di);
+        di.graph
+            .node_indices()
+            .filter(|&idx| idx != di.root)
+            .map(|idx| {
+                (
+                    String::from_utf8_lossy(&di.graph[idx].name()).into_owned(),
+                    ids[idx.index()].clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn check_stable_ids() {
+        let before = ids(br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/aaaa-firefox-120.0","size":10},
+            {"id":4,"path":"/nix/store/bbbb-glibc-2.38","size":15}
+        ],"edges":[[1,3],[1,4],[2,4]]}"#);
+        // glibc was upgraded, and is now the first node
+        let after = ids(br#"{"nodes":[
+            {"id":1,"path":"/nix/store/cccc-glibc-2.39","size":16},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"kind":"Link","path":"/r1","root":true},
+            {"id":4,"path":"/nix/store/aaaa-firefox-120.0","size":10}
+        ],"edges":[[3,4],[3,1],[2,1]]}"#);
+        assert_eq!(before.len(), 3);
+        assert_eq!(before["/r1"], after["/r1"]);
+        assert_eq!(before["/r2"], after["/r2"]);
+        assert_eq!(before["glibc-2.38"], after["glibc-2.39"]);
+        assert_ne!(before["/r1"], before["/r2"]);
+        assert!(before.values().all(|id| id.len() == 16));
+    }
+}
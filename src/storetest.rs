@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Running the whole pipeline on a scratch store, `nix-du selftest --with-store DIR`, so
+//! that changes to how the store is read can be validated on real data without touching
+//! the store of the system. Only built with the `store-selftest` feature.
+//!
+//! The scratch store is the chroot store `local?root=DIR`, which libnixstore initializes
+//! on first use. The fixtures are added to it, and its gc-roots created, through the
+//! adapter, so that the graph read back is known in advance.
+
+use crate::bindings;
+use crate::depgraph::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::{c_char, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path;
+
+/// The store paths of the fixture: their name, their contents and the names of the
+/// fixtures they refer to, listed after them
+pub const FIXTURES: &[(&str, &str, &[&str])] = &[
+    ("nix-du-lib", "a library", &[]),
+    ("nix-du-app", "an application", &["nix-du-lib"]),
+    ("nix-du-doc", "its documentation", &["nix-du-lib"]),
+    ("nix-du-garbage", "an old version", &["nix-du-lib"]),
+];
+
+/// The gc-roots of the fixture: the name of the link, in `DIR/roots`, and the name of the
+/// fixture it points to. `nix-du-garbage` remains garbage.
+pub const ROOTS: &[(&str, &str)] = &[("app", "nix-du-app"), ("doc", "nix-du-doc")];
+
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn store_error(code: i32, error: bindings::store_error_t) -> io::Error {
+    io::Error::other(StoreError::from_adapter(code, error))
+}
+
+/// Adds a text file named `name` to the store opened by libnixstore, referring to the
+/// store paths `references`, and returns its store path.
+pub fn add_text_path(name: &str, contents: &str, references: &[Path]) -> io::Result<Path> {
+    let name = c_string(name.as_bytes())?;
+    let contents = c_string(contents.as_bytes())?;
+    let references = references
+        .iter()
+        .map(|r| c_string(r))
+        .collect::<io::Result<Vec<_>>>()?;
+    let pointers: Vec<*const c_char> = references.iter().map(|r| r.as_ptr()).collect();
+    let mut result: *mut c_char = std::ptr::null_mut();
+    let mut error = bindings::store_error_t {
+        err_no: 0,
+        message: std::ptr::null_mut(),
+    };
+    let res = unsafe {
+        bindings::addTextPath(
+            name.as_ptr(),
+            contents.as_ptr(),
+            pointers.as_ptr() as *mut *const c_char,
+            pointers.len() as std::os::raw::c_uint,
+            &mut result,
+            &mut error,
+        )
+    };
+    if res != 0 {
+        return Err(store_error(res, error));
+    }
+    let path = unsafe {
+        let path = CStr::from_ptr(result).to_bytes().to_vec();
+        libc::free(result as *mut c_void);
+        path
+    };
+    Ok(path)
+}
+
+/// Makes `link` a gc-root of the store path `target` of the store opened by libnixstore.
+pub fn add_root(target: &[u8], link: &path::Path) -> io::Result<()> {
+    let target = c_string(target)?;
+    let link = c_string(link.as_os_str().as_bytes())?;
+    let mut error = bindings::store_error_t {
+        err_no: 0,
+        message: std::ptr::null_mut(),
+    };
+    let res = unsafe { bindings::addRoot(target.as_ptr(), link.as_ptr(), &mut error) };
+    if res != 0 {
+        return Err(store_error(res, error));
+    }
+    Ok(())
+}
+
+/// Returns how `di`, as read from the scratch store, differs from the fixtures, whose
+/// store paths are `paths`, and from the gc-roots `links`, the fixture of each link.
+pub fn check(
+    di: &DepInfos,
+    paths: &BTreeMap<&str, Path>,
+    links: &BTreeMap<Path, &str>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let find = |path: &Path| {
+        di.graph
+            .node_indices()
+            .find(|&idx| di.graph[idx].description.path() == Some(path))
+    };
+    let show = |paths: BTreeSet<&[u8]>| {
+        let paths: Vec<_> = paths.iter().map(|p| String::from_utf8_lossy(p)).collect();
+        format!("[{}]", paths.join(", "))
+    };
+    for &(name, _, references) in FIXTURES {
+        let idx = match find(&paths[name]) {
+            Some(idx) => idx,
+            None => {
+                problems.push(format!("{} is missing", name));
+                continue;
+            }
+        };
+        if di.graph[idx].size == 0 {
+            problems.push(format!("{} has no size", name));
+        }
+        let expected: BTreeSet<&[u8]> = references.iter().map(|r| &paths[r][..]).collect();
+        let actual: BTreeSet<&[u8]> = di
+            .graph
+            .neighbors(idx)
+            .filter_map(|n| di.graph[n].description.path().map(Vec::as_slice))
+            .collect();
+        if actual != expected {
+            problems.push(format!(
+                "{} refers to {} instead of {}",
+                name,
+                show(actual),
+                show(expected)
+            ));
+        }
+    }
+    let mut seen = BTreeSet::new();
+    for root in di.roots() {
+        let link = match &di.graph[root].description {
+            NodeDescription::Link(link) => link,
+            _ => continue,
+        };
+        let shown = String::from_utf8_lossy(link);
+        match links.get(link) {
+            None => problems.push(format!("unexpected gc-root {}", shown)),
+            Some(target) => {
+                seen.insert(link);
+                if !di.graph.neighbors(root).any(|n| {
+                    di.graph[n].description.path().map(Vec::as_slice) == Some(&paths[target][..])
+                }) {
+                    problems.push(format!("the gc-root {} does not retain {}", shown, target));
+                }
+            }
+        }
+    }
+    for link in links.keys().filter(|link| !seen.contains(link)) {
+        problems.push(format!(
+            "the gc-root {} is missing",
+            String::from_utf8_lossy(link)
+        ));
+    }
+    problems
+}
+
+/// Fills a scratch store in `dir` with the fixtures, reads it back and runs the reductions
+/// and renderers on the graph.
+///
+/// Panics if an invariant of the reductions does not hold.
+pub fn run(dir: &path::Path) -> io::Result<()> {
+    if dir.join("nix/var/nix/db").exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} already contains a store, the selftest needs a scratch directory",
+                dir.display()
+            ),
+        ));
+    }
+    std::fs::create_dir_all(dir.join("roots"))?;
+    let dir = dir.canonicalize()?;
+    std::env::set_var("NIX_REMOTE", format!("local?root={}", dir.display()));
+
+    let mut paths: BTreeMap<&str, Path> = BTreeMap::new();
+    for &(name, contents, references) in FIXTURES {
+        let references: Vec<Path> = references.iter().map(|r| paths[r].clone()).collect();
+        let path = add_text_path(name, contents, &references)?;
+        paths.insert(name, path);
+    }
+    let mut links: BTreeMap<Path, &str> = BTreeMap::new();
+    for &(link, target) in ROOTS {
+        let link = dir.join("roots").join(link);
+        add_root(&paths[target], &link)?;
+        links.insert(link.as_os_str().as_bytes().to_vec(), target);
+    }
+    msg!(
+        "Added {} store paths and {} gc-roots to {}\n",
+        paths.len(),
+        links.len(),
+        dir.display()
+    );
+
+    let di =
+        DepInfos::read_from_store(Vec::new(), None, false, false, 1).map_err(io::Error::other)?;
+    let problems = check(&di, &paths, &links);
+    if !problems.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the graph read from the store differs from the fixtures: {}",
+                problems.join("; ")
+            ),
+        ));
+    }
+    msg!("The graph read from the store matches the fixtures.\n");
+
+    crate::selftest::check_reductions(di.clone());
+    let condensed = crate::reduction::condense(di.clone());
+    crate::dot::render(&condensed, &crate::dot::Style::default(), &mut io::sink())?;
+    let mut json = Vec::new();
+    crate::json::render_with(&di, &crate::json::Options::default(), &mut json)?;
+    let (imported, _) = crate::import::from_json(&crate::json::parse(&json)?)?;
+    if imported.reachable_size() != di.reachable_size() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the graph exported to JSON retains {} bytes instead of {}",
+                imported.reachable_size(),
+                di.reachable_size()
+            ),
+        ));
+    }
+    msg!("All checks passed.\n");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storetest::*;
+
+    #[test]
+    fn check_fixture_graph() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/tmp/s/roots/app","root":true},
+            {"id":2,"kind":"Link","path":"/tmp/s/roots/doc","root":true},
+            {"id":3,"path":"/nix/store/a-nix-du-lib","size":1},
+            {"id":4,"path":"/nix/store/b-nix-du-app","size":1},
+            {"id":5,"path":"/nix/store/c-nix-du-doc","size":1},
+            {"id":6,"path":"/nix/store/d-nix-du-garbage","size":1}
+        ],"edges":[[1,4],[2,5],[4,3],[5,3],[6,3]]}"#;
+        let di = crate::import::graph(text);
+        let paths: BTreeMap<&str, Path> = FIXTURES
+            .iter()
+            .zip(["a", "b", "c", "d"].iter())
+            .map(|(&(name, _, _), hash)| (name, format!("/nix/store/{}-{}", hash, name).into()))
+            .collect();
+        let mut links: BTreeMap<Path, &str> = ROOTS
+            .iter()
+            .map(|&(link, target)| (format!("/tmp/s/roots/{}", link).into(), target))
+            .collect();
+        assert_eq!(check(&di, &paths, &links), Vec::<String>::new());
+        links.insert(b"/tmp/s/roots/old".to_vec(), "nix-du-garbage");
+        assert_eq!(
+            check(&di, &paths, &links),
+            vec!["the gc-root /tmp/s/roots/old is missing".to_owned()]
+        );
+    }
+}
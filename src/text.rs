@@ -0,0 +1,89 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! The biggest nodes of the condensed graph as a plain text table, `--format text`, to
+//! triage over ssh without graphviz: their size, their label and the gc-roots retaining
+//! them.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::io::{self, Write};
+
+/// gc-roots listed per node, the others being counted
+const ROOTS_SHOWN: usize = 3;
+/// width of the label column, longer labels overflowing it
+const MAX_LABEL_WIDTH: usize = 50;
+
+/// Returns the `n` biggest nodes of `di` but its root, biggest first
+pub fn top(di: &DepInfos, n: usize) -> Vec<NodeIndex> {
+    let mut res: Vec<NodeIndex> = di
+        .graph
+        .node_indices()
+        .filter(|&idx| idx != di.root && di.graph[idx].size > 0)
+        .collect();
+    res.sort_by_cached_key(|&idx| (std::cmp::Reverse(di.graph[idx].size), di.label(idx)));
+    res.truncate(n);
+    res
+}
+
+/// Writes the `n` biggest nodes of `di`, one per line, with a header line.
+pub fn render<W: Write>(di: &DepInfos, n: usize, w: &mut W) -> io::Result<()> {
+    let rows: Vec<(NodeIndex, String)> = top(di, n)
+        .into_iter()
+        .map(|idx| (idx, String::from_utf8_lossy(&di.label(idx)).into_owned()))
+        .collect();
+    let width = rows
+        .iter()
+        .map(|(_, label)| label.chars().count())
+        .max()
+        .unwrap_or(0)
+        .clamp("NODE".len(), MAX_LABEL_WIDTH);
+    writeln!(w, "{:>10}  {:width$}  GC-ROOTS", "SIZE", "NODE")?;
+    for (idx, label) in rows {
+        let mut roots: Vec<String> = crate::unlock::roots_of(di, idx)
+            .into_iter()
+            .map(|root| String::from_utf8_lossy(&di.label(root)).into_owned())
+            .collect();
+        roots.sort();
+        let more = roots.len().saturating_sub(ROOTS_SHOWN);
+        roots.truncate(ROOTS_SHOWN);
+        if more > 0 {
+            roots.push(format!("and {} more", more));
+        }
+        writeln!(
+            w,
+            "{:>10}  {:width$}  {}",
+            ByteSize::b(di.graph[idx].size).to_string(),
+            label,
+            roots.join(", ")
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::text::*;
+
+    #[test]
+    fn check_render() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"kind":"Link","path":"/r3","root":true},
+            {"id":4,"kind":"Link","path":"/r4","root":true},
+            {"id":5,"path":"/nix/store/a-firefox","size":1000},
+            {"id":6,"path":"/nix/store/b-glibc","size":50},
+            {"id":7,"path":"/nix/store/c-hello","size":10}
+        ],"edges":[[1,5],[5,6],[2,6],[3,6],[4,6],[4,7]]}"#;
+        let di = crate::import::graph(text);
+        let mut out = Vec::new();
+        render(&di, 2, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "      SIZE  NODE     GC-ROOTS\n\
+             \x20   1.0 KB  firefox  /r1\n\
+             \x20     50 B  glibc    /r1, /r2, /r3, and 1 more\n"
+        );
+    }
+}
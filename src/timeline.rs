@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! A plot of the size retained by each gc-root against its creation date, as a Vega-Lite
+//! specification, to tell whether space is taken by recent builds or by old leftovers.
+
+use crate::depgraph::*;
+use crate::json::write_string;
+use crate::tree::DominatorTree;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+/// Returns the creation date of a gc-root, as the modification time of its link, in
+/// milliseconds since the epoch. Only links have one: the date of other roots is unknown.
+fn created(node: &DepNode) -> Option<u128> {
+    if node.kind() != NodeKind::Link {
+        return None;
+    }
+    let path = node.description.path_as_os_str()?;
+    let modified = std::path::Path::new(path)
+        .symlink_metadata()
+        .and_then(|m| m.modified())
+        .ok()?;
+    Some(
+        modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()?
+            .as_millis(),
+    )
+}
+
+/// Returns the gc-roots to plot: the roots of `di`, except groups of roots, which are
+/// replaced by their members.
+fn plotted_roots(di: &DepInfos) -> Vec<NodeIndex> {
+    let mut res = Vec::new();
+    for idx in di.roots() {
+        match di.graph[idx].kind() {
+            NodeKind::Group | NodeKind::Transient => res.extend(di.graph.neighbors(idx)),
+            _ => res.push(idx),
+        }
+    }
+    res
+}
+
+/// Writes a Vega-Lite specification plotting the size freed by removing each gc-root against
+/// the date it was created. Roots without a date, like in-memory roots, are listed in the
+/// data but not plotted.
+pub fn render<W: Write>(di: &DepInfos, w: &mut W) -> io::Result<()> {
+    let tree = DominatorTree::new(di);
+    let ids = crate::stableid::stable_ids(di);
+    w.write_all(br#"{"$schema":"https://vega.github.io/schema/vega-lite/v5.json","#)?;
+    w.write_all(br#""description":"size freed by removing each gc-root, by creation date","#)?;
+    w.write_all(br#""data":{"values":["#)?;
+    for (i, idx) in plotted_roots(di).into_iter().enumerate() {
+        if i > 0 {
+            w.write_all(b",")?;
+        }
+        let node = &di.graph[idx];
+        w.write_all(b"\n{\"root\":")?;
+        write_string(w, &di.label(idx))?;
+        write!(w, ",\"stable_id\":\"{}\"", ids[idx.index()])?;
+        w.write_all(b",\"path\":")?;
+        match node.description.path() {
+            Some(path) => write_string(w, path)?,
+            None => w.write_all(b"null")?,
+        }
+        w.write_all(b",\"created\":")?;
+        match created(node) {
+            Some(ms) => write!(w, "{}", ms)?,
+            None => w.write_all(b"null")?,
+        }
+        let retained = tree.retained[idx.index()];
+        write!(
+            w,
+            ",\"retained\":{},\"size\":\"{}\"}}",
+            retained,
+            ByteSize::b(retained)
+        )?;
+    }
+    w.write_all(b"\n]},")?;
+    w.write_all(br#""mark":{"type":"point","tooltip":true},"encoding":{"#)?;
+    w.write_all(br#""x":{"field":"created","type":"temporal","title":"creation date"},"#)?;
+    w.write_all(br#""y":{"field":"retained","type":"quantitative","title":"bytes freed by removing the root"},"#)?;
+    w.write_all(
+        br#""tooltip":[{"field":"root"},{"field":"size"},{"field":"created","type":"temporal"}]"#,
+    )?;
+    w.write_all(b"}}\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::timeline::*;
+
+    #[test]
+    fn check_render() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/","root":true},
+            {"id":2,"kind":"Memory","path":"/proc/1","root":true},
+            {"id":3,"path":"/nix/store/a","size":10},
+            {"id":4,"path":"/nix/store/b","size":100}
+        ],"edges":[[1,3],[2,4],[1,4]]}"#;
+        let di = crate::import::graph(text);
+        let mut out = Vec::new();
+        render(&di, &mut out).unwrap();
+        let spec = crate::json::parse(&out).unwrap();
+        let values = spec.get("data").unwrap().get("values").unwrap();
+        let values = values.as_array().unwrap();
+        assert_eq!(values.len(), 2);
+        let link = values
+            .iter()
+            .find(|v| v.get("path").unwrap().as_str() == Some("/"))
+            .unwrap();
+        assert_eq!(link.get("retained").unwrap().as_u64(), Some(10));
+        assert!(link.get("created").unwrap().as_u64().is_some());
+        let memory = values
+            .iter()
+            .find(|v| v.get("path").unwrap().as_str() == Some("/proc/1"))
+            .unwrap();
+        assert_eq!(memory.get("created"), Some(&crate::json::Value::Null));
+    }
+}
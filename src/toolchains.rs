@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Finds toolchains present in several versions in the store.
+//!
+//! Toolchains are big, and different gc-roots often keep different versions of the same
+//! compiler alive. This is a heuristic based on the names of store paths.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use lazy_static::lazy_static;
+use petgraph::prelude::NodeIndex;
+use petgraph::visit::{Dfs, Reversed};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// How many roots to list for each instance of a toolchain
+const MAX_ROOTS: usize = 5;
+
+/// A store path recognized as a toolchain
+#[derive(Debug)]
+pub struct Instance {
+    pub node: NodeIndex,
+    /// size of the closure of the path
+    pub closure_size: u64,
+    /// gc-roots keeping the path alive
+    pub roots: Vec<NodeIndex>,
+}
+
+/// Returns the family of the toolchain, like `gcc`, if the name of the store path is the
+/// one of a toolchain. Wrappers are ignored, as their closure contains the toolchain.
+pub fn family(name: &[u8]) -> Option<&'static str> {
+    lazy_static! {
+        static ref TOOLCHAIN: regex::bytes::Regex = regex::bytes::Regex::new(
+            r"^(gcc|gfortran|clang|llvm|rustc|cargo|ghc|go|openjdk|nodejs|binutils)(?:-unwrapped)?-[0-9][^-]*$"
+        )
+        .expect("regex compilation failed");
+    }
+    const FAMILIES: &[&str] = &[
+        "gcc", "gfortran", "clang", "llvm", "rustc", "cargo", "ghc", "go", "openjdk", "nodejs",
+        "binutils",
+    ];
+    let captures = TOOLCHAIN.captures(name)?;
+    let family = captures.get(1).unwrap().as_bytes();
+    FAMILIES.iter().copied().find(|f| f.as_bytes() == family)
+}
+
+/// Finds the toolchains of which several copies whose closure is at least `min_size` are in
+/// `di`, which must not be condensed. Returns them sorted by decreasing total size.
+pub fn find(di: &DepInfos, min_size: u64) -> Vec<(&'static str, Vec<Instance>)> {
+    let mut families: BTreeMap<&'static str, Vec<Instance>> = BTreeMap::new();
+    for idx in di.graph.node_indices() {
+        let node = &di.graph[idx];
+        if node.kind() != NodeKind::Path {
+            continue;
+        }
+        let family = match family(&node.name()) {
+            Some(f) => f,
+            None => continue,
+        };
+        let mut dfs = Dfs::new(&di.graph, idx);
+        let mut closure_size = 0;
+        while let Some(child) = dfs.next(&di.graph) {
+            closure_size += di.graph[child].size;
+        }
+        if closure_size < min_size {
+            continue;
+        }
+        let reversed = Reversed(&di.graph);
+        let mut dfs = Dfs::new(reversed, idx);
+        let mut roots = Vec::new();
+        while let Some(parent) = dfs.next(reversed) {
+            if di.graph.contains_edge(di.root, parent) {
+                roots.push(parent);
+            }
+        }
+        families.entry(family).or_default().push(Instance {
+            node: idx,
+            closure_size,
+            roots,
+        });
+    }
+    let total = |copies: &[Instance]| copies.iter().map(|c| c.closure_size).sum::<u64>();
+    let mut res: Vec<_> = families
+        .into_iter()
+        .filter(|(_, copies)| copies.len() > 1)
+        .collect();
+    for (_, copies) in res.iter_mut() {
+        copies.sort_by_key(|c| std::cmp::Reverse(c.closure_size));
+    }
+    res.sort_by_key(|(_, copies)| std::cmp::Reverse(total(copies)));
+    res
+}
+
+/// Prints the toolchains found by `find`, with the gc-roots responsible for each copy.
+pub fn render<W: Write>(di: &DepInfos, min_size: u64, w: &mut W) -> io::Result<()> {
+    let found = find(di, min_size);
+    if found.is_empty() {
+        writeln!(
+            w,
+            "No toolchain above {} in several copies.",
+            ByteSize::b(min_size)
+        )?;
+        return Ok(());
+    }
+    for (family, copies) in found {
+        let total: u64 = copies.iter().map(|c| c.closure_size).sum();
+        writeln!(
+            w,
+            "{}: {} copies, {} in total",
+            family,
+            copies.len(),
+            ByteSize::b(total)
+        )?;
+        for copy in copies {
+            let path = di.graph[copy.node].description.path().unwrap();
+            write!(
+                w,
+                "  {} ({}), kept alive by",
+                String::from_utf8_lossy(path),
+                ByteSize::b(copy.closure_size)
+            )?;
+            if copy.roots.is_empty() {
+                write!(w, " no gc-root")?;
+            }
+            for (i, &root) in copy.roots.iter().take(MAX_ROOTS).enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(w, "{} {}", sep, String::from_utf8_lossy(&di.label(root)))?;
+            }
+            if copy.roots.len() > MAX_ROOTS {
+                write!(w, " and {} more", copy.roots.len() - MAX_ROOTS)?;
+            }
+            writeln!(w)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::toolchains::*;
+
+    #[test]
+    fn check_find() {
+        assert_eq!(family(b"gcc-12.3.0"), Some("gcc"));
+        assert_eq!(family(b"rustc-unwrapped-1.75.0"), Some("rustc"));
+        assert_eq!(family(b"gcc-wrapper-12.3.0"), None);
+        assert_eq!(family(b"gcc-12.3.0-lib"), None);
+        assert_eq!(family(b"golang-1.0"), None);
+
+        let text = br#"{"nodes":[
+            {"id":1,"path":"/nix/store/aaaa-gcc-11.0","size":100},
+            {"id":2,"path":"/nix/store/bbbb-gcc-12.0","size":100},
+            {"id":3,"path":"/nix/store/cccc-libgcc-12.0","size":50},
+            {"id":4,"path":"/nix/store/dddd-rustc-1.0","size":10},
+            {"id":5,"path":"/nix/store/eeee-rustc-2.0","size":10},
+            {"id":6,"kind":"Link","path":"/root/a","root":true},
+            {"id":7,"kind":"Link","path":"/root/b","root":true}
+        ],"edges":[[6,1],[6,4],[7,2],[7,5],[2,3]]}"#;
+        let di = crate::import::graph(text);
+        let node = |path| crate::import::node(&di, path);
+        let found = find(&di, 80);
+        assert_eq!(found.len(), 1);
+        let (family, copies) = &found[0];
+        assert_eq!(*family, "gcc");
+        assert_eq!(copies[0].node, node("/nix/store/bbbb-gcc-12.0"));
+        assert_eq!(copies[0].closure_size, 150);
+        assert_eq!(copies[0].roots, vec![node("/root/b")]);
+        assert_eq!(copies[1].roots, vec![node("/root/a")]);
+    }
+}
@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::io::{self, Write};
+
+/// The dominator tree of a dependency graph.
+///
+/// A node `a` dominates `b` if all paths from the root to `b` go through `a`, so
+/// the nodes dominated by a root are exactly the ones freed when this root is removed.
+pub struct DominatorTree {
+    /// `children[i]` are the nodes immediately dominated by node `i`, biggest first
+    pub children: Vec<Vec<NodeIndex>>,
+    /// `retained[i]` is the total size of the nodes dominated by node `i`, itself included
+    pub retained: Vec<u64>,
+}
+
+impl DominatorTree {
+    /// Computes the dominator tree of `di` rooted at `di.root`.
+    ///
+    /// Nodes unreachable from the root are not part of the tree.
+    pub fn new(di: &DepInfos) -> Self {
+        let dominators = petgraph::algo::dominators::simple_fast(&di.graph, di.root);
+        let n = di.graph.node_count();
+        let mut children = vec![Vec::new(); n];
+        for idx in di.graph.node_indices() {
+            if idx == di.root {
+                continue;
+            }
+            if let Some(parent) = dominators.immediate_dominator(idx) {
+                children[parent.index()].push(idx);
+            }
+        }
+
+        // post order traversal of the tree to sum sizes
+        let mut retained: Vec<u64> = di.graph.raw_nodes().iter().map(|n| n.weight.size).collect();
+        let mut stack = vec![(di.root, false)];
+        while let Some((idx, visited)) = stack.pop() {
+            if visited {
+                let below: u64 = children[idx.index()]
+                    .iter()
+                    .map(|child| retained[child.index()])
+                    .sum();
+                retained[idx.index()] += below;
+            } else {
+                stack.push((idx, true));
+                stack.extend(children[idx.index()].iter().map(|&child| (child, false)));
+            }
+        }
+
+        for c in children.iter_mut() {
+            c.sort_by_key(|child| std::cmp::Reverse(retained[child.index()]));
+        }
+        DominatorTree { children, retained }
+    }
+}
+
+fn render_subtree<W: Write>(
+    di: &DepInfos,
+    tree: &DominatorTree,
+    idx: NodeIndex,
+    prefix: &mut String,
+    w: &mut W,
+) -> io::Result<()> {
+    let children = &tree.children[idx.index()];
+    for (i, &child) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        write!(w, "{}{}", prefix, if last { "└── " } else { "├── " })?;
+        w.write_all(&di.label(child))?;
+        writeln!(
+            w,
+            " ({}, {} itself)",
+            ByteSize::b(tree.retained[child.index()]),
+            ByteSize::b(di.graph[child].size)
+        )?;
+        let len = prefix.len();
+        prefix.push_str(if last { "    " } else { "│   " });
+        render_subtree(di, tree, child, prefix, w)?;
+        prefix.truncate(len);
+    }
+    Ok(())
+}
+
+/// Prints the dominator tree of `di` with indentation, each node labeled with the size
+/// freed by removing it (as in `du`) and its own size.
+pub fn render<W: Write>(di: &DepInfos, w: &mut W) -> io::Result<()> {
+    let tree = DominatorTree::new(di);
+    let root = &di.graph[di.root];
+    if root.kind() != NodeKind::Dummy {
+        w.write_all(&di.label(di.root))?;
+        writeln!(w, " ({})", ByteSize::b(tree.retained[di.root.index()]))?;
+    }
+    render_subtree(di, &tree, di.root, &mut String::new(), w)
+}
+
+/// Returns each gc-root of `di` with the size freed by removing only this gc-root, biggest
+/// first. `tree` is the dominator tree of `di`.
+pub fn root_sizes(di: &DepInfos, tree: &DominatorTree) -> Vec<(NodeIndex, u64)> {
+    let mut res: Vec<(NodeIndex, u64)> = di
+        .roots()
+        .map(|idx| (idx, tree.retained[idx.index()]))
+        .collect();
+    res.sort_by(|&(a, sa), &(b, sb)| sb.cmp(&sa).then_with(|| di.label(a).cmp(&di.label(b))));
+    res.dedup();
+    res
+}
+
+/// Prints a table of the size freed by removing each gc-root of `di` alone, biggest first,
+/// then their total and the size they only retain together.
+pub fn render_root_sizes<W: Write>(di: &DepInfos, w: &mut W) -> io::Result<()> {
+    let tree = DominatorTree::new(di);
+    let sizes = root_sizes(di, &tree);
+    for &(idx, size) in &sizes {
+        write!(w, "{}\t", ByteSize::b(size))?;
+        w.write_all(&di.label(idx))?;
+        writeln!(w)?;
+    }
+    let total: u64 = sizes.iter().map(|&(_, size)| size).sum();
+    writeln!(w, "{}\ttotal", ByteSize::b(total))?;
+    let shared = tree.retained[di.root.index()] - total - di.graph[di.root].size;
+    writeln!(w, "{}\tshared by several gc-roots", ByteSize::b(shared))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::selftest::generate_random;
+    use crate::tree::*;
+
+    #[test]
+    fn check_retained_sizes() {
+        for _ in 0..40 {
+            let di = crate::reduction::keep_reachable(generate_random(100, 3, false));
+            let tree = DominatorTree::new(&di);
+            assert_eq!(tree.retained[di.root.index()], di.reachable_size());
+            for idx in di.graph.node_indices() {
+                let children = &tree.children[idx.index()];
+                let below: u64 = children.iter().map(|c| tree.retained[c.index()]).sum();
+                assert_eq!(tree.retained[idx.index()], di.graph[idx].size + below);
+                for pair in children.windows(2) {
+                    assert!(tree.retained[pair[0].index()] >= tree.retained[pair[1].index()]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_root_sizes() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/a-app","size":100},
+            {"id":4,"path":"/nix/store/b-lib","size":20},
+            {"id":5,"path":"/nix/store/c-glibc","size":4000},
+            {"id":6,"path":"/nix/store/d-tool","size":300}
+        ],"edges":[[1,3],[3,4],[3,5],[2,6],[6,5]]}"#;
+        let di = crate::import::graph(text);
+        let di = crate::reduction::keep_reachable(di);
+        let mut out = Vec::new();
+        render_root_sizes(&di, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "300 B\t/r2\n120 B\t/r1\n420 B\ttotal\n4.0 KB\tshared by several gc-roots\n"
+        );
+    }
+}
@@ -0,0 +1,279 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Browsing the condensed graph in the terminal, like ncdu, with `--interactive`: starting
+//! from the gc-roots, one drills into a node to see what it refers to, biggest first, and
+//! marks the nodes to clean up, which are printed on exit.
+//!
+//! Each line shows the size of the node itself, which is what only its gc-roots retain,
+//! and the size of everything it refers to, shared or not.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use console::{Key, Term};
+use petgraph::prelude::NodeIndex;
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+/// Where the user is in the graph
+pub struct Browser<'a> {
+    di: &'a DepInfos,
+    /// the nodes drilled into, starting with the root of the graph, with the position of the
+    /// cursor among their children
+    path: Vec<(NodeIndex, usize)>,
+    /// the nodes marked by the user
+    marked: BTreeSet<NodeIndex>,
+}
+
+impl<'a> Browser<'a> {
+    /// Starts browsing `di` at its gc-roots
+    pub fn new(di: &'a DepInfos) -> Self {
+        Browser {
+            di,
+            path: vec![(di.root, 0)],
+            marked: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the nodes `idx` refers to, biggest first
+    pub fn children(&self, idx: NodeIndex) -> Vec<NodeIndex> {
+        let di = self.di;
+        let mut res: Vec<NodeIndex> = di.graph.neighbors(idx).collect();
+        res.sort_by(|&a, &b| {
+            di.graph[b]
+                .size
+                .cmp(&di.graph[a].size)
+                .then_with(|| di.label(a).cmp(&di.label(b)))
+        });
+        res.dedup();
+        res
+    }
+
+    /// Returns the size of `idx` and of everything it refers to
+    pub fn closure_size(&self, idx: NodeIndex) -> u64 {
+        let mut dfs = petgraph::visit::Dfs::new(&self.di.graph, idx);
+        let mut size = 0;
+        while let Some(n) = dfs.next(&self.di.graph) {
+            size += self.di.graph[n].size;
+        }
+        size
+    }
+
+    /// Returns the node under the cursor, if the current node has children
+    pub fn selected(&self) -> Option<NodeIndex> {
+        let &(idx, cursor) = self.path.last()?;
+        self.children(idx).get(cursor).copied()
+    }
+
+    /// Returns the marked nodes, biggest first
+    pub fn marked(&self) -> Vec<NodeIndex> {
+        let mut res: Vec<NodeIndex> = self.marked.iter().copied().collect();
+        res.sort_by_key(|&idx| std::cmp::Reverse(self.di.graph[idx].size));
+        res
+    }
+
+    /// Handles the key `key`, with `page` lines of children shown. Returns false if the user
+    /// quits.
+    pub fn key(&mut self, key: &Key, page: usize) -> bool {
+        let (idx, cursor) = *self
+            .path
+            .last()
+            .expect("the root of the graph is never left");
+        let count = self.children(idx).len();
+        let last = count.saturating_sub(1);
+        let moved = match key {
+            Key::ArrowUp | Key::Char('k') => cursor.saturating_sub(1),
+            Key::ArrowDown | Key::Char('j') => (cursor + 1).min(last),
+            Key::PageUp => cursor.saturating_sub(page.max(1)),
+            Key::PageDown => (cursor + page.max(1)).min(last),
+            Key::Home => 0,
+            Key::End => last,
+            Key::ArrowRight | Key::Enter | Key::Char('l') => {
+                if let Some(child) = self.selected() {
+                    if self.di.graph.neighbors(child).next().is_some() {
+                        self.path.push((child, 0));
+                    }
+                }
+                return true;
+            }
+            Key::ArrowLeft | Key::Backspace | Key::Char('h') => {
+                if self.path.len() > 1 {
+                    self.path.pop();
+                }
+                return true;
+            }
+            Key::Char(' ') | Key::Char('m') => {
+                if let Some(child) = self.selected() {
+                    if !self.marked.insert(child) {
+                        self.marked.remove(&child);
+                    }
+                }
+                (cursor + 1).min(last)
+            }
+            Key::Char('q') | Key::Escape => return false,
+            _ => cursor,
+        };
+        if let Some(last) = self.path.last_mut() {
+            last.1 = moved;
+        }
+        true
+    }
+
+    /// Returns the `height` lines of at most `width` characters showing the current node
+    pub fn render(&self, width: usize, height: usize) -> Vec<String> {
+        let di = self.di;
+        let (idx, cursor) = *self
+            .path
+            .last()
+            .expect("the root of the graph is never left");
+        let mut location = vec!["nix-du".to_owned()];
+        location.extend(
+            self.path[1..]
+                .iter()
+                .map(|&(idx, _)| String::from_utf8_lossy(&di.label(idx)).into_owned()),
+        );
+        let mut lines = vec![format!(
+            "{} ({})",
+            location.join(" > "),
+            ByteSize::b(self.closure_size(idx))
+        )];
+        let rows = height.saturating_sub(2);
+        let children = self.children(idx);
+        if children.is_empty() {
+            lines.push("  (no references)".to_owned());
+        }
+        let offset = (cursor + 1).saturating_sub(rows);
+        for (i, &child) in children.iter().enumerate().skip(offset).take(rows) {
+            lines.push(format!(
+                "{}{}{:>10} {:>10}  {}{}",
+                if i == cursor { '>' } else { ' ' },
+                if self.marked.contains(&child) {
+                    '*'
+                } else {
+                    ' '
+                },
+                ByteSize::b(di.graph[child].size).to_string(),
+                ByteSize::b(self.closure_size(child)).to_string(),
+                String::from_utf8_lossy(&di.label(child)),
+                if di.graph.neighbors(child).next().is_some() {
+                    "/"
+                } else {
+                    ""
+                }
+            ));
+        }
+        lines.truncate(height.saturating_sub(1));
+        while lines.len() + 1 < height {
+            lines.push(String::new());
+        }
+        let marked_size: u64 = self.marked.iter().map(|&n| di.graph[n].size).sum();
+        lines.push(format!(
+            "↑↓ move  → open  ← back  space mark  q quit   {} marked, {}",
+            self.marked.len(),
+            ByteSize::b(marked_size)
+        ));
+        lines
+            .into_iter()
+            .map(|line| line.chars().take(width).collect())
+            .collect()
+    }
+}
+
+/// Browses `di` on the terminal `term` until the user quits, and returns the marked nodes,
+/// biggest first.
+pub fn run(di: &DepInfos, term: &Term) -> io::Result<Vec<NodeIndex>> {
+    if !term.is_term() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--interactive needs a terminal",
+        ));
+    }
+    let mut browser = Browser::new(di);
+    term.hide_cursor()?;
+    let res = (|| loop {
+        let (height, width) = term.size();
+        let (height, width) = (height as usize, width as usize);
+        term.clear_screen()?;
+        let mut frame = String::new();
+        for (i, line) in browser.render(width, height).iter().enumerate() {
+            if i > 0 {
+                frame.push('\n');
+            }
+            if line.starts_with('>') {
+                frame.push_str(&console::style(line).reverse().to_string());
+            } else {
+                frame.push_str(line);
+            }
+        }
+        (&*term).write_all(frame.as_bytes())?;
+        term.flush()?;
+        if !browser.key(&term.read_key()?, height.saturating_sub(2)) {
+            return Ok(());
+        }
+    })();
+    term.clear_screen()?;
+    term.show_cursor()?;
+    res.map(|()| browser.marked())
+}
+
+/// Writes the nodes `marked` of `di` with their size, and their total.
+pub fn render_marked<W: Write>(di: &DepInfos, marked: &[NodeIndex], w: &mut W) -> io::Result<()> {
+    if marked.is_empty() {
+        return Ok(());
+    }
+    for &idx in marked {
+        write!(w, "{}\t", ByteSize::b(di.graph[idx].size))?;
+        w.write_all(&di.label(idx))?;
+        writeln!(w)?;
+    }
+    let total: u64 = marked.iter().map(|&idx| di.graph[idx].size).sum();
+    writeln!(w, "{}\ttotal", ByteSize::b(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tui::*;
+
+    #[test]
+    fn check_browser() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/aaaa-firefox-120.0","size":1000},
+            {"id":4,"path":"/nix/store/bbbb-libfoo-1.0","size":10},
+            {"id":5,"path":"/nix/store/cccc-glibc-2.38","size":50}
+        ],"edges":[[1,3],[3,4],[3,5],[2,5]]}"#;
+        let di = crate::import::graph(text);
+        let mut browser = Browser::new(&di);
+        let label =
+            |idx: Option<NodeIndex>| String::from_utf8_lossy(&di.label(idx.unwrap())).into_owned();
+        assert_eq!(label(browser.selected()), "/r1");
+        assert!(browser.key(&Key::ArrowDown, 10));
+        assert_eq!(label(browser.selected()), "/r2");
+        assert!(browser.key(&Key::ArrowDown, 10));
+        assert_eq!(label(browser.selected()), "/r2");
+        browser.key(&Key::ArrowUp, 10);
+        browser.key(&Key::Enter, 10);
+        assert_eq!(label(browser.selected()), "firefox-120.0");
+        browser.key(&Key::Enter, 10);
+        assert_eq!(label(browser.selected()), "glibc-2.38");
+        browser.key(&Key::Char(' '), 10);
+        assert_eq!(label(browser.selected()), "libfoo-1.0");
+        let lines = browser.render(80, 5);
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "nix-du > /r1 > firefox-120.0 (1.1 KB)");
+        assert_eq!(lines[1], " *      50 B       50 B  glibc-2.38");
+        assert_eq!(lines[2], ">       10 B       10 B  libfoo-1.0");
+        assert!(lines[4].ends_with("1 marked, 50 B"));
+        browser.key(&Key::Backspace, 10);
+        browser.key(&Key::Backspace, 10);
+        browser.key(&Key::Backspace, 10);
+        assert_eq!(label(browser.selected()), "/r1");
+        assert!(!browser.key(&Key::Char('q'), 10));
+        let mut out = Vec::new();
+        render_marked(&di, &browser.marked(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "50 B\tglibc-2.38\n50 B\ttotal\n"
+        );
+    }
+}
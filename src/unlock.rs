@@ -0,0 +1,324 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Finding what keeps a given store path alive.
+//!
+//! To free a store path, all the gc-roots it is reachable from must be deleted. These are
+//! often numerous, but all their references to the path may go through a handful of nodes:
+//! a minimum vertex cut between the root and the path, computed by max flow.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use fixedbitset::FixedBitSet;
+use petgraph::prelude::NodeIndex;
+use petgraph::visit::{Dfs, Reversed};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+/// capacity of edges which must not be cut
+const INFINITE: u32 = u32::MAX;
+
+/// A residual graph for max flow
+struct Network {
+    /// for each vertex, the indices of its edges in `edges`
+    adjacency: Vec<Vec<usize>>,
+    /// target and remaining capacity. Edge `i ^ 1` is the reverse of edge `i`.
+    edges: Vec<(usize, u32)>,
+}
+
+impl Network {
+    fn new(vertices: usize) -> Self {
+        Network {
+            adjacency: vec![Vec::new(); vertices],
+            edges: Vec::new(),
+        }
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize, capacity: u32) {
+        self.adjacency[from].push(self.edges.len());
+        self.edges.push((to, capacity));
+        self.adjacency[to].push(self.edges.len());
+        self.edges.push((from, 0));
+    }
+
+    /// Returns the vertices reachable from `source` in the residual graph, and for each of
+    /// them the edge it was reached by.
+    fn bfs(&self, source: usize) -> (FixedBitSet, Vec<usize>) {
+        let mut seen = FixedBitSet::with_capacity(self.adjacency.len());
+        let mut parent = vec![usize::MAX; self.adjacency.len()];
+        let mut queue = VecDeque::new();
+        seen.insert(source);
+        queue.push_back(source);
+        while let Some(v) = queue.pop_front() {
+            for &e in &self.adjacency[v] {
+                let (to, capacity) = self.edges[e];
+                if capacity > 0 && !seen.put(to) {
+                    parent[to] = e;
+                    queue.push_back(to);
+                }
+            }
+        }
+        (seen, parent)
+    }
+
+    /// Pushes flow from `source` to `sink` until there is no augmenting path left, and
+    /// returns the vertices still reachable from `source`. Stops with `sink` reachable if a
+    /// path of infinite capacity links them.
+    fn max_flow(&mut self, source: usize, sink: usize) -> FixedBitSet {
+        loop {
+            let (seen, parent) = self.bfs(source);
+            if !seen.contains(sink) {
+                return seen;
+            }
+            let mut path = Vec::new();
+            let mut v = sink;
+            while v != source {
+                path.push(parent[v]);
+                v = self.edges[parent[v] ^ 1].0;
+            }
+            let amount = path.iter().map(|&e| self.edges[e].1).min().unwrap();
+            if amount == INFINITE {
+                // this path cannot be saturated
+                return seen;
+            }
+            for e in path {
+                if self.edges[e].1 != INFINITE {
+                    self.edges[e].1 -= amount;
+                }
+                if self.edges[e ^ 1].1 != INFINITE {
+                    self.edges[e ^ 1].1 += amount;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the gc-roots `target` is reachable from.
+pub fn roots_of(di: &DepInfos, target: NodeIndex) -> Vec<NodeIndex> {
+    let reversed = Reversed(&di.graph);
+    let mut dfs = Dfs::new(reversed, target);
+    let mut roots = Vec::new();
+    while let Some(idx) = dfs.next(reversed) {
+        if di.graph.contains_edge(di.root, idx) {
+            roots.push(idx);
+        }
+    }
+    roots
+}
+
+/// Same as `roots_of`, but only follows references, so that the gc-roots merged into a node,
+/// like transient roots, are returned instead of this node.
+pub fn gc_roots_of(di: &DepInfos, target: NodeIndex) -> Vec<NodeIndex> {
+    use petgraph::visit::EdgeRef;
+    let mut seen = FixedBitSet::with_capacity(di.graph.node_count());
+    let mut stack = vec![target];
+    let mut roots = Vec::new();
+    while let Some(idx) = stack.pop() {
+        if seen.put(idx.index()) {
+            continue;
+        }
+        let mut is_root = false;
+        for e in di.graph.edges_directed(idx, petgraph::Direction::Incoming) {
+            if e.weight().is_synthetic() {
+                is_root = true;
+            } else {
+                stack.push(e.source());
+            }
+        }
+        if is_root {
+            roots.push(idx);
+        }
+    }
+    roots
+}
+
+/// Returns a smallest set of nodes, other than the root and `target`, such that all paths
+/// from the root to `target` go through one of them. Empty if `target` is not reachable,
+/// or if there is no such set because `target` is a gc-root.
+pub fn min_vertex_cut(di: &DepInfos, target: NodeIndex) -> Vec<NodeIndex> {
+    if target == di.root || di.graph.contains_edge(di.root, target) {
+        return Vec::new();
+    }
+    // each node v is split into an entry vertex 2v and an exit vertex 2v+1, linked by an
+    // edge of capacity 1, so that cutting this edge is removing v.
+    let n = di.graph.node_count();
+    let mut network = Network::new(2 * n);
+    let reversed = Reversed(&di.graph);
+    let mut ancestors = Dfs::new(reversed, target);
+    while let Some(idx) = ancestors.next(reversed) {
+        let v = idx.index();
+        let capacity = if idx == di.root || idx == target {
+            INFINITE
+        } else {
+            1
+        };
+        network.add_edge(2 * v, 2 * v + 1, capacity);
+    }
+    for edge in di.graph.raw_edges() {
+        if ancestors.discovered.contains(edge.target().index()) {
+            network.add_edge(
+                2 * edge.source().index() + 1,
+                2 * edge.target().index(),
+                INFINITE,
+            );
+        }
+    }
+    let reachable = network.max_flow(2 * di.root.index() + 1, 2 * target.index());
+    ancestors
+        .discovered
+        .ones()
+        .filter(|&v| reachable.contains(2 * v) && !reachable.contains(2 * v + 1))
+        .map(NodeIndex::new)
+        .collect()
+}
+
+/// Finds the store path designated by `query`: a path, possibly a symlink to the store, if
+/// it contains a `/`, or else the name of the store path without its hash.
+pub fn find_node(di: &DepInfos, query: &str) -> io::Result<NodeIndex> {
+    use std::os::unix::ffi::OsStrExt;
+    let not_found = |msg: String| io::Error::new(io::ErrorKind::NotFound, msg);
+    let candidates: Vec<NodeIndex> = if query.contains('/') {
+        let path = std::path::Path::new(query)
+            .canonicalize()
+            .unwrap_or_else(|_| query.into());
+        let path = path.as_os_str().as_bytes();
+        di.graph
+            .node_indices()
+            .filter(|&idx| match &di.graph[idx].description {
+                NodeDescription::Path(p) | NodeDescription::Derivation(p) => p.as_slice() == path,
+                _ => false,
+            })
+            .collect()
+    } else {
+        di.graph
+            .node_indices()
+            .filter(|&idx| {
+                di.graph[idx].kind().is_store_path()
+                    && di.graph[idx].name().as_ref() == query.as_bytes()
+            })
+            .collect()
+    };
+    match candidates.len() {
+        0 => Err(not_found(format!("no store path matches «{}»", query))),
+        1 => Ok(candidates[0]),
+        n => {
+            let mut msg = format!("{} store paths match «{}», pass a full path:", n, query);
+            for &idx in candidates.iter().take(5) {
+                let path = di.graph[idx].description.path().unwrap();
+                msg.push_str(&format!("\n  {}", String::from_utf8_lossy(path)));
+            }
+            Err(not_found(msg))
+        }
+    }
+}
+
+/// Prints what must be deleted to free `target`. Unless `synthetic`, the gc-roots merged
+/// into one node are listed one by one.
+pub fn render<W: Write>(
+    di: &DepInfos,
+    target: NodeIndex,
+    synthetic: bool,
+    w: &mut W,
+) -> io::Result<()> {
+    let label = |idx: NodeIndex| String::from_utf8_lossy(&di.label(idx)).into_owned();
+    let roots = if synthetic {
+        roots_of(di, target)
+    } else {
+        gc_roots_of(di, target)
+    };
+    writeln!(
+        w,
+        "{} ({}) is kept alive by {} gc-roots, which must all be deleted to free it:",
+        label(target),
+        ByteSize::b(di.graph[target].size),
+        roots.len()
+    )?;
+    for &root in &roots {
+        writeln!(w, "  {}", label(root))?;
+    }
+    let cut = min_vertex_cut(di, target);
+    if !cut.is_empty() && cut.len() < roots.len() {
+        writeln!(
+            w,
+            "All their references to it go through only {} nodes:",
+            cut.len()
+        )?;
+        for idx in cut {
+            let roots = roots_of(di, idx).len();
+            writeln!(w, "  {}, kept alive by {} gc-roots", label(idx), roots)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::unlock::*;
+
+    #[test]
+    fn check_min_vertex_cut() {
+        // three roots reach blob through mid, and one directly
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"kind":"Link","path":"/r3","root":true},
+            {"id":4,"kind":"Link","path":"/r4","root":true},
+            {"id":5,"path":"/nix/store/a-mid","size":1},
+            {"id":6,"path":"/nix/store/b-blob","size":1},
+            {"id":7,"path":"/nix/store/c-other","size":1}
+        ],"edges":[[1,5],[2,5],[3,5],[5,6],[4,6],[4,7]]}"#;
+        let di = crate::import::graph(text);
+        let node = |path| crate::import::node(&di, path);
+        let blob = node("/nix/store/b-blob");
+        assert_eq!(roots_of(&di, blob).len(), 4);
+        assert_eq!(gc_roots_of(&di, blob).len(), 4);
+        let mut cut = min_vertex_cut(&di, blob);
+        cut.sort();
+        let mut expected = vec![node("/nix/store/a-mid"), node("/r4")];
+        expected.sort();
+        assert_eq!(cut, expected);
+        assert_eq!(
+            min_vertex_cut(&di, node("/nix/store/c-other")),
+            vec![node("/r4")]
+        );
+    }
+
+    #[test]
+    fn check_min_vertex_cut_of_root() {
+        // like --root A --root B: the dummy root refers to the paths directly
+        let text = br#"{"nodes":[
+            {"id":1,"path":"/nix/store/a-app","size":1,"root":true},
+            {"id":2,"path":"/nix/store/b-tool","size":1,"root":true},
+            {"id":3,"path":"/nix/store/c-lib","size":1}
+        ],"edges":[[1,3],[2,3],[2,1]]}"#;
+        let di = crate::import::graph(text);
+        let app = crate::import::node(&di, "/nix/store/a-app");
+        assert!(di.graph.contains_edge(di.root, app));
+        assert_eq!(min_vertex_cut(&di, app), vec![]);
+        let mut out = Vec::new();
+        render(&di, app, false, &mut out).unwrap();
+    }
+
+    #[test]
+    fn check_gc_roots_of() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Memory","path":"/proc/1","root":true},
+            {"id":2,"kind":"Temporary","path":"/proc/2","root":true},
+            {"id":3,"kind":"Link","path":"/r","root":true},
+            {"id":4,"path":"/nix/store/a","size":1}
+        ],"edges":[[1,4],[2,4],[3,4]]}"#;
+        let di = crate::import::graph(text);
+        let di = crate::reduction::merge_transient_roots(di);
+        let a = crate::import::node(&di, "/nix/store/a");
+        // the transient roots are merged into one root
+        assert_eq!(roots_of(&di, a).len(), 2);
+        let mut names: Vec<_> = gc_roots_of(&di, a)
+            .into_iter()
+            .map(|idx| di.label(idx).into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![b"/proc/1".to_vec(), b"/proc/2".to_vec(), b"/r".to_vec()]
+        );
+    }
+}
@@ -0,0 +1,298 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Cross-checking a sample of the graph against `nix-store --query`, to make sure that the
+//! graph read by nix-du, or imported from elsewhere, describes the store faithfully.
+//!
+//! Edges towards derivations, added when `keep-derivations` or `keep-outputs` are set, are
+//! not references, so only store paths which are not derivations are checked, and the
+//! derivations they are linked to are ignored.
+
+use crate::depgraph::*;
+use crate::sample::Random;
+use bytesize::ByteSize;
+use petgraph::prelude::NodeIndex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::process::Command;
+
+/// How `nix-store` and the graph disagree about a store path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// a reference known to nix has no edge in the graph
+    MissingReference(Path),
+    /// an edge of the graph is not a reference according to nix
+    ExtraReference(Path),
+    /// nix and the graph disagree on whether the path refers to itself
+    SelfReference { nix: bool },
+    /// the size of the path itself
+    Size { ours: u64, nix: u64 },
+    /// the size and number of paths of the closure
+    Closure {
+        ours: (u64, usize),
+        nix: (u64, usize),
+    },
+}
+
+impl std::fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Discrepancy::MissingReference(p) => {
+                write!(f, "missing reference to {}", String::from_utf8_lossy(p))
+            }
+            Discrepancy::ExtraReference(p) => {
+                write!(f, "extra reference to {}", String::from_utf8_lossy(p))
+            }
+            Discrepancy::SelfReference { nix } => write!(
+                f,
+                "nix says it {} itself",
+                if *nix {
+                    "refers to"
+                } else {
+                    "does not refer to"
+                }
+            ),
+            Discrepancy::Size { ours, nix } => write!(
+                f,
+                "size is {} but nix says {}",
+                ByteSize::b(*ours),
+                ByteSize::b(*nix)
+            ),
+            Discrepancy::Closure { ours, nix } => write!(
+                f,
+                "closure is {} in {} paths but nix says {} in {} paths",
+                ByteSize::b(ours.0),
+                ours.1,
+                ByteSize::b(nix.0),
+                nix.1
+            ),
+        }
+    }
+}
+
+fn is_drv(path: &[u8]) -> bool {
+    path.ends_with(b".drv")
+}
+
+/// Runs `nix-store --query` with `args` and returns the lines of its output.
+fn query(args: &[&[u8]]) -> io::Result<Vec<Path>> {
+    use std::os::unix::ffi::OsStrExt;
+    let out = Command::new("nix-store")
+        .arg("--query")
+        .args(args.iter().map(|a| std::ffi::OsStr::from_bytes(a)))
+        .output()?;
+    if !out.status.success() {
+        return Err(io::Error::other(format!(
+            "nix-store --query failed with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(out
+        .stdout
+        .split(|&c| c == b'\n')
+        .filter(|l| !l.is_empty())
+        .map(<[u8]>::to_vec)
+        .collect())
+}
+
+/// Compares the edges of `idx` with `references`, the references of its path according to
+/// nix.
+pub fn compare_references(di: &DepInfos, idx: NodeIndex, references: &[Path]) -> Vec<Discrepancy> {
+    let node = &di.graph[idx];
+    let own = node.description.path();
+    let nix: BTreeSet<&[u8]> = references
+        .iter()
+        .map(Path::as_slice)
+        .filter(|&p| Some(p) != own.map(Path::as_slice))
+        .collect();
+    let ours: BTreeSet<&[u8]> = di
+        .graph
+        .neighbors(idx)
+        .filter_map(|n| match &di.graph[n].description {
+            NodeDescription::Path(p) => Some(p.as_slice()),
+            _ => None,
+        })
+        .collect();
+    let mut res = Vec::new();
+    for &p in nix.difference(&ours) {
+        res.push(Discrepancy::MissingReference(p.to_vec()));
+    }
+    for &p in ours.difference(&nix) {
+        if !is_drv(p) {
+            res.push(Discrepancy::ExtraReference(p.to_vec()));
+        }
+    }
+    let self_reference = own.is_some_and(|own| references.contains(own));
+    if self_reference != node.self_reference {
+        res.push(Discrepancy::SelfReference {
+            nix: self_reference,
+        });
+    }
+    res
+}
+
+/// Compares the size and closure of `idx` with `closure`, the requisites of its path and
+/// their sizes according to nix.
+pub fn compare_closure(
+    di: &DepInfos,
+    idx: NodeIndex,
+    closure: &BTreeMap<Path, u64>,
+) -> Vec<Discrepancy> {
+    let mut res = Vec::new();
+    let node = &di.graph[idx];
+    if let Some(&nix) = node.description.path().and_then(|p| closure.get(p)) {
+        if nix != node.size {
+            res.push(Discrepancy::Size {
+                ours: node.size,
+                nix,
+            });
+        }
+    }
+    // edges to derivations, and thus out of them, are not followed
+    let mut ours = (0, 0);
+    let mut seen = BTreeSet::new();
+    let mut stack = vec![idx];
+    while let Some(n) = stack.pop() {
+        if !seen.insert(n) {
+            continue;
+        }
+        ours.0 += di.graph[n].size;
+        ours.1 += 1;
+        stack.extend(di.graph.neighbors(n).filter(
+            |&m| matches!(&di.graph[m].description, NodeDescription::Path(p) if !is_drv(p)),
+        ));
+    }
+    let nix = (closure.values().sum(), closure.len());
+    if ours != nix {
+        res.push(Discrepancy::Closure { ours, nix });
+    }
+    res
+}
+
+/// Queries nix about the path of `idx` and compares with the graph.
+pub fn check(di: &DepInfos, idx: NodeIndex) -> io::Result<Vec<Discrepancy>> {
+    let path = di.graph[idx]
+        .description
+        .path()
+        .expect("only store paths are checked");
+    let references = query(&[b"--references", path])?;
+    let mut res = compare_references(di, idx, &references);
+    let requisites = query(&[b"--requisites", path])?;
+    let mut args: Vec<&[u8]> = vec![b"--size"];
+    args.extend(requisites.iter().map(Path::as_slice));
+    let sizes = query(&args)?;
+    if sizes.len() != requisites.len() {
+        return Err(io::Error::other(
+            "unexpected output of nix-store --query --size",
+        ));
+    }
+    let mut closure = BTreeMap::new();
+    for (p, size) in requisites.into_iter().zip(sizes) {
+        let size = String::from_utf8_lossy(&size)
+            .trim()
+            .parse()
+            .map_err(io::Error::other)?;
+        closure.insert(p, size);
+    }
+    res.extend(compare_closure(di, idx, &closure));
+    Ok(res)
+}
+
+/// Returns `n` random store paths of `di` which are not derivations.
+pub fn sample(di: &DepInfos, n: usize, rng: &mut Random) -> Vec<NodeIndex> {
+    let candidates: Vec<NodeIndex> = di
+        .graph
+        .node_indices()
+        .filter(|&idx| match &di.graph[idx].description {
+            NodeDescription::Path(p) => !is_drv(p),
+            _ => false,
+        })
+        .collect();
+    rng.choose_weighted(&candidates, n, |_| 1.)
+}
+
+/// Checks `n` random store paths of `di`, prints the discrepancies found, and returns their
+/// number.
+pub fn render<W: Write>(di: &DepInfos, n: usize, w: &mut W) -> io::Result<usize> {
+    let sampled = sample(di, n, &mut Random::new());
+    let mut count = 0;
+    for &idx in &sampled {
+        let found = check(di, idx)?;
+        if found.is_empty() {
+            continue;
+        }
+        count += found.len();
+        w.write_all(
+            di.graph[idx]
+                .description
+                .path()
+                .expect("sampled a store path"),
+        )?;
+        writeln!(w, ":")?;
+        for d in found {
+            writeln!(w, "  {}", d)?;
+        }
+    }
+    writeln!(
+        w,
+        "{} store paths checked, {} discrepancies found.",
+        sampled.len(),
+        count
+    )?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::verify::*;
+
+    #[test]
+    fn check_compare() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r","root":true},
+            {"id":2,"path":"/nix/store/a","size":10},
+            {"id":3,"path":"/nix/store/b","size":5},
+            {"id":4,"path":"/nix/store/a.drv","size":1},
+            {"id":5,"path":"/nix/store/c","size":2}
+        ],"edges":[[1,2],[2,3],[2,4],[4,5]]}"#;
+        let di = crate::import::graph(text);
+        let a = crate::import::node(&di, "/nix/store/a");
+        let refs =
+            |paths: &[&str]| -> Vec<Path> { paths.iter().map(|p| p.as_bytes().to_vec()).collect() };
+        assert_eq!(compare_references(&di, a, &refs(&["/nix/store/b"])), vec![]);
+        assert_eq!(
+            compare_references(&di, a, &refs(&["/nix/store/a", "/nix/store/c"])),
+            vec![
+                Discrepancy::MissingReference(b"/nix/store/c".to_vec()),
+                Discrepancy::ExtraReference(b"/nix/store/b".to_vec()),
+                Discrepancy::SelfReference { nix: true },
+            ]
+        );
+
+        let closure = |paths: &[(&str, u64)]| -> BTreeMap<Path, u64> {
+            paths
+                .iter()
+                .map(|&(p, s)| (p.as_bytes().to_vec(), s))
+                .collect()
+        };
+        // the derivation and what it refers to are not part of the closure
+        assert_eq!(
+            compare_closure(
+                &di,
+                a,
+                &closure(&[("/nix/store/a", 10), ("/nix/store/b", 5)])
+            ),
+            vec![]
+        );
+        assert_eq!(
+            compare_closure(&di, a, &closure(&[("/nix/store/a", 12)])),
+            vec![
+                Discrepancy::Size { ours: 10, nix: 12 },
+                Discrepancy::Closure {
+                    ours: (15, 2),
+                    nix: (12, 1)
+                }
+            ]
+        );
+    }
+}
@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Watching the size retained by some packages from one run to the next, to catch
+//! regressions like a closure growing after a nixpkgs bump.
+//!
+//! The retained size of a package is what removing all the store paths whose name matches
+//! its pattern would free. The sizes of the last run are saved in the cache directory.
+
+use crate::depgraph::*;
+use crate::digest::delta;
+use bytesize::ByteSize;
+use regex::bytes::Regex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+/// Returns the size retained by the store paths of `di` whose name matches `pattern`, and
+/// the number of such paths.
+pub fn retained(di: &DepInfos, pattern: &Regex) -> (u64, usize) {
+    let matching: Vec<bool> = di
+        .graph
+        .node_weights()
+        .map(|node| node.kind() == NodeKind::Path && pattern.is_match(&node.name()))
+        .collect();
+    // what stays reachable without the matching paths; a dfs would go through them
+    let mut seen = fixedbitset::FixedBitSet::with_capacity(di.graph.node_count());
+    let mut stack = vec![di.root];
+    let mut without = 0;
+    while let Some(idx) = stack.pop() {
+        if matching[idx.index()] || seen.put(idx.index()) {
+            continue;
+        }
+        without += di.graph[idx].size;
+        stack.extend(di.graph.neighbors(idx));
+    }
+    let count = matching.iter().filter(|&&m| m).count();
+    (di.reachable_size() - without, count)
+}
+
+/// Returns the size retained by each of `patterns`, by pattern
+pub fn measure(di: &DepInfos, patterns: &[Regex]) -> BTreeMap<String, u64> {
+    patterns
+        .iter()
+        .map(|pattern| (pattern.as_str().to_owned(), retained(di, pattern).0))
+        .collect()
+}
+
+/// Reads the sizes saved by `save`. Returns an empty map if the file does not exist.
+pub fn load(path: &std::path::Path) -> io::Result<BTreeMap<String, u64>> {
+    let f = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupted watch state");
+    let mut res = BTreeMap::new();
+    for line in io::BufReader::new(f).lines() {
+        let line = line?;
+        let (size, pattern) = line.split_once('\t').ok_or_else(invalid)?;
+        res.insert(pattern.to_owned(), size.parse().map_err(|_| invalid())?);
+    }
+    Ok(res)
+}
+
+/// Saves `sizes` to `path`, one `SIZE\tPATTERN` line per pattern.
+pub fn save(sizes: &BTreeMap<String, u64>, path: &std::path::Path) -> io::Result<()> {
+    let mut f = io::BufWriter::new(fs::File::create(path)?);
+    for (pattern, size) in sizes {
+        if !pattern.contains('\n') {
+            writeln!(f, "{}\t{}", size, pattern)?;
+        }
+    }
+    f.flush()
+}
+
+/// Prints the size retained by each watched pattern and its growth since `previous`.
+/// Returns the number of patterns which grew by more than `threshold`.
+pub fn render<W: Write>(
+    current: &BTreeMap<String, u64>,
+    previous: &BTreeMap<String, u64>,
+    threshold: u64,
+    w: &mut W,
+) -> io::Result<usize> {
+    let mut regressions = 0;
+    for (pattern, &size) in current {
+        match previous.get(pattern) {
+            None => writeln!(w, "{}: {} (first run)", pattern, ByteSize::b(size))?,
+            Some(&old) => {
+                let alarm = size > old && size - old > threshold;
+                writeln!(
+                    w,
+                    "{}: {} ({}){}",
+                    pattern,
+                    ByteSize::b(size),
+                    delta(size, old),
+                    if alarm { " REGRESSION" } else { "" }
+                )?;
+                regressions += alarm as usize;
+            }
+        }
+    }
+    Ok(regressions)
+}
+
+/// Measures `patterns` in `di`, an uncondensed graph, reports their growth since the last
+/// run to `w`, and saves their sizes for the next one. Returns the number of regressions.
+pub fn run<W: Write>(
+    di: &DepInfos,
+    patterns: &[Regex],
+    threshold: u64,
+    w: &mut W,
+) -> io::Result<usize> {
+    let state: PathBuf = crate::cache::cache_dir()?.join("watch");
+    let mut previous = load(&state)?;
+    let current = measure(di, patterns);
+    let regressions = render(&current, &previous, threshold, w)?;
+    // keep the sizes of the patterns not watched this time
+    previous.extend(current);
+    save(&previous, &state)?;
+    Ok(regressions)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::watch::*;
+
+    #[test]
+    fn check_retained() {
+        // firefox alone keeps libfoo, glibc is also kept by bash
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"path":"/nix/store/aaaa-firefox-120.0","size":100},
+            {"id":4,"path":"/nix/store/bbbb-firefox-unwrapped-120.0","size":1000},
+            {"id":5,"path":"/nix/store/cccc-libfoo-1.0","size":10},
+            {"id":6,"path":"/nix/store/dddd-glibc-2.38","size":50},
+            {"id":7,"path":"/nix/store/eeee-bash-5.2","size":5}
+        ],"edges":[[1,3],[3,4],[4,5],[4,6],[2,7],[7,6]]}"#;
+        let di = crate::import::graph(text);
+        let firefox = Regex::new("^firefox").unwrap();
+        assert_eq!(retained(&di, &firefox), (1110, 2));
+        assert_eq!(retained(&di, &Regex::new("^glibc-").unwrap()), (50, 1));
+        assert_eq!(retained(&di, &Regex::new("^nothing").unwrap()), (0, 0));
+
+        let current = measure(&di, &[firefox]);
+        let mut out = Vec::new();
+        assert_eq!(render(&current, &BTreeMap::new(), 0, &mut out).unwrap(), 0);
+        let previous: BTreeMap<String, u64> =
+            vec![("^firefox".to_owned(), 1000)].into_iter().collect();
+        assert_eq!(render(&current, &previous, 200, &mut out).unwrap(), 0);
+        assert_eq!(render(&current, &previous, 100, &mut out).unwrap(), 1);
+
+        let dir = std::env::temp_dir().join(format!("nix-du-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        save(&current, &dir.join("state")).unwrap();
+        assert_eq!(load(&dir.join("state")).unwrap(), current);
+        assert!(load(&dir.join("missing")).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
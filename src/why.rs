@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Telling why a store path is alive: every gc-root it is reachable from, each with one
+//! chain of references leading from the gc-root to the path, like `nix-store --query
+//! --roots` with the reason.
+//!
+//! Chains are found by a breadth first search from the path along reversed references, so
+//! that each is as short as possible.
+
+use crate::depgraph::*;
+use bytesize::ByteSize;
+use fixedbitset::FixedBitSet;
+use petgraph::prelude::NodeIndex;
+use petgraph::visit::EdgeRef;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+/// Returns, for each gc-root `target` is reachable from, a shortest chain of references
+/// from this gc-root to `target`, both included. Shortest chains come first, then by label
+/// of the gc-root.
+pub fn chains(di: &DepInfos, target: NodeIndex) -> Vec<Vec<NodeIndex>> {
+    let n = di.graph.node_count();
+    // the node after each node on a shortest chain to target
+    let mut next: Vec<Option<NodeIndex>> = vec![None; n];
+    let mut seen = FixedBitSet::with_capacity(n);
+    seen.insert(target.index());
+    let mut queue = VecDeque::from([target]);
+    let mut roots = Vec::new();
+    while let Some(idx) = queue.pop_front() {
+        if di.graph.contains_edge(di.root, idx) {
+            roots.push(idx);
+        }
+        for e in di.graph.edges_directed(idx, petgraph::Direction::Incoming) {
+            if e.weight().is_synthetic() || seen.put(e.source().index()) {
+                continue;
+            }
+            next[e.source().index()] = Some(idx);
+            queue.push_back(e.source());
+        }
+    }
+    let mut res: Vec<Vec<NodeIndex>> = roots
+        .into_iter()
+        .map(|root| {
+            let mut chain = vec![root];
+            while let Some(n) = next[chain[chain.len() - 1].index()] {
+                chain.push(n);
+            }
+            chain
+        })
+        .collect();
+    res.sort_by_cached_key(|chain| (chain.len(), di.label(chain[0]).into_owned()));
+    res
+}
+
+/// Prints the gc-roots keeping `target` alive, each followed by the chain of references
+/// through which it does.
+pub fn render<W: Write>(di: &DepInfos, target: NodeIndex, w: &mut W) -> io::Result<()> {
+    let chains = chains(di, target);
+    let node = &di.graph[target];
+    w.write_all(
+        node.description
+            .path()
+            .map(Vec::as_slice)
+            .unwrap_or(&di.label(target)),
+    )?;
+    if chains.is_empty() {
+        return writeln!(
+            w,
+            " ({}) is not kept alive by any gc-root",
+            ByteSize::b(node.size)
+        );
+    }
+    writeln!(
+        w,
+        " ({}) is kept alive by {} gc-roots:",
+        ByteSize::b(node.size),
+        chains.len()
+    )?;
+    for chain in chains {
+        w.write_all(&di.label(chain[0]))?;
+        writeln!(w)?;
+        for &idx in &chain[1..] {
+            w.write_all(b"  -> ")?;
+            w.write_all(&di.label(idx))?;
+            writeln!(w)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::why::*;
+
+    #[test]
+    fn check_chains() {
+        let text = br#"{"nodes":[
+            {"id":1,"kind":"Link","path":"/r1","root":true},
+            {"id":2,"kind":"Link","path":"/r2","root":true},
+            {"id":3,"kind":"Link","path":"/r3","root":true},
+            {"id":4,"path":"/nix/store/a-app","size":1},
+            {"id":5,"path":"/nix/store/b-lib","size":1},
+            {"id":6,"path":"/nix/store/c-zlib","size":1000},
+            {"id":7,"path":"/nix/store/d-other","size":1}
+        ],"edges":[[1,4],[4,5],[5,6],[4,6],[2,5],[3,7]]}"#;
+        let di = crate::import::graph(text);
+        let zlib = crate::unlock::find_node(&di, "zlib").unwrap();
+        let mut out = Vec::new();
+        render(&di, zlib, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "/nix/store/c-zlib (1.0 KB) is kept alive by 2 gc-roots:\n\
+             /r1\n  -> app\n  -> zlib\n\
+             /r2\n  -> lib\n  -> zlib\n"
+        );
+    }
+}
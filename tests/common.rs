@@ -583,3 +583,156 @@ dec_test!(
         assert_matches(&real, &expected);
     }
 );
+
+dec_test!(
+    gc_running_abort = |t| {
+        use std::os::unix::io::AsRawFd;
+        dec_spec!(spec = (foo;));
+        prepare_store(&spec, "", &t);
+
+        // pretend a garbage collection is running
+        let lock = fs::File::create(t.path("nixstore/var/nix/gc.lock")).unwrap();
+        assert_eq!(unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) }, 0);
+
+        let status = call_self(&t).arg("--on-gc=abort").status().unwrap();
+        assert_eq!(status.code(), Some(1));
+    }
+);
+
+dec_test!(
+    tree_format = |t| {
+        dec_spec!(spec = (coucou, foo, bar; coucou -> foo, bar -> foo));
+        prepare_store(&spec, "", &t);
+
+        let out = call_self(&t).arg("--format=tree").expect_success();
+        let out = out.stdout_str();
+        println!("{}", out);
+        // foo is needed by both roots, so it is dominated by neither
+        assert_eq!(out.lines().count(), 3);
+        assert!(out
+            .lines()
+            .all(|l| l.starts_with("├── ") || l.starts_with("└── ")));
+    }
+);
+
+dec_test!(
+    digest = |t| {
+        dec_spec!(spec = (coucou, foo, bar; coucou -> foo, bar -> foo));
+        prepare_store(&spec, "", &t);
+
+        let state = t.path("digest.state");
+        let first = call_self(&t)
+            .arg("digest")
+            .arg("--state")
+            .arg(&state)
+            .expect_success();
+        println!("{}", first.stdout_str());
+        assert!(first.stdout_str().contains("(first run)"));
+        assert!(first.stdout_str().contains("Gc-roots: 2,"));
+
+        let second = call_self(&t)
+            .arg("digest")
+            .arg("--state")
+            .arg(&state)
+            .expect_success();
+        println!("{}", second.stdout_str());
+        assert!(second.stdout_str().contains("since the last run"));
+    }
+);
+
+dec_test!(
+    compare_profiles = |t| {
+        dec_spec!(spec = (coucou, foo, bar; coucou -> foo, bar -> foo));
+        prepare_store(&spec, "", &t);
+
+        let out = call_self(&t)
+            .args(&["compare-profiles", "roots/coucou", "roots/bar"])
+            .expect_success();
+        let out = out.stdout_str();
+        println!("{}", out);
+        assert!(out.contains("Shared by both closures: 100."));
+        assert!(out.contains("Only in the closure of roots/coucou: 100."));
+        assert!(out.contains("Only in the closure of roots/bar: 100."));
+    }
+);
+
+dec_test!(
+    csv_format = |t| {
+        dec_spec!(spec = (coucou, foo, bar; coucou -> foo, bar -> foo));
+        prepare_store(&spec, "", &t);
+
+        let out = call_self(&t).arg("--format=csv").expect_success();
+        let out = out.stdout_str();
+        println!("{}", out);
+        assert_eq!(out.lines().count(), 4);
+        // foo is referred to by coucou and bar
+        assert_eq!(
+            out.lines()
+                .filter(|l| l.ends_with(",2,0,false,false"))
+                .count(),
+            1
+        );
+        assert_eq!(
+            out.lines()
+                .filter(|l| l.ends_with(",0,1,false,true"))
+                .count(),
+            2
+        );
+    }
+);
+
+dec_test!(
+    save_and_load_graph = |t| {
+        dec_spec!(spec = (coucou, foo, bar; coucou -> foo, bar -> foo));
+        prepare_store(&spec, "", &t);
+
+        let snapshot = t.path("graph");
+        let saved = call_self(&t)
+            .arg("--format=csv")
+            .arg("--save-graph")
+            .arg(&snapshot)
+            .expect_success();
+        let loaded = call_self(&t)
+            .arg("--format=csv")
+            .arg("--load-graph")
+            .arg(&snapshot)
+            .expect_success();
+        assert_eq!(saved.stdout_str(), loaded.stdout_str());
+    }
+);
+
+dec_test!(
+    anonymize = |t| {
+        dec_spec!(spec = (coucou, foo, bar; coucou -> foo, bar -> foo));
+        prepare_store(&spec, "", &t);
+
+        let plain = call_self(&t).arg("--format=csv").expect_success();
+        let plain = plain.stdout_str();
+        let anonymized = call_self(&t)
+            .arg("--format=csv")
+            .arg("--anonymize")
+            .expect_success();
+        let anonymized = anonymized.stdout_str();
+        println!("{}\n{}", plain, anonymized);
+        let sizes = |out: &str| {
+            let mut sizes: Vec<String> = out
+                .lines()
+                .map(|l| l.split(',').nth(4).unwrap().to_owned())
+                .collect();
+            sizes.sort();
+            sizes
+        };
+        assert_eq!(sizes(plain), sizes(anonymized));
+        for line in plain.lines().skip(1) {
+            let path = line.split(',').nth(3).unwrap();
+            let name = path.rsplit('/').next().unwrap();
+            if name.len() > 32 && name.as_bytes()[32] == b'-' {
+                assert!(
+                    !anonymized.contains(&name[..32]),
+                    "{} was not anonymized",
+                    path
+                );
+            }
+        }
+    }
+);
@@ -6,13 +6,17 @@
 */
 #include <sstream>
 
+#include <algorithm>
+#include <cstring>
 #include <iostream>
+#include <iterator>
 #include <unordered_map>
 #include <nix/config.h> // #define SYSTEM
 #include <nix/util.hh> // restoreSignals
 #include <nix/shared.hh> // initNix
 #include <nix/local-store.hh>
 #include <nix/remote-store.hh>
+#include <nix/thread-pool.hh>
 
 #include "wrapper.hpp"
 
@@ -20,23 +24,29 @@
 #include <nix/gc-store.hh>
 #include <nix/store-cast.hh>
 #define findroots(store) require<GcStore>(*store).findRoots(false)
+#define HAS_GC(store) (dynamic_cast<GcStore*>(&*store) != nullptr)
 #else
 #if NIXVER >= 207
 #include <nix/gc-store.hh>
 #define findroots(store) requireGcStore(*store).findRoots(false)
+#define HAS_GC(store) (dynamic_cast<GcStore*>(&*store) != nullptr)
 #else
 #if NIXVER >= 203
 #define findroots(store) store->findRoots(false)
 #else
 #define findroots(store) store->findRoots()
 #endif
+// before GcStore, the stores which do not support it throw when asked for their roots
+#define HAS_GC(store) true
 #endif
 #endif
 
 #if NIXVER >= 204
 #define PATH StorePath
+#define PATHSET StorePathSet
 #else
 #define PATH Path
+#define PATHSET PathSet
 #endif
 
 #if NIXVER >= 204
@@ -49,6 +59,41 @@
 #define DERIVER_GET(d) d
 #endif
 
+#if NIXVER >= 204
+#define IS_DERIVATION(p) p.isDerivation()
+#else
+#define IS_DERIVATION(p) isDerivation(p)
+#endif
+
+namespace {
+// Runs f on the store at storeUri, or the default store if null, and reports the exception
+// it throws, if any, to error.
+template<typename F> int withStore(const char* storeUri, store_error_t* error, F f) {
+  using namespace nix;
+  error->err_no = 0;
+  error->message = nullptr;
+  int retcode = 0;
+  try {
+    initNix();
+    auto store = storeUri ? openStore(storeUri) : openStore();
+    f(store);
+  } catch (SysError &e) {
+    error->err_no = e.errNo;
+    error->message = strdup(e.what());
+    retcode = 1;
+  } catch (std::exception &e) {
+    error->message = strdup(e.what());
+    retcode = 1;
+  }
+#if NIXVER >= 204
+  restoreProcessContext();
+#else
+  restoreSignals();
+#endif
+  return retcode;
+}
+}
+
 extern "C" {
   typedef struct {
     std::shared_ptr<const nix::ValidPathInfo> data;
@@ -56,32 +101,61 @@ extern "C" {
   } Info;
   extern void register_node(void *graph, path_t *node);
   extern void register_edge(void *graph, unsigned from, unsigned to);
-  int populateGraph(void *graph, const char* rootPath) {
+  // tells that the node index is the store path of the next of rootPaths
+  extern void register_root(void *graph, unsigned index);
+  int populateGraph(void *graph, const char* storeUri, const char** rootPaths, unsigned nroots, int withSystems, int withBuildDeps, unsigned jobs, store_error_t* error) {
     using namespace nix;
-    int retcode = handleExceptions("nix-du", [graph, rootPath]() {
-      initNix();
-      auto store = openStore();
-
+    return withStore(storeUri, error, [&] (ref<Store> store) {
+      if (nroots == 0 && !HAS_GC(store)) {
+        // like the legacy ssh:// store, which can neither list its paths nor its roots
+        throw Error("the store '%s' cannot list its gc-roots: use ssh-ng:// instead of ssh://, or pass the PATHS to measure", store->getUri());
+      }
       std::unordered_map<PATH, Info> node_to_id;
+      // Returns the platform of the derivation which built p, or "" if unknown
+      auto get_system = [&] (const PATH& p, const ValidPathInfo& info) {
+        try {
+          if (IS_DERIVATION(p)) {
+            return store->derivationFromPath(p).platform;
+          }
+          if (!DERIVER_IS_EMPTY(info.deriver) && store->isValidPath(DERIVER_GET(info.deriver))) {
+            return store->derivationFromPath(DERIVER_GET(info.deriver)).platform;
+          }
+        } catch (Error &) {
+          // a corrupted or unreadable derivation, the system remains unknown
+        }
+        return std::string();
+      };
       // Registers the node if it was not already registered, and return its path info
       // Returns: pair of a boolean indicating if it was already visited, and path info
+      // The path info is null if it could not be queried, the node then has no references.
       auto get_infos = [&] (const PATH& p) {
         auto it = node_to_id.find(p);
         if (it==node_to_id.end()) {
-          Info info = {
-            store->queryPathInfo(p).get_ptr(), //data
-            (unsigned)(node_to_id.size()), // index
-          };
+          Info info;
+          info.index = node_to_id.size();
+          std::string failure;
+          try {
+            info.data = store->queryPathInfo(p).get_ptr();
+          } catch (Error &e) {
+            // a permission error or a corrupted entry, the rest of the store can be read
+            failure = e.what();
+          }
           path_t entry;
           entry.is_root = 0;
-          entry.size = info.data->narSize;
+          entry.size = info.data ? info.data->narSize : 0;
 #if NIXVER >= 204
           std::string path = store->storeDir + "/";
           path.append(p.to_string());
 #else
-          std::string path = info.data->path;
+          std::string path = p;
 #endif
           entry.path = path.c_str();
+          std::string system;
+          if (withSystems && info.data) {
+            system = get_system(p, *info.data);
+          }
+          entry.system = system.empty() ? nullptr : system.c_str();
+          entry.error = info.data ? nullptr : failure.c_str();
           node_to_id[p] = info;
           register_node(graph, &entry);
           return std::make_pair(false, info);
@@ -93,55 +167,128 @@ extern "C" {
       // queue for graph traversal
       std::vector<PATH> queue;
       // initialise with either all nodes or just the root we want
-      if (!rootPath) {
+      if (nroots == 0) {
         // dump all the store
         std::set<PATH> paths = store->queryAllValidPaths();
         std::copy(paths.begin(), paths.end(), std::back_inserter(queue));
       } else {
-        // dump only the recursive closure of rootPath
+        // dump only the recursive closures of rootPaths
+        for (unsigned i = 0; i < nroots; ++i) {
 #if NIXVER >= 204
-        const PATH rootDrv = store->followLinksToStorePath(rootPath);
+          const PATH rootDrv = store->followLinksToStorePath(rootPaths[i]);
 #else
-        const Path naiveRootPath(rootPath);
-        const PATH rootDrv = store->followLinksToStorePath(naiveRootPath);
+          const Path naiveRootPath(rootPaths[i]);
+          const PATH rootDrv = store->followLinksToStorePath(naiveRootPath);
 #endif
-        if (!store->isValidPath(rootDrv)) {
-          throw Error("'%s' is not a valid path", rootPath);
+          if (!store->isValidPath(rootDrv)) {
+            throw Error("'%s' is not a valid path", rootPaths[i]);
+          }
+          Info root; bool cached;
+          std::tie(cached, root) = get_infos(rootDrv);
+          register_root(graph, root.index);
+          if (!cached) {
+            queue.push_back(rootDrv);
+          }
         }
-        queue.push_back(rootDrv);
       }
 
+      // fills the path info cache of a remote store with concurrent queries of the paths
+      // not registered yet among paths. Errors are reported when they are registered.
+      const bool prefetch = jobs > 1 && !store.dynamic_pointer_cast<LocalFSStore>();
+      auto prefetch_infos = [&] (const std::set<PATH>& paths) {
+        ThreadPool pool(jobs);
+        for (const PATH& p: paths) {
+          pool.enqueue([&store, p] {
+            try {
+              store->queryPathInfo(p);
+            } catch (Error &) {
+            }
+          });
+        }
+        pool.process();
+      };
+      auto unregistered = [&] (const PATH& p) {
+        return node_to_id.find(p) == node_to_id.end();
+      };
+
       // follow references in graph traversal, register corresponding edges
       while (!queue.empty()) {
-        PATH path = queue.back();
-        queue.pop_back();
-        Info from = get_infos(path).second;
-        // register edges to references
-        for (const PATH& dep: from.data->references) {
-          Info to; bool cached;
-          std::tie(cached, to) = get_infos(dep);
-          register_edge(graph, from.index, to.index);
-          if (!cached) {
-            queue.push_back(dep);
+        // the traversal goes by waves of at most a thousand paths, whose infos and those of
+        // their references are prefetched, few enough to remain in the cache of the store
+        std::vector<PATH> wave;
+        while (!queue.empty() && wave.size() < 1000) {
+          wave.push_back(queue.back());
+          queue.pop_back();
+        }
+        if (prefetch) {
+          std::set<PATH> unknown;
+          std::copy_if(wave.begin(), wave.end(), std::inserter(unknown, unknown.end()), unregistered);
+          prefetch_infos(unknown);
+          unknown.clear();
+          for (const PATH& path: wave) {
+            Info info = get_infos(path).second;
+            if (info.data) {
+              std::copy_if(info.data->references.begin(), info.data->references.end(), std::inserter(unknown, unknown.end()), unregistered);
+            }
           }
+          prefetch_infos(unknown);
         }
-        // register edges from/to drv if this path has a derivation
-        if ((settings.gcKeepOutputs || settings.gcKeepDerivations) && (!DERIVER_IS_EMPTY(from.data->deriver)) && store->isValidPath(DERIVER_GET(from.data->deriver))) {
-          Info drv; bool drv_was_cached;
-          std::tie(drv_was_cached, drv) = get_infos(DERIVER_GET(from.data->deriver));
-          if (settings.gcKeepDerivations) {
-            register_edge(graph, from.index, drv.index);
+        for (const PATH& path: wave) {
+          Info from = get_infos(path).second;
+          if (!from.data) {
+            continue;
+          }
+          // register edges to references
+          for (const PATH& dep: from.data->references) {
+            Info to; bool cached;
+            std::tie(cached, to) = get_infos(dep);
+            register_edge(graph, from.index, to.index);
+            if (!cached) {
+              queue.push_back(dep);
+            }
           }
-          if (settings.gcKeepOutputs) {
-            register_edge(graph, drv.index, from.index);
+          // register edges from/to drv if this path has a derivation
+          if ((settings.gcKeepOutputs || settings.gcKeepDerivations) && (!DERIVER_IS_EMPTY(from.data->deriver)) && store->isValidPath(DERIVER_GET(from.data->deriver))) {
+            Info drv; bool drv_was_cached;
+            std::tie(drv_was_cached, drv) = get_infos(DERIVER_GET(from.data->deriver));
+            if (settings.gcKeepDerivations) {
+              register_edge(graph, from.index, drv.index);
+            }
+            if (settings.gcKeepOutputs) {
+              register_edge(graph, drv.index, from.index);
+            }
+            if (!drv_was_cached) {
+              queue.push_back(DERIVER_GET(from.data->deriver));
+            }
           }
-          if (!drv_was_cached) {
-            queue.push_back(DERIVER_GET(from.data->deriver));
+          // register edges from a derivation to all its outputs, also those whose deriver
+          // is another derivation and those not otherwise reached
+          if (withBuildDeps && settings.gcKeepOutputs && IS_DERIVATION(path)) {
+            PATHSET outputs;
+            try {
+              outputs = store->queryDerivationOutputs(path);
+            } catch (Error &) {
+              // an unreadable derivation, its outputs are only linked through their deriver
+            }
+            for (const PATH& out: outputs) {
+              if (!store->isValidPath(out)) {
+                continue;
+              }
+              Info to; bool cached;
+              std::tie(cached, to) = get_infos(out);
+              // the edge from its own deriver is registered when out is traversed
+              if (!(to.data && !DERIVER_IS_EMPTY(to.data->deriver) && DERIVER_GET(to.data->deriver) == path)) {
+                register_edge(graph, from.index, to.index);
+              }
+              if (!cached) {
+                queue.push_back(out);
+              }
+            }
           }
         }
       }
 
-      if (!rootPath) {
+      if (nroots == 0) {
         // register roots and add edge to corresponding store path
         unsigned index = node_to_id.size();
 #if NIXVER >= 203
@@ -157,6 +304,8 @@ extern "C" {
             entry.is_root = 1;
             entry.size = link.size();
             entry.path = link.c_str();
+            entry.system = nullptr;
+            entry.error = nullptr;
             register_node(graph, &entry);
             Info to = get_infos(storepath).second;
             register_edge(graph, index, to.index);
@@ -166,12 +315,39 @@ extern "C" {
         }
       }
     });
+  }
+
+  int addTextPath(const char* name, const char* contents, const char** references, unsigned nreferences, char** result, store_error_t* error) {
+    using namespace nix;
+    *result = nullptr;
+    return withStore(nullptr, error, [&] (ref<Store> store) {
 #if NIXVER >= 204
-    restoreProcessContext();
+      StorePathSet refs;
+      for (unsigned i = 0; i < nreferences; ++i) {
+        refs.insert(store->parseStorePath(references[i]));
+      }
+      auto path = store->printStorePath(store->addTextToStore(name, contents, refs));
+#else
+      PathSet refs(references, references + nreferences);
+      auto path = store->addTextToStore(name, contents, refs);
+#endif
+      *result = strdup(path.c_str());
+    });
+  }
+
+  int addRoot(const char* target, const char* link, store_error_t* error) {
+    using namespace nix;
+    return withStore(nullptr, error, [&] (ref<Store> store) {
+      auto local = store.dynamic_pointer_cast<LocalFSStore>();
+      if (!local) {
+        throw Error("the store '%s' has no gc-roots", store->getUri());
+      }
+#if NIXVER >= 204
+      local->addPermRoot(store->parseStorePath(target), link);
 #else
-    restoreSignals();
+      local->addPermRoot(target, link, true, true);
 #endif
-    return retcode;
+    });
   }
 }
 
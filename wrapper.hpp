@@ -7,8 +7,34 @@ extern "C" {
     const char* path;
     uint64_t size;
     int is_root;
+    // the platform of the derivation which built the path, or NULL if unknown
+    const char* system;
+    // why the path info could not be queried, or NULL. The path is then registered
+    // without references.
+    const char* error;
   } path_t;
-  int populateGraph(void *graph, const char* rootPath);
+  typedef struct {
+    // the errno of the failed system call, or 0 if the error was not a system error
+    int err_no;
+    // the message of the nix exception, allocated with malloc, or NULL
+    char* message;
+  } store_error_t;
+  // reads the union of the closures of the nroots paths rootPaths, or the whole store if
+  // nroots is 0, in which case gc-roots are registered too
+  // systems of paths are only looked up if withSystems is not 0, as it is slow
+  // up to jobs path infos are queried at once
+  // the store is opened at storeUri, like ssh://host, or is the default one if NULL
+  // if withBuildDeps is not 0, derivations also refer to all their valid outputs when
+  // keep-outputs is set, so that the build-time closures it retains are read
+  // returns 0 on success, and fills error otherwise
+  int populateGraph(void *graph, const char* storeUri, const char** rootPaths, unsigned nroots, int withSystems, int withBuildDeps, unsigned jobs, store_error_t* error);
+  // adds a text file name with contents to the store, referring to the nreferences store
+  // paths references, and writes its store path, allocated with malloc, to result
+  // returns 0 on success, and fills error otherwise
+  int addTextPath(const char* name, const char* contents, const char** references, unsigned nreferences, char** result, store_error_t* error);
+  // makes link an indirect gc-root of the store path target
+  // returns 0 on success, and fills error otherwise
+  int addRoot(const char* target, const char* link, store_error_t* error);
 }
 
 